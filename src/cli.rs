@@ -2,9 +2,9 @@ use std::path::PathBuf;
 
 use clap::{ArgAction, Args, Parser, Subcommand};
 
-use crate::config::Mode;
+use crate::config::{ByteSize, DedupeKey, Mode, Prefer, Since};
 
-#[derive(Debug, Parser)]
+#[derive(Clone, Debug, Parser)]
 #[command(
     name = "w4dj",
     version,
@@ -16,10 +16,11 @@ pub struct Cli {
     #[command(subcommand)]
     pub command: Option<Command>,
 
-    /// One or more files or directories to scan.
+    /// One or more files or directories to scan. Overrides W4DJ_SOURCE and the config file.
     #[arg(
         long,
         short = 'i',
+        visible_alias = "source",
         value_name = "PATH",
         num_args = 1..,
         action = ArgAction::Append
@@ -30,32 +31,167 @@ pub struct Cli {
     #[arg(value_name = "PATH")]
     pub dropped_input: Vec<PathBuf>,
 
-    /// Output directory. Defaults to w4djdump in the platform Music directory.
-    #[arg(long, short = 'o', value_name = "DIR")]
+    /// Output directory. Overrides W4DJ_DEST and the config file; defaults to w4djdump in
+    /// the platform Music directory.
+    #[arg(long, short = 'o', visible_alias = "destination", value_name = "DIR")]
     pub output: Option<PathBuf>,
 
     /// Output profile.
     #[arg(long, short = 'm', value_enum)]
     pub mode: Option<Mode>,
 
-    /// TOML configuration file. Defaults to the platform configuration directory.
-    #[arg(long, short = 'c', value_name = "FILE")]
-    pub config: Option<PathBuf>,
+    /// Configuration file in TOML, or JSON/YAML by extension. Defaults to the platform
+    /// configuration directory. Repeat it to merge several files; later files win.
+    #[arg(long, short = 'c', value_name = "FILE", global = true)]
+    pub config: Vec<PathBuf>,
+
+    /// Show more detail; -v adds status lines, -vv adds the decision for every file.
+    #[arg(long, short = 'v', action = ArgAction::Count, global = true)]
+    pub verbose: u8,
+
+    /// Show less output; -q hides the summary, -qq shows errors only.
+    #[arg(long, short = 'q', action = ArgAction::Count, global = true, conflicts_with = "verbose")]
+    pub quiet: u8,
+
+    /// Number of worker threads. Defaults to the number of logical CPUs; 1 runs sequentially.
+    #[arg(long, short = 'j', value_name = "N")]
+    pub jobs: Option<usize>,
+
+    /// Write at most this many bytes in one run, for example 20G; files that do not fit wait.
+    #[arg(long, value_name = "SIZE")]
+    pub max_bytes: Option<ByteSize>,
+
+    /// Wait for another w4dj run on the same output instead of refusing to start.
+    #[arg(long)]
+    pub wait: bool,
+
+    /// Exit successfully even when some files failed; failures are still listed.
+    #[arg(long)]
+    pub keep_going: bool,
+
+    /// Report sources that are the same song, by identical content or artist and title tags.
+    #[arg(long, value_enum, value_name = "KEY")]
+    pub dedupe_source: Option<DedupeKey>,
+
+    /// Sync only the best copy of each duplicate found with --dedupe-source.
+    #[arg(long)]
+    pub skip_duplicates: bool,
+
+    /// Write an M3U8 playlist of the synced library to this file after the run.
+    #[arg(long, value_name = "FILE")]
+    pub playlist: Option<PathBuf>,
+
+    /// Write a JSON report of the run to this file, even when some files failed.
+    #[arg(long, value_name = "FILE")]
+    pub report: Option<PathBuf>,
+
+    /// Delete synced outputs whose source is no longer in any input.
+    #[arg(long)]
+    pub delete: bool,
+
+    /// Copy audio added directly to the output back into the first input folder.
+    #[arg(long)]
+    pub two_way: bool,
+
+    /// Resolve songs that differ between the output and the inputs during --two-way.
+    #[arg(long, value_enum, value_name = "SIDE")]
+    pub prefer: Option<Prefer>,
+
+    /// Delete each source file once its output was written and validated.
+    #[arg(long = "move")]
+    pub move_sources: bool,
+
+    /// Only sync sources modified within an age such as 24h or after a date such as
+    /// 2024-01-01 (UTC); the output is still checked in full.
+    #[arg(long, value_name = "WHEN")]
+    pub since: Option<Since>,
+
+    /// Re-sync every source even when its output is up to date.
+    #[arg(long)]
+    pub force: bool,
+
+    /// Log why each skipped source was not synced, such as an up-to-date output or a
+    /// better copy of the same song.
+    #[arg(long)]
+    pub explain: bool,
+
+    /// Print the planned actions without writing anything to the output directory.
+    #[arg(long)]
+    pub dry_run: bool,
+
+    /// Write every file into .w4dj-staging in the output and move them into place only if
+    /// the whole run succeeded; otherwise the output is left untouched.
+    #[arg(long)]
+    pub staging: bool,
+
+    /// Start a run that deletes files (--delete, --move or --two-way --prefer) without asking
+    /// for confirmation.
+    #[arg(long, short = 'y')]
+    pub yes: bool,
+
+    /// Keep running after the first sync and re-sync when audio in the inputs changes.
+    #[arg(long)]
+    pub watch: bool,
+
+    /// Run the [[profiles]] entry with this name from the config file; `doctor` checks it.
+    #[arg(long, value_name = "NAME", global = true)]
+    pub profile: Option<String>,
+
+    /// Run every [[profiles]] entry from the config file, one after another.
+    #[arg(long, conflicts_with_all = ["profile", "watch"])]
+    pub all: bool,
+
+    /// Do not print the W4DJ header before syncing; also set W4DJ_NO_BANNER to disable it.
+    #[arg(long)]
+    pub no_banner: bool,
 }
 
-#[derive(Debug, Subcommand)]
+#[derive(Clone, Debug, Subcommand)]
 pub enum Command {
+    /// Write a commented starter config.toml to --config or the standard location.
+    Init(InitArgs),
     /// Check FFmpeg support and optionally install it.
     Doctor(DoctorArgs),
+    /// Manage the FFmpeg build stored next to the w4dj executable.
+    #[command(subcommand)]
+    Ffmpeg(FfmpegCommand),
+}
+
+#[derive(Clone, Debug, Args)]
+pub struct InitArgs {
+    /// Overwrite an existing configuration file.
+    #[arg(long)]
+    pub force: bool,
 }
 
-#[derive(Debug, Args)]
+#[derive(Clone, Debug, Args)]
 pub struct DoctorArgs {
     /// Install FFmpeg with the first supported system package manager.
     #[arg(long)]
     pub install: bool,
 }
 
+#[derive(Clone, Debug, Subcommand)]
+pub enum FfmpegCommand {
+    /// Download a static FFmpeg build into the w4dj executable directory.
+    Fetch(FetchArgs),
+}
+
+#[derive(Clone, Debug, Args)]
+pub struct FetchArgs {
+    /// Archive to download instead of the default build for this platform.
+    #[arg(long, value_name = "URL", requires = "sha256")]
+    pub url: Option<String>,
+
+    /// Expected SHA-256 of the archive. Defaults to the checksum published with the build.
+    #[arg(long, value_name = "HEX")]
+    pub sha256: Option<String>,
+
+    /// Download even when a usable FFmpeg is already installed.
+    #[arg(long)]
+    pub force: bool,
+}
+
 impl Cli {
     pub fn take_inputs(&mut self) -> Vec<PathBuf> {
         self.input.append(&mut self.dropped_input);
@@ -97,4 +233,26 @@ mod tests {
         ));
         assert!(cli.dropped_input.is_empty());
     }
+
+    #[test]
+    fn ffmpeg_fetch_requires_a_checksum_for_custom_urls() {
+        assert!(
+            Cli::try_parse_from([
+                "w4dj",
+                "ffmpeg",
+                "fetch",
+                "--url",
+                "https://example.com/f.zip"
+            ])
+            .is_err()
+        );
+        let cli = Cli::try_parse_from(["w4dj", "ffmpeg", "fetch", "--force"]).unwrap();
+        assert!(matches!(
+            cli.command,
+            Some(Command::Ffmpeg(FfmpegCommand::Fetch(FetchArgs {
+                force: true,
+                ..
+            })))
+        ));
+    }
 }
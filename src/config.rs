@@ -1,17 +1,25 @@
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashSet};
 use std::env;
+use std::fmt;
 use std::fs;
 use std::io::Write;
 use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use anyhow::{Context, Result, bail};
 use clap::ValueEnum;
 use directories::{BaseDirs, UserDirs};
-use serde::{Deserialize, Serialize};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use indicatif::ProgressStyle;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use tempfile::NamedTempFile;
 
 use crate::cli::Cli;
 
+/// Overrides the inputs from the config file; several paths are separated like `PATH`.
+const SOURCE_ENV: &str = "W4DJ_SOURCE";
+/// Overrides the output directory from the config file; the whole value is one path.
+const DEST_ENV: &str = "W4DJ_DEST";
 pub const DEFAULT_WINDOW_OPACITY: f32 = 0.84;
 pub const MIN_WINDOW_OPACITY: f32 = 0.55;
 pub const MAX_WINDOW_OPACITY: f32 = 1.0;
@@ -35,6 +43,84 @@ pub enum Mode {
     #[value(alias = "legacy")]
     Mp3,
     Wav,
+    /// AAC in an `.m4a` container.
+    #[serde(alias = "m4a")]
+    #[value(alias = "m4a")]
+    Aac,
+    Opus,
+}
+
+/// What makes two sources with different IDs the same song for `dedupe_source`.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum DedupeKey {
+    /// Identical file content.
+    Hash,
+    /// The same artist and title tags, ignoring case and spacing.
+    Tags,
+}
+
+/// What identifies a song that has no platform ID and no W4DJ_ID tag.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MatchBy {
+    /// Title, artist, album and duration; the file name stands in for a missing title.
+    #[default]
+    Tags,
+    /// The file name without its extension.
+    Stem,
+    /// The path below the input folder without its extension, so songs with the same file
+    /// name in different folders stay apart.
+    Relpath,
+}
+
+/// How sources that need no conversion are placed in the output.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Link {
+    /// Write an independent copy.
+    #[default]
+    Copy,
+    /// Hard-link the source when it is on the same filesystem, and copy it otherwise.
+    Hard,
+}
+
+/// Which copy wins when `--two-way` finds a song changed on both sides.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, ValueEnum)]
+pub enum Prefer {
+    /// Keep the input and remove the file added to the output.
+    Source,
+    /// Replace the input with the file added to the output.
+    Dest,
+    /// Keep whichever file was modified last.
+    Newer,
+}
+
+/// What a `[rules]` entry does with sources of one extension, instead of following `mode`.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RuleAction {
+    /// Keep an unencrypted source as it is.
+    Copy,
+    /// Re-encode with the configured mode, or to MP3 when the mode is `original`.
+    Transcode,
+    /// Decrypt an NCM, QMC or KGM source to its native format.
+    Dump,
+}
+
+/// How a source is compared with the variant that produced the current output.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Compare {
+    /// Compare whole file sizes.
+    #[default]
+    Size,
+    /// Compare file sizes after subtracting tags and embedded artwork.
+    PayloadSize,
+    /// Re-sync whenever the source content hash changed.
+    Hash,
+    /// Re-sync when title, artist, album or cover presence differ from the output's tags.
+    Tags,
 }
 
 impl Mode {
@@ -43,6 +129,8 @@ impl Mode {
             Self::Original => "original-v1",
             Self::Mp3 => "mp3-q2-v1",
             Self::Wav => "wav-pcm16-v1",
+            Self::Aac => "aac-256k-v1",
+            Self::Opus => "opus-160k-v1",
         }
     }
 
@@ -51,6 +139,8 @@ impl Mode {
             Self::Original => source_format,
             Self::Mp3 => "mp3",
             Self::Wav => "wav",
+            Self::Aac => "m4a",
+            Self::Opus => "opus",
         }
     }
 
@@ -59,11 +149,649 @@ impl Mode {
     }
 }
 
+/// A byte count written as a plain integer or with a binary unit such as `20G` or `512MiB`.
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub struct ByteSize(pub u64);
+
+impl std::str::FromStr for ByteSize {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        let value = value.trim();
+        let split = value
+            .find(|character: char| !(character.is_ascii_digit() || character == '.'))
+            .unwrap_or(value.len());
+        let (number, unit) = value.split_at(split);
+        let number: f64 = number
+            .parse()
+            .with_context(|| format!("invalid size {value:?}; use a value such as 500M or 20G"))?;
+        let multiplier: u64 = match unit.trim().to_ascii_lowercase().as_str() {
+            "" | "b" => 1,
+            "k" | "kb" | "kib" => 1 << 10,
+            "m" | "mb" | "mib" => 1 << 20,
+            "g" | "gb" | "gib" => 1 << 30,
+            "t" | "tb" | "tib" => 1 << 40,
+            other => bail!("unknown size unit {other:?} in {value:?}; use K, M, G, or T"),
+        };
+        Ok(Self((number * multiplier as f64).round() as u64))
+    }
+}
+
+impl fmt::Display for ByteSize {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+        let mut value = self.0 as f64;
+        let mut unit = 0;
+        while value >= 1024.0 && unit + 1 < UNITS.len() {
+            value /= 1024.0;
+            unit += 1;
+        }
+        if unit == 0 {
+            write!(formatter, "{} B", self.0)
+        } else {
+            write!(formatter, "{value:.1} {}", UNITS[unit])
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for ByteSize {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Bytes(u64),
+            Text(String),
+        }
+
+        match Raw::deserialize(deserializer)? {
+            Raw::Bytes(bytes) => Ok(Self(bytes)),
+            Raw::Text(text) => text.parse().map_err(serde::de::Error::custom),
+        }
+    }
+}
+
+impl Serialize for ByteSize {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64(self.0)
+    }
+}
+
+/// A modification time cutoff, written as an age such as `24h` or `7d` or as a
+/// `YYYY-MM-DD` date, which starts at midnight UTC.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Since {
+    Ago(Duration),
+    Date(SystemTime),
+}
+
+impl Since {
+    /// The oldest modification time that is still synced when the run starts at `now`.
+    pub fn cutoff(self, now: SystemTime) -> SystemTime {
+        match self {
+            Self::Ago(age) => now.checked_sub(age).unwrap_or(UNIX_EPOCH),
+            Self::Date(date) => date,
+        }
+    }
+}
+
+impl std::str::FromStr for Since {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        let value = value.trim();
+        if let Some(date) = parse_date(value) {
+            return Ok(Self::Date(date));
+        }
+        let split = value
+            .find(|character: char| !character.is_ascii_digit())
+            .unwrap_or(value.len());
+        let (number, unit) = value.split_at(split);
+        let number: u64 = number.parse().with_context(|| {
+            format!("invalid time {value:?}; use an age such as 24h or a date such as 2024-01-01")
+        })?;
+        let seconds: u64 = match unit.trim().to_ascii_lowercase().as_str() {
+            "s" => 1,
+            "m" | "min" => 60,
+            "h" => 60 * 60,
+            "d" => 24 * 60 * 60,
+            "w" => 7 * 24 * 60 * 60,
+            other => bail!("unknown time unit {other:?} in {value:?}; use s, m, h, d, or w"),
+        };
+        Ok(Self::Ago(Duration::from_secs(
+            number.saturating_mul(seconds),
+        )))
+    }
+}
+
+/// Midnight UTC of a `YYYY-MM-DD` date.
+fn parse_date(value: &str) -> Option<SystemTime> {
+    let mut parts = value.splitn(3, '-');
+    let (year, month, day) = (parts.next()?, parts.next()?, parts.next()?);
+    if year.len() != 4 || month.len() != 2 || day.len() != 2 {
+        return None;
+    }
+    let (year, month, day) = (
+        year.parse::<i64>().ok()?,
+        month.parse::<i64>().ok()?,
+        day.parse::<i64>().ok()?,
+    );
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+    // Days since 1970-01-01 in the proleptic Gregorian calendar, counting years from March
+    // so that the leap day is the last day of the year.
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146_097 + day_of_era - 719_468;
+    let seconds = u64::try_from(days).ok()? * 24 * 60 * 60;
+    Some(UNIX_EPOCH + Duration::from_secs(seconds))
+}
+
+/// An audio bitrate in kbit/s, written as `192k` or a plain number.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Bitrate(pub u32);
+
+impl std::str::FromStr for Bitrate {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        let trimmed = value.trim();
+        let number = trimmed.strip_suffix(['k', 'K']).unwrap_or(trimmed).trim();
+        let kbps = number
+            .parse()
+            .with_context(|| format!("invalid bitrate {value:?}; use a value such as 192k"))?;
+        Ok(Self(kbps))
+    }
+}
+
+impl<'de> Deserialize<'de> for Bitrate {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Kbps(u32),
+            Text(String),
+        }
+
+        match Raw::deserialize(deserializer)? {
+            Raw::Kbps(kbps) => Ok(Self(kbps)),
+            Raw::Text(text) => text.parse().map_err(serde::de::Error::custom),
+        }
+    }
+}
+
+impl Serialize for Bitrate {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&format!("{}k", self.0))
+    }
+}
+
+/// LAME settings used by the MP3 mode.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Mp3Encoding {
+    /// Variable bitrate quality from 0 (best) to 9.
+    Vbr(u8),
+    /// Constant bitrate in kbit/s.
+    Cbr(u32),
+}
+
+pub const DEFAULT_MP3_QUALITY: u8 = 2;
+/// A same-format source must be this much larger, as a fraction, to replace an output.
+pub const DEFAULT_SIZE_TOLERANCE: f64 = 0.05;
+/// Bytes read and written at a time when copying or decrypting a file.
+pub const DEFAULT_COPY_BUFFER: u64 = 64 * 1024;
+/// indicatif template of the file count bar in the command-line progress display.
+pub const DEFAULT_PROGRESS_TEMPLATE: &str =
+    "{spinner:.green} [{elapsed_precise}] [{bar:36.cyan/blue}] {pos}/{len} {msg}";
+
+/// Synchronization settings shared by the configuration file, the CLI and the GUI.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(default)]
+pub struct SyncOptions {
+    /// Worker threads; unset uses one per logical CPU and 1 runs sequentially.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub jobs: Option<usize>,
+    /// Write at most this many bytes in one run; files that do not fit wait for the next.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_bytes: Option<ByteSize>,
+    /// Warn before a run that would leave less than this much free space on the output drive.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub free_space_margin: Option<ByteSize>,
+    /// Bytes read and written at a time per copy; larger buffers mean fewer round trips on
+    /// network drives.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub copy_buffer: Option<ByteSize>,
+    /// Cap on the write throughput of all workers together, in megabits per second.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_mbps: Option<f64>,
+    /// LAME VBR quality for MP3 output, 0 (best) to 9.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mp3_quality: Option<u8>,
+    /// Constant MP3 bitrate such as `192k`; cannot be combined with `mp3_quality`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mp3_bitrate: Option<Bitrate>,
+    /// In MP3 mode, copy MP3 sources at or below this bitrate instead of re-encoding them.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub transcode_above_bitrate: Option<Bitrate>,
+    /// Highest sample rate in Hz that transcodes may write; faster sources are resampled.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_sample_rate: Option<u32>,
+    /// Highest bit depth the device plays. Transcodes never exceed 16 bits, so this only
+    /// flags lossless sources that are copied unchanged.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_bit_depth: Option<u8>,
+    /// JPEG quality from 1 to 100 used when cover art has to be re-encoded.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cover_jpeg_quality: Option<u8>,
+    /// FFmpeg binary to use instead of searching next to w4dj and in `PATH`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ffmpeg_path: Option<PathBuf>,
+    /// Folder for decrypted audio that is transcoded afterwards, instead of the output folder.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temp_dir: Option<PathBuf>,
+    /// Folder that keeps the audio extracted from zip archives between runs, instead of the
+    /// system cache folder.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub archive_cache: Option<PathBuf>,
+    /// Longest edge in pixels for embedded covers; larger covers are scaled down to JPEG.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_cover_size: Option<u32>,
+    /// Extra attempts for files that fail with a transient I/O error.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retries: Option<u32>,
+    /// Read copied and decrypted audio back and compare hashes before publishing it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub verify: Option<bool>,
+    /// Decode FLAC sources with FFmpeg before syncing them so truncated files fail; slow.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub verify_flac: Option<bool>,
+    /// Finish with success even when some files failed; failures are still reported.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub keep_going: Option<bool>,
+    /// Size comparison used to decide whether a source upgrades an existing output.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub compare: Option<Compare>,
+    /// Identity of songs without a platform ID or W4DJ_ID tag.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub match_by: Option<MatchBy>,
+    /// Report sources that are the same song under this key.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dedupe_source: Option<DedupeKey>,
+    /// Sync only the best copy of each duplicate group reported by `dedupe_source`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub skip_duplicates: Option<bool>,
+    /// Most FFmpeg processes to run at once; copies still use every worker thread.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_transcodes: Option<usize>,
+    /// Integrated loudness in LUFS that re-encoded outputs are normalized to; copies are
+    /// never touched.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target_lufs: Option<f64>,
+    /// Measure every output with FFmpeg and tag its ReplayGain track gain and peak, leaving
+    /// the audio untouched.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub replaygain: Option<bool>,
+    /// Fraction a same-format source must grow by before it replaces the output; 0.05 by default.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size_tolerance: Option<f64>,
+    /// indicatif template for the command-line progress bar, for example to fit a narrow terminal.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub progress_template: Option<String>,
+    /// Seconds a single file may run before a "still working" notice; 0 disables it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub watchdog_secs: Option<u64>,
+    /// Wait for another run on the same output to finish instead of failing.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub wait_for_lock: Option<bool>,
+    /// Recreate each file's folder path below its input folder in the output.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preserve_structure: Option<bool>,
+    /// Path of new outputs built from tags, such as `{artist}/{album}/{title}`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub organize: Option<String>,
+    /// Descend into symlinked folders and pick up symlinked files while scanning inputs.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub follow_symlinks: Option<bool>,
+    /// Ignore files and folders whose name starts with a dot while scanning inputs.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub skip_hidden: Option<bool>,
+    /// Write embedded lyrics to a `.lrc` file next to each output.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub export_lyrics: Option<bool>,
+    /// Fill tags a source lacks from a `<stem>.json` or `<stem>.cue` next to it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sidecar_metadata: Option<bool>,
+    /// Save each album's cover as `cover.jpg` in its output folder; needs
+    /// `preserve_structure` or `organize` so that folders hold a single album.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub extract_cover: Option<bool>,
+    /// Also keep the untranscoded audio of every transcoded song under `.w4dj-originals/` in
+    /// the output, decrypted and at the same relative path.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub keep_original: Option<bool>,
+    /// Hard-link untranscoded sources into the output instead of copying them, where the
+    /// filesystem allows it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub link: Option<Link>,
+    /// Remove every tag and embedded cover from outputs, keeping only the track ID that
+    /// w4dj uses to recognize them.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub strip_metadata: Option<bool>,
+    /// Replace characters FAT32 and exFAT reject and shorten long names in new output paths.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sanitize_filenames: Option<bool>,
+    /// Per-extension overrides of `mode`, keyed by extension without the dot.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub rules: BTreeMap<String, RuleAction>,
+    /// Source extensions to sync, without the dot; empty syncs every supported format.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub extensions: Vec<String>,
+    /// Globs matched against paths relative to an input folder; when set, only matches are synced.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub include: Vec<String>,
+    /// Globs matched against paths relative to an input folder; matches are never synced.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub exclude: Vec<String>,
+    /// Write an M3U8 playlist of the synced library to this file after each successful run.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub playlist: Option<PathBuf>,
+    /// List only the files written by the run in the playlist instead of the whole library.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub playlist_new_only: Option<bool>,
+    /// Write a JSON report of every action and error to this file after each run.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub report: Option<PathBuf>,
+    /// Delete outputs whose source disappeared; only set from the command line so that
+    /// one-off GUI drops can never remove the rest of a library.
+    #[serde(skip)]
+    pub delete: bool,
+    /// Send outputs removed by `--delete` or replaced by a better source to the system trash.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub use_trash: Option<bool>,
+    /// Copy audio added directly to the output back into the first input folder; only set
+    /// from the command line.
+    #[serde(skip)]
+    pub two_way: bool,
+    /// How `two_way` resolves a song that differs between the output and the inputs.
+    #[serde(skip)]
+    pub prefer: Option<Prefer>,
+    /// Remove each source once its output was written and validated; only set from the
+    /// command line.
+    #[serde(skip)]
+    pub move_sources: bool,
+    /// Only sync sources modified after this time; only set from the command line.
+    #[serde(skip)]
+    pub since: Option<Since>,
+    /// Rewrite every output, including up-to-date ones; only set from the command line.
+    #[serde(skip)]
+    pub force: bool,
+    /// Log why every skipped source was not synced; only set from the command line.
+    #[serde(skip)]
+    pub explain: bool,
+    /// Plan the run without writing to the output; only set from the command line.
+    #[serde(skip)]
+    pub dry_run: bool,
+    /// Write into a staging folder in the output and move everything into place only if
+    /// the whole run succeeded; only set from the command line.
+    #[serde(skip)]
+    pub staging: bool,
+    /// Start runs that delete files without asking; only set from the command line.
+    #[serde(skip)]
+    pub yes: bool,
+}
+
+impl SyncOptions {
+    pub fn validate(&self) -> Result<()> {
+        if self.move_sources && self.delete {
+            bail!(
+                "--move cannot be combined with --delete: moved sources would make their outputs look orphaned"
+            );
+        }
+        if self.move_sources && self.staging {
+            bail!(
+                "--move cannot be combined with --staging: sources would be gone before the run is promoted"
+            );
+        }
+        if self.prefer.is_some() && !self.two_way {
+            bail!("--prefer only applies to --two-way");
+        }
+        if self.since.is_some() && self.delete {
+            bail!(
+                "--since cannot be combined with --delete: older sources would make their outputs look orphaned"
+            );
+        }
+        if self.skip_duplicates == Some(true) && self.dedupe_source.is_none() {
+            bail!("skip_duplicates needs dedupe_source to say how duplicates are found");
+        }
+        if self.jobs == Some(0) {
+            bail!("jobs must be at least 1");
+        }
+        if self.max_transcodes == Some(0) {
+            bail!("max_transcodes must be at least 1");
+        }
+        if let Some(mbps) = self.max_mbps
+            && !(mbps.is_finite() && mbps > 0.0)
+        {
+            bail!("max_mbps must be greater than 0, got {mbps}");
+        }
+        if let Some(quality) = self.cover_jpeg_quality
+            && !(1..=100).contains(&quality)
+        {
+            bail!("cover_jpeg_quality must be between 1 and 100, got {quality}");
+        }
+        if let Some(tolerance) = self.size_tolerance
+            && !(0.0..=1.0).contains(&tolerance)
+        {
+            bail!("size_tolerance must be between 0.0 and 1.0, got {tolerance}");
+        }
+        if let Some(lufs) = self.target_lufs
+            && !(-70.0..=-5.0).contains(&lufs)
+        {
+            bail!("target_lufs must be between -70 and -5, got {lufs}");
+        }
+        if let Some(size) = self.copy_buffer
+            && !(4 << 10..=64 << 20).contains(&size.0)
+        {
+            bail!("copy_buffer must be between 4K and 64M, got {size}");
+        }
+        if self.max_cover_size == Some(0) {
+            bail!("max_cover_size must be at least 1 pixel");
+        }
+        if let Some(rate) = self.max_sample_rate
+            && !(8_000..=192_000).contains(&rate)
+        {
+            bail!("max_sample_rate must be between 8000 and 192000 Hz, got {rate}");
+        }
+        if let Some(depth) = self.max_bit_depth
+            && !matches!(depth, 16 | 24)
+        {
+            bail!("max_bit_depth must be 16 or 24, got {depth}");
+        }
+        match (self.mp3_quality, self.mp3_bitrate) {
+            (Some(_), Some(_)) => {
+                bail!("set either mp3_quality (VBR) or mp3_bitrate (CBR), not both")
+            }
+            (Some(quality), None) if quality > 9 => {
+                bail!("mp3_quality must be between 0 and 9, got {quality}")
+            }
+            (None, Some(Bitrate(kbps))) if !(8..=320).contains(&kbps) => {
+                bail!("mp3_bitrate must be between 8k and 320k, got {kbps}k")
+            }
+            _ => {}
+        }
+        if let Some(path) = &self.ffmpeg_path
+            && !path.is_file()
+        {
+            bail!("ffmpeg_path {} does not exist", path.display());
+        }
+        if let Some(path) = &self.temp_dir
+            && !path.is_dir()
+        {
+            bail!("temp_dir {} is not a folder", path.display());
+        }
+        if let Some(template) = &self.organize {
+            if self.preserve_structure == Some(true) {
+                bail!("set either organize or preserve_structure, not both");
+            }
+            validate_organize_template(template)?;
+        }
+        if self.strip_metadata == Some(true) {
+            let cover_options = [
+                ("cover_jpeg_quality", self.cover_jpeg_quality.is_some()),
+                ("max_cover_size", self.max_cover_size.is_some()),
+                ("extract_cover", self.extract_cover == Some(true)),
+            ];
+            if let Some((name, _)) = cover_options.iter().find(|(_, set)| *set) {
+                bail!("strip_metadata removes covers, so it cannot be combined with {name}");
+            }
+            // Stripped outputs never carry the tags that compare = "tags" looks for.
+            if self.compare == Some(Compare::Tags) {
+                bail!(
+                    "strip_metadata removes the tags that compare = \"tags\" checks; use another compare"
+                );
+            }
+        }
+        if self.extract_cover == Some(true)
+            && self.organize.is_none()
+            && self.preserve_structure != Some(true)
+        {
+            bail!(
+                "extract_cover needs preserve_structure or organize to give albums their own folders"
+            );
+        }
+        for (extension, action) in &self.rules {
+            let extension = extension.trim_start_matches('.').to_ascii_lowercase();
+            let sample = PathBuf::from(format!("rule.{extension}"));
+            if !crate::sync::is_supported(&sample) {
+                bail!("rules: .{extension} is not a supported audio extension");
+            }
+            match action {
+                RuleAction::Copy if crate::dump::is_encrypted(&sample) => bail!(
+                    "rules: .{extension} files are encrypted and cannot be copied; use \"dump\""
+                ),
+                RuleAction::Dump if !crate::dump::is_encrypted(&sample) => bail!(
+                    "rules: only NCM, QMC and KGM files can be dumped; use \"copy\" for .{extension}"
+                ),
+                _ => {}
+            }
+        }
+        for extension in &self.extensions {
+            let extension = extension.trim_start_matches('.').to_ascii_lowercase();
+            if !crate::sync::AUDIO_EXTENSIONS.contains(&extension.as_str())
+                && !crate::dump::QMC_EXTENSIONS.contains(&extension.as_str())
+                && !crate::dump::KGM_EXTENSIONS.contains(&extension.as_str())
+            {
+                bail!("extensions: .{extension} is not a format w4dj can sync");
+            }
+        }
+        self.progress_style()?;
+        PathFilter::new(self)?;
+        Ok(())
+    }
+
+    pub fn size_tolerance(&self) -> f64 {
+        self.size_tolerance.unwrap_or(DEFAULT_SIZE_TOLERANCE)
+    }
+
+    pub fn copy_buffer_size(&self) -> usize {
+        self.copy_buffer
+            .map_or(DEFAULT_COPY_BUFFER, |size| size.0)
+            .try_into()
+            .unwrap_or(usize::MAX)
+    }
+
+    pub fn progress_style(&self) -> Result<ProgressStyle> {
+        let template = self
+            .progress_template
+            .as_deref()
+            .unwrap_or(DEFAULT_PROGRESS_TEMPLATE);
+        ProgressStyle::with_template(template)
+            .with_context(|| format!("progress_template {template:?} is not a valid template"))
+    }
+
+    pub fn mp3_encoding(&self) -> Mp3Encoding {
+        match self.mp3_bitrate {
+            Some(Bitrate(kbps)) => Mp3Encoding::Cbr(kbps),
+            None => Mp3Encoding::Vbr(self.mp3_quality.unwrap_or(DEFAULT_MP3_QUALITY)),
+        }
+    }
+}
+
+/// Include and exclude globs applied to files found inside input folders, and the
+/// extension allowlist applied to every source.
+#[derive(Debug)]
+pub struct PathFilter {
+    include: Option<GlobSet>,
+    exclude: GlobSet,
+    extensions: Option<HashSet<String>>,
+}
+
+impl PathFilter {
+    pub fn new(options: &SyncOptions) -> Result<Self> {
+        let include = if options.include.is_empty() {
+            None
+        } else {
+            Some(build_glob_set("include", &options.include)?)
+        };
+        let extensions = (!options.extensions.is_empty()).then(|| {
+            options
+                .extensions
+                .iter()
+                .map(|extension| extension.trim_start_matches('.').to_ascii_lowercase())
+                .collect()
+        });
+        Ok(Self {
+            include,
+            exclude: build_glob_set("exclude", &options.exclude)?,
+            extensions,
+        })
+    }
+
+    /// Whether `path` has an extension listed in `extensions`. Archives are let through so
+    /// their entries can be checked once extracted.
+    pub fn allows_extension(&self, path: &Path) -> bool {
+        let Some(extensions) = &self.extensions else {
+            return true;
+        };
+        path.extension()
+            .and_then(|extension| extension.to_str())
+            .is_some_and(|extension| {
+                let extension = extension.to_ascii_lowercase();
+                extension == "zip" || extensions.contains(&extension)
+            })
+    }
+
+    /// Exclude wins when a path matches both lists.
+    pub fn allows(&self, relative: &Path) -> bool {
+        !self.exclude.is_match(relative)
+            && self
+                .include
+                .as_ref()
+                .is_none_or(|include| include.is_match(relative))
+    }
+}
+
+fn build_glob_set(field: &str, patterns: &[String]) -> Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        builder.add(
+            Glob::new(pattern).with_context(|| format!("invalid {field} pattern {pattern:?}"))?,
+        );
+    }
+    builder
+        .build()
+        .with_context(|| format!("invalid {field} patterns"))
+}
+
 #[derive(Clone, Debug)]
 pub struct Config {
     pub inputs: Vec<PathBuf>,
     pub output: PathBuf,
     pub mode: Mode,
+    pub options: SyncOptions,
 }
 
 #[derive(Clone, Debug)]
@@ -74,27 +802,361 @@ pub struct EditableConfig {
     pub mode: Mode,
     pub theme: GuiTheme,
     pub window_opacity: f32,
+    pub options: SyncOptions,
     default_output: PathBuf,
+    /// Kept only so that saving from the GUI does not drop them.
+    profiles: Vec<FileProfile>,
 }
 
-#[derive(Debug, Default, Deserialize)]
+#[derive(Clone, Debug, Default, Deserialize)]
 struct FileConfig {
     #[serde(default, alias = "input", alias = "source")]
     inputs: Option<OneOrManyPaths>,
     #[serde(alias = "destination")]
     output: Option<PathBuf>,
-    mode: Option<Mode>,
+    /// Kept as text so a misspelled mode gets a suggestion instead of a serde error.
+    mode: Option<String>,
+    #[serde(flatten)]
+    options: SyncOptions,
     #[serde(default)]
     gui: FileGuiConfig,
+    #[serde(default)]
+    profiles: Vec<FileProfile>,
+}
+
+/// A named `[[profiles]]` entry, run with `--profile` or `--all`. Anything it sets replaces
+/// the top-level value of the same key; the rest is inherited.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct FileProfile {
+    name: String,
+    #[serde(
+        default,
+        alias = "input",
+        alias = "source",
+        skip_serializing_if = "Option::is_none"
+    )]
+    inputs: Option<OneOrManyPaths>,
+    #[serde(alias = "destination", skip_serializing_if = "Option::is_none")]
+    output: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mode: Option<String>,
+    #[serde(flatten)]
+    options: SyncOptions,
+}
+
+impl FileConfig {
+    /// Checks what parsing cannot: blank paths, the mode name and option ranges. Every
+    /// problem is listed in one error so the file can be fixed in a single pass.
+    fn validate(&self) -> Result<()> {
+        let mut problems = Vec::new();
+        if self.inputs.as_ref().is_some_and(|inputs| {
+            inputs
+                .as_slice()
+                .iter()
+                .any(|path| path.as_os_str().is_empty())
+        }) {
+            problems.push("inputs contains an empty path".to_string());
+        }
+        if self
+            .output
+            .as_ref()
+            .is_some_and(|path| path.as_os_str().is_empty())
+        {
+            problems
+                .push("output is empty; remove it to use the default output folder".to_string());
+        }
+        if let Err(error) = self.mode() {
+            problems.push(error.to_string());
+        }
+        if let Err(error) = self.options.validate() {
+            problems.push(format!("{error:#}"));
+        }
+        let mut names = HashSet::new();
+        for profile in &self.profiles {
+            if profile.name.trim().is_empty() {
+                problems.push("a profile has an empty name".to_string());
+            } else if !names.insert(profile.name.as_str()) {
+                problems.push(format!("profile {} is defined twice", profile.name));
+            }
+            if let Some(Err(error)) = profile.mode.as_deref().map(parse_mode) {
+                problems.push(format!("profile {}: {error}", profile.name));
+            }
+            if let Err(error) = profile.options.validate() {
+                problems.push(format!("profile {}: {error:#}", profile.name));
+            }
+        }
+        match problems.as_slice() {
+            [] => Ok(()),
+            [problem] => bail!("{problem}"),
+            problems => bail!("invalid configuration:\n  - {}", problems.join("\n  - ")),
+        }
+    }
+
+    fn mode(&self) -> Result<Option<Mode>> {
+        self.mode.as_deref().map(parse_mode).transpose()
+    }
+
+    /// Makes the paths that are relative to this file absolute, so they keep pointing at the
+    /// same place once files from other folders are merged in.
+    fn rebase(mut self, config_dir: &Path) -> Self {
+        self.inputs = self.inputs.map(|inputs| rebase_paths(config_dir, inputs));
+        self.output = self.output.map(|path| rebase_path(config_dir, path));
+        rebase_options(config_dir, &mut self.options);
+        for profile in &mut self.profiles {
+            profile.inputs = profile
+                .inputs
+                .take()
+                .map(|inputs| rebase_paths(config_dir, inputs));
+            profile.output = profile
+                .output
+                .take()
+                .map(|path| rebase_path(config_dir, path));
+            rebase_options(config_dir, &mut profile.options);
+        }
+        self
+    }
+
+    /// Lays a later `--config` file over this one: every key it sets wins, and a profile
+    /// with the name of an earlier one is merged into it the same way.
+    fn merge(self, later: Self) -> Result<Self> {
+        let mut profiles = self.profiles;
+        for profile in later.profiles {
+            match profiles
+                .iter_mut()
+                .find(|existing| existing.name == profile.name)
+            {
+                Some(existing) => {
+                    existing.inputs = profile.inputs.or(existing.inputs.take());
+                    existing.output = profile.output.or(existing.output.take());
+                    existing.mode = profile.mode.or(existing.mode.take());
+                    existing.options = merge_options(&existing.options, &profile.options)?;
+                }
+                None => profiles.push(profile),
+            }
+        }
+        Ok(Self {
+            inputs: later.inputs.or(self.inputs),
+            output: later.output.or(self.output),
+            mode: later.mode.or(self.mode),
+            options: merge_options(&self.options, &later.options)?,
+            gui: FileGuiConfig {
+                theme: later.gui.theme.or(self.gui.theme),
+                opacity: later.gui.opacity.or(self.gui.opacity),
+            },
+            profiles,
+        })
+    }
+
+    /// The top-level settings with those of the profile called `name` laid over them.
+    fn with_profile(self, name: &str) -> Result<Self> {
+        let Some(profile) = self.profiles.iter().find(|profile| profile.name == name) else {
+            let names = self
+                .profiles
+                .iter()
+                .map(|profile| profile.name.as_str())
+                .collect::<Vec<_>>();
+            if names.is_empty() {
+                bail!("unknown profile {name}; the config file has no [[profiles]]");
+            }
+            bail!(
+                "unknown profile {name}; expected one of {}",
+                names.join(", ")
+            );
+        };
+        let profile = profile.clone();
+        Ok(Self {
+            inputs: profile.inputs.or(self.inputs),
+            output: profile.output.or(self.output),
+            mode: profile.mode.or(self.mode),
+            options: merge_options(&self.options, &profile.options)?,
+            gui: self.gui,
+            profiles: Vec::new(),
+        })
+    }
+}
+
+fn ffmpeg_needed(mode: Mode, options: &SyncOptions) -> bool {
+    mode.needs_ffmpeg()
+        || options.verify_flac == Some(true)
+        || options.replaygain == Some(true)
+        || options
+            .rules
+            .values()
+            .any(|action| *action == RuleAction::Transcode)
+}
+
+/// What `w4dj doctor` checks this machine against.
+#[derive(Clone, Debug)]
+pub struct Requirements {
+    pub mode: Mode,
+    /// Whether the mode, the rules or an option such as `verify_flac` run FFmpeg.
+    pub ffmpeg: bool,
+    /// The format FFmpeg encodes to, if the mode or a transcode rule re-encodes.
+    pub encodes: Option<Mode>,
+    pub ffmpeg_path: Option<PathBuf>,
+}
+
+impl Requirements {
+    /// Reads only the config files, `--profile` and `--mode`, so a machine without its
+    /// music folders attached can still be checked.
+    pub fn resolve(mut cli: Cli) -> Result<Self> {
+        let (config_path, file_config) = load_cli_config(&mut cli)?;
+        let file_config = match cli.profile.take() {
+            Some(name) => file_config
+                .with_profile(&name)
+                .with_context(|| format!("in configuration file {}", config_path.display()))?,
+            None => file_config,
+        };
+        let mode = cli.mode.or(file_config.mode()?).unwrap_or(Mode::Original);
+        let transcodes = file_config
+            .options
+            .rules
+            .values()
+            .any(|action| *action == RuleAction::Transcode);
+        let encodes = if mode.needs_ffmpeg() {
+            Some(mode)
+        } else {
+            transcodes.then_some(Mode::Mp3)
+        };
+        Ok(Self {
+            mode,
+            ffmpeg: ffmpeg_needed(mode, &file_config.options),
+            encodes,
+            ffmpeg_path: file_config.options.ffmpeg_path,
+        })
+    }
+}
+
+/// Lays the keys set in `overrides` over `base`, leaving the keys it does not mention.
+/// `rules` is keyed by extension, so its entries are laid over one by one.
+fn merge_options(base: &SyncOptions, overrides: &SyncOptions) -> Result<SyncOptions> {
+    let mut merged = serde_json::to_value(base).context("failed to merge options")?;
+    if let (serde_json::Value::Object(merged), serde_json::Value::Object(overrides)) = (
+        &mut merged,
+        serde_json::to_value(overrides).context("failed to merge options")?,
+    ) {
+        merged.extend(overrides);
+    }
+    let mut merged: SyncOptions =
+        serde_json::from_value(merged).context("failed to merge options")?;
+    merged.rules = base.rules.clone();
+    merged.rules.extend(overrides.rules.clone());
+    Ok(merged)
+}
+
+/// Resolves a path written in a config file against the file's folder. Empty paths are
+/// kept so that validation can still point them out.
+fn rebase_path(config_dir: &Path, path: PathBuf) -> PathBuf {
+    if path.as_os_str().is_empty() {
+        path
+    } else {
+        absolutize(config_dir, path)
+    }
+}
+
+fn rebase_paths(config_dir: &Path, paths: OneOrManyPaths) -> OneOrManyPaths {
+    match paths {
+        OneOrManyPaths::One(path) => OneOrManyPaths::One(rebase_path(config_dir, path)),
+        OneOrManyPaths::Many(paths) => OneOrManyPaths::Many(
+            paths
+                .into_iter()
+                .map(|path| rebase_path(config_dir, path))
+                .collect(),
+        ),
+    }
+}
+
+fn rebase_options(config_dir: &Path, options: &mut SyncOptions) {
+    options.report = options
+        .report
+        .take()
+        .map(|path| rebase_path(config_dir, path));
+    options.playlist = options
+        .playlist
+        .take()
+        .map(|path| rebase_path(config_dir, path));
+    options.archive_cache = options
+        .archive_cache
+        .take()
+        .map(|path| rebase_path(config_dir, path));
+}
+
+/// Tags that `organize` templates can refer to.
+pub const ORGANIZE_FIELDS: [&str; 3] = ["artist", "album", "title"];
+
+fn validate_organize_template(template: &str) -> Result<()> {
+    if !template.contains("{title}") {
+        bail!("organize must contain {{title}} so that every song gets its own file name");
+    }
+    let mut rest = template.to_string();
+    for field in ORGANIZE_FIELDS {
+        rest = rest.replace(&format!("{{{field}}}"), "");
+    }
+    if rest.contains(['{', '}']) {
+        bail!(
+            "organize {template:?} uses an unknown placeholder; use {}",
+            ORGANIZE_FIELDS
+                .map(|field| format!("{{{field}}}"))
+                .join(", ")
+        );
+    }
+    if template
+        .split(['/', '\\'])
+        .any(|part| matches!(part, "." | ".."))
+    {
+        bail!("organize {template:?} must stay inside the output folder");
+    }
+    Ok(())
+}
+
+/// Parses a mode name or alias, suggesting the closest known name for a typo.
+fn parse_mode(value: &str) -> Result<Mode> {
+    if let Ok(mode) = Mode::from_str(value.trim(), true) {
+        return Ok(mode);
+    }
+    let names = Mode::value_variants()
+        .iter()
+        .filter_map(ValueEnum::to_possible_value)
+        .map(|value| value.get_name().to_string())
+        .collect::<Vec<_>>();
+    let suggestion = names
+        .iter()
+        .map(|name| (edit_distance(&value.to_ascii_lowercase(), name), name))
+        .filter(|(distance, _)| *distance <= 2)
+        .min()
+        .map(|(_, name)| format!(" (did you mean {name:?}?)"))
+        .unwrap_or_default();
+    bail!(
+        "unknown mode {value:?}{suggestion}; use one of {}",
+        names.join(", ")
+    )
+}
+
+fn edit_distance(left: &str, right: &str) -> usize {
+    let right = right.chars().collect::<Vec<_>>();
+    let mut previous = (0..=right.len()).collect::<Vec<_>>();
+    for (row, left_char) in left.chars().enumerate() {
+        let mut current = vec![row + 1];
+        for (column, right_char) in right.iter().enumerate() {
+            let substitution = previous[column] + usize::from(left_char != *right_char);
+            current.push(
+                substitution
+                    .min(previous[column + 1] + 1)
+                    .min(current[column] + 1),
+            );
+        }
+        previous = current;
+    }
+    previous[right.len()]
 }
 
-#[derive(Debug, Default, Deserialize)]
+#[derive(Clone, Debug, Default, Deserialize)]
 struct FileGuiConfig {
     theme: Option<GuiTheme>,
     opacity: Option<f32>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 #[serde(untagged)]
 enum OneOrManyPaths {
     One(PathBuf),
@@ -107,7 +1169,11 @@ struct WritableFileConfig<'a> {
     #[serde(skip_serializing_if = "Option::is_none")]
     output: Option<&'a Path>,
     mode: Mode,
+    #[serde(flatten)]
+    options: &'a SyncOptions,
     gui: WritableGuiConfig,
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    profiles: &'a [FileProfile],
 }
 
 #[derive(Serialize)]
@@ -117,6 +1183,13 @@ struct WritableGuiConfig {
 }
 
 impl OneOrManyPaths {
+    fn as_slice(&self) -> &[PathBuf] {
+        match self {
+            Self::One(path) => std::slice::from_ref(path),
+            Self::Many(paths) => paths,
+        }
+    }
+
     fn into_vec(self) -> Vec<PathBuf> {
         match self {
             Self::One(path) => vec![path],
@@ -126,53 +1199,215 @@ impl OneOrManyPaths {
 }
 
 impl Config {
+    /// Identifies the encoder settings recorded with each output so changes trigger a re-encode.
+    pub fn profile(&self) -> String {
+        self.profile_for(self.mode)
+    }
+
+    /// Manifest profile of outputs written with `mode`; a change re-syncs them.
+    pub fn profile_for(&self, mode: Mode) -> String {
+        let profile = match (mode, self.options.mp3_encoding()) {
+            (Mode::Mp3, Mp3Encoding::Vbr(quality)) => format!("mp3-q{quality}-v1"),
+            (Mode::Mp3, Mp3Encoding::Cbr(kbps)) => format!("mp3-cbr{kbps}k-v1"),
+            (mode, _) => mode.profile().to_string(),
+        };
+        let profile = match self.options.target_lufs {
+            Some(lufs) if mode.needs_ffmpeg() => format!("{profile}-loudnorm{lufs}"),
+            _ => profile,
+        };
+        let profile = match self.options.max_sample_rate {
+            Some(rate) if mode.needs_ffmpeg() && mode != Mode::Opus => {
+                format!("{profile}-max{rate}hz")
+            }
+            _ => profile,
+        };
+        let profile = if self.options.keep_original == Some(true) && mode.needs_ffmpeg() {
+            format!("{profile}-originals")
+        } else {
+            profile
+        };
+        let profile = if self.options.replaygain == Some(true) {
+            format!("{profile}-replaygain")
+        } else {
+            profile
+        };
+        if self.options.strip_metadata == Some(true) {
+            format!("{profile}-stripped")
+        } else {
+            profile
+        }
+    }
+
+    /// The mode a source is synced with: the rule for its extension, or `mode`.
+    pub fn mode_for(&self, path: &Path) -> Mode {
+        let extension = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .unwrap_or_default();
+        let rule = self.options.rules.iter().find_map(|(key, action)| {
+            key.trim_start_matches('.')
+                .eq_ignore_ascii_case(extension)
+                .then_some(*action)
+        });
+        match rule {
+            Some(RuleAction::Copy | RuleAction::Dump) => Mode::Original,
+            Some(RuleAction::Transcode) if !self.mode.needs_ffmpeg() => Mode::Mp3,
+            Some(RuleAction::Transcode) | None => self.mode,
+        }
+    }
+
+    /// Whether the mode or any rule re-encodes, or FLAC checks are on, so the run has to
+    /// find FFmpeg.
+    pub fn needs_ffmpeg(&self) -> bool {
+        ffmpeg_needed(self.mode, &self.options)
+    }
+
+    /// Resolves the run described by the command line, the environment and the config file,
+    /// using the `[[profiles]]` entry picked with `--profile` if any.
     pub fn resolve(mut cli: Cli) -> Result<Self> {
+        let (config_path, file_config) = load_cli_config(&mut cli)?;
+        let file_config = match cli.profile.take() {
+            Some(name) => file_config
+                .with_profile(&name)
+                .with_context(|| format!("in configuration file {}", config_path.display()))?,
+            None => file_config,
+        };
+        Self::resolve_file(cli, &config_path, file_config)
+    }
+
+    /// Resolves every `[[profiles]]` entry for `--all`, in the order of the config file.
+    pub fn resolve_all(mut cli: Cli) -> Result<Vec<(String, Self)>> {
+        let (config_path, file_config) = load_cli_config(&mut cli)?;
+        if file_config.profiles.is_empty() {
+            bail!(
+                "--all needs [[profiles]] in configuration file {}",
+                config_path.display()
+            );
+        }
+        if !cli.input.is_empty() || !cli.dropped_input.is_empty() || cli.output.is_some() {
+            bail!("--all cannot be combined with input or output paths; set them per profile");
+        }
+        let names = file_config
+            .profiles
+            .iter()
+            .map(|profile| profile.name.clone())
+            .collect::<Vec<_>>();
+        let mut configs = Vec::new();
+        for name in names {
+            let profile = file_config.clone().with_profile(&name)?;
+            let config = Self::resolve_file(cli.clone(), &config_path, profile)
+                .with_context(|| format!("profile {name}"))?;
+            configs.push((name, config));
+        }
+        Ok(configs)
+    }
+
+    fn resolve_file(mut cli: Cli, config_path: &Path, file_config: FileConfig) -> Result<Self> {
         let (exe_dir, cwd) = application_directories()?;
-        let (config_path, explicit_config) = resolve_config_path(cli.config.take(), &cwd)?;
-        let file_config = load_file_config(&config_path, explicit_config)?;
+        let file_mode = file_config.mode()?;
         let config_dir = config_path.parent().unwrap_or(&exe_dir);
         let default_output = default_output_path()?;
 
-        let cli_inputs = cli.take_inputs();
-        let raw_inputs: Vec<PathBuf> = if cli_inputs.is_empty() {
-            file_config
-                .inputs
-                .map(OneOrManyPaths::into_vec)
-                .unwrap_or_default()
-                .into_iter()
-                .map(|path| absolutize(config_dir, path))
-                .collect()
-        } else {
-            cli_inputs
-                .into_iter()
-                .map(|path| absolutize(&cwd, path))
-                .collect()
-        };
+        let (raw_inputs, inputs_origin) = first_set([
+            (cli.take_inputs(), cwd.as_path(), Origin::CommandLine),
+            (
+                env_paths(SOURCE_ENV),
+                cwd.as_path(),
+                Origin::Environment(SOURCE_ENV),
+            ),
+            (
+                file_config
+                    .inputs
+                    .map(OneOrManyPaths::into_vec)
+                    .unwrap_or_default(),
+                config_dir,
+                Origin::ConfigFile,
+            ),
+        ])
+        .unwrap_or((Vec::new(), Origin::Default));
+        let (output, output_origin) = first_set([
+            (
+                Vec::from_iter(cli.output.take()),
+                cwd.as_path(),
+                Origin::CommandLine,
+            ),
+            (
+                Vec::from_iter(env_path(DEST_ENV)),
+                cwd.as_path(),
+                Origin::Environment(DEST_ENV),
+            ),
+            (
+                Vec::from_iter(file_config.output),
+                config_dir,
+                Origin::ConfigFile,
+            ),
+        ])
+        .map(|(mut paths, origin)| (paths.remove(0), origin))
+        .unwrap_or((default_output, Origin::Default));
+        log::debug!("config : {}", config_path.display());
+        log::info!("Inputs from {inputs_origin}, output from {output_origin}");
 
-        let output = cli
-            .output
+        let mut options = file_config.options;
+        options.jobs = cli.jobs.or(options.jobs);
+        options.max_bytes = cli.max_bytes.or(options.max_bytes);
+        options.wait_for_lock = cli.wait.then_some(true).or(options.wait_for_lock);
+        options.keep_going = cli.keep_going.then_some(true).or(options.keep_going);
+        options.dedupe_source = cli.dedupe_source.or(options.dedupe_source);
+        options.skip_duplicates = cli
+            .skip_duplicates
+            .then_some(true)
+            .or(options.skip_duplicates);
+        options.report = cli
+            .report
             .take()
             .map(|path| absolutize(&cwd, path))
-            .or_else(|| file_config.output.map(|path| absolutize(config_dir, path)))
-            .unwrap_or(default_output);
+            .or(options.report.map(|path| absolutize(config_dir, path)));
+        options.playlist = cli
+            .playlist
+            .take()
+            .map(|path| absolutize(&cwd, path))
+            .or(options.playlist.map(|path| absolutize(config_dir, path)));
+        options.delete = cli.delete;
+        options.two_way = cli.two_way;
+        options.prefer = cli.prefer;
+        options.move_sources = cli.move_sources;
+        options.since = cli.since;
+        options.force = cli.force;
+        options.explain = cli.explain;
+        options.dry_run = cli.dry_run;
+        options.staging = cli.staging;
+        options.yes = cli.yes;
 
         Self::from_paths(
             raw_inputs,
             output,
-            cli.mode.or(file_config.mode).unwrap_or(Mode::Original),
+            cli.mode.or(file_mode).unwrap_or(Mode::Original),
+            options,
         )
         .with_context(|| format!("configuration resolved from {}", config_path.display()))
     }
 
-    pub fn from_paths(inputs: Vec<PathBuf>, output: PathBuf, mode: Mode) -> Result<Self> {
+    pub fn from_paths(
+        inputs: Vec<PathBuf>,
+        output: PathBuf,
+        mode: Mode,
+        options: SyncOptions,
+    ) -> Result<Self> {
         if inputs.is_empty() {
             bail!("no input was provided; add or drop at least one file or directory");
         }
+        options.validate()?;
 
-        fs::create_dir_all(&output)
-            .with_context(|| format!("failed to create output directory {}", output.display()))?;
-        let output = fs::canonicalize(&output)
-            .with_context(|| format!("failed to resolve output directory {}", output.display()))?;
+        let output = if options.dry_run && !output.exists() {
+            output
+        } else {
+            fs::create_dir_all(&output).with_context(|| {
+                format!("failed to create output directory {}", output.display())
+            })?;
+            fs::canonicalize(&output).with_context(|| {
+                format!("failed to resolve output directory {}", output.display())
+            })?
+        };
 
         let mut seen = HashSet::new();
         let mut normalized_inputs = Vec::new();
@@ -182,6 +1417,28 @@ impl Config {
             }
             let input = fs::canonicalize(&input)
                 .with_context(|| format!("failed to resolve input {}", input.display()))?;
+            if input == output {
+                bail!(
+                    "output {} is also an input; choose a separate output folder",
+                    output.display()
+                );
+            }
+            // An output inside an input is skipped by the input scan, but an input inside the
+            // output would be walked as existing outputs and adopted as copies of itself.
+            if input.starts_with(&output) {
+                bail!(
+                    "input {} is inside output {}; choose an output folder outside the inputs",
+                    input.display(),
+                    output.display()
+                );
+            }
+            if options.move_sources && output.starts_with(&input) {
+                bail!(
+                    "--move needs separate folders, but {} and output {} are nested",
+                    input.display(),
+                    output.display()
+                );
+            }
             if seen.insert(input.clone()) {
                 normalized_inputs.push(input);
             }
@@ -191,6 +1448,7 @@ impl Config {
             inputs: normalized_inputs,
             output,
             mode,
+            options,
         })
     }
 }
@@ -205,7 +1463,9 @@ impl EditableConfig {
             mode: Mode::Original,
             theme: GuiTheme::System,
             window_opacity: DEFAULT_WINDOW_OPACITY,
+            options: SyncOptions::default(),
             default_output: default_output_path()?,
+            profiles: Vec::new(),
         })
     }
 
@@ -213,15 +1473,17 @@ impl EditableConfig {
         let mut editable = Self::empty_default()?;
         let create_default = !editable.path.exists();
         let file_config = load_file_config(&editable.path, false)?;
+        editable.mode = file_config.mode()?.unwrap_or(Mode::Original);
         editable.inputs = file_config
             .inputs
             .map(OneOrManyPaths::into_vec)
             .unwrap_or_default();
         editable.output = file_config.output;
-        editable.mode = file_config.mode.unwrap_or(Mode::Original);
         editable.theme = file_config.gui.theme.unwrap_or_default();
         editable.window_opacity =
             normalize_window_opacity(file_config.gui.opacity.unwrap_or(DEFAULT_WINDOW_OPACITY));
+        editable.options = file_config.options;
+        editable.profiles = file_config.profiles;
         if create_default {
             editable.save()?;
         }
@@ -248,7 +1510,12 @@ impl EditableConfig {
     pub fn runtime_config(&self, session_inputs: &[PathBuf]) -> Result<Config> {
         let mut inputs = self.resolved_inputs();
         inputs.extend(session_inputs.iter().cloned());
-        Config::from_paths(inputs, self.resolved_output(), self.mode)
+        Config::from_paths(
+            inputs,
+            self.resolved_output(),
+            self.mode,
+            self.options.clone(),
+        )
     }
 
     pub fn save(&self) -> Result<()> {
@@ -266,33 +1533,239 @@ impl EditableConfig {
             inputs: &self.inputs,
             output: self.output.as_deref(),
             mode: self.mode,
+            options: &self.options,
             gui: WritableGuiConfig {
                 theme: self.theme,
                 opacity: config_window_opacity(self.window_opacity),
             },
+            profiles: &self.profiles,
         })
         .context("failed to serialize configuration")?;
-        let mut temporary = NamedTempFile::new_in(parent).with_context(|| {
-            format!(
-                "failed to create configuration temporary file in {}",
-                parent.display()
-            )
-        })?;
-        temporary
-            .write_all(document.as_bytes())
-            .context("failed to write configuration temporary file")?;
-        temporary
-            .as_file()
-            .sync_all()
-            .context("failed to sync configuration temporary file")?;
-        temporary
-            .persist(&self.path)
-            .map_err(|error| error.error)
-            .with_context(|| format!("failed to publish configuration {}", self.path.display()))?;
-        Ok(())
+        write_config_file(&self.path, parent, &document)
+    }
+}
+
+/// Writes a commented starter configuration to `--config` or the standard location.
+pub fn init(explicit: Option<PathBuf>, force: bool) -> Result<()> {
+    let (_, cwd) = application_directories()?;
+    let (path, _) = resolve_config_path(explicit, &cwd)?;
+    if path.exists() && !force {
+        bail!(
+            "configuration file {} already exists; pass --force to overwrite it",
+            path.display()
+        );
+    }
+    if ConfigFormat::of(&path) != ConfigFormat::Toml {
+        bail!(
+            "the starter configuration is written as TOML; choose a .toml path instead of {}",
+            path.display()
+        );
     }
+    let parent = path
+        .parent()
+        .context("configuration file has no parent directory")?;
+    fs::create_dir_all(parent).with_context(|| {
+        format!(
+            "failed to create configuration directory {}",
+            parent.display()
+        )
+    })?;
+    write_config_file(&path, parent, CONFIG_TEMPLATE)?;
+    log::info!("Wrote {}", path.display());
+    Ok(())
 }
 
+/// Replaces the configuration through a temporary file so a crash never leaves half of it.
+fn write_config_file(path: &Path, parent: &Path, document: &str) -> Result<()> {
+    let mut temporary = NamedTempFile::new_in(parent).with_context(|| {
+        format!(
+            "failed to create configuration temporary file in {}",
+            parent.display()
+        )
+    })?;
+    temporary
+        .write_all(document.as_bytes())
+        .context("failed to write configuration temporary file")?;
+    temporary
+        .as_file()
+        .sync_all()
+        .context("failed to sync configuration temporary file")?;
+    temporary
+        .persist(path)
+        .map_err(|error| error.error)
+        .with_context(|| format!("failed to publish configuration {}", path.display()))?;
+    Ok(())
+}
+
+const CONFIG_TEMPLATE: &str = r#"# w4dj configuration. Relative paths are resolved against this file's directory.
+
+# Files or folders to scan. Also settable with --input or the W4DJ_SOURCE variable.
+inputs = [
+    # 'D:\CloudMusic',
+    # '/home/me/Music/CloudMusic',
+]
+
+# Where the synced library is written. Defaults to w4djdump in the Music folder.
+# output = 'D:\DJ Library'
+
+# Output profile:
+#   original  keep the source format (NCM, QMC and KGM files are decrypted)
+#   mp3       MP3 VBR for old CDJs and players; needs FFmpeg
+#   aac       AAC 256k in .m4a; needs FFmpeg
+#   opus      Opus 160k; needs FFmpeg
+#   wav       16-bit PCM WAV; needs FFmpeg
+mode = "original"
+
+# Mirror the Artist/Album folders of the inputs instead of a flat output folder.
+# preserve_structure = true
+
+# Lay out new outputs by tags instead of a flat folder; a missing tag uses the file name.
+# organize = "{artist}/{album}/{title}"
+
+# Only sync matching files, and skip matching files (paths relative to each input).
+# A .w4djignore file in gitignore syntax overrides both for its own folder and below.
+# include = ["**/*.flac"]
+# exclude = ["**/podcasts/**"]
+
+# Only sync these source formats, for example lossless only; defaults to every format
+# w4dj supports. .w4djignore files cannot bring other formats back.
+# extensions = ["flac", "wav"]
+
+# How a source is compared with its output: size | payload-size | hash | tags
+# hash and tags cache what they read in the output folder, keyed by size and mtime.
+# compare = "size"
+
+# What identifies songs without a platform ID: tags | stem | relpath. relpath keeps
+# equally named files in different folders apart. Choose it before the first sync;
+# changing it later syncs those songs again as new files.
+# match_by = "tags"
+
+# Report sources that are the same song: hash (identical files) | tags (artist and title).
+# With skip_duplicates only the best copy is synced: lossless first, then the larger file.
+# dedupe_source = "tags"
+# skip_duplicates = true
+
+# How much larger, as a fraction, a same-format source must be to replace its output.
+# size_tolerance = 0.05
+
+# Layout of the command-line progress bar (indicatif template syntax).
+# progress_template = "{bar:20} {pos}/{len} {msg}"
+
+# Worker threads; defaults to the number of logical CPUs.
+# jobs = 4
+
+# Warn when a run would leave less than this free on the output drive. A run that
+# clearly does not fit is always refused before it starts.
+# free_space_margin = "2G"
+
+# Bytes copied at a time per file, from 4K to 64M. Larger buffers help on network drives.
+# copy_buffer = "1M"
+
+# Cap on the total write speed in megabits per second, so the sync leaves room for other
+# traffic on a network drive. 100 is about 12 MB/s.
+# max_mbps = 100
+
+# Most FFmpeg transcodes at once, while copies keep using every worker thread.
+# max_transcodes = 2
+
+# Decrypt NCM, QMC and KGM files that are transcoded into this folder instead of the
+# output folder, so a small SD card never holds the intermediate and the result at once.
+# temp_dir = "/tmp"
+
+# Keep the audio extracted from zip archives here instead of the system cache folder.
+# An archive whose size and modification time are unchanged is not extracted again.
+# archive_cache = "D:/w4dj-archives"
+
+# MP3 VBR quality from 0 (best) to 9, or a constant bitrate such as "192k".
+# mp3_quality = 2
+# mp3_bitrate = "192k"
+
+# In MP3 mode, copy MP3 sources at or below this bitrate instead of re-encoding them;
+# lossless and other formats are always transcoded.
+# transcode_above_bitrate = "256k"
+
+# Normalize re-encoded outputs to this loudness with FFmpeg's loudnorm filter; slower.
+# target_lufs = -14
+
+# Tag every output with its ReplayGain track gain and peak (R128 gain for Opus) so
+# players even out the volume at playback; the audio itself is not changed. Needs FFmpeg.
+# replaygain = true
+
+# Scale embedded covers down to this many pixels on the longest edge.
+# max_cover_size = 600
+
+# For players that cannot handle hi-res audio: transcodes resample sources above this
+# rate, and lossless sources that are copied unchanged are reported. Transcodes are
+# always written at 16 bits or less.
+# max_sample_rate = 48000
+# max_bit_depth = 16
+
+# Decode every FLAC source with FFmpeg first so truncated or corrupt files fail instead
+# of reaching the device; slow.
+# verify_flac = true
+
+# Follow symlinked folders and files inside the inputs; each real folder is scanned once.
+# follow_symlinks = true
+
+# Ignore dot files and dot folders such as .DS_Store, .stfolder and sync conflicts.
+# skip_hidden = true
+
+# Write embedded lyrics next to each output as .lrc files.
+# export_lyrics = true
+
+# Fill tags a source lacks from <stem>.json or <stem>.cue next to it. The JSON may set
+# title, artist, album, album_artist, genre, date, track, track_total, disc and disc_total.
+# sidecar_metadata = true
+
+# Save the cover of each album as cover.jpg in its output folder, for players that read
+# folder art; needs preserve_structure or organize.
+# extract_cover = true
+
+# Keep the untranscoded audio of transcoded songs under .w4dj-originals/ in the output,
+# with the same relative path; NCM, QMC and KGM files are kept decrypted. Only useful
+# with a transcoding mode or rule.
+# keep_original = true
+
+# Hard-link sources that are copied unchanged instead of duplicating them: hard | copy.
+# Only works when the output is on the same filesystem and w4dj has no tags to write,
+# so never with strip_metadata, replaygain, sidecar_metadata or cover resizing; other
+# files are still copied. A linked output is the source file itself, so a later edit to
+# either shows up on both sides.
+# link = "hard"
+
+# Remove every tag, comment and cover from the outputs, for devices shared with others.
+# Only the W4DJ_ID tag stays so that later runs still recognize the files. Cannot be
+# combined with compare = "tags", which reads the tags stripped here.
+# strip_metadata = true
+
+# Move outputs removed by --delete or replaced by a better source to the system trash.
+# use_trash = true
+
+# Replace characters that FAT32 and exFAT drives reject in output names.
+# sanitize_filenames = true
+
+# Override the mode per extension: copy | transcode | dump (NCM, QMC and KGM only).
+# [rules]
+# flac = "copy"
+# wav = "transcode"
+# ncm = "dump"
+
+[gui]
+theme = "system" # light | dark | system
+
+# Named syncs run with --profile NAME, or one after another with --all. Keys set in a
+# profile replace the ones above; everything else is inherited.
+# [[profiles]]
+# name = "phone"
+# output = '/media/phone/Music'
+# mode = "mp3"
+#
+# [[profiles]]
+# name = "car"
+# output = 'E:\Music'
+# mode = "aac"
+"#;
+
 pub fn normalize_window_opacity(opacity: f32) -> f32 {
     if opacity.is_finite() {
         opacity.clamp(MIN_WINDOW_OPACITY, MAX_WINDOW_OPACITY)
@@ -306,6 +1779,40 @@ fn config_window_opacity(opacity: f32) -> f64 {
     (opacity * 100.0).round() / 100.0
 }
 
+/// Loads the `--config` files, or the standard one, and merges them in order. Only the
+/// merged result is validated, so a base file may leave a setting for an override to fix.
+fn load_cli_config(cli: &mut Cli) -> Result<(PathBuf, FileConfig)> {
+    let (_, cwd) = application_directories()?;
+    let mut paths = if cli.config.is_empty() {
+        vec![resolve_config_path(None, &cwd)?]
+    } else {
+        cli.config
+            .drain(..)
+            .map(|path| resolve_config_path(Some(path), &cwd))
+            .collect::<Result<Vec<_>>>()?
+    };
+    let mut merged: Option<FileConfig> = None;
+    for (path, required) in &paths {
+        let file_config = load_file_config(path, *required)?.rebase(path.parent().unwrap_or(&cwd));
+        merged = Some(match merged {
+            Some(base) => base
+                .merge(file_config)
+                .with_context(|| format!("failed to merge {}", path.display()))?,
+            None => file_config,
+        });
+    }
+    let file_config = merged.unwrap_or_default();
+    let names = paths
+        .iter()
+        .map(|(path, _)| path.display().to_string())
+        .collect::<Vec<_>>();
+    file_config
+        .validate()
+        .with_context(|| format!("invalid configuration file {}", names.join(" + ")))?;
+    let (config_path, _) = paths.pop().expect("at least one configuration path");
+    Ok((config_path, file_config))
+}
+
 fn load_file_config(path: &Path, required: bool) -> Result<FileConfig> {
     if !path.exists() {
         if required {
@@ -316,15 +1823,113 @@ fn load_file_config(path: &Path, required: bool) -> Result<FileConfig> {
 
     let content = fs::read_to_string(path)
         .with_context(|| format!("failed to read configuration file {}", path.display()))?;
-    toml::from_str(&content).with_context(|| {
+    let format = ConfigFormat::of(path);
+    let parsed = match format {
+        ConfigFormat::Toml => toml::from_str(&content).map_err(anyhow::Error::from),
+        ConfigFormat::Json => serde_json::from_str(&content).map_err(anyhow::Error::from),
+        ConfigFormat::Yaml => serde_yaml::from_str(&content).map_err(anyhow::Error::from),
+    };
+    parsed.with_context(|| {
         format!(
-            "failed to parse configuration file {}. Windows paths can use TOML literal strings \
-             such as 'C:\\Music', or escaped basic strings such as \"C:\\\\Music\"",
-            path.display()
+            "failed to parse configuration file {}. {}",
+            path.display(),
+            format.windows_path_hint()
         )
     })
 }
 
+/// Syntax of a configuration file, chosen by its extension; anything else is read as TOML.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum ConfigFormat {
+    Toml,
+    Json,
+    Yaml,
+}
+
+impl ConfigFormat {
+    fn of(path: &Path) -> Self {
+        match path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .map(str::to_ascii_lowercase)
+            .as_deref()
+        {
+            Some("json") => Self::Json,
+            Some("yaml" | "yml") => Self::Yaml,
+            _ => Self::Toml,
+        }
+    }
+
+    fn windows_path_hint(self) -> &'static str {
+        match self {
+            Self::Toml => {
+                "Windows paths can use TOML literal strings such as 'C:\\Music', or escaped \
+                 basic strings such as \"C:\\\\Music\""
+            }
+            Self::Json => "Windows paths need escaped backslashes, such as \"C:\\\\Music\"",
+            Self::Yaml => {
+                "Windows paths can use single-quoted strings such as 'C:\\Music', or escaped \
+                 double-quoted strings such as \"C:\\\\Music\""
+            }
+        }
+    }
+}
+
+/// Where the inputs or the output of a run came from, logged so the precedence of the
+/// command line over the environment over the config file can be checked with `-v`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Origin {
+    CommandLine,
+    Environment(&'static str),
+    ConfigFile,
+    Default,
+}
+
+impl fmt::Display for Origin {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::CommandLine => formatter.write_str("the command line"),
+            Self::Environment(name) => write!(formatter, "the {name} environment variable"),
+            Self::ConfigFile => formatter.write_str("the config file"),
+            Self::Default => formatter.write_str("the default"),
+        }
+    }
+}
+
+/// Picks the first candidate with any paths and resolves them against its base directory.
+fn first_set<'a>(
+    candidates: impl IntoIterator<Item = (Vec<PathBuf>, &'a Path, Origin)>,
+) -> Option<(Vec<PathBuf>, Origin)> {
+    candidates
+        .into_iter()
+        .find(|(paths, _, _)| !paths.is_empty())
+        .map(|(paths, base, origin)| {
+            let paths = paths
+                .into_iter()
+                .map(|path| absolutize(base, path))
+                .collect();
+            (paths, origin)
+        })
+}
+
+/// Reads a list of paths separated like `PATH` (`:` on Unix, `;` on Windows).
+fn env_paths(name: &str) -> Vec<PathBuf> {
+    env::var_os(name)
+        .map(|value| {
+            env::split_paths(&value)
+                .filter(|path| !path.as_os_str().is_empty())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Reads a single path, which may itself contain the `PATH` separator.
+fn env_path(name: &str) -> Option<PathBuf> {
+    env::var_os(name)
+        .filter(|value| !value.is_empty())
+        .map(PathBuf::from)
+}
+
 fn absolutize(base: &Path, path: PathBuf) -> PathBuf {
     if path.is_absolute() {
         path
@@ -381,6 +1986,9 @@ mod tests {
         assert_eq!(Mode::Original.profile(), "original-v1");
         assert_eq!(Mode::Mp3.profile(), "mp3-q2-v1");
         assert_eq!(Mode::Wav.profile(), "wav-pcm16-v1");
+        assert_eq!(Mode::Aac.profile(), "aac-256k-v1");
+        assert_eq!(Mode::Aac.extension("flac"), "m4a");
+        assert_eq!(Mode::Opus.extension("mp3"), "opus");
     }
 
     #[test]
@@ -424,6 +2032,35 @@ mode = "original"
         Ok(())
     }
 
+    #[test]
+    fn json_and_yaml_configs_are_chosen_by_extension() -> Result<()> {
+        let workspace = tempfile::tempdir()?;
+        let json = workspace.path().join("w4dj.json");
+        fs::write(
+            &json,
+            r#"{"inputs": "C:\\Music", "mode": "mp3", "max_bytes": "2G", "gui": {"theme": "dark"}}"#,
+        )?;
+        let yaml = workspace.path().join("w4dj.YML");
+        fs::write(
+            &yaml,
+            "inputs:\n  - 'C:\\Music'\nmode: mp3\nmax_bytes: 2G\ngui:\n  theme: dark\n",
+        )?;
+
+        for path in [&json, &yaml] {
+            let config = load_file_config(path, true)?;
+            let inputs = config.inputs.as_ref().context("missing inputs")?.as_slice();
+            assert_eq!(inputs, [PathBuf::from(r"C:\Music")]);
+            assert_eq!(config.mode()?, Some(Mode::Mp3));
+            assert_eq!(config.options.max_bytes, Some(ByteSize(2 << 30)));
+            assert_eq!(config.gui.theme, Some(GuiTheme::Dark));
+        }
+
+        fs::write(&yaml, "mode: [mp3\n")?;
+        let error = load_file_config(&yaml, true).unwrap_err();
+        assert!(format!("{error:#}").contains("single-quoted"));
+        Ok(())
+    }
+
     #[cfg(windows)]
     #[test]
     fn resolves_windows_extended_length_paths() -> Result<()> {
@@ -453,7 +2090,31 @@ mode = "original"
             dropped_input: Vec::new(),
             output: None,
             mode: None,
-            config: Some(config_path),
+            config: vec![config_path],
+            verbose: 0,
+            quiet: 0,
+            jobs: None,
+            max_bytes: None,
+            wait: false,
+            keep_going: false,
+            dedupe_source: None,
+            skip_duplicates: false,
+            report: None,
+            playlist: None,
+            delete: false,
+            two_way: false,
+            prefer: None,
+            move_sources: false,
+            since: None,
+            force: false,
+            explain: false,
+            dry_run: false,
+            staging: false,
+            yes: false,
+            watch: false,
+            profile: None,
+            all: false,
+            no_banner: false,
         })?;
 
         assert_eq!(resolved.inputs, vec![fs::canonicalize(&input)?]);
@@ -461,6 +2122,58 @@ mode = "original"
         Ok(())
     }
 
+    #[test]
+    fn command_line_paths_win_over_environment_over_config_file() {
+        let cwd = Path::new("/work");
+        let config_dir = Path::new("/etc/w4dj");
+        let candidates = |cli: &[&str]| {
+            [
+                (
+                    cli.iter().map(PathBuf::from).collect(),
+                    cwd,
+                    Origin::CommandLine,
+                ),
+                (
+                    vec![PathBuf::from("env")],
+                    cwd,
+                    Origin::Environment(SOURCE_ENV),
+                ),
+                (vec![PathBuf::from("file")], config_dir, Origin::ConfigFile),
+            ]
+        };
+
+        assert_eq!(
+            first_set(candidates(&["cli"])),
+            Some((vec![cwd.join("cli")], Origin::CommandLine))
+        );
+        assert_eq!(
+            first_set(candidates(&[])),
+            Some((vec![cwd.join("env")], Origin::Environment(SOURCE_ENV)))
+        );
+        assert_eq!(
+            first_set(candidates(&[]).into_iter().skip(2)),
+            Some((vec![config_dir.join("file")], Origin::ConfigFile))
+        );
+    }
+
+    #[test]
+    fn init_writes_a_valid_template_and_refuses_to_overwrite() -> Result<()> {
+        let workspace = tempfile::tempdir()?;
+        let path = workspace.path().join("nested").join("config.toml");
+
+        init(Some(path.clone()), false)?;
+        let written = load_file_config(&path, true)?;
+        written.validate()?;
+        assert_eq!(written.mode()?, Some(Mode::Original));
+
+        fs::write(&path, "mode = 'mp3'\n")?;
+        assert!(init(Some(path.clone()), false).is_err());
+        assert_eq!(fs::read_to_string(&path)?, "mode = 'mp3'\n");
+        init(Some(path.clone()), true)?;
+        assert_eq!(fs::read_to_string(&path)?, CONFIG_TEMPLATE);
+        Ok(())
+    }
+
     #[test]
     fn explicit_config_path_takes_precedence() -> Result<()> {
         let workspace = tempfile::tempdir()?;
@@ -498,7 +2211,23 @@ mode = "original"
             mode: Mode::Mp3,
             theme: GuiTheme::Light,
             window_opacity: 0.72,
+            options: SyncOptions {
+                jobs: Some(2),
+                max_bytes: Some(ByteSize(20 << 30)),
+                cover_jpeg_quality: Some(70),
+                compare: Some(Compare::PayloadSize),
+                watchdog_secs: Some(300),
+                wait_for_lock: Some(true),
+                ..SyncOptions::default()
+            },
             default_output: workspace.path().join("w4djdump"),
+            profiles: vec![FileProfile {
+                name: "phone".to_string(),
+                inputs: None,
+                output: Some(PathBuf::from("/media/phone")),
+                mode: Some("mp3".to_string()),
+                options: SyncOptions::default(),
+            }],
         };
 
         editable.save()?;
@@ -508,9 +2237,12 @@ mode = "original"
             editable.inputs
         );
         assert_eq!(loaded.output, editable.output);
-        assert_eq!(loaded.mode, Some(Mode::Mp3));
+        assert_eq!(loaded.mode.as_deref(), Some("mp3"));
         assert_eq!(loaded.gui.theme, Some(GuiTheme::Light));
         assert_eq!(loaded.gui.opacity, Some(0.72));
+        assert_eq!(loaded.options, editable.options);
+        assert_eq!(loaded.profiles[0].name, "phone");
+        assert_eq!(loaded.profiles[0].mode.as_deref(), Some("mp3"));
         Ok(())
     }
 
@@ -532,7 +2264,9 @@ mode = "original"
             mode: Mode::Original,
             theme: GuiTheme::System,
             window_opacity: 0.6,
+            options: SyncOptions::default(),
             default_output: workspace.path().join("w4djdump"),
+            profiles: Vec::new(),
         };
 
         editable.save()?;
@@ -555,4 +2289,375 @@ mode = "original"
         }
         Ok(())
     }
+
+    #[test]
+    fn byte_sizes_accept_binary_units_and_plain_integers() -> Result<()> {
+        assert_eq!("512".parse::<ByteSize>()?, ByteSize(512));
+        assert_eq!("20G".parse::<ByteSize>()?, ByteSize(20 << 30));
+        assert_eq!("1.5 MiB".parse::<ByteSize>()?, ByteSize(3 << 19));
+        assert!("20 parsecs".parse::<ByteSize>().is_err());
+
+        let config: FileConfig = toml::from_str("max_bytes = '2G'\n")?;
+        assert_eq!(config.options.max_bytes, Some(ByteSize(2 << 30)));
+        let config: FileConfig = toml::from_str("max_bytes = 4096\n")?;
+        assert_eq!(config.options.max_bytes, Some(ByteSize(4096)));
+
+        let config: FileConfig = toml::from_str("copy_buffer = '1M'\n")?;
+        config.options.validate()?;
+        assert_eq!(config.options.copy_buffer_size(), 1 << 20);
+        let config: FileConfig = toml::from_str("copy_buffer = 512\n")?;
+        assert!(config.options.validate().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn repeated_configs_merge_in_order_and_validate_together() -> Result<()> {
+        use clap::Parser as _;
+
+        let workspace = tempfile::tempdir()?;
+        let shared = workspace.path().join("shared");
+        let laptop = workspace.path().join("laptop");
+        fs::create_dir_all(shared.join("music"))?;
+        fs::create_dir_all(&laptop)?;
+        let base = shared.join("base.toml");
+        // Alone, extract_cover lacks the folder layout that the override adds.
+        fs::write(
+            &base,
+            "inputs = ['music']\nmode = 'mp3'\njobs = 4\nextract_cover = true\n\n\
+             [rules]\nflac = 'copy'\nwav = 'copy'\n",
+        )?;
+        let overrides = laptop.join("laptop.yaml");
+        fs::write(
+            &overrides,
+            "output: out\njobs: 2\norganize: '{artist}/{title}'\nrules:\n  wav: transcode\n",
+        )?;
+        let resolve = |configs: &[&Path]| {
+            let mut arguments = vec!["w4dj".as_ref()];
+            for config in configs {
+                arguments.extend(["--config".as_ref(), config.as_os_str()]);
+            }
+            Config::resolve(Cli::try_parse_from(arguments)?)
+        };
+
+        let config = resolve(&[&base, &overrides])?;
+        assert_eq!(config.inputs, vec![fs::canonicalize(shared.join("music"))?]);
+        assert_eq!(config.output, fs::canonicalize(laptop.join("out"))?);
+        assert_eq!(config.mode, Mode::Mp3);
+        assert_eq!(config.options.jobs, Some(2));
+        assert_eq!(config.options.extract_cover, Some(true));
+        // Rules are keyed by extension, so a later file overrides only the ones it names.
+        assert_eq!(
+            config.options.rules,
+            BTreeMap::from([
+                ("flac".to_string(), RuleAction::Copy),
+                ("wav".to_string(), RuleAction::Transcode),
+            ])
+        );
+        let error = resolve(&[&base]).unwrap_err();
+        assert!(format!("{error:#}").contains("extract_cover"), "{error:#}");
+        Ok(())
+    }
+
+    #[test]
+    fn doctor_requirements_follow_the_mode_rules_and_options() -> Result<()> {
+        use clap::Parser as _;
+
+        let workspace = tempfile::tempdir()?;
+        let path = workspace.path().join("config.toml");
+        fs::write(
+            &path,
+            "mode = 'mp3'\n\n[[profiles]]\nname = 'usb'\nmode = 'original'\n",
+        )?;
+        let requirements = |extra: &[&str]| {
+            let mut arguments = vec!["w4dj", "doctor", "--config", path.to_str().unwrap()];
+            arguments.extend(extra);
+            Requirements::resolve(Cli::try_parse_from(arguments)?)
+        };
+
+        assert!(requirements(&[])?.ffmpeg);
+        let usb = requirements(&["--profile", "usb"])?;
+        assert_eq!(usb.mode, Mode::Original);
+        assert!(!usb.ffmpeg);
+        fs::write(&path, "mode = 'original'\n\n[rules]\nwav = 'transcode'\n")?;
+        assert!(requirements(&[])?.ffmpeg);
+        Ok(())
+    }
+
+    #[test]
+    fn profiles_override_only_the_keys_they_set() -> Result<()> {
+        let config: FileConfig = toml::from_str(
+            r#"
+inputs = '/music'
+output = '/server'
+mode = "original"
+jobs = 2
+
+[rules]
+ncm = "dump"
+
+[[profiles]]
+name = "phone"
+output = '/phone'
+mode = "mp3"
+mp3_bitrate = "192k"
+
+[[profiles]]
+name = "car"
+destination = '/car'
+mode = "aac"
+jobs = 1
+"#,
+        )?;
+        config.validate()?;
+
+        let phone = config.clone().with_profile("phone")?;
+        assert_eq!(phone.output, Some(PathBuf::from("/phone")));
+        assert_eq!(phone.mode()?, Some(Mode::Mp3));
+        assert_eq!(phone.options.mp3_bitrate, Some(Bitrate(192)));
+        assert_eq!(phone.options.jobs, Some(2));
+        assert_eq!(phone.options.rules["ncm"], RuleAction::Dump);
+        assert_eq!(
+            phone.inputs.map(OneOrManyPaths::into_vec),
+            Some(vec![PathBuf::from("/music")])
+        );
+        let car = config.clone().with_profile("car")?;
+        assert_eq!(car.output, Some(PathBuf::from("/car")));
+        assert_eq!(car.options.jobs, Some(1));
+
+        let error = config.with_profile("boat").unwrap_err().to_string();
+        assert!(error.contains("expected one of phone, car"), "{error}");
+        let twice: FileConfig =
+            toml::from_str("[[profiles]]\nname = 'a'\n\n[[profiles]]\nname = 'a'\n")?;
+        assert!(twice.validate().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn since_accepts_ages_and_dates() -> Result<()> {
+        let now = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        assert_eq!(
+            "24h".parse::<Since>()?.cutoff(now),
+            now - Duration::from_secs(86_400)
+        );
+        assert_eq!(
+            "2w".parse::<Since>()?,
+            Since::Ago(Duration::from_secs(14 * 86_400))
+        );
+        assert_eq!(
+            "2024-01-01".parse::<Since>()?,
+            Since::Date(UNIX_EPOCH + Duration::from_secs(1_704_067_200))
+        );
+        assert_eq!(
+            "2000-03-01".parse::<Since>()?,
+            Since::Date(UNIX_EPOCH + Duration::from_secs(951_868_800))
+        );
+        assert!("2024-13-01".parse::<Since>().is_err());
+        assert!("yesterday".parse::<Since>().is_err());
+
+        let options = SyncOptions {
+            since: Some("1d".parse()?),
+            delete: true,
+            ..SyncOptions::default()
+        };
+        assert!(options.validate().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn cover_jpeg_quality_must_be_a_percentage() {
+        let options = |quality| SyncOptions {
+            cover_jpeg_quality: Some(quality),
+            ..SyncOptions::default()
+        };
+        assert!(options(85).validate().is_ok());
+        assert!(options(0).validate().is_err());
+        assert!(options(101).validate().is_err());
+    }
+
+    #[test]
+    fn exclude_patterns_win_over_include_patterns() -> Result<()> {
+        let options = SyncOptions {
+            include: vec!["**/*.flac".to_string(), "**/*sample*".to_string()],
+            exclude: vec!["**/podcasts/**".to_string(), "**/*sample*".to_string()],
+            ..SyncOptions::default()
+        };
+        let filter = PathFilter::new(&options)?;
+        assert!(filter.allows(Path::new("Album/Song.flac")));
+        assert!(!filter.allows(Path::new("Album/Song.mp3")));
+        assert!(!filter.allows(Path::new("podcasts/Episode.flac")));
+        assert!(!filter.allows(Path::new("Album/sample clip.flac")));
+
+        let invalid = SyncOptions {
+            exclude: vec!["[".to_string()],
+            ..SyncOptions::default()
+        };
+        assert!(invalid.validate().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn mp3_quality_and_bitrate_select_the_profile() -> Result<()> {
+        let mut config = Config {
+            inputs: Vec::new(),
+            output: PathBuf::new(),
+            mode: Mode::Mp3,
+            options: SyncOptions::default(),
+        };
+        assert_eq!(config.profile(), Mode::Mp3.profile());
+
+        config.options = toml::from_str("mp3_quality = 0\n")?;
+        assert_eq!(config.profile(), "mp3-q0-v1");
+        config.options = toml::from_str("mp3_bitrate = '192k'\n")?;
+        assert_eq!(config.options.mp3_encoding(), Mp3Encoding::Cbr(192));
+        assert_eq!(config.profile(), "mp3-cbr192k-v1");
+
+        config.mode = Mode::Wav;
+        assert_eq!(config.profile(), Mode::Wav.profile());
+        config.options.target_lufs = Some(-14.0);
+        assert_eq!(config.profile(), "wav-pcm16-v1-loudnorm-14");
+        config.mode = Mode::Original;
+        assert_eq!(config.profile(), Mode::Original.profile());
+        config.options.replaygain = Some(true);
+        assert_eq!(config.profile(), "original-v1-replaygain");
+        config.options.target_lufs = Some(0.0);
+        assert!(config.options.validate().is_err());
+
+        let both: SyncOptions = toml::from_str("mp3_quality = 0\nmp3_bitrate = 320\n")?;
+        assert!(both.validate().is_err());
+        let too_high: SyncOptions = toml::from_str("mp3_quality = 10\n")?;
+        assert!(too_high.validate().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn rules_override_the_mode_per_extension() -> Result<()> {
+        let mut config = Config {
+            inputs: Vec::new(),
+            output: PathBuf::from("out"),
+            mode: Mode::Original,
+            options: toml::from_str(
+                "[rules]\nFLAC = 'copy'\n'.wav' = 'transcode'\nncm = 'dump'\n",
+            )?,
+        };
+        config.options.validate()?;
+        assert_eq!(config.mode_for(Path::new("a/Song.flac")), Mode::Original);
+        assert_eq!(config.mode_for(Path::new("a/Song.WAV")), Mode::Mp3);
+        assert_eq!(config.mode_for(Path::new("a/Song.ncm")), Mode::Original);
+        assert_eq!(config.mode_for(Path::new("a/Song.mp3")), Mode::Original);
+        assert!(config.needs_ffmpeg());
+
+        config.mode = Mode::Aac;
+        assert_eq!(config.mode_for(Path::new("a/Song.flac")), Mode::Original);
+        assert_eq!(config.mode_for(Path::new("a/Song.wav")), Mode::Aac);
+        assert_eq!(config.mode_for(Path::new("a/Song.ogg")), Mode::Aac);
+        assert_ne!(
+            config.profile_for(Mode::Original),
+            config.profile_for(Mode::Aac)
+        );
+
+        let invalid = |rules: &str| {
+            toml::from_str::<SyncOptions>(rules)
+                .map_err(anyhow::Error::from)
+                .and_then(|options| options.validate())
+                .is_err()
+        };
+        assert!(invalid("[rules]\nncm = 'copy'\n"));
+        assert!(invalid("[rules]\nflac = 'dump'\n"));
+        assert!(invalid("[rules]\ntxt = 'copy'\n"));
+        assert!(invalid("[rules]\nflac = 'encode'\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn organize_templates_need_a_title_and_known_tags() {
+        let options = |template: &str| SyncOptions {
+            organize: Some(template.to_string()),
+            ..SyncOptions::default()
+        };
+        assert!(options("{artist}/{album}/{title}").validate().is_ok());
+        assert!(options("{artist}/{album}").validate().is_err());
+        assert!(options("{genre}/{title}").validate().is_err());
+        assert!(options("../{title}").validate().is_err());
+    }
+
+    #[test]
+    fn zero_jobs_is_rejected() {
+        let options = |jobs| SyncOptions {
+            jobs: Some(jobs),
+            ..SyncOptions::default()
+        };
+        assert!(options(1).validate().is_ok());
+        assert!(options(0).validate().is_err());
+    }
+
+    #[test]
+    fn malformed_progress_templates_are_a_config_error() {
+        let options = |template: Option<&str>| SyncOptions {
+            progress_template: template.map(str::to_string),
+            ..SyncOptions::default()
+        };
+        assert!(options(None).validate().is_ok());
+        assert!(options(Some("{bar:20} {pos}/{len}")).validate().is_ok());
+        let error = options(Some("{bar:x}")).validate().unwrap_err();
+        assert!(format!("{error:#}").contains("progress_template"));
+    }
+
+    #[test]
+    fn missing_ffmpeg_path_is_rejected() -> Result<()> {
+        let temp = tempfile::tempdir()?;
+        let ffmpeg = temp.path().join("ffmpeg");
+        let options = SyncOptions {
+            ffmpeg_path: Some(ffmpeg.clone()),
+            ..SyncOptions::default()
+        };
+        assert!(options.validate().is_err());
+
+        fs::write(&ffmpeg, b"")?;
+        assert!(options.validate().is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn config_problems_are_listed_together_with_a_mode_suggestion() -> Result<()> {
+        let config: FileConfig = toml::from_str(
+            r#"
+            inputs = ""
+            mode = "mp4"
+            jobs = 0
+            "#,
+        )?;
+        let message = format!("{:#}", config.validate().unwrap_err());
+        assert!(message.contains("inputs contains an empty path"));
+        assert!(message.contains(r#"unknown mode "mp4" (did you mean "mp3"?)"#));
+        assert!(message.contains("jobs must be at least 1"));
+
+        let config: FileConfig = toml::from_str(r#"mode = "Legacy""#)?;
+        assert_eq!(config.mode()?, Some(Mode::Mp3));
+
+        let temp = tempfile::tempdir()?;
+        let error = Config::from_paths(
+            vec![temp.path().to_path_buf()],
+            temp.path().to_path_buf(),
+            Mode::Original,
+            SyncOptions::default(),
+        )
+        .unwrap_err();
+        assert!(error.to_string().contains("is also an input"));
+        fs::create_dir(temp.path().join("Inbox"))?;
+        let error = Config::from_paths(
+            vec![temp.path().join("Inbox")],
+            temp.path().to_path_buf(),
+            Mode::Original,
+            SyncOptions::default(),
+        )
+        .unwrap_err();
+        assert!(error.to_string().contains("is inside output"), "{error}");
+        Config::from_paths(
+            vec![temp.path().to_path_buf()],
+            temp.path().join("Device"),
+            Mode::Original,
+            SyncOptions::default(),
+        )?;
+        Ok(())
+    }
 }
@@ -0,0 +1,116 @@
+use std::io::Cursor;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageFormat, ImageReader};
+
+pub const DEFAULT_JPEG_QUALITY: u8 = 85;
+
+/// Prepares embedded cover art for writing.
+///
+/// JPEG and PNG covers pass through byte-for-byte. Formats that many players cannot display
+/// inside audio tags, such as WebP or BMP, are re-encoded as JPEG at `jpeg_quality`. Covers
+/// that cannot be decoded are kept unchanged rather than dropped.
+///
+/// With `max_size`, covers whose longest edge is larger are scaled down to fit and
+/// re-encoded as JPEG; smaller covers are left alone.
+pub fn prepare_cover(bytes: Vec<u8>, jpeg_quality: u8, max_size: Option<u32>) -> Vec<u8> {
+    if let Some(max_size) = max_size
+        && dimensions(&bytes).is_some_and(|(width, height)| width.max(height) > max_size)
+    {
+        return downscale(&bytes, max_size, jpeg_quality).unwrap_or(bytes);
+    }
+    match image::guess_format(&bytes) {
+        Ok(ImageFormat::Jpeg | ImageFormat::Png) | Err(_) => bytes,
+        Ok(_) => encode_jpeg(&bytes, jpeg_quality).unwrap_or(bytes),
+    }
+}
+
+/// File name for a cover stored next to the audio, matching what the bytes contain.
+pub fn folder_file_name(bytes: &[u8]) -> &'static str {
+    match image::guess_format(bytes) {
+        Ok(ImageFormat::Png) => "cover.png",
+        _ => "cover.jpg",
+    }
+}
+
+/// Whether `path` is named like a cover written by [`folder_file_name`].
+pub fn is_folder_cover(path: &Path) -> bool {
+    path.file_name()
+        .is_some_and(|name| name == "cover.jpg" || name == "cover.png")
+}
+
+/// Reads the image size from its header without decoding the pixels.
+fn dimensions(bytes: &[u8]) -> Option<(u32, u32)> {
+    ImageReader::new(Cursor::new(bytes))
+        .with_guessed_format()
+        .ok()?
+        .into_dimensions()
+        .ok()
+}
+
+fn downscale(bytes: &[u8], max_size: u32, quality: u8) -> Result<Vec<u8>> {
+    let image = image::load_from_memory(bytes).context("failed to decode cover art")?;
+    encode_image(
+        &image.resize(max_size, max_size, FilterType::Lanczos3),
+        quality,
+    )
+}
+
+fn encode_jpeg(bytes: &[u8], quality: u8) -> Result<Vec<u8>> {
+    let image = image::load_from_memory(bytes).context("failed to decode cover art")?;
+    encode_image(&image, quality)
+}
+
+fn encode_image(image: &DynamicImage, quality: u8) -> Result<Vec<u8>> {
+    let mut encoded = Vec::new();
+    JpegEncoder::new_with_quality(&mut encoded, quality)
+        .encode_image(&image.to_rgb8())
+        .context("failed to encode cover art as JPEG")?;
+    Ok(encoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgb, RgbImage};
+
+    use super::*;
+
+    fn encoded(format: ImageFormat) -> Vec<u8> {
+        encoded_sized(format, 32, 32)
+    }
+
+    fn encoded_sized(format: ImageFormat, width: u32, height: u32) -> Vec<u8> {
+        let image = RgbImage::from_pixel(width, height, Rgb([200, 40, 90]));
+        let mut bytes = Cursor::new(Vec::new());
+        image.write_to(&mut bytes, format).unwrap();
+        bytes.into_inner()
+    }
+
+    #[test]
+    fn unsupported_cover_formats_are_converted_to_jpeg() {
+        let bmp = encoded(ImageFormat::Bmp);
+        let cover = prepare_cover(bmp, 60, None);
+        assert_eq!(image::guess_format(&cover).unwrap(), ImageFormat::Jpeg);
+    }
+
+    #[test]
+    fn jpeg_png_and_unknown_covers_pass_through_unchanged() {
+        for bytes in [
+            encoded(ImageFormat::Jpeg),
+            encoded(ImageFormat::Png),
+            b"not an image".to_vec(),
+        ] {
+            assert_eq!(prepare_cover(bytes.clone(), 60, Some(600)), bytes);
+        }
+    }
+
+    #[test]
+    fn oversized_covers_are_scaled_to_fit_the_limit() {
+        let cover = prepare_cover(encoded_sized(ImageFormat::Png, 1200, 800), 80, Some(600));
+        assert_eq!(image::guess_format(&cover).unwrap(), ImageFormat::Jpeg);
+        assert_eq!(dimensions(&cover), Some((600, 400)));
+    }
+}
@@ -1,5 +1,7 @@
 use std::collections::HashSet;
 use std::env;
+use std::fs::{self, File};
+use std::io::{self, Write};
 use std::path::{Path, PathBuf};
 use std::process::{Command, Output};
 
@@ -7,44 +9,88 @@ use std::process::{Command, Output};
 use std::os::windows::process::CommandExt;
 
 use anyhow::{Context, Result, bail};
+use sha2::{Digest, Sha256};
+use tempfile::{NamedTempFile, TempDir};
+use walkdir::WalkDir;
 
-use crate::cli::DoctorArgs;
+use clap::ValueEnum;
+
+use crate::cli::{DoctorArgs, FetchArgs};
+use crate::config::{Mode, Requirements};
+use crate::dump::{KGM_EXTENSIONS, QMC_EXTENSIONS, UNSUPPORTED_ENCRYPTED_EXTENSIONS};
+use crate::sync::AUDIO_EXTENSIONS;
 
 #[cfg(windows)]
 const CREATE_NO_WINDOW: u32 = 0x0800_0000;
 
+const STATIC_BUILD_BASE_URL: &str =
+    "https://github.com/BtbN/FFmpeg-Builds/releases/download/latest/";
+const STATIC_BUILD_CHECKSUMS: &str = "checksums.sha256";
+
 pub(crate) fn ffmpeg_command(path: &Path) -> Command {
+    #[cfg_attr(not(windows), allow(unused_mut))]
     let mut command = Command::new(path);
     #[cfg(windows)]
     command.creation_flags(CREATE_NO_WINDOW);
     command
 }
 
-pub fn run(args: DoctorArgs) -> Result<()> {
+/// Reports what this machine can do. FFmpeg problems only fail the check when the
+/// configuration needs FFmpeg; an unreadable configuration is assumed to need it.
+pub fn run(args: DoctorArgs, requirements: Result<Requirements>) -> Result<()> {
     println!("W4DJ doctor");
     println!("  system : {} {}", env::consts::OS, env::consts::ARCH);
+    let requirements = requirements.unwrap_or_else(|error| {
+        eprintln!("  config : {error:#}");
+        Requirements {
+            mode: Mode::Original,
+            ffmpeg: true,
+            encodes: None,
+            ffmpeg_path: None,
+        }
+    });
+    let mode = requirements
+        .mode
+        .to_possible_value()
+        .map(|value| value.get_name().to_string())
+        .unwrap_or_default();
+    let needs = if requirements.ffmpeg {
+        "needs FFmpeg"
+    } else {
+        "FFmpeg optional"
+    };
+    println!("  mode   : {mode} ({needs})");
+    print_formats();
 
-    if let Some(path) = find_ffmpeg() {
+    if let Some(path) = find_ffmpeg(requirements.ffmpeg_path.as_deref()) {
         let report = verify_ffmpeg(&path)?;
         print_report(&path, &report);
+        print_ffprobe(requirements.ffmpeg_path.as_deref());
+        check_encoder(&report, requirements.encodes, args.install)?;
         println!("  status : ready");
         return Ok(());
     }
 
-    if let Some(path) = find_any_ffmpeg() {
+    let problem = if let Some(path) = find_any_ffmpeg() {
         match verify_ffmpeg(&path) {
             Ok(report) => print_report(&path, &report),
             Err(error) => eprintln!("  invalid: {} ({error:#})", path.display()),
         }
-        if !args.install {
-            bail!("FFmpeg is missing an encoder required by W4DJ; run `w4dj doctor --install`");
+        "FFmpeg is missing an encoder required by W4DJ"
+    } else {
+        println!("  ffmpeg : missing");
+        "FFmpeg was not found"
+    };
+    if !args.install {
+        if !requirements.ffmpeg {
+            println!("  status : ready for {mode} mode; {problem}, so other modes will fail");
+            return Ok(());
         }
-    } else if !args.install {
-        bail!("FFmpeg was not found; run `w4dj doctor --install`");
+        bail!("{problem}; run `w4dj doctor --install`");
     }
 
     install_ffmpeg()?;
-    let path = find_ffmpeg().context(
+    let path = find_ffmpeg(None).context(
         "the package manager completed but FFmpeg is not visible yet; reopen the terminal and run `w4dj doctor`",
     )?;
     let report = verify_ffmpeg(&path)?;
@@ -52,16 +98,241 @@ pub fn run(args: DoctorArgs) -> Result<()> {
     if !report.is_usable() {
         bail!("the installed FFmpeg build does not provide all encoders required by W4DJ");
     }
+    check_encoder(&report, requirements.encodes, false)?;
+    println!("  status : installed and ready");
+    Ok(())
+}
+
+pub fn fetch(args: FetchArgs) -> Result<()> {
+    println!("W4DJ ffmpeg fetch");
+    println!("  system : {} {}", env::consts::OS, env::consts::ARCH);
+
+    if !args.force
+        && let Some(path) = find_ffmpeg(None)
+    {
+        println!("  ffmpeg : {}", path.display());
+        println!("  status : already installed (use --force to download anyway)");
+        return Ok(());
+    }
+
+    let install_dir = env::current_exe()
+        .context("failed to locate the w4dj executable")?
+        .parent()
+        .map(Path::to_path_buf)
+        .context("the w4dj executable has no parent directory")?;
+    let workspace = TempDir::with_prefix(".w4dj-ffmpeg-")
+        .context("failed to create a temporary download directory")?;
+
+    let (url, expected) = match args.url {
+        Some(url) => {
+            let expected = args
+                .sha256
+                .context("--sha256 is required together with --url")?;
+            (url, expected)
+        }
+        None => {
+            let asset = static_build_asset(env::consts::OS, env::consts::ARCH).with_context(|| {
+                format!(
+                    "no default FFmpeg build for {} {}; pass --url and --sha256, or run `w4dj doctor --install`",
+                    env::consts::OS,
+                    env::consts::ARCH
+                )
+            })?;
+            let expected = match args.sha256 {
+                Some(expected) => expected,
+                None => {
+                    let checksums = workspace.path().join(STATIC_BUILD_CHECKSUMS);
+                    download(
+                        &format!("{STATIC_BUILD_BASE_URL}{STATIC_BUILD_CHECKSUMS}"),
+                        &checksums,
+                    )?;
+                    let listing = fs::read_to_string(&checksums)
+                        .with_context(|| format!("failed to read {}", checksums.display()))?;
+                    published_checksum(&listing, asset).with_context(|| {
+                        format!("{STATIC_BUILD_CHECKSUMS} does not list {asset}")
+                    })?
+                }
+            };
+            (format!("{STATIC_BUILD_BASE_URL}{asset}"), expected)
+        }
+    };
+
+    let archive_name = url
+        .rsplit('/')
+        .next()
+        .filter(|name| !name.is_empty())
+        .unwrap_or("ffmpeg-archive");
+    let archive = workspace.path().join(archive_name);
+    println!("  source : {url}");
+    download(&url, &archive)?;
+
+    let actual = sha256_file(&archive)?;
+    if !actual.eq_ignore_ascii_case(expected.trim()) {
+        bail!(
+            "checksum mismatch for {url}: expected {}, got {actual}",
+            expected.trim()
+        );
+    }
+    println!("  sha256 : {actual}");
+
+    let extracted = workspace.path().join("extracted");
+    fs::create_dir(&extracted)
+        .with_context(|| format!("failed to create {}", extracted.display()))?;
+    extract_archive(&archive, &extracted)?;
+    let binary = find_extracted_ffmpeg(&extracted)
+        .with_context(|| format!("{archive_name} does not contain an ffmpeg executable"))?;
+
+    let target = install_dir.join(if cfg!(windows) {
+        "ffmpeg.exe"
+    } else {
+        "ffmpeg"
+    });
+    install_binary(&binary, &target)?;
+    let report = match verify_ffmpeg(&target) {
+        Ok(report) => report,
+        Err(error) => {
+            let _ = fs::remove_file(&target);
+            return Err(error.context("the downloaded FFmpeg does not run on this system"));
+        }
+    };
+    print_report(&target, &report);
+    if !report.is_usable() {
+        let _ = fs::remove_file(&target);
+        bail!("the downloaded FFmpeg build does not provide all encoders required by W4DJ");
+    }
     println!("  status : installed and ready");
     Ok(())
 }
 
-pub fn find_ffmpeg() -> Option<PathBuf> {
+fn static_build_asset(os: &str, arch: &str) -> Option<&'static str> {
+    match (os, arch) {
+        ("windows", "x86_64") => Some("ffmpeg-master-latest-win64-gpl.zip"),
+        ("windows", "aarch64") => Some("ffmpeg-master-latest-winarm64-gpl.zip"),
+        ("linux", "x86_64") => Some("ffmpeg-master-latest-linux64-gpl.tar.xz"),
+        ("linux", "aarch64") => Some("ffmpeg-master-latest-linuxarm64-gpl.tar.xz"),
+        _ => None,
+    }
+}
+
+fn published_checksum(listing: &str, asset: &str) -> Option<String> {
+    listing.lines().find_map(|line| {
+        let mut fields = line.split_whitespace();
+        let hash = fields.next()?;
+        let name = fields.next()?.trim_start_matches('*');
+        (name == asset).then(|| hash.to_ascii_lowercase())
+    })
+}
+
+fn download(url: &str, destination: &Path) -> Result<()> {
+    let curl = find_program("curl")
+        .context("curl was not found; install curl or download FFmpeg manually")?;
+    let output = Command::new(curl)
+        .args([
+            "--fail",
+            "--location",
+            "--silent",
+            "--show-error",
+            "--output",
+        ])
+        .arg(destination)
+        .arg(url)
+        .output()
+        .context("failed to start curl")?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        bail!("failed to download {url}: {}", stderr.trim());
+    }
+    Ok(())
+}
+
+fn sha256_file(path: &Path) -> Result<String> {
+    let mut file =
+        File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher)
+        .with_context(|| format!("failed to hash {}", path.display()))?;
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect())
+}
+
+fn extract_archive(archive: &Path, destination: &Path) -> Result<()> {
+    let tar = find_program("tar").context("tar was not found; it is needed to unpack FFmpeg")?;
+    let output = Command::new(tar)
+        .arg("-xf")
+        .arg(archive)
+        .arg("-C")
+        .arg(destination)
+        .output()
+        .context("failed to start tar")?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        bail!("failed to unpack {}: {}", archive.display(), stderr.trim());
+    }
+    Ok(())
+}
+
+fn find_extracted_ffmpeg(root: &Path) -> Option<PathBuf> {
+    WalkDir::new(root)
+        .into_iter()
+        .filter_map(Result::ok)
+        .filter(|entry| entry.file_type().is_file())
+        .map(|entry| entry.into_path())
+        .find(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.eq_ignore_ascii_case("ffmpeg.exe") || name == "ffmpeg")
+        })
+}
+
+fn install_binary(source: &Path, target: &Path) -> Result<()> {
+    let directory = target
+        .parent()
+        .with_context(|| format!("{} has no parent directory", target.display()))?;
+    let mut staged = NamedTempFile::with_prefix_in(".w4dj-", directory)
+        .with_context(|| format!("failed to write into {}", directory.display()))?;
+    let mut input =
+        File::open(source).with_context(|| format!("failed to open {}", source.display()))?;
+    io::copy(&mut input, &mut staged)
+        .with_context(|| format!("failed to copy {}", source.display()))?;
+    staged.flush()?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(staged.path(), fs::Permissions::from_mode(0o755))
+            .with_context(|| format!("failed to mark {} executable", staged.path().display()))?;
+    }
+    staged
+        .persist(target)
+        .with_context(|| format!("failed to install {}", target.display()))?;
+    Ok(())
+}
+
+/// Returns the configured FFmpeg when it exists, otherwise the first usable build found
+/// next to w4dj, in `PATH` or in a package manager location.
+pub fn find_ffmpeg(configured: Option<&Path>) -> Option<PathBuf> {
+    if let Some(path) = configured
+        && path.is_file()
+    {
+        return Some(path.to_path_buf());
+    }
     ffmpeg_candidates()
         .into_iter()
         .find(|path| verify_ffmpeg(path).is_ok_and(|report| report.is_usable()))
 }
 
+/// Returns the ffprobe installed next to the FFmpeg that [`find_ffmpeg`] picks, otherwise
+/// the one in `PATH`.
+pub fn find_ffprobe(configured_ffmpeg: Option<&Path>) -> Option<PathBuf> {
+    let name = format!("ffprobe{}", env::consts::EXE_SUFFIX);
+    find_ffmpeg(configured_ffmpeg)
+        .and_then(|ffmpeg| Some(ffmpeg.parent()?.join(&name)))
+        .filter(|path| path.is_file())
+        .or_else(|| which::which("ffprobe").ok())
+}
+
 fn find_any_ffmpeg() -> Option<PathBuf> {
     ffmpeg_candidates()
         .into_iter()
@@ -73,12 +344,46 @@ struct DoctorReport {
     version: String,
     libmp3lame: bool,
     pcm_s16le: bool,
+    aac: bool,
+    libopus: bool,
 }
 
 impl DoctorReport {
     fn is_usable(&self) -> bool {
         self.libmp3lame && self.pcm_s16le
     }
+
+    /// The encoder `mode` needs when this build lacks it.
+    fn missing_encoder(&self, mode: Mode) -> Option<&'static str> {
+        let (encoder, present) = match mode {
+            Mode::Original => return None,
+            Mode::Mp3 => ("libmp3lame", self.libmp3lame),
+            Mode::Wav => ("pcm_s16le", self.pcm_s16le),
+            Mode::Aac => ("aac", self.aac),
+            Mode::Opus => ("libopus", self.libopus),
+        };
+        (!present).then_some(encoder)
+    }
+}
+
+/// Fails when the FFmpeg found cannot encode to `encodes`, the format the configuration
+/// transcodes to.
+fn check_encoder(report: &DoctorReport, encodes: Option<Mode>, install: bool) -> Result<()> {
+    let Some((mode, encoder)) =
+        encodes.and_then(|mode| Some((mode, report.missing_encoder(mode)?)))
+    else {
+        return Ok(());
+    };
+    let name = mode
+        .to_possible_value()
+        .map(|value| value.get_name().to_string())
+        .unwrap_or_default();
+    let hint = if install {
+        "install an FFmpeg build that includes it and set ffmpeg_path"
+    } else {
+        "run `w4dj doctor --install` or set ffmpeg_path to a build that includes it"
+    };
+    bail!("FFmpeg has no {encoder} encoder, which {name} output needs; {hint}")
 }
 
 fn verify_ffmpeg(path: &Path) -> Result<DoctorReport> {
@@ -103,6 +408,8 @@ fn verify_ffmpeg(path: &Path) -> Result<DoctorReport> {
         version,
         libmp3lame: encoder_is_present(&listing, "libmp3lame"),
         pcm_s16le: encoder_is_present(&listing, "pcm_s16le"),
+        aac: encoder_is_present(&listing, "aac"),
+        libopus: encoder_is_present(&listing, "libopus"),
     })
 }
 
@@ -139,6 +446,27 @@ fn print_report(path: &Path, report: &DoctorReport) {
         availability(report.libmp3lame)
     );
     println!("  wav    : {} (pcm_s16le)", availability(report.pcm_s16le));
+    println!("  aac    : {} (aac)", availability(report.aac));
+    println!("  opus   : {} (libopus)", availability(report.libopus));
+}
+
+/// Lists the input formats this build reads; decryption is always compiled in.
+fn print_formats() {
+    println!("  inputs : {}, zip archives", AUDIO_EXTENSIONS.join(", "));
+    println!("  qmc    : {}", QMC_EXTENSIONS.join(", "));
+    println!("  kgm    : {}", KGM_EXTENSIONS.join(", "));
+    println!(
+        "  locked : {} (recognized, cannot be decrypted yet)",
+        UNSUPPORTED_ENCRYPTED_EXTENSIONS.join(", ")
+    );
+}
+
+/// ffprobe is optional, so its absence is reported but never fails the check.
+fn print_ffprobe(configured_ffmpeg: Option<&Path>) {
+    match find_ffprobe(configured_ffmpeg) {
+        Some(path) => println!("  ffprobe: {}", path.display()),
+        None => println!("  ffprobe: missing (optional)"),
+    }
 }
 
 fn availability(available: bool) -> &'static str {
@@ -375,6 +703,24 @@ mod tests {
         assert!(!encoder_is_present(listing, "mp3"));
     }
 
+    #[test]
+    fn configured_output_format_needs_its_encoder() {
+        let report = DoctorReport {
+            version: String::new(),
+            libmp3lame: true,
+            pcm_s16le: true,
+            aac: false,
+            libopus: false,
+        };
+        assert_eq!(report.missing_encoder(Mode::Original), None);
+        assert_eq!(report.missing_encoder(Mode::Mp3), None);
+        assert_eq!(report.missing_encoder(Mode::Opus), Some("libopus"));
+        assert!(check_encoder(&report, None, false).is_ok());
+        assert!(check_encoder(&report, Some(Mode::Wav), false).is_ok());
+        let error = check_encoder(&report, Some(Mode::Aac), false).unwrap_err();
+        assert!(error.to_string().contains("no aac encoder"));
+    }
+
     #[test]
     fn package_manager_selection_follows_platform_priority() {
         let manager = select_package_manager("windows", |name| matches!(name, "winget" | "choco"));
@@ -383,4 +729,28 @@ mod tests {
         let manager = select_package_manager("linux", |name| matches!(name, "pacman" | "apk"));
         assert_eq!(manager, Some(PackageManager::Pacman));
     }
+
+    #[test]
+    fn static_builds_cover_windows_and_linux_only() {
+        assert_eq!(
+            static_build_asset("windows", "x86_64"),
+            Some("ffmpeg-master-latest-win64-gpl.zip")
+        );
+        assert_eq!(
+            static_build_asset("linux", "aarch64"),
+            Some("ffmpeg-master-latest-linuxarm64-gpl.tar.xz")
+        );
+        assert_eq!(static_build_asset("macos", "aarch64"), None);
+    }
+
+    #[test]
+    fn published_checksums_match_exact_asset_names() {
+        let listing = "ABC123  ffmpeg-master-latest-win64-gpl-shared.zip\n\
+                       def456 *ffmpeg-master-latest-win64-gpl.zip\n";
+        assert_eq!(
+            published_checksum(listing, "ffmpeg-master-latest-win64-gpl.zip").as_deref(),
+            Some("def456")
+        );
+        assert_eq!(published_checksum(listing, "ffmpeg.zip"), None);
+    }
 }
@@ -1,27 +1,52 @@
+use std::collections::HashSet;
 use std::fs::{self, File};
-use std::io::{Read, Write};
+use std::io::{self, BufReader, Read, Seek, Write};
 use std::path::{Path, PathBuf};
 use std::process::Stdio;
 use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
 use std::thread;
 use std::time::Duration;
 
 use anyhow::{Context, Result, bail};
 use id3::frame::{ExtendedText, Picture, PictureType as Id3PictureType};
 use id3::{TagLike, Version};
-use lofty::config::ParseOptions;
+use lofty::config::{ParseOptions, WriteOptions};
 use lofty::file::{AudioFile, TaggedFileExt};
-use lofty::picture::PictureType as LoftyPictureType;
+use lofty::mp4::{Atom, AtomData, AtomIdent, Ilst, Mp4File};
+use lofty::ogg::{OggPictureStorage, OpusFile, VorbisComments, VorbisFile};
+use lofty::picture::{
+    MimeType, Picture as LoftyPicture, PictureInformation, PictureType as LoftyPictureType,
+};
 use lofty::probe::Probe;
-use lofty::tag::{Accessor, ItemKey};
-use ncmdump::{NcmInfo, Ncmdump};
+use lofty::tag::items::Timestamp;
+use lofty::tag::{Accessor, ItemKey, ItemValue, TagExt};
+use ncmdump::{NcmInfo, Ncmdump, QmcDump};
 use serde::{Deserialize, Serialize};
 use tempfile::{Builder as TempBuilder, TempPath};
+use unicode_normalization::UnicodeNormalization;
+use walkdir::WalkDir;
 
-use crate::config::Mode;
+use crate::config::{Compare, Link, Mode, Mp3Encoding, SyncOptions};
+use crate::cover::{self, DEFAULT_JPEG_QUALITY};
 use crate::doctor;
+use crate::error::SyncError;
+use crate::hashes::{self, FingerprintCache, HashingWriter};
+use crate::kgm::KgmReader;
 
 const W4DJ_ID: &str = "W4DJ_ID";
+const ITUNES_MEAN: &str = "com.apple.iTunes";
+/// Loudness that ReplayGain 2.0 plays tracks back at.
+const REPLAYGAIN_REFERENCE_LUFS: f64 = -18.0;
+/// Loudness that Opus `R128_TRACK_GAIN` tags are relative to.
+const R128_REFERENCE_LUFS: f64 = -23.0;
+/// QQ Music formats using the original static-key cipher, which `QmcDump` can decrypt.
+pub(crate) const QMC_EXTENSIONS: [&str; 4] = ["qmc0", "qmc3", "qmcflac", "qmcogg"];
+/// Kugou formats, which [`KgmReader`] decrypts.
+pub(crate) const KGM_EXTENSIONS: [&str; 3] = ["kgm", "kgma", "vpr"];
+/// Encrypted formats that are recognized but cannot be decrypted yet: `mflac` and `mgg` need
+/// keys stored outside the file, so these sources fail one by one.
+pub(crate) const UNSUPPORTED_ENCRYPTED_EXTENSIONS: [&str; 2] = ["mflac", "mgg"];
 
 #[derive(Debug)]
 pub(crate) struct Cancelled;
@@ -34,6 +59,22 @@ impl std::fmt::Display for Cancelled {
 
 impl std::error::Error for Cancelled {}
 
+/// A zero-byte source, which is skipped with a warning instead of failing the run.
+#[derive(Debug)]
+pub(crate) struct EmptySource;
+
+impl std::fmt::Display for EmptySource {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        formatter.write_str("the file is empty (0 bytes), probably an incomplete download")
+    }
+}
+
+impl std::error::Error for EmptySource {}
+
+pub(crate) fn is_empty_source(error: &anyhow::Error) -> bool {
+    error.downcast_ref::<EmptySource>().is_some()
+}
+
 pub(crate) fn ensure_not_cancelled(cancel: &AtomicBool) -> Result<()> {
     if cancel.load(Ordering::Relaxed) {
         Err(Cancelled.into())
@@ -51,10 +92,18 @@ pub struct SourceVariant {
     pub format: String,
     pub bitrate: Option<u64>,
     pub size: u64,
+    /// File size without tags and embedded artwork, recorded for `compare = "payload-size"`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub payload: Option<u64>,
+    /// Content hash of the source, recorded for `compare = "hash"`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
 }
 
 impl SourceVariant {
-    pub fn is_better_than(&self, other: &Self) -> bool {
+    /// Whether this variant should replace `other`: a better format or bitrate wins, and
+    /// otherwise the size must grow by more than `size_tolerance` (a fraction of `other`).
+    pub fn is_better_than(&self, other: &Self, compare: Compare, size_tolerance: f64) -> bool {
         let rank = format_rank(&self.format);
         let other_rank = format_rank(&other.format);
         if rank != other_rank {
@@ -64,8 +113,14 @@ impl SourceVariant {
         match (self.bitrate, other.bitrate) {
             (Some(current), Some(previous)) if current != previous => current > previous,
             _ => {
-                let threshold = other.size / 20;
-                self.size > other.size.saturating_add(threshold)
+                let (size, other_size) = match (compare, self.payload, other.payload) {
+                    (Compare::PayloadSize, Some(payload), Some(other_payload)) => {
+                        (payload, other_payload)
+                    }
+                    _ => (self.size, other.size),
+                };
+                let threshold = (other_size as f64 * size_tolerance) as u64;
+                size > other_size.saturating_add(threshold)
             }
         }
     }
@@ -78,6 +133,32 @@ pub struct SourceItem {
     pub fallback_id: String,
     pub display_name: String,
     pub variant: SourceVariant,
+    /// Tags read while inspecting the source, used to lay out `organize` outputs.
+    pub tags: SourceTags,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct SourceTags {
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub title: Option<String>,
+}
+
+impl SourceTags {
+    fn from_metadata(metadata: &MediaMetadata) -> Self {
+        let present = |value: &Option<String>| {
+            value
+                .as_deref()
+                .map(str::trim)
+                .filter(|value| !value.is_empty())
+                .map(str::to_string)
+        };
+        Self {
+            artist: present(&metadata.artist),
+            album: present(&metadata.album),
+            title: present(&metadata.title),
+        }
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -94,6 +175,316 @@ pub struct Job {
     pub old_output: Option<PathBuf>,
     pub mode: Mode,
     pub ffmpeg: Option<PathBuf>,
+    /// Shared limit on concurrent FFmpeg processes, when `max_transcodes` is set.
+    pub transcode_slots: Option<Arc<TranscodeSlots>>,
+    /// Output folders this run already created, shared by every job.
+    pub output_dirs: Arc<OutputDirectories>,
+    /// Where `keep_original` saves the untranscoded audio next to the transcode.
+    pub original: Option<PathBuf>,
+    /// Set with `--staging`, which writes the job's files into the staging folder instead.
+    pub staging: Option<Arc<Staging>>,
+    /// Set with `--move` for sources the run deletes once the manifest records their output.
+    pub move_source: bool,
+}
+
+impl Job {
+    /// Where this job writes `path`, a file in the output: the path itself, or the same
+    /// place in the staging folder.
+    pub(crate) fn write_path(&self, path: &Path) -> PathBuf {
+        match &self.staging {
+            Some(staging) => staging.path(path),
+            None => path.to_path_buf(),
+        }
+    }
+}
+
+/// A folder inside the output that receives every file of a `--staging` run, so the real
+/// output only changes once the whole run succeeded.
+#[derive(Debug)]
+pub struct Staging {
+    output: PathBuf,
+    root: PathBuf,
+}
+
+impl Staging {
+    pub fn new(output: &Path, root: PathBuf) -> Self {
+        Self {
+            output: output.to_path_buf(),
+            root,
+        }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// The place of `path`, a file in the output, inside the staging folder.
+    pub fn path(&self, path: &Path) -> PathBuf {
+        match path.strip_prefix(&self.output) {
+            Ok(relative) => self.root.join(relative),
+            Err(_) => path.to_path_buf(),
+        }
+    }
+
+    /// Whether an earlier promotion stopped halfway, leaving the output partly replaced.
+    /// Its staging folder then holds the only copy of the files it had not moved yet.
+    pub fn interrupted(&self) -> bool {
+        self.root.join(PROMOTING_MARKER).exists()
+    }
+
+    /// Renames every staged file over its place in the output and removes the staging
+    /// folder. Folder covers the output already has are kept, as when writing directly.
+    ///
+    /// Files that are replaced are first moved aside into the staging folder. If a rename
+    /// fails, the files moved so far are put back and the staged files stay where they
+    /// were, so the output is either fully promoted or unchanged.
+    pub fn promote(&self) -> Result<usize> {
+        if !self.root.exists() {
+            return Ok(0);
+        }
+        let mut moves = Vec::new();
+        for entry in WalkDir::new(&self.root).min_depth(1).sort_by_file_name() {
+            let entry = entry.with_context(|| {
+                format!("failed to read staging folder {}", self.root.display())
+            })?;
+            if !entry.file_type().is_file() {
+                continue;
+            }
+            let relative = entry.path().strip_prefix(&self.root)?;
+            let destination = self.output.join(relative);
+            if cover::is_folder_cover(&destination)
+                && fs::metadata(&destination).is_ok_and(|metadata| metadata.len() > 0)
+            {
+                continue;
+            }
+            moves.push((entry.path().to_path_buf(), destination));
+        }
+        let marker = self.root.join(PROMOTING_MARKER);
+        fs::write(&marker, b"").with_context(|| format!("failed to write {}", marker.display()))?;
+        let replaced = self.root.join(REPLACED_DIR);
+        let mut done = Vec::new();
+        for (index, (staged, destination)) in moves.iter().enumerate() {
+            let backup = destination
+                .exists()
+                .then(|| replaced.join(index.to_string()));
+            if let Err(error) = promote_file(staged, destination, backup.as_deref()) {
+                return match roll_back(&done) {
+                    Ok(()) => {
+                        fs::remove_file(&marker)
+                            .with_context(|| format!("failed to remove {}", marker.display()))?;
+                        Err(error.context("the output was left unchanged"))
+                    }
+                    Err(rollback) => Err(error.context(format!(
+                        "the output is partly replaced and could not be restored ({rollback:#}); \
+                         the remaining files are in {}",
+                        self.root.display()
+                    ))),
+                };
+            }
+            done.push((staged.as_path(), destination.as_path(), backup));
+        }
+        fs::remove_dir_all(&self.root)
+            .with_context(|| format!("failed to remove staging folder {}", self.root.display()))?;
+        Ok(moves.len())
+    }
+}
+
+/// Marks a staging folder whose files are being moved into the output.
+const PROMOTING_MARKER: &str = ".w4dj-promoting";
+/// Folder inside the staging folder that keeps the outputs a promotion replaces until it
+/// is done.
+const REPLACED_DIR: &str = ".w4dj-replaced";
+
+/// Moves `destination` to `backup`, if given, and `staged` into its place.
+fn promote_file(staged: &Path, destination: &Path, backup: Option<&Path>) -> Result<()> {
+    if let Some(parent) = destination.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("failed to create output directory {}", parent.display()))?;
+    }
+    if let Some(backup) = backup {
+        if let Some(parent) = backup.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("failed to create {}", parent.display()))?;
+        }
+        fs::rename(destination, backup)
+            .with_context(|| format!("failed to move {} aside", destination.display()))?;
+    }
+    if let Err(error) = fs::rename(staged, destination) {
+        if let Some(backup) = backup {
+            let _ = fs::rename(backup, destination);
+        }
+        return Err(error).with_context(|| {
+            format!(
+                "failed to move {} into place at {}",
+                staged.display(),
+                destination.display()
+            )
+        });
+    }
+    Ok(())
+}
+
+/// Undoes the promoted files in `done`, newest first.
+fn roll_back(done: &[(&Path, &Path, Option<PathBuf>)]) -> Result<()> {
+    for (staged, destination, backup) in done.iter().rev() {
+        fs::rename(destination, staged)
+            .with_context(|| format!("failed to move {} back", destination.display()))?;
+        if let Some(backup) = backup {
+            fs::rename(backup, destination)
+                .with_context(|| format!("failed to restore {}", destination.display()))?;
+        }
+    }
+    Ok(())
+}
+
+/// What a finished job noticed about its source beyond success.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Processed {
+    /// The NCM source carried no cover art, so the output was tagged without one.
+    pub missing_cover: bool,
+    /// The `--move` source already is its output, so nothing was written; the run renames it
+    /// onto the target with [`move_source`] once the manifest records it.
+    pub rename_source: bool,
+}
+
+/// Remembers which output folders exist, so songs that share an album folder create it
+/// once instead of asking the drive again for every file, which is slow on network shares.
+#[derive(Debug, Default)]
+pub struct OutputDirectories {
+    created: Mutex<HashSet<PathBuf>>,
+}
+
+impl OutputDirectories {
+    pub(crate) fn create(&self, directory: &Path) -> Result<()> {
+        if self.created.lock().unwrap().contains(directory) {
+            return Ok(());
+        }
+        fs::create_dir_all(directory).with_context(|| {
+            format!("failed to create output directory {}", directory.display())
+        })?;
+        self.created.lock().unwrap().insert(directory.to_path_buf());
+        Ok(())
+    }
+}
+
+/// Caps how many FFmpeg processes run at once, independently of the worker count, so
+/// copies keep the full pool while CPU-bound transcodes take turns.
+#[derive(Debug)]
+pub struct TranscodeSlots {
+    free: Mutex<usize>,
+    released: Condvar,
+}
+
+pub(crate) struct TranscodeSlot<'a> {
+    slots: &'a TranscodeSlots,
+}
+
+impl TranscodeSlots {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            free: Mutex::new(limit),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Waits for a free slot, giving up when the run is cancelled.
+    pub(crate) fn acquire(&self, cancel: &AtomicBool) -> Result<TranscodeSlot<'_>> {
+        let mut free = self
+            .free
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        loop {
+            ensure_not_cancelled(cancel)?;
+            if *free > 0 {
+                *free -= 1;
+                return Ok(TranscodeSlot { slots: self });
+            }
+            free = self
+                .released
+                .wait_timeout(free, Duration::from_millis(100))
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .0;
+        }
+    }
+}
+
+impl Drop for TranscodeSlot<'_> {
+    fn drop(&mut self) {
+        *self
+            .slots
+            .free
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) += 1;
+        self.slots.released.notify_one();
+    }
+}
+
+/// What processing a job needs, as shown in dry-run plans.
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobAction {
+    Copy,
+    Decrypt,
+    Transcode,
+    #[serde(rename = "decrypt+transcode")]
+    DecryptAndTranscode,
+}
+
+impl std::fmt::Display for JobAction {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        formatter.write_str(match self {
+            Self::Copy => "copy",
+            Self::Decrypt => "decrypt",
+            Self::Transcode => "transcode",
+            Self::DecryptAndTranscode => "decrypt+transcode",
+        })
+    }
+}
+
+impl Job {
+    pub fn action(&self) -> JobAction {
+        match (is_encrypted(&self.source.path), self.mode.needs_ffmpeg()) {
+            (false, false) => JobAction::Copy,
+            (true, false) => JobAction::Decrypt,
+            (false, true) => JobAction::Transcode,
+            (true, true) => JobAction::DecryptAndTranscode,
+        }
+    }
+}
+
+const TAG_CACHE_NAME: &str = ".w4dj-tags.json";
+
+/// The tags `compare = "tags"` looks at, normalized for comparison.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub(crate) struct TagSummary {
+    title: Option<String>,
+    artist: Option<String>,
+    album: Option<String>,
+    /// Unknown for NCM sources, whose artwork is only read when they are decrypted.
+    has_cover: Option<bool>,
+}
+
+impl TagSummary {
+    fn from_metadata(metadata: &MediaMetadata, has_cover: Option<bool>) -> Self {
+        let normalized = |value: &Option<String>| value.as_deref().map(normalize);
+        Self {
+            title: normalized(&metadata.title),
+            artist: normalized(&metadata.artist),
+            album: normalized(&metadata.album),
+            has_cover,
+        }
+    }
+
+    /// Whether `output` is missing or contradicts a tag this source carries. Tags the source
+    /// lacks are ignored, matching the writers, which never clear existing output tags.
+    fn differs_from(&self, output: &Self) -> bool {
+        let differs =
+            |source: &Option<String>, output: &Option<String>| source.is_some() && source != output;
+        differs(&self.title, &output.title)
+            || differs(&self.artist, &output.artist)
+            || differs(&self.album, &output.album)
+            || (self.has_cover == Some(true) && output.has_cover != Some(true))
+    }
 }
 
 #[derive(Clone, Debug, Default)]
@@ -101,7 +492,9 @@ struct MediaMetadata {
     title: Option<String>,
     artist: Option<String>,
     album: Option<String>,
+    album_artist: Option<String>,
     genre: Option<String>,
+    date: Option<Timestamp>,
     track: Option<u32>,
     track_total: Option<u32>,
     disc: Option<u32>,
@@ -109,28 +502,69 @@ struct MediaMetadata {
     duration_secs: u64,
     platform_id: Option<String>,
     cover: Option<Vec<u8>>,
+    lyrics: Option<String>,
 }
 
-pub fn inspect_source(path: &Path) -> Result<SourceItem> {
+impl MediaMetadata {
+    /// Fills the fields this metadata lacks from `other`, keeping the ones it has.
+    fn fill_from(&mut self, other: Self) {
+        fn fill<T>(field: &mut Option<T>, other: Option<T>) {
+            if field.is_none() {
+                *field = other;
+            }
+        }
+        fill(&mut self.title, other.title);
+        fill(&mut self.artist, other.artist);
+        fill(&mut self.album, other.album);
+        fill(&mut self.album_artist, other.album_artist);
+        fill(&mut self.genre, other.genre);
+        fill(&mut self.date, other.date);
+        fill(&mut self.track, other.track);
+        fill(&mut self.track_total, other.track_total);
+        fill(&mut self.disc, other.disc);
+        fill(&mut self.disc_total, other.disc_total);
+        fill(&mut self.platform_id, other.platform_id);
+        fill(&mut self.cover, other.cover);
+        fill(&mut self.lyrics, other.lyrics);
+    }
+}
+
+pub fn inspect_source(path: &Path, compare: Compare) -> Result<SourceItem> {
     let size = fs::metadata(path)
-        .with_context(|| format!("failed to read metadata for {}", path.display()))?
+        .with_context(|| format!("cannot read {}; skipping it", path.display()))?
         .len();
+    if size == 0 {
+        return Err(EmptySource.into());
+    }
     let display_name = path
         .file_stem()
         .and_then(|name| name.to_str())
         .filter(|name| !name.is_empty())
         .unwrap_or("track")
-        .to_string();
+        .nfc()
+        .collect::<String>();
     let extension = extension(path);
 
     if extension == "ncm" {
         inspect_ncm(path, size, display_name)
+    } else if let Some(cipher) = Cipher::of(path) {
+        inspect_encrypted(path, size, display_name, cipher)
+    } else if UNSUPPORTED_ENCRYPTED_EXTENSIONS.contains(&extension.as_str()) {
+        bail!(
+            "cannot decrypt .{extension} files; only NCM, QMC ({}) and KGM ({}) are supported",
+            QMC_EXTENSIONS.join(", "),
+            KGM_EXTENSIONS.join(", ")
+        )
     } else {
         let (metadata, properties) = read_regular_metadata(path, false)?;
         let fallback_id = metadata_id(&metadata, &display_name);
         let id = read_embedded_id(path)
             .or(metadata.platform_id.clone())
             .unwrap_or_else(|| fallback_id.clone());
+        let payload = match compare {
+            Compare::Size | Compare::Hash | Compare::Tags => None,
+            Compare::PayloadSize => Some(size.saturating_sub(tag_overhead(path)?)),
+        };
         Ok(SourceItem {
             path: path.to_path_buf(),
             id,
@@ -140,7 +574,10 @@ pub fn inspect_source(path: &Path) -> Result<SourceItem> {
                 format: extension,
                 bitrate: properties.audio_bitrate().map(u64::from),
                 size,
+                payload,
+                hash: None,
             },
+            tags: SourceTags::from_metadata(&metadata),
         })
     }
 }
@@ -149,9 +586,11 @@ pub fn inspect_output(path: &Path) -> Result<OutputIdentity> {
     let display_name = path
         .file_stem()
         .and_then(|name| name.to_str())
-        .unwrap_or("track");
+        .unwrap_or("track")
+        .nfc()
+        .collect::<String>();
     let (metadata, _) = read_regular_metadata(path, false)?;
-    let fallback_id = metadata_id(&metadata, display_name);
+    let fallback_id = metadata_id(&metadata, &display_name);
     let embedded_id = read_embedded_id(path);
     let has_embedded_id = embedded_id.is_some();
     let id = embedded_id
@@ -164,19 +603,422 @@ pub fn inspect_output(path: &Path) -> Result<OutputIdentity> {
     })
 }
 
-pub(crate) fn process_with_cancel(job: &Job, cancel: &AtomicBool) -> Result<()> {
+/// Tag summaries of sources and outputs, so `compare = "tags"` only reads files that changed.
+pub(crate) type TagCache = FingerprintCache<TagSummary>;
+
+impl TagCache {
+    pub(crate) fn load_tags(output: &Path) -> Self {
+        Self::load(output, TAG_CACHE_NAME)
+    }
+}
+
+/// Reads the tags of both files, or their cached summaries, and reports whether the output
+/// needs to be rewritten.
+pub(crate) fn tags_differ(source: &SourceItem, output: &Path, cache: &TagCache) -> Result<bool> {
+    let source_tags = cache.get(&source.path, |path| {
+        if extension(path) == "ncm" {
+            Ok(TagSummary::from_metadata(
+                &metadata_from_ncm(&read_ncm_info(path)?, None),
+                None,
+            ))
+        } else if let Some(cipher) = Cipher::of(path) {
+            let (metadata, _) = read_encrypted_metadata(path, cipher, true)?;
+            Ok(TagSummary::from_metadata(
+                &metadata,
+                Some(metadata.cover.is_some()),
+            ))
+        } else {
+            read_tag_summary(path)
+        }
+    })?;
+    let output_tags = cache.get(output, read_tag_summary)?;
+    Ok(source_tags.value.differs_from(&output_tags.value))
+}
+
+fn read_tag_summary(path: &Path) -> Result<TagSummary> {
+    let (metadata, _) = read_regular_metadata(path, true)?;
+    Ok(TagSummary::from_metadata(
+        &metadata,
+        Some(metadata.cover.is_some()),
+    ))
+}
+
+/// Syncs one job, classifying a failure by the stage it happened in. `on_copied` is called
+/// with the size of every chunk of audio copied so the caller can show progress inside
+/// large files.
+pub(crate) fn process_with_cancel(
+    job: &Job,
+    options: &SyncOptions,
+    cancel: &AtomicBool,
+    on_copied: &dyn Fn(u64),
+) -> Result<Processed, SyncError> {
+    process_job(job, options, cancel, on_copied).map_err(|error| {
+        if is_cancelled(&error) {
+            return SyncError::Cancelled;
+        }
+        error
+            .downcast::<SyncError>()
+            .unwrap_or_else(|error| Stage::Io.error(&job.source.display_name, error))
+    })
+}
+
+/// Processing step of a job whose errors map to one [`SyncError`] variant.
+#[derive(Clone, Copy)]
+enum Stage {
+    Ncm,
+    Qmc,
+    Kgm,
+    Transcode,
+    Metadata,
+    Validation,
+    Io,
+}
+
+impl Stage {
+    fn error(self, song: &str, error: anyhow::Error) -> SyncError {
+        let song = song.to_string();
+        let source = error.into();
+        match self {
+            Self::Ncm => SyncError::Ncm { song, source },
+            Self::Qmc => SyncError::Qmc { song, source },
+            Self::Kgm => SyncError::Kgm { song, source },
+            Self::Transcode => SyncError::Transcode { song, source },
+            Self::Metadata => SyncError::MetadataInject { song, source },
+            Self::Validation => SyncError::Validation { song, source },
+            Self::Io => SyncError::Io { song, source },
+        }
+    }
+
+    /// Wraps `error` as this stage's [`SyncError`], leaving cancellation untouched.
+    fn fail(self, song: &str, error: anyhow::Error) -> anyhow::Error {
+        if is_cancelled(&error) {
+            error
+        } else {
+            self.error(song, error).into()
+        }
+    }
+}
+
+fn process_job(
+    job: &Job,
+    options: &SyncOptions,
+    cancel: &AtomicBool,
+    on_copied: &dyn Fn(u64),
+) -> Result<Processed> {
     ensure_not_cancelled(cancel)?;
-    let parent = job
-        .target
+    let target = job.write_path(&job.target);
+    let parent = target
         .parent()
         .context("target file has no parent directory")?;
-    fs::create_dir_all(parent)
-        .with_context(|| format!("failed to create output directory {}", parent.display()))?;
+    job.output_dirs.create(parent)?;
 
-    let (metadata, prepared_audio) = prepare_source(&job.source, parent, cancel)?;
+    let (metadata, prepared_audio) = decode(job, options, parent, cancel, on_copied)?;
     ensure_not_cancelled(cancel)?;
+    if renames_source(job, options, &prepared_audio, parent) {
+        write_sidecars(&target, parent, &metadata, options)?;
+        remove_superseded(job, options);
+        return Ok(Processed {
+            missing_cover: lacks_cover(&job.source.path, &metadata, options),
+            rename_source: true,
+        });
+    }
+    let original_target = job
+        .original
+        .as_deref()
+        .map(|original| job.write_path(original));
+    let original = original_target
+        .as_deref()
+        .map(|original| stage_original(job, options, original, &prepared_audio, &metadata, cancel))
+        .transpose()?;
+    ensure_not_cancelled(cancel)?;
+    let staged = encode(job, options, parent, prepared_audio, cancel, on_copied)?;
+    ensure_not_cancelled(cancel)?;
+    // A linked output already reads back as the job's track and has nothing to change.
+    if let Some(owned) = staged.owned_path() {
+        tag_output(owned, job, &metadata, options)?;
+        ensure_not_cancelled(cancel)?;
+        if options.replaygain.unwrap_or(false) {
+            tag_replaygain(owned, job, cancel)?;
+        }
+    }
+    staged.publish(&target)?;
+    if let (Some(staged), Some(original)) = (original, &original_target) {
+        staged.publish(original)?;
+    }
+    write_sidecars(&target, parent, &metadata, options)?;
+    // A staged run swaps outputs only once it succeeded, so it replaces them then.
+    if job.staging.is_none() {
+        remove_superseded(job, options);
+    }
+    Ok(Processed {
+        missing_cover: lacks_cover(&job.source.path, &metadata, options),
+        rename_source: false,
+    })
+}
+
+/// Whether a `--move` source is left untouched now and renamed onto its target after the
+/// manifest is saved. That needs a source w4dj would not write to, on the output's drive:
+/// the rename then costs nothing, and until it happens the song is still the user's file.
+fn renames_source(job: &Job, options: &SyncOptions, audio: &PreparedAudio, parent: &Path) -> bool {
+    let PreparedAudio::Borrowed(source) = audio else {
+        return false;
+    };
+    job.move_source
+        && job.staging.is_none()
+        && job.original.is_none()
+        && !job.mode.needs_ffmpeg()
+        && same_device(source, parent)
+        && can_link(job, options, source)
+}
+
+/// Whether `path` and the folder `directory` live on the same drive.
+#[cfg(unix)]
+fn same_device(path: &Path, directory: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    match (fs::metadata(path), fs::metadata(directory)) {
+        (Ok(path), Ok(directory)) => path.dev() == directory.dev(),
+        _ => false,
+    }
+}
+
+/// Whether `path` and the folder `directory` live on the same drive.
+#[cfg(windows)]
+fn same_device(path: &Path, directory: &Path) -> bool {
+    use std::path::Component;
+
+    let drive = |path: &Path| match fs::canonicalize(path).ok()?.components().next()? {
+        Component::Prefix(prefix) => Some(prefix.as_os_str().to_ascii_lowercase()),
+        _ => None,
+    };
+    matches!((drive(path), drive(directory)), (Some(path), Some(directory)) if path == directory)
+}
+
+#[cfg(not(any(unix, windows)))]
+fn same_device(_path: &Path, _directory: &Path) -> bool {
+    false
+}
+
+/// Moves a source that [`renames_source`] left in place onto its `target`. Where the rename
+/// crosses drives after all, for example through a bind mount, the source is copied with
+/// its modification time, so the output still matches the stamp the manifest recorded, and
+/// then removed.
+pub(crate) fn move_source(source: &Path, target: &Path, options: &SyncOptions) -> Result<()> {
+    match fs::rename(source, target) {
+        Ok(()) => return Ok(()),
+        Err(error) if error.kind() == io::ErrorKind::CrossesDevices => {
+            log::debug!(
+                "copying {} instead of renaming it: {error}",
+                source.display()
+            );
+        }
+        Err(error) => {
+            return Err(error).with_context(|| {
+                format!(
+                    "failed to move {} to {}",
+                    source.display(),
+                    target.display()
+                )
+            });
+        }
+    }
+    let parent = target
+        .parent()
+        .context("target file has no parent directory")?;
+    let temp = create_temp(parent, &extension(target))?;
+    let temp_path: &Path = temp.as_ref();
+    let mut input = File::open(source)
+        .with_context(|| format!("failed to open {} for copying", source.display()))?;
+    let modified = input
+        .metadata()
+        .and_then(|metadata| metadata.modified())
+        .with_context(|| {
+            format!(
+                "failed to read the modification time of {}",
+                source.display()
+            )
+        })?;
+    let output =
+        File::create(temp_path).context("failed to create temporary output for copying")?;
+    copy_to_file(
+        &mut input,
+        output,
+        temp_path,
+        options.verify.unwrap_or(false),
+        options.copy_buffer_size(),
+        &AtomicBool::new(false),
+        &|_| {},
+    )
+    .with_context(|| format!("failed to copy {} to a temporary file", source.display()))?;
+    fs::OpenOptions::new()
+        .write(true)
+        .open(temp_path)
+        .and_then(|file| file.set_modified(modified))
+        .context("failed to set the modification time of the copy")?;
+    StagedOutput {
+        temp,
+        linked: false,
+    }
+    .publish(target)?;
+    fs::remove_file(source).with_context(|| format!("failed to remove {}", source.display()))
+}
+
+/// Whether an NCM source came without cover art. Some legitimately have none; the song is
+/// still synced, and the run lists it rather than failing. Other formats often keep their
+/// art in a folder image instead, so only NCM is worth reporting.
+fn lacks_cover(source: &Path, metadata: &MediaMetadata, options: &SyncOptions) -> bool {
+    extension(source) == "ncm"
+        && metadata.cover.is_none()
+        && !options.strip_metadata.unwrap_or(false)
+}
+
+/// Decrypts or opens the source and reads its metadata, with the cover prepared for
+/// embedding.
+fn decode(
+    job: &Job,
+    options: &SyncOptions,
+    parent: &Path,
+    cancel: &AtomicBool,
+    on_copied: &dyn Fn(u64),
+) -> Result<(MediaMetadata, PreparedAudio)> {
+    let song = job.source.display_name.as_str();
+    let source_stage = if extension(&job.source.path) == "ncm" {
+        Stage::Ncm
+    } else {
+        Cipher::of(&job.source.path).map_or(Stage::Io, Cipher::stage)
+    };
+    let verify = options.verify.unwrap_or(false);
+    let buffer_size = options.copy_buffer_size();
+    // Audio that FFmpeg reads and then discards can go to `temp_dir`, since only the
+    // transcode lands in the output folder. Anything else becomes the output itself and is
+    // renamed into place, so it stays on the output drive.
+    let scratch = match &options.temp_dir {
+        Some(temp_dir) if job.mode.needs_ffmpeg() => temp_dir.as_path(),
+        _ => parent,
+    };
+    let (mut metadata, prepared_audio) =
+        prepare_source(&job.source, scratch, verify, buffer_size, cancel, on_copied)
+            .map_err(|error| source_stage.fail(song, error))?;
+    if options.sidecar_metadata.unwrap_or(false) {
+        match read_sidecar_metadata(&job.source.path) {
+            Ok(Some(sidecar)) => metadata.fill_from(sidecar),
+            Ok(None) => {}
+            // A broken sidecar only loses the extra tags, never the song.
+            Err(error) => log::warn!("{error:#}"),
+        }
+    }
+    if options.verify_flac.unwrap_or(false) && job.source.variant.format == "flac" {
+        check_decodes(
+            job.ffmpeg
+                .as_deref()
+                .context("FFmpeg is required by verify_flac")?,
+            prepared_audio.path(),
+            cancel,
+        )
+        .map_err(|error| Stage::Validation.fail(song, error))?;
+    }
+    let strip = options.strip_metadata.unwrap_or(false);
+    let jpeg_quality = options.cover_jpeg_quality.unwrap_or(DEFAULT_JPEG_QUALITY);
+    metadata.cover = metadata
+        .cover
+        .filter(|_| !strip)
+        .map(|cover| cover::prepare_cover(cover, jpeg_quality, options.max_cover_size));
+    Ok((metadata, prepared_audio))
+}
+
+/// An output written to a temporary file next to its target, not yet published.
+struct StagedOutput {
+    temp: TempPath,
+    /// A hard link to the source, which must never be written to.
+    linked: bool,
+}
+
+impl StagedOutput {
+    /// The temporary file when w4dj may write to it: never a hard link to the source.
+    fn owned_path(&self) -> Option<&Path> {
+        (!self.linked).then(|| self.temp.as_ref())
+    }
+
+    /// Flushes the temporary file to disk and renames it over `target`.
+    fn publish(self, target: &Path) -> Result<()> {
+        let temp_path: &Path = self.temp.as_ref();
+        fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(temp_path)
+            .and_then(|file| file.sync_all())
+            .with_context(|| format!("failed to sync temporary output for {}", target.display()))?;
+        self.temp
+            .persist(target)
+            .map_err(|error| error.error)
+            .with_context(|| format!("failed to publish output file {}", target.display()))?;
+        Ok(())
+    }
+}
+
+/// Copies the untranscoded audio into a temporary file next to `original`. NCM headers are
+/// the only place their tags live, so decrypted NCM audio is tagged like the output; other
+/// sources are kept byte for byte.
+fn stage_original(
+    job: &Job,
+    options: &SyncOptions,
+    original: &Path,
+    audio: &PreparedAudio,
+    metadata: &MediaMetadata,
+    cancel: &AtomicBool,
+) -> Result<StagedOutput> {
+    let parent = original
+        .parent()
+        .context("original file has no parent directory")?;
+    job.output_dirs.create(parent)?;
+    let format = job.source.variant.format.as_str();
+    let temp = create_temp(parent, format)?;
+    let temp_path: &Path = temp.as_ref();
+    let mut input = File::open(audio.path())
+        .with_context(|| format!("failed to open {} for copying", audio.path().display()))?;
+    let output = File::create(temp_path).context("failed to create temporary original")?;
+    // Not reported as progress: the run's byte total only counts the outputs.
+    copy_to_file(
+        &mut input,
+        output,
+        temp_path,
+        options.verify.unwrap_or(false),
+        options.copy_buffer_size(),
+        cancel,
+        &|_| {},
+    )
+    .with_context(|| {
+        format!(
+            "failed to keep the original of {}",
+            job.source.path.display()
+        )
+    })?;
+    if extension(&job.source.path) == "ncm" && !options.strip_metadata.unwrap_or(false) {
+        write_metadata(temp_path, format, metadata, &job.source.id)
+            .map_err(|error| Stage::Metadata.fail(&job.source.display_name, error))?;
+    }
+    Ok(StagedOutput {
+        temp,
+        linked: false,
+    })
+}
+
+/// Transcodes the prepared audio, or copies or links it, into a temporary output.
+fn encode(
+    job: &Job,
+    options: &SyncOptions,
+    parent: &Path,
+    prepared_audio: PreparedAudio,
+    cancel: &AtomicBool,
+    on_copied: &dyn Fn(u64),
+) -> Result<StagedOutput> {
     let target_format = job.mode.extension(&job.source.variant.format);
-    let final_temp = if job.mode.needs_ffmpeg() {
+    if job.mode.needs_ffmpeg() {
+        let _slot = job
+            .transcode_slots
+            .as_deref()
+            .map(|slots| slots.acquire(cancel))
+            .transpose()?;
         let temp = create_temp(parent, target_format)?;
         transcode(
             job.ffmpeg
@@ -185,77 +1027,185 @@ pub(crate) fn process_with_cancel(job: &Job, cancel: &AtomicBool) -> Result<()>
             prepared_audio.path(),
             temp.as_ref(),
             job.mode,
+            options,
             cancel,
-        )?;
-        temp
-    } else {
-        match prepared_audio {
-            PreparedAudio::Temporary(path) => path,
-            PreparedAudio::Borrowed(path) => {
-                let temp = create_temp(parent, target_format)?;
-                let temp_path: &Path = temp.as_ref();
-                let mut input = File::open(&path)
-                    .with_context(|| format!("failed to open {} for copying", path.display()))?;
-                let mut output = File::create(temp_path)
-                    .context("failed to create temporary output for copying")?;
-                copy_with_cancel(&mut input, &mut output, cancel).with_context(|| {
-                    format!("failed to copy {} to a temporary file", path.display())
-                })?;
-                temp
-            }
+        )
+        .map_err(|error| Stage::Transcode.fail(&job.source.display_name, error))?;
+        return Ok(StagedOutput {
+            temp,
+            linked: false,
+        });
+    }
+
+    let path = match prepared_audio {
+        PreparedAudio::Temporary(temp) => {
+            return Ok(StagedOutput {
+                temp,
+                linked: false,
+            });
         }
+        PreparedAudio::Borrowed(path) => path,
     };
+    // A `--move` source that needs new tags is copied too: they are written into the
+    // temporary file, which must never be the source.
+    let temp = create_temp(parent, target_format)?;
+    let temp_path: &Path = temp.as_ref();
+    let linked = options.link == Some(Link::Hard)
+        && can_link(job, options, &path)
+        && hard_link(&path, temp_path, on_copied);
+    if !linked {
+        let mut input = File::open(&path)
+            .with_context(|| format!("failed to open {} for copying", path.display()))?;
+        let output =
+            File::create(temp_path).context("failed to create temporary output for copying")?;
+        copy_to_file(
+            &mut input,
+            output,
+            temp_path,
+            options.verify.unwrap_or(false),
+            options.copy_buffer_size(),
+            cancel,
+            on_copied,
+        )
+        .with_context(|| format!("failed to copy {} to a temporary file", path.display()))?;
+    }
+    Ok(StagedOutput { temp, linked })
+}
 
-    ensure_not_cancelled(cancel)?;
-    write_metadata(
-        final_temp.as_ref(),
-        target_format,
-        &metadata,
-        &job.source.id,
-    )?;
-    ensure_not_cancelled(cancel)?;
-    let identity = inspect_output(final_temp.as_ref())
-        .with_context(|| format!("output validation failed for {}", job.source.path.display()))?;
+/// Whether the source can be the output as it is. A hard link shares the source's data, so
+/// it is only used when w4dj would write nothing to it: the source already identifies as
+/// the job's track and no option rewrites tags or covers.
+fn can_link(job: &Job, options: &SyncOptions, source: &Path) -> bool {
+    !options.strip_metadata.unwrap_or(false)
+        && !options.replaygain.unwrap_or(false)
+        && !options.sidecar_metadata.unwrap_or(false)
+        && options.max_cover_size.is_none()
+        && options.cover_jpeg_quality.is_none()
+        && inspect_output(source)
+            .is_ok_and(|identity| identity_matches_source(&identity, &job.source))
+}
+
+/// Replaces the empty temporary file at `temp` with a hard link to `source`. Returns
+/// `false` when that is impossible, for example across filesystems, so the caller falls
+/// back to copying.
+fn hard_link(source: &Path, temp: &Path, on_copied: &dyn Fn(u64)) -> bool {
+    let linked = fs::remove_file(temp).and_then(|()| fs::hard_link(source, temp));
+    match linked {
+        Ok(()) => {
+            on_copied(fs::metadata(temp).map_or(0, |metadata| metadata.len()));
+            true
+        }
+        Err(error) => {
+            log::debug!(
+                "copying {} instead of linking it: {error}",
+                source.display()
+            );
+            false
+        }
+    }
+}
+
+/// Writes the tags of a staged output and checks that it reads back as the job's track.
+fn tag_output(
+    path: &Path,
+    job: &Job,
+    metadata: &MediaMetadata,
+    options: &SyncOptions,
+) -> Result<()> {
+    let song = job.source.display_name.as_str();
+    let format = job.mode.extension(&job.source.variant.format);
+    let written = if options.strip_metadata.unwrap_or(false) {
+        strip_tags(path)
+            .and_then(|()| write_metadata(path, format, &MediaMetadata::default(), &job.source.id))
+    } else {
+        write_metadata(path, format, metadata, &job.source.id)
+    };
+    written.map_err(|error| Stage::Metadata.fail(song, error))?;
+    let identity = inspect_output(path).map_err(|error| Stage::Validation.fail(song, error))?;
     if !identity_matches_source(&identity, &job.source) {
-        bail!(
-            "output validation found the wrong track ID for {}",
-            job.source.path.display()
+        return Err(Stage::Validation.fail(
+            song,
+            anyhow::anyhow!(
+                "found the wrong track ID in the output of {}",
+                job.source.path.display()
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Writes the lyrics and folder cover that accompany a published output.
+fn write_sidecars(
+    target: &Path,
+    parent: &Path,
+    metadata: &MediaMetadata,
+    options: &SyncOptions,
+) -> Result<()> {
+    if options.export_lyrics.unwrap_or(false)
+        && let Some(lyrics) = &metadata.lyrics
+    {
+        let sidecar = target.with_extension("lrc");
+        fs::write(&sidecar, lyrics)
+            .with_context(|| format!("failed to write lyrics to {}", sidecar.display()))?;
+    }
+    if options.extract_cover.unwrap_or(false)
+        && let Some(cover) = &metadata.cover
+    {
+        write_folder_cover(parent, cover)?;
+    }
+    Ok(())
+}
+
+/// Removes the output a better source replaced, if it was written for this job's track,
+/// along with its lyrics unless the new output shares them.
+pub(crate) fn remove_superseded(job: &Job, options: &SyncOptions) {
+    let Some(old_output) = &job.old_output else {
+        return;
+    };
+    if old_output == &job.target || !old_output.exists() {
+        return;
+    }
+    let belongs_to_job = inspect_output(old_output)
+        .map(|identity| identity_matches_source(&identity, &job.source))
+        .unwrap_or(false);
+    if !belongs_to_job {
+        return;
+    }
+    let use_trash = options.use_trash.unwrap_or(false);
+    if let Err(error) = remove_output(old_output, use_trash) {
+        log::warn!(
+            "failed to remove superseded output {}: {}",
+            old_output.display(),
+            error
         );
+        return;
     }
-    let final_temp_path: &Path = final_temp.as_ref();
-    fs::OpenOptions::new()
-        .read(true)
-        .write(true)
-        .open(final_temp_path)
-        .and_then(|file| file.sync_all())
-        .with_context(|| {
-            format!(
-                "failed to sync temporary output for {}",
-                job.target.display()
-            )
-        })?;
-    final_temp
-        .persist(&job.target)
-        .map_err(|error| error.error)
-        .with_context(|| format!("failed to publish output file {}", job.target.display()))?;
-
-    if let Some(old_output) = &job.old_output
-        && old_output != &job.target
-        && old_output.exists()
+    let lyrics = old_output.with_extension("lrc");
+    if lyrics != job.target.with_extension("lrc")
+        && lyrics.is_file()
+        && let Err(error) = remove_output(&lyrics, use_trash)
     {
-        let belongs_to_job = inspect_output(old_output)
-            .map(|identity| identity_matches_source(&identity, &job.source))
-            .unwrap_or(false);
-        if belongs_to_job && let Err(error) = fs::remove_file(old_output) {
-            eprintln!(
-                "cleanup warning for superseded output {}: {}",
-                old_output.display(),
-                error
-            );
-        }
+        log::warn!(
+            "failed to remove superseded lyrics {}: {}",
+            lyrics.display(),
+            error
+        );
     }
+}
 
-    Ok(())
+/// Deletes an output, through the system trash when `use_trash` is set. Where the platform
+/// has no usable trash the file is deleted outright with a warning.
+pub(crate) fn remove_output(path: &Path, use_trash: bool) -> std::io::Result<()> {
+    if use_trash {
+        match trash::delete(path) {
+            Ok(()) => return Ok(()),
+            Err(error) => log::warn!(
+                "could not move {} to the trash, deleting it instead: {error}",
+                path.display()
+            ),
+        }
+    }
+    fs::remove_file(path)
 }
 
 fn identity_matches_source(identity: &OutputIdentity, source: &SourceItem) -> bool {
@@ -264,12 +1214,9 @@ fn identity_matches_source(identity: &OutputIdentity, source: &SourceItem) -> bo
 }
 
 fn inspect_ncm(path: &Path, size: u64, display_name: String) -> Result<SourceItem> {
-    let info = read_ncm_info(path)?;
-    let format = if info.format.trim().is_empty() {
-        sniff_ncm_format(path)?
-    } else {
-        info.format.to_ascii_lowercase()
-    };
+    let mut ncm = open_ncm(path)?;
+    let info = ncm_info(&mut ncm, path)?;
+    let format = ncm_format(&info.format, sniff_ncm_format(&mut ncm, path)?, path)?;
     let metadata = metadata_from_ncm(&info, None);
     let fallback_id = metadata_id(&metadata, &display_name);
     let id = if info.id > 0 {
@@ -287,40 +1234,183 @@ fn inspect_ncm(path: &Path, size: u64, display_name: String) -> Result<SourceIte
             format,
             bitrate: normalize_ncm_bitrate(info.bitrate),
             size,
+            payload: None,
+            hash: None,
+        },
+        tags: SourceTags::from_metadata(&metadata),
+    })
+}
+
+fn inspect_encrypted(
+    path: &Path,
+    size: u64,
+    display_name: String,
+    cipher: Cipher,
+) -> Result<SourceItem> {
+    let mut header = [0_u8; 12];
+    let read = cipher
+        .open(path)?
+        .read(&mut header)
+        .with_context(|| format!("failed to inspect {cipher} audio in {}", path.display()))?;
+    let format = sniff_audio_format(&header[..read]).with_context(|| {
+        format!(
+            "unsupported audio format inside {cipher} file {}",
+            path.display()
+        )
+    })?;
+    let (metadata, properties) = read_encrypted_metadata(path, cipher, false)?;
+    let fallback_id = metadata_id(&metadata, &display_name);
+    let id = metadata
+        .platform_id
+        .clone()
+        .unwrap_or_else(|| fallback_id.clone());
+
+    Ok(SourceItem {
+        path: path.to_path_buf(),
+        id,
+        fallback_id,
+        display_name,
+        variant: SourceVariant {
+            format: format.to_string(),
+            bitrate: properties.audio_bitrate().map(u64::from),
+            size,
+            payload: None,
+            hash: None,
         },
+        tags: SourceTags::from_metadata(&metadata),
     })
 }
 
+/// A cipher that is undone while reading, so its sources are probed and copied like plain
+/// audio.
+#[derive(Clone, Copy, Debug)]
+enum Cipher {
+    Qmc,
+    Kgm,
+}
+
+impl std::fmt::Display for Cipher {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        formatter.write_str(match self {
+            Self::Qmc => "QMC",
+            Self::Kgm => "KGM",
+        })
+    }
+}
+
+/// A decrypting reader lofty can probe.
+trait ReadSeek: Read + Seek {}
+
+impl<T: Read + Seek> ReadSeek for T {}
+
+impl Cipher {
+    fn of(path: &Path) -> Option<Self> {
+        let extension = extension(path);
+        if QMC_EXTENSIONS.contains(&extension.as_str()) {
+            Some(Self::Qmc)
+        } else if KGM_EXTENSIONS.contains(&extension.as_str()) {
+            Some(Self::Kgm)
+        } else {
+            None
+        }
+    }
+
+    fn stage(self) -> Stage {
+        match self {
+            Self::Qmc => Stage::Qmc,
+            Self::Kgm => Stage::Kgm,
+        }
+    }
+
+    /// Opens `path` at the start of its decrypted audio.
+    fn open(self, path: &Path) -> Result<Box<dyn ReadSeek>> {
+        let file =
+            File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+        let file = BufReader::new(file);
+        let reader: Result<Box<dyn ReadSeek>> = match self {
+            Self::Qmc => QmcDump::from_reader(file)
+                .map(|reader| Box::new(reader) as _)
+                .map_err(anyhow::Error::from),
+            Self::Kgm => KgmReader::new(file).map(|reader| Box::new(reader) as _),
+        };
+        reader.with_context(|| format!("invalid {self} file {}", path.display()))
+    }
+}
+
+/// Reads tags through the decrypting reader, so scanning never writes the plain audio.
+fn read_encrypted_metadata(
+    path: &Path,
+    cipher: Cipher,
+    include_cover: bool,
+) -> Result<(MediaMetadata, lofty::properties::FileProperties)> {
+    let tagged = Probe::new(cipher.open(path)?)
+        .guess_file_type()
+        .with_context(|| format!("failed to identify audio format in {}", path.display()))?
+        .options(ParseOptions::new().read_cover_art(include_cover))
+        .read()
+        .with_context(|| format!("failed to read audio metadata from {}", path.display()))?;
+    Ok(metadata_from_tagged(&tagged, include_cover))
+}
+
 fn prepare_source(
     source: &SourceItem,
     temp_dir: &Path,
+    verify: bool,
+    buffer_size: usize,
     cancel: &AtomicBool,
+    on_copied: &dyn Fn(u64),
 ) -> Result<(MediaMetadata, PreparedAudio)> {
     ensure_not_cancelled(cancel)?;
     if extension(&source.path) == "ncm" {
-        let file = File::open(&source.path)
-            .with_context(|| format!("failed to open {}", source.path.display()))?;
-        let mut ncm = Ncmdump::from_reader(file)
-            .with_context(|| format!("invalid NCM file {}", source.path.display()))?;
-        let info = ncm.get_info().with_context(|| {
-            format!("failed to read NCM metadata from {}", source.path.display())
-        })?;
+        // One handle serves metadata, cover and audio; the audio is streamed to disk in
+        // chunks, so memory stays flat however large the track or many the workers.
+        let mut ncm = open_ncm(&source.path)?;
+        let info = ncm_info(&mut ncm, &source.path)?;
+        // An empty cover block just means the song has no art; only a block that cannot
+        // be read is corruption.
         let image = ncm
             .get_image()
             .with_context(|| format!("failed to read NCM cover from {}", source.path.display()))?;
-        let metadata = metadata_from_ncm(&info, if image.is_empty() { None } else { Some(image) });
+        let mut metadata =
+            metadata_from_ncm(&info, if image.is_empty() { None } else { Some(image) });
 
         let temp = create_temp(temp_dir, &source.variant.format)?;
-        let input = File::open(&source.path)
-            .with_context(|| format!("failed to reopen {}", source.path.display()))?;
-        let mut ncm = Ncmdump::from_reader(input)
-            .with_context(|| format!("invalid NCM file {}", source.path.display()))?;
+        ncm.rewind()
+            .with_context(|| format!("failed to seek NCM audio in {}", source.path.display()))?;
         let temp_path: &Path = temp.as_ref();
-        let mut output =
-            File::create(temp_path).context("failed to create NCM temporary output")?;
-        copy_with_cancel(&mut ncm, &mut output, cancel)
-            .with_context(|| format!("failed to dump NCM file {}", source.path.display()))?;
-        output.flush()?;
+        let output = File::create(temp_path).context("failed to create NCM temporary output")?;
+        copy_to_file(
+            &mut ncm,
+            output,
+            temp_path,
+            verify,
+            buffer_size,
+            cancel,
+            on_copied,
+        )
+        .with_context(|| format!("failed to dump NCM file {}", source.path.display()))?;
+        // NCM headers carry no track numbers, dates, genres or lyrics, but the decrypted
+        // audio often has them embedded.
+        if let Ok((decrypted, _)) = read_regular_metadata(temp_path, false) {
+            metadata.fill_from(decrypted);
+        }
+        Ok((metadata, PreparedAudio::Temporary(temp)))
+    } else if let Some(cipher) = Cipher::of(&source.path) {
+        let temp = create_temp(temp_dir, &source.variant.format)?;
+        let temp_path: &Path = temp.as_ref();
+        let output =
+            File::create(temp_path).context("failed to create temporary output to decrypt to")?;
+        copy_to_file(
+            &mut cipher.open(&source.path)?,
+            output,
+            temp_path,
+            verify,
+            buffer_size,
+            cancel,
+            on_copied,
+        )
+        .with_context(|| format!("failed to decrypt {cipher} file {}", source.path.display()))?;
+        let (metadata, _) = read_regular_metadata(temp_path, true)?;
         Ok((metadata, PreparedAudio::Temporary(temp)))
     } else {
         let (metadata, _) = read_regular_metadata(&source.path, true)?;
@@ -347,6 +1437,7 @@ fn transcode(
     input: &Path,
     output: &Path,
     mode: Mode,
+    options: &SyncOptions,
     cancel: &AtomicBool,
 ) -> Result<()> {
     ensure_not_cancelled(cancel)?;
@@ -362,28 +1453,223 @@ fn transcode(
         .arg("-map")
         .arg("0:a:0")
         .arg("-map_metadata")
-        .arg("0")
+        .arg(if options.strip_metadata.unwrap_or(false) {
+            "-1"
+        } else {
+            "0"
+        })
         .arg("-threads")
         .arg("1");
+    if let Some(lufs) = options.target_lufs {
+        // Single-pass loudnorm with the EBU R128 defaults for true peak and loudness range.
+        command
+            .arg("-af")
+            .arg(format!("loudnorm=I={lufs}:TP=-1.5:LRA=11"));
+    }
+    // Opus always encodes at 48 kHz and rejects most other rates.
+    if let Some(limit) = options.max_sample_rate
+        && mode != Mode::Opus
+        && read_regular_metadata(input, false)
+            .ok()
+            .and_then(|(_, properties)| properties.sample_rate())
+            .is_some_and(|rate| rate > limit)
+    {
+        command.arg("-ar").arg(limit.to_string());
+    }
 
     match mode {
         Mode::Mp3 => {
-            command
-                .arg("-c:a")
-                .arg("libmp3lame")
-                .arg("-q:a")
-                .arg("2")
-                .arg("-id3v2_version")
-                .arg("4");
+            command.arg("-c:a").arg("libmp3lame");
+            match options.mp3_encoding() {
+                Mp3Encoding::Vbr(quality) => command.arg("-q:a").arg(quality.to_string()),
+                Mp3Encoding::Cbr(kbps) => command.arg("-b:a").arg(format!("{kbps}k")),
+            };
+            command.arg("-id3v2_version").arg("4");
         }
         Mode::Wav => {
             command.arg("-c:a").arg("pcm_s16le");
         }
+        Mode::Aac => {
+            command
+                .arg("-c:a")
+                .arg("aac")
+                .arg("-b:a")
+                .arg("256k")
+                .arg("-movflags")
+                .arg("+faststart");
+        }
+        Mode::Opus => {
+            command.arg("-c:a").arg("libopus").arg("-b:a").arg("160k");
+        }
         Mode::Original => bail!("original mode must not invoke FFmpeg"),
     }
 
+    run_ffmpeg(command.arg(output), ffmpeg, input, cancel)?;
+    Ok(())
+}
+
+/// Measures a staged output and writes its ReplayGain tags.
+fn tag_replaygain(path: &Path, job: &Job, cancel: &AtomicBool) -> Result<()> {
+    let song = job.source.display_name.as_str();
+    let ffmpeg = job
+        .ffmpeg
+        .as_deref()
+        .context("FFmpeg is required by replaygain")?;
+    let loudness = measure_loudness(ffmpeg, path, cancel)
+        .map_err(|error| Stage::Validation.fail(song, error))?;
+    let format = job.mode.extension(&job.source.variant.format);
+    write_replaygain(path, format, loudness).map_err(|error| Stage::Metadata.fail(song, error))
+}
+
+/// Integrated loudness and true peak of a track, as FFmpeg's EBU R128 filter reports them.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Loudness {
+    integrated_lufs: f64,
+    true_peak_dbfs: f64,
+}
+
+fn measure_loudness(ffmpeg: &Path, input: &Path, cancel: &AtomicBool) -> Result<Loudness> {
+    ensure_not_cancelled(cancel)?;
+    let mut command = doctor::ffmpeg_command(ffmpeg);
+    command
+        .arg("-nostdin")
+        .arg("-hide_banner")
+        .arg("-nostats")
+        .arg("-i")
+        .arg(input)
+        .arg("-map")
+        .arg("0:a:0")
+        .arg("-af")
+        .arg("ebur128=peak=true")
+        .arg("-f")
+        .arg("null")
+        .arg("-");
+    let log = run_ffmpeg(&mut command, ffmpeg, input, cancel)?;
+    parse_loudness(&log).with_context(|| format!("failed to measure {}", input.display()))
+}
+
+/// Reads the summary that the `ebur128` filter logs once the whole track was decoded.
+fn parse_loudness(log: &str) -> Result<Loudness> {
+    let summary = log
+        .rsplit_once("Summary:")
+        .context("FFmpeg printed no loudness summary")?
+        .1;
+    let value = |label: &str| {
+        summary
+            .lines()
+            .find_map(|line| line.trim().strip_prefix(label))
+            .and_then(|rest| rest.split_whitespace().next())
+            .and_then(|number| number.parse::<f64>().ok())
+            .with_context(|| format!("FFmpeg printed no {label} value"))
+    };
+    Ok(Loudness {
+        integrated_lufs: value("I:")?,
+        true_peak_dbfs: value("Peak:")?,
+    })
+}
+
+/// Writes the track gain and peak in each format's usual fields. Opus players apply
+/// `R128_TRACK_GAIN` and ignore ReplayGain comments.
+fn write_replaygain(path: &Path, format: &str, loudness: Loudness) -> Result<()> {
+    let gain = format!(
+        "{:.2} dB",
+        REPLAYGAIN_REFERENCE_LUFS - loudness.integrated_lufs
+    );
+    let peak = format!("{:.6}", 10_f64.powf(loudness.true_peak_dbfs / 20.0));
+    let fields = [
+        ("REPLAYGAIN_TRACK_GAIN", gain),
+        ("REPLAYGAIN_TRACK_PEAK", peak),
+    ];
+    match format {
+        "mp3" | "wav" => {
+            let mut tag = id3::Tag::read_from_path(path).unwrap_or_default();
+            for (name, value) in fields {
+                tag.remove_extended_text(Some(name), None);
+                tag.add_frame(ExtendedText {
+                    description: name.to_string(),
+                    value,
+                });
+            }
+            tag.write_to_path(path, Version::Id3v24)
+                .with_context(|| format!("failed to write ReplayGain to {}", path.display()))
+        }
+        "flac" => {
+            let mut tag = metaflac::Tag::read_from_path(path)
+                .with_context(|| format!("failed to read FLAC metadata from {}", path.display()))?;
+            for (name, value) in fields {
+                tag.set_vorbis(name, vec![value]);
+            }
+            tag.save()
+                .with_context(|| format!("failed to write ReplayGain to {}", path.display()))
+        }
+        "m4a" => {
+            let mut ilst = read_mp4_tag(path)?;
+            for (name, value) in fields {
+                let ident = AtomIdent::Freeform {
+                    mean: ITUNES_MEAN.into(),
+                    name: name.to_ascii_lowercase().into(),
+                };
+                ilst.replace_atom(Atom::new(ident, AtomData::UTF8(value)));
+            }
+            ilst.save_to_path(path, WriteOptions::default())
+                .with_context(|| format!("failed to write ReplayGain to {}", path.display()))
+        }
+        "ogg" | "opus" => {
+            let mut comments = read_ogg_tag(path)?;
+            if format == "opus" {
+                // Q7.8 fixed point, relative to the Opus reference loudness.
+                let gain = ((R128_REFERENCE_LUFS - loudness.integrated_lufs) * 256.0).round();
+                comments.insert("R128_TRACK_GAIN".to_string(), (gain as i16).to_string());
+            } else {
+                for (name, value) in fields {
+                    comments.insert(name.to_string(), value);
+                }
+            }
+            comments
+                .save_to_path(path, WriteOptions::default())
+                .with_context(|| format!("failed to write ReplayGain to {}", path.display()))
+        }
+        other => bail!("cannot write ReplayGain for unsupported output format {other}"),
+    }
+}
+
+/// Decodes `input` completely and discards the audio, failing on any decoding error.
+fn check_decodes(ffmpeg: &Path, input: &Path, cancel: &AtomicBool) -> Result<()> {
+    ensure_not_cancelled(cancel)?;
+    let mut command = doctor::ffmpeg_command(ffmpeg);
+    command
+        .arg("-nostdin")
+        .arg("-loglevel")
+        .arg("error")
+        .arg("-i")
+        .arg(input)
+        .arg("-map")
+        .arg("0:a:0")
+        .arg("-f")
+        .arg("null")
+        .arg("-");
+    // FFmpeg skips over damaged frames and still exits successfully, so any reported
+    // error counts as corruption.
+    let errors = run_ffmpeg(&mut command, ffmpeg, input, cancel)?;
+    if !errors.trim().is_empty() {
+        bail!(
+            "{} does not decode cleanly: {}",
+            input.display(),
+            errors.trim()
+        );
+    }
+    Ok(())
+}
+
+/// Runs a prepared FFmpeg command until it exits or the run is cancelled and returns its
+/// error output.
+fn run_ffmpeg(
+    command: &mut std::process::Command,
+    ffmpeg: &Path,
+    input: &Path,
+    cancel: &AtomicBool,
+) -> Result<String> {
     let mut child = command
-        .arg(output)
         .stdin(Stdio::null())
         .stdout(Stdio::null())
         .stderr(Stdio::piped())
@@ -418,8 +1704,8 @@ fn transcode(
         .map_err(|_| anyhow::anyhow!("FFmpeg error reader panicked"))?;
     stderr_result.context("failed to read FFmpeg error output")?;
     if !status.success() {
-        let detail = String::from_utf8_lossy(&stderr);
-        if detail.trim().is_empty() {
+        let detail = last_lines(&String::from_utf8_lossy(&stderr), FFMPEG_ERROR_LINES);
+        if detail.is_empty() {
             bail!(
                 "FFmpeg failed for {} with status {}",
                 input.display(),
@@ -430,18 +1716,89 @@ fn transcode(
             "FFmpeg failed for {} with status {}: {}",
             input.display(),
             status,
-            detail.trim()
+            detail
+        );
+    }
+    Ok(String::from_utf8_lossy(&stderr).into_owned())
+}
+
+/// FFmpeg output kept in the error of a failed run; the cause is printed last, after
+/// banners and per-stream warnings that would bury it.
+const FFMPEG_ERROR_LINES: usize = 5;
+
+/// The last `count` non-blank lines of `text`, joined on one line for the error message.
+fn last_lines(text: &str, count: usize) -> String {
+    let lines = text
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>();
+    lines[lines.len().saturating_sub(count)..].join(" | ")
+}
+
+/// Copies `input` into `output` `buffer_size` bytes at a time. With `verify`, the bytes are
+/// hashed as they are read from `input` (the source file, or the decrypted stream of an NCM
+/// or QMC source), and the written file is synced and hashed again. On Linux the cached pages
+/// are dropped first, so the read-back comes from the device; elsewhere it may be served by
+/// the page cache and only catches corruption on the way to it.
+fn copy_to_file(
+    input: &mut impl Read,
+    mut output: File,
+    path: &Path,
+    verify: bool,
+    buffer_size: usize,
+    cancel: &AtomicBool,
+    on_copied: &dyn Fn(u64),
+) -> Result<()> {
+    if !verify {
+        copy_with_cancel(input, &mut output, buffer_size, cancel, on_copied)?;
+        output.flush()?;
+        return Ok(());
+    }
+    let mut writer = HashingWriter::new(&mut output);
+    copy_with_cancel(input, &mut writer, buffer_size, cancel, on_copied)?;
+    writer.flush()?;
+    let expected = writer.finish();
+    output.sync_all()?;
+    drop_cached_pages(&output);
+    let actual = hashes::hash_file(path)?;
+    if actual != expected {
+        bail!(
+            "verification failed for {}: wrote {expected}, read back {actual}",
+            path.display()
         );
     }
     Ok(())
 }
 
+/// Asks the kernel to forget the cached pages of a synced file, so the next read goes to the
+/// device.
+#[cfg(all(target_os = "linux", target_pointer_width = "64"))]
+fn drop_cached_pages(file: &File) {
+    use std::os::fd::AsRawFd;
+
+    const POSIX_FADV_DONTNEED: i32 = 4;
+    unsafe extern "C" {
+        fn posix_fadvise(fd: i32, offset: i64, len: i64, advice: i32) -> i32;
+    }
+
+    // Advisory only: if the kernel keeps the pages, the read-back is served from the cache.
+    unsafe {
+        posix_fadvise(file.as_raw_fd(), 0, 0, POSIX_FADV_DONTNEED);
+    }
+}
+
+#[cfg(not(all(target_os = "linux", target_pointer_width = "64")))]
+fn drop_cached_pages(_file: &File) {}
+
 fn copy_with_cancel(
     input: &mut impl Read,
     output: &mut impl Write,
+    buffer_size: usize,
     cancel: &AtomicBool,
+    on_copied: &dyn Fn(u64),
 ) -> Result<u64> {
-    let mut buffer = [0_u8; 64 * 1024];
+    let mut buffer = vec![0_u8; buffer_size];
     let mut copied = 0_u64;
     loop {
         ensure_not_cancelled(cancel)?;
@@ -451,41 +1808,119 @@ fn copy_with_cancel(
         }
         output.write_all(&buffer[..read])?;
         copied += read as u64;
+        on_copied(read as u64);
     }
 }
 
-fn read_ncm_info(path: &Path) -> Result<NcmInfo> {
+fn open_ncm(path: &Path) -> Result<Ncmdump<File>> {
     let file = File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
-    let mut ncm = Ncmdump::from_reader(file)
-        .with_context(|| format!("invalid NCM file {}", path.display()))?;
+    Ncmdump::from_reader(file).with_context(|| format!("invalid NCM file {}", path.display()))
+}
+
+fn read_ncm_info(path: &Path) -> Result<NcmInfo> {
+    ncm_info(&mut open_ncm(path)?, path)
+}
+
+fn ncm_info(ncm: &mut Ncmdump<File>, path: &Path) -> Result<NcmInfo> {
     ncm.get_info()
         .with_context(|| format!("failed to read NCM metadata from {}", path.display()))
 }
 
-fn sniff_ncm_format(path: &Path) -> Result<String> {
-    let file = File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
-    let mut ncm = Ncmdump::from_reader(file)
-        .with_context(|| format!("invalid NCM file {}", path.display()))?;
+/// Reads the first bytes of the audio. Reading metadata moves the underlying reader, so the
+/// decrypting stream is rewound to the start of the audio first.
+fn sniff_ncm_format(ncm: &mut Ncmdump<File>, path: &Path) -> Result<Option<&'static str>> {
+    ncm.rewind()
+        .with_context(|| format!("failed to seek NCM audio in {}", path.display()))?;
     let mut header = [0_u8; 12];
     let size = ncm
         .read(&mut header)
         .with_context(|| format!("failed to inspect NCM audio in {}", path.display()))?;
-    if header[..size].starts_with(b"fLaC") {
-        Ok("flac".to_string())
-    } else if header[..size].starts_with(b"ID3")
-        || header[..size]
-            .windows(2)
-            .any(|bytes| bytes[0] == 0xff && bytes[1] & 0xe0 == 0xe0)
-    {
-        Ok("mp3".to_string())
-    } else {
-        bail!(
+    Ok(sniff_audio_format(&header[..size]).filter(|format| matches!(*format, "flac" | "mp3")))
+}
+
+/// Picks the container of NCM audio. Some files declare a format that does not match the
+/// audio they carry, and the extension decides how the output is tagged, so the bytes win.
+fn ncm_format(declared: &str, sniffed: Option<&str>, path: &Path) -> Result<String> {
+    let declared = declared.trim().to_ascii_lowercase();
+    match sniffed {
+        Some(format) => {
+            if !declared.is_empty() && declared != format {
+                log::warn!(
+                    "{} declares {declared} audio but contains {format}; saving it as {format}",
+                    path.display()
+                );
+            }
+            Ok(format.to_string())
+        }
+        None if !declared.is_empty() => Ok(declared),
+        None => bail!(
             "unsupported audio format inside NCM file {}",
             path.display()
-        )
+        ),
+    }
+}
+
+/// Identifies decrypted audio from its first bytes.
+fn sniff_audio_format(header: &[u8]) -> Option<&'static str> {
+    if header.starts_with(b"fLaC") {
+        Some("flac")
+    } else if header.starts_with(b"OggS") {
+        Some("ogg")
+    } else if header.starts_with(b"RIFF") && header.get(8..12) == Some(b"WAVE") {
+        Some("wav")
+    } else if header.starts_with(b"ID3") || is_mpeg_frame_header(header) {
+        Some("mp3")
+    } else {
+        None
     }
 }
 
+/// Whether `header` starts with an MPEG audio frame header. Since the result overrides the
+/// declared NCM format, a bare 11-bit sync is not enough: AAC ADTS and random bytes share
+/// it, so the reserved version, layer, bitrate and sample rate values are refused too.
+fn is_mpeg_frame_header(header: &[u8]) -> bool {
+    let [0xff, second, third, ..] = *header else {
+        return false;
+    };
+    let version = (second >> 3) & 0b11;
+    let layer = (second >> 1) & 0b11;
+    let bitrate = third >> 4;
+    let sample_rate = (third >> 2) & 0b11;
+    second & 0xe0 == 0xe0 && version != 0b01 && layer != 0 && bitrate != 0xf && sample_rate != 0b11
+}
+
+/// Estimates the bytes spent on tags and artwork, which re-muxing can change
+/// without touching the audio stream.
+fn tag_overhead(path: &Path) -> Result<u64> {
+    let tagged = Probe::open(path)
+        .with_context(|| format!("failed to open audio metadata for {}", path.display()))?
+        .guess_file_type()
+        .with_context(|| format!("failed to identify audio format for {}", path.display()))?
+        .options(ParseOptions::new().read_cover_art(true))
+        .read()
+        .with_context(|| format!("failed to read audio metadata from {}", path.display()))?;
+    let overhead = tagged
+        .tags()
+        .iter()
+        .map(|tag| {
+            let pictures = tag
+                .pictures()
+                .iter()
+                .map(|picture| picture.data().len())
+                .sum::<usize>();
+            let items = tag
+                .items()
+                .map(|item| match item.value() {
+                    ItemValue::Text(text) | ItemValue::Locator(text) => text.len(),
+                    ItemValue::Binary(data) => data.len(),
+                })
+                .sum::<usize>();
+            pictures + items
+        })
+        .sum::<usize>();
+    Ok(overhead as u64)
+}
+
 fn read_regular_metadata(
     path: &Path,
     include_cover: bool,
@@ -497,6 +1932,13 @@ fn read_regular_metadata(
         .options(ParseOptions::new().read_cover_art(include_cover))
         .read()
         .with_context(|| format!("failed to read audio metadata from {}", path.display()))?;
+    Ok(metadata_from_tagged(&tagged, include_cover))
+}
+
+fn metadata_from_tagged(
+    tagged: &lofty::file::TaggedFile,
+    include_cover: bool,
+) -> (MediaMetadata, lofty::properties::FileProperties) {
     let properties = tagged.properties().clone();
     let mut metadata = MediaMetadata {
         duration_secs: properties.duration().as_secs(),
@@ -512,13 +1954,23 @@ fn read_regular_metadata(
         metadata.album = metadata
             .album
             .or_else(|| tag.album().map(|value| value.into_owned()));
+        metadata.album_artist = metadata
+            .album_artist
+            .or_else(|| tag.get_string(ItemKey::AlbumArtist).and_then(non_empty));
         metadata.genre = metadata
             .genre
             .or_else(|| tag.genre().map(|value| value.into_owned()));
+        metadata.date = metadata.date.or_else(|| tag.date());
         metadata.track = metadata.track.or_else(|| tag.track());
         metadata.track_total = metadata.track_total.or_else(|| tag.track_total());
         metadata.disc = metadata.disc.or_else(|| tag.disk());
         metadata.disc_total = metadata.disc_total.or_else(|| tag.disk_total());
+        metadata.lyrics = metadata.lyrics.or_else(|| {
+            tag.get_string(ItemKey::UnsyncLyrics)
+                .or_else(|| tag.get_string(ItemKey::Lyrics))
+                .filter(|lyrics| !lyrics.trim().is_empty())
+                .map(str::to_string)
+        });
         metadata.platform_id = metadata.platform_id.or_else(|| {
             tag.get_string(ItemKey::MusicBrainzRecordingId)
                 .map(|id| format!("mb:{}", normalize(id)))
@@ -536,30 +1988,155 @@ fn read_regular_metadata(
                 .map(|picture| picture.data().to_vec());
         }
     }
-    Ok((metadata, properties))
+    (metadata, properties)
 }
 
+/// NCM headers leave unknown fields as empty strings; those become missing tags rather
+/// than empty ones.
 fn metadata_from_ncm(info: &NcmInfo, cover: Option<Vec<u8>>) -> MediaMetadata {
     MediaMetadata {
-        title: Some(info.name.clone()),
-        artist: Some(
-            info.artist
+        title: non_empty(&info.name),
+        artist: non_empty(
+            &info
+                .artist
                 .iter()
-                .map(|artist| artist.0.as_str())
+                .filter_map(|artist| non_empty(&artist.0))
                 .collect::<Vec<_>>()
                 .join("/"),
         ),
-        album: Some(info.album.clone()),
+        album: non_empty(&info.album),
         duration_secs: info.duration / 1000,
         cover,
         ..MediaMetadata::default()
     }
 }
 
+/// Tags a `<stem>.json` sidecar may carry.
+#[derive(Default, Deserialize)]
+#[serde(default)]
+struct SidecarTags {
+    title: Option<String>,
+    artist: Option<String>,
+    album: Option<String>,
+    album_artist: Option<String>,
+    genre: Option<String>,
+    date: Option<String>,
+    track: Option<u32>,
+    track_total: Option<u32>,
+    disc: Option<u32>,
+    disc_total: Option<u32>,
+}
+
+/// Reads `<stem>.json` next to `source`, or failing that `<stem>.cue`.
+fn read_sidecar_metadata(source: &Path) -> Result<Option<MediaMetadata>> {
+    let json = source.with_extension("json");
+    if json.is_file() {
+        let tags: SidecarTags = serde_json::from_slice(
+            &fs::read(&json).with_context(|| format!("failed to read {}", json.display()))?,
+        )
+        .with_context(|| format!("failed to parse sidecar tags in {}", json.display()))?;
+        return Ok(Some(MediaMetadata {
+            title: tags.title.as_deref().and_then(non_empty),
+            artist: tags.artist.as_deref().and_then(non_empty),
+            album: tags.album.as_deref().and_then(non_empty),
+            album_artist: tags.album_artist.as_deref().and_then(non_empty),
+            genre: tags.genre.as_deref().and_then(non_empty),
+            date: tags.date.and_then(|date| date.trim().parse().ok()),
+            track: tags.track,
+            track_total: tags.track_total,
+            disc: tags.disc,
+            disc_total: tags.disc_total,
+            ..MediaMetadata::default()
+        }));
+    }
+    let cue = source.with_extension("cue");
+    if cue.is_file() {
+        let bytes = fs::read(&cue).with_context(|| format!("failed to read {}", cue.display()))?;
+        return Ok(Some(parse_cue(&String::from_utf8_lossy(&bytes))));
+    }
+    Ok(None)
+}
+
+/// Reads the album fields of a cue sheet, and the track fields when it describes a single
+/// track, since a sheet for a whole-album image cannot say which track a file is.
+fn parse_cue(text: &str) -> MediaMetadata {
+    let mut album = MediaMetadata::default();
+    let mut tracks = Vec::<MediaMetadata>::new();
+    for line in text.trim_start_matches('\u{feff}').lines() {
+        let line = line.trim();
+        let (command, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let value = || non_empty(rest.trim().trim_matches('"'));
+        let current = tracks.last_mut().unwrap_or(&mut album);
+        match command.to_ascii_uppercase().as_str() {
+            "TRACK" => tracks.push(MediaMetadata {
+                track: rest
+                    .split_whitespace()
+                    .next()
+                    .and_then(|number| number.parse().ok()),
+                ..MediaMetadata::default()
+            }),
+            "TITLE" => current.title = value(),
+            "PERFORMER" => current.artist = value(),
+            "REM" => {
+                let (key, rest) = rest
+                    .trim()
+                    .split_once(char::is_whitespace)
+                    .unwrap_or((rest, ""));
+                let value = non_empty(rest.trim().trim_matches('"'));
+                match key.to_ascii_uppercase().as_str() {
+                    "GENRE" => album.genre = value,
+                    "DATE" => album.date = value.and_then(|date| date.parse().ok()),
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+    let mut metadata = MediaMetadata {
+        album: album.title,
+        album_artist: album.artist,
+        genre: album.genre,
+        date: album.date,
+        ..MediaMetadata::default()
+    };
+    if let [track] = tracks.as_slice() {
+        metadata.title = track.title.clone();
+        metadata.artist = track
+            .artist
+            .clone()
+            .or_else(|| metadata.album_artist.clone());
+        metadata.track = track.track;
+    }
+    metadata
+}
+
+fn non_empty(value: &str) -> Option<String> {
+    let value = value.trim();
+    (!value.is_empty()).then(|| value.to_string())
+}
+
+/// Removes every tag, including embedded pictures, that the file carries.
+fn strip_tags(path: &Path) -> Result<()> {
+    let tagged = Probe::open(path)
+        .with_context(|| format!("failed to open audio metadata for {}", path.display()))?
+        .guess_file_type()
+        .with_context(|| format!("failed to identify audio format for {}", path.display()))?
+        .read()
+        .with_context(|| format!("failed to read audio metadata from {}", path.display()))?;
+    for tag in tagged.tags() {
+        tag.tag_type()
+            .remove_from_path(path)
+            .with_context(|| format!("failed to remove metadata from {}", path.display()))?;
+    }
+    Ok(())
+}
+
 fn write_metadata(path: &Path, format: &str, metadata: &MediaMetadata, id: &str) -> Result<()> {
     match format {
         "mp3" | "wav" => write_id3_metadata(path, metadata, id),
         "flac" => write_flac_metadata(path, metadata, id),
+        "m4a" => write_mp4_metadata(path, metadata, id),
+        "ogg" | "opus" => write_ogg_metadata(path, metadata, id),
         other => bail!("cannot write metadata for unsupported output format {other}"),
     }
 }
@@ -575,9 +2152,15 @@ fn write_id3_metadata(path: &Path, metadata: &MediaMetadata, id: &str) -> Result
     if let Some(album) = &metadata.album {
         tag.set_album(album);
     }
+    if let Some(album_artist) = &metadata.album_artist {
+        tag.set_album_artist(album_artist);
+    }
     if let Some(genre) = &metadata.genre {
         tag.set_genre(genre);
     }
+    if let Some(date) = &metadata.date {
+        tag.set_text("TDRC", date.to_string());
+    }
     if let Some(track) = metadata.track {
         tag.set_track(track);
     }
@@ -626,15 +2209,27 @@ fn write_flac_metadata(path: &Path, metadata: &MediaMetadata, id: &str) -> Resul
     if let Some(album) = &metadata.album {
         comments.set_album(vec![album.clone()]);
     }
+    if let Some(album_artist) = &metadata.album_artist {
+        comments.set_album_artist(vec![album_artist.clone()]);
+    }
     if let Some(genre) = &metadata.genre {
         comments.set_genre(vec![genre.clone()]);
     }
+    if let Some(date) = &metadata.date {
+        comments.set("DATE", vec![date.to_string()]);
+    }
     if let Some(track) = metadata.track {
         comments.set_track(track);
     }
     if let Some(total) = metadata.track_total {
         comments.set_total_tracks(total);
     }
+    if let Some(disc) = metadata.disc {
+        comments.set("DISCNUMBER", vec![disc.to_string()]);
+    }
+    if let Some(total) = metadata.disc_total {
+        comments.set("DISCTOTAL", vec![total.to_string()]);
+    }
     tag.set_vorbis(W4DJ_ID, vec![id]);
     if let Some(cover) = &metadata.cover {
         let already_present = tag.pictures().any(|picture| picture.data == *cover);
@@ -651,6 +2246,121 @@ fn write_flac_metadata(path: &Path, metadata: &MediaMetadata, id: &str) -> Resul
         .with_context(|| format!("failed to write FLAC metadata to {}", path.display()))
 }
 
+fn write_mp4_metadata(path: &Path, metadata: &MediaMetadata, id: &str) -> Result<()> {
+    let mut ilst = read_mp4_tag(path)?;
+    apply_lofty_metadata(&mut ilst, metadata);
+    if let Some(album_artist) = &metadata.album_artist {
+        ilst.replace_atom(Atom::new(
+            AtomIdent::Fourcc(*b"aART"),
+            AtomData::UTF8(album_artist.clone()),
+        ));
+    }
+    ilst.replace_atom(Atom::new(w4dj_id_atom(), AtomData::UTF8(id.to_string())));
+    if let Some(cover) = &metadata.cover {
+        let already_present = ilst
+            .pictures()
+            .is_some_and(|mut pictures| pictures.any(|picture| picture.data() == cover.as_slice()));
+        if !already_present {
+            ilst.remove_pictures();
+            ilst.insert_picture(cover_picture(cover));
+        }
+    }
+    ilst.save_to_path(path, WriteOptions::default())
+        .with_context(|| format!("failed to write MP4 metadata to {}", path.display()))
+}
+
+fn write_ogg_metadata(path: &Path, metadata: &MediaMetadata, id: &str) -> Result<()> {
+    let mut comments = read_ogg_tag(path)?;
+    apply_lofty_metadata(&mut comments, metadata);
+    if let Some(album_artist) = &metadata.album_artist {
+        comments.insert("ALBUMARTIST".to_string(), album_artist.clone());
+    }
+    comments.insert(W4DJ_ID.to_string(), id.to_string());
+    if let Some(cover) = &metadata.cover {
+        let already_present = comments
+            .pictures()
+            .iter()
+            .any(|(picture, _)| picture.data() == cover.as_slice());
+        if !already_present {
+            comments.remove_picture_type(LoftyPictureType::CoverFront);
+            let picture = cover_picture(cover);
+            let information = PictureInformation::from_picture(&picture).unwrap_or_default();
+            comments
+                .insert_picture(picture, Some(information))
+                .with_context(|| format!("failed to embed cover in {}", path.display()))?;
+        }
+    }
+    comments
+        .save_to_path(path, WriteOptions::default())
+        .with_context(|| format!("failed to write Ogg metadata to {}", path.display()))
+}
+
+fn apply_lofty_metadata(tag: &mut impl Accessor, metadata: &MediaMetadata) {
+    if let Some(title) = &metadata.title {
+        tag.set_title(title.clone());
+    }
+    if let Some(artist) = &metadata.artist {
+        tag.set_artist(artist.clone());
+    }
+    if let Some(album) = &metadata.album {
+        tag.set_album(album.clone());
+    }
+    if let Some(genre) = &metadata.genre {
+        tag.set_genre(genre.clone());
+    }
+    if let Some(date) = metadata.date {
+        tag.set_date(date);
+    }
+    if let Some(track) = metadata.track {
+        tag.set_track(track);
+    }
+    if let Some(total) = metadata.track_total {
+        tag.set_track_total(total);
+    }
+    if let Some(disc) = metadata.disc {
+        tag.set_disk(disc);
+    }
+    if let Some(total) = metadata.disc_total {
+        tag.set_disk_total(total);
+    }
+}
+
+fn cover_picture(cover: &[u8]) -> LoftyPicture {
+    LoftyPicture::unchecked(cover.to_vec())
+        .pic_type(LoftyPictureType::CoverFront)
+        .mime_type(MimeType::from_str(image_mime_type(cover)))
+        .build()
+}
+
+fn w4dj_id_atom() -> AtomIdent<'static> {
+    AtomIdent::Freeform {
+        mean: ITUNES_MEAN.into(),
+        name: W4DJ_ID.into(),
+    }
+}
+
+fn read_mp4_tag(path: &Path) -> Result<Ilst> {
+    let mut file =
+        File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+    let mp4 = Mp4File::read_from(&mut file, ParseOptions::new())
+        .with_context(|| format!("failed to read MP4 metadata from {}", path.display()))?;
+    Ok(mp4.ilst().cloned().unwrap_or_default())
+}
+
+/// Ogg files may carry Vorbis or Opus audio regardless of their extension.
+fn read_ogg_tag(path: &Path) -> Result<VorbisComments> {
+    let mut file =
+        File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+    if let Ok(vorbis) = VorbisFile::read_from(&mut file, ParseOptions::new()) {
+        return Ok(vorbis.vorbis_comments().clone());
+    }
+    let mut file =
+        File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+    let opus = OpusFile::read_from(&mut file, ParseOptions::new())
+        .with_context(|| format!("failed to read Ogg metadata from {}", path.display()))?;
+    Ok(opus.vorbis_comments().clone())
+}
+
 fn read_embedded_id(path: &Path) -> Option<String> {
     match extension(path).as_str() {
         "mp3" | "wav" => id3::Tag::read_from_path(path).ok().and_then(|tag| {
@@ -662,6 +2372,17 @@ fn read_embedded_id(path: &Path) -> Option<String> {
             tag.get_vorbis(W4DJ_ID)
                 .and_then(|mut values| values.next().map(str::to_string))
         }),
+        "m4a" => read_mp4_tag(path).ok().and_then(|ilst| {
+            ilst.get(&w4dj_id_atom())
+                .and_then(|atom| atom.data().next().cloned())
+                .and_then(|data| match data {
+                    AtomData::UTF8(value) | AtomData::UTF16(value) => Some(value),
+                    _ => None,
+                })
+        }),
+        "ogg" | "opus" => read_ogg_tag(path)
+            .ok()
+            .and_then(|comments| comments.get(W4DJ_ID).map(str::to_string)),
         _ => None,
     }
 }
@@ -678,14 +2399,24 @@ fn metadata_id(metadata: &MediaMetadata, fallback_name: &str) -> String {
     format!("meta:v1:{:016x}", fnv1a(value.as_bytes()))
 }
 
-fn normalize(value: &str) -> String {
+/// Compares text the same way on every file system: whitespace runs collapse, case is
+/// ignored and Unicode is composed (NFC), so macOS's decomposed names match everyone else's.
+pub(crate) fn normalize(value: &str) -> String {
     value
+        .nfc()
+        .collect::<String>()
         .split_whitespace()
         .collect::<Vec<_>>()
         .join(" ")
         .to_lowercase()
 }
 
+/// ID of a song keyed by its file name or relative path instead of its tags, for
+/// `match_by`; `kind` tells the two apart.
+pub(crate) fn name_id(kind: &str, name: &str) -> String {
+    format!("{kind}:v1:{:016x}", fnv1a(normalize(name).as_bytes()))
+}
+
 fn fnv1a(bytes: &[u8]) -> u64 {
     let mut hash = 0xcbf29ce484222325_u64;
     for byte in bytes {
@@ -705,15 +2436,37 @@ fn normalize_ncm_bitrate(bitrate: u64) -> Option<u64> {
     }
 }
 
-fn format_rank(format: &str) -> u8 {
+pub(crate) fn format_rank(format: &str) -> u8 {
     match format {
         "wav" => 4,
         "flac" => 3,
-        "mp3" => 2,
+        "mp3" | "m4a" | "ogg" | "opus" => 2,
         _ => 1,
     }
 }
 
+/// Saves `cover` next to the outputs in `directory` unless the folder already has one.
+fn write_folder_cover(directory: &Path, cover: &[u8]) -> Result<()> {
+    let path = directory.join(cover::folder_file_name(cover));
+    match fs::metadata(&path) {
+        Ok(metadata) if metadata.len() > 0 => return Ok(()),
+        Ok(_) => fs::remove_file(&path)
+            .with_context(|| format!("failed to replace empty cover {}", path.display()))?,
+        Err(_) => {}
+    }
+    let temp = create_temp(directory, "jpg")?;
+    fs::write(&temp, cover)
+        .with_context(|| format!("failed to write cover to {}", path.display()))?;
+    match temp.persist_noclobber(&path) {
+        Ok(()) => Ok(()),
+        // Another track of the same album saved the cover first.
+        Err(error) if error.error.kind() == io::ErrorKind::AlreadyExists => Ok(()),
+        Err(error) => {
+            Err(error.error).with_context(|| format!("failed to write cover to {}", path.display()))
+        }
+    }
+}
+
 fn create_temp(directory: &Path, extension: &str) -> Result<TempPath> {
     let suffix = format!(".{extension}");
     let file = TempBuilder::new()
@@ -724,6 +2477,10 @@ fn create_temp(directory: &Path, extension: &str) -> Result<TempPath> {
     Ok(file.into_temp_path())
 }
 
+pub(crate) fn is_encrypted(path: &Path) -> bool {
+    extension(path) == "ncm" || Cipher::of(path).is_some()
+}
+
 fn extension(path: &Path) -> String {
     path.extension()
         .and_then(|extension| extension.to_str())
@@ -756,6 +2513,32 @@ mod tests {
     use tempfile::tempdir;
 
     use super::*;
+    use crate::config::{DEFAULT_COPY_BUFFER, DEFAULT_SIZE_TOLERANCE};
+
+    #[test]
+    fn a_failed_promotion_puts_the_output_back() -> Result<()> {
+        let workspace = tempdir()?;
+        let output = workspace.path().join("output");
+        let staging = Staging::new(&output, output.join(".w4dj-staging"));
+        fs::create_dir_all(staging.root().join("blocked"))?;
+        fs::write(output.join("a.wav"), b"old")?;
+        fs::write(output.join("blocked"), b"a file where a folder is staged")?;
+        fs::write(staging.root().join("a.wav"), b"new")?;
+        fs::write(staging.root().join("blocked").join("b.wav"), b"new")?;
+
+        let error = staging.promote().unwrap_err();
+        assert!(format!("{error:#}").contains("left unchanged"));
+        assert_eq!(fs::read(output.join("a.wav"))?, b"old");
+        assert_eq!(fs::read(staging.root().join("a.wav"))?, b"new");
+        assert!(!staging.interrupted());
+
+        fs::remove_file(output.join("blocked"))?;
+        assert_eq!(staging.promote()?, 2);
+        assert_eq!(fs::read(output.join("a.wav"))?, b"new");
+        assert!(output.join("blocked").join("b.wav").is_file());
+        assert!(!staging.root().exists());
+        Ok(())
+    }
 
     #[test]
     fn cancelled_copy_stops_before_writing() {
@@ -763,26 +2546,406 @@ mod tests {
         let mut input = Cursor::new(vec![1_u8; 128 * 1024]);
         let mut output = Vec::new();
 
-        let error = copy_with_cancel(&mut input, &mut output, &cancel).unwrap_err();
+        let error = copy_with_cancel(
+            &mut input,
+            &mut output,
+            DEFAULT_COPY_BUFFER as usize,
+            &cancel,
+            &|_| {},
+        )
+        .unwrap_err();
 
         assert!(is_cancelled(&error));
         assert!(output.is_empty());
     }
 
+    #[test]
+    fn ncm_audio_bytes_override_the_declared_format() -> Result<()> {
+        let path = Path::new("Song.ncm");
+        let sniffed = sniff_audio_format(b"ID3\x04\0\0\0\0\0\0\0\0");
+
+        assert_eq!(ncm_format("FLAC", sniffed, path)?, "mp3");
+        // An MPEG-1 Layer III frame at the start is MP3; AAC ADTS or a sync found later is not.
+        assert_eq!(sniff_audio_format(b"\xff\xfb\x90\x64"), Some("mp3"));
+        assert_eq!(sniff_audio_format(b"\xff\xf1\x50\x80"), None);
+        assert_eq!(sniff_audio_format(b"\0\0\xff\xfb\x90\x64"), None);
+        assert_eq!(ncm_format("", Some("flac"), path)?, "flac");
+        assert_eq!(ncm_format(" Mp3 ", None, path)?, "mp3");
+        assert!(ncm_format("", None, path).is_err());
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn failed_ffmpeg_runs_report_the_end_of_their_error_output() {
+        let mut command = std::process::Command::new("sh");
+        command.args([
+            "-c",
+            "for i in 1 2 3 4 5 6 7; do echo \"warning $i\" >&2; done; \
+             echo 'Invalid data found when processing input' >&2; exit 1",
+        ]);
+        let error = run_ffmpeg(
+            &mut command,
+            Path::new("sh"),
+            Path::new("Song.flac"),
+            &AtomicBool::new(false),
+        )
+        .unwrap_err()
+        .to_string();
+
+        assert!(error.starts_with("FFmpeg failed for Song.flac with status"));
+        assert!(error.ends_with(
+            "warning 4 | warning 5 | warning 6 | warning 7 | Invalid data found when processing input"
+        ));
+        assert!(!error.contains("warning 3"));
+    }
+
+    #[test]
+    fn transcode_slots_wait_for_a_release_or_cancellation() -> Result<()> {
+        let slots = TranscodeSlots::new(1);
+        let cancel = AtomicBool::new(false);
+        let first = slots.acquire(&cancel)?;
+
+        let cancelled = AtomicBool::new(true);
+        assert!(is_cancelled(&slots.acquire(&cancelled).err().unwrap()));
+
+        std::thread::scope(|scope| {
+            let waiter = scope.spawn(|| slots.acquire(&cancel).map(drop));
+            std::thread::sleep(Duration::from_millis(50));
+            drop(first);
+            waiter.join().unwrap()
+        })?;
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn transcodes_resample_only_sources_above_max_sample_rate() -> Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let workspace = tempdir()?;
+        let song = workspace.path().join("Song.wav");
+        write_test_wav(&song)?;
+        let arguments = workspace.path().join("arguments");
+        let ffmpeg = workspace.path().join("ffmpeg");
+        fs::write(
+            &ffmpeg,
+            format!("#!/bin/sh\necho \"$@\" > '{}'\n", arguments.display()),
+        )?;
+        fs::set_permissions(&ffmpeg, fs::Permissions::from_mode(0o755))?;
+        let output = workspace.path().join("Song.mp3");
+        let cancel = AtomicBool::new(false);
+        let limited = |rate| SyncOptions {
+            max_sample_rate: Some(rate),
+            ..SyncOptions::default()
+        };
+
+        transcode(&ffmpeg, &song, &output, Mode::Mp3, &limited(8_000), &cancel)?;
+        assert!(!fs::read_to_string(&arguments)?.contains("-ar"));
+        transcode(&ffmpeg, &song, &output, Mode::Mp3, &limited(4_000), &cancel)?;
+        assert!(fs::read_to_string(&arguments)?.contains("-ar 4000"));
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn decode_checks_fail_on_any_reported_error() -> Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let workspace = tempdir()?;
+        let song = workspace.path().join("Song.flac");
+        fs::write(&song, b"fLaC")?;
+        let fake_ffmpeg = |name: &str, script: &str| -> Result<PathBuf> {
+            let path = workspace.path().join(name);
+            fs::write(&path, format!("#!/bin/sh\n{script}\n"))?;
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o755))?;
+            Ok(path)
+        };
+        let cancel = AtomicBool::new(false);
+
+        let clean = fake_ffmpeg("clean", "exit 0")?;
+        check_decodes(&clean, &song, &cancel)?;
+        let truncated = fake_ffmpeg("truncated", "echo 'invalid sync code' >&2; exit 0")?;
+        let error = check_decodes(&truncated, &song, &cancel).unwrap_err();
+        assert!(format!("{error:#}").contains("invalid sync code"));
+        Ok(())
+    }
+
+    #[test]
+    fn failures_are_classified_by_stage_and_carry_the_song() -> Result<()> {
+        let workspace = tempdir()?;
+        let source = workspace.path().join("Song.wav");
+        write_test_wav(&source)?;
+        let job = Job {
+            source: inspect_source(&source, Compare::Size)?,
+            target: workspace.path().join("out").join("Song.mp3"),
+            old_output: None,
+            mode: Mode::Mp3,
+            ffmpeg: Some(workspace.path().join("missing-ffmpeg")),
+            transcode_slots: None,
+            output_dirs: Arc::default(),
+            original: None,
+            staging: None,
+            move_source: false,
+        };
+        let options = SyncOptions::default();
+
+        let error =
+            process_with_cancel(&job, &options, &AtomicBool::new(false), &|_| {}).unwrap_err();
+        assert!(
+            matches!(&error, SyncError::Transcode { song, .. } if *song == job.source.display_name)
+        );
+        let cancelled = process_with_cancel(&job, &options, &AtomicBool::new(true), &|_| {});
+        assert!(matches!(cancelled, Err(SyncError::Cancelled)));
+        Ok(())
+    }
+
+    #[test]
+    fn ncm_sources_without_cover_art_are_reported_not_failed() -> Result<()> {
+        let workspace = tempdir()?;
+        let source = workspace.path().join("Song.wav");
+        write_test_wav(&source)?;
+        let job = Job {
+            source: inspect_source(&source, Compare::Size)?,
+            target: workspace.path().join("out").join("Song.wav"),
+            old_output: None,
+            mode: Mode::Original,
+            ffmpeg: None,
+            transcode_slots: None,
+            output_dirs: Arc::default(),
+            original: None,
+            staging: None,
+            move_source: false,
+        };
+        let options = SyncOptions::default();
+        let processed = process_with_cancel(&job, &options, &AtomicBool::new(false), &|_| {})?;
+        assert!(!processed.missing_cover);
+
+        let ncm = Path::new("Song.ncm");
+        let bare = MediaMetadata::default();
+        assert!(lacks_cover(ncm, &bare, &options));
+        let with_cover = MediaMetadata {
+            cover: Some(vec![0xff, 0xd8, 0xff]),
+            ..MediaMetadata::default()
+        };
+        assert!(!lacks_cover(ncm, &with_cover, &options));
+        let stripped = SyncOptions {
+            strip_metadata: Some(true),
+            ..SyncOptions::default()
+        };
+        assert!(!lacks_cover(ncm, &bare, &stripped));
+        Ok(())
+    }
+
+    #[test]
+    fn only_audio_for_ffmpeg_is_decrypted_into_temp_dir() -> Result<()> {
+        let workspace = tempdir()?;
+        let plain = workspace.path().join("plain.wav");
+        write_test_wav(&plain)?;
+        // The QMC cipher is a XOR stream, so decrypting plain audio encrypts it.
+        let source = workspace.path().join("Song.qmc0");
+        fs::write(
+            &source,
+            QmcDump::from_reader(File::open(&plain)?)?.get_data()?,
+        )?;
+        let scratch = workspace.path().join("scratch");
+        let output = workspace.path().join("output");
+        fs::create_dir_all(&scratch)?;
+        fs::create_dir_all(&output)?;
+        let options = SyncOptions {
+            temp_dir: Some(scratch.clone()),
+            ..SyncOptions::default()
+        };
+        let cancel = AtomicBool::new(false);
+        let mut job = Job {
+            source: inspect_source(&source, Compare::Size)?,
+            target: output.join("Song.mp3"),
+            old_output: None,
+            mode: Mode::Mp3,
+            ffmpeg: None,
+            transcode_slots: None,
+            output_dirs: Arc::default(),
+            original: None,
+            staging: None,
+            move_source: false,
+        };
+
+        let (_, transcoded) = decode(&job, &options, &output, &cancel, &|_| {})?;
+        assert_eq!(transcoded.path().parent(), Some(scratch.as_path()));
+        job.mode = Mode::Original;
+        let (_, copied) = decode(&job, &options, &output, &cancel, &|_| {})?;
+        assert_eq!(copied.path().parent(), Some(output.as_path()));
+        Ok(())
+    }
+
+    #[test]
+    fn tagging_phase_reports_its_own_failures() -> Result<()> {
+        let workspace = tempdir()?;
+        let source = workspace.path().join("Song.wav");
+        write_test_wav(&source)?;
+        let job = Job {
+            source: inspect_source(&source, Compare::Size)?,
+            target: workspace.path().join("Song.wav"),
+            old_output: None,
+            mode: Mode::Original,
+            ffmpeg: None,
+            transcode_slots: None,
+            output_dirs: Arc::default(),
+            original: None,
+            staging: None,
+            move_source: false,
+        };
+        let options = SyncOptions::default();
+        let staged = workspace.path().join(".w4dj-part.wav");
+        fs::copy(&source, &staged)?;
+        tag_output(&staged, &job, &MediaMetadata::default(), &options)?;
+        assert_eq!(inspect_output(&staged)?.id, job.source.id);
+
+        let phase = |path: &Path| {
+            tag_output(path, &job, &MediaMetadata::default(), &options)
+                .unwrap_err()
+                .downcast::<SyncError>()
+        };
+        let missing = workspace.path().join(".w4dj-missing.wav");
+        assert!(matches!(phase(&missing)?, SyncError::MetadataInject { .. }));
+        fs::write(&staged, b"not audio")?;
+        assert!(matches!(phase(&staged)?, SyncError::Validation { .. }));
+        Ok(())
+    }
+
+    #[test]
+    fn verified_copies_match_the_bytes_read_back() -> Result<()> {
+        let workspace = tempdir()?;
+        let path = workspace.path().join("copy.mp3");
+        let cancel = AtomicBool::new(false);
+        let mut input = Cursor::new(vec![7_u8; 200 * 1024]);
+        let reported = std::cell::Cell::new(0_u64);
+
+        copy_to_file(
+            &mut input,
+            File::create(&path)?,
+            &path,
+            true,
+            4096,
+            &cancel,
+            &|bytes| reported.set(reported.get() + bytes),
+        )?;
+
+        assert_eq!(fs::read(&path)?, vec![7_u8; 200 * 1024]);
+        assert_eq!(reported.get(), 200 * 1024);
+        Ok(())
+    }
+
+    #[test]
+    fn replaygain_tags_come_from_the_loudness_summary() -> Result<()> {
+        let log = "[Parsed_ebur128_0 @ 0x1] t: 1.0 M: -20.1 S: -120.7 I: -20.1 LUFS\n\
+                   [Parsed_ebur128_0 @ 0x1] Summary:\n\n  Integrated loudness:\n    \
+                   I:         -11.5 LUFS\n    Threshold: -21.8 LUFS\n\n  True peak:\n    \
+                   Peak:        0.4 dBFS\n";
+        let loudness = parse_loudness(log)?;
+        assert_eq!(
+            loudness,
+            Loudness {
+                integrated_lufs: -11.5,
+                true_peak_dbfs: 0.4,
+            }
+        );
+        assert!(parse_loudness("Conversion failed!").is_err());
+
+        let workspace = tempdir()?;
+        let path = workspace.path().join("Song.wav");
+        write_test_wav(&path)?;
+        write_replaygain(&path, "wav", loudness)?;
+        let tag = id3::Tag::read_from_path(&path)?;
+        let value = |name: &str| {
+            tag.extended_texts()
+                .find(|text| text.description == name)
+                .map(|text| text.value.clone())
+        };
+        assert_eq!(value("REPLAYGAIN_TRACK_GAIN").as_deref(), Some("-6.50 dB"));
+        assert_eq!(value("REPLAYGAIN_TRACK_PEAK").as_deref(), Some("1.047129"));
+        Ok(())
+    }
+
+    #[test]
+    fn output_directories_are_created_once_per_run() -> Result<()> {
+        let workspace = tempdir()?;
+        let album = workspace.path().join("Artist").join("Album");
+        let directories = OutputDirectories::default();
+
+        directories.create(&album)?;
+        assert!(album.is_dir());
+        fs::remove_dir(&album)?;
+        directories.create(&album)?;
+        assert!(!album.exists());
+        Ok(())
+    }
+
     #[test]
     fn lossless_source_replaces_lossy_source() {
         let mp3 = SourceVariant {
             format: "mp3".to_string(),
             bitrate: Some(320),
             size: 10,
+            payload: None,
+            hash: None,
         };
         let flac = SourceVariant {
             format: "flac".to_string(),
             bitrate: Some(900),
             size: 20,
+            payload: None,
+            hash: None,
+        };
+        assert!(flac.is_better_than(&mp3, Compare::Size, DEFAULT_SIZE_TOLERANCE));
+        assert!(!mp3.is_better_than(&flac, Compare::Size, DEFAULT_SIZE_TOLERANCE));
+    }
+
+    #[test]
+    fn size_tolerance_sets_how_much_larger_a_replacement_must_be() {
+        let original = SourceVariant {
+            format: "flac".to_string(),
+            bitrate: None,
+            size: 1_000_000,
+            payload: None,
+            hash: None,
+        };
+        let larger = SourceVariant {
+            size: 1_030_000,
+            ..original.clone()
+        };
+        assert!(!larger.is_better_than(&original, Compare::Size, DEFAULT_SIZE_TOLERANCE));
+        assert!(larger.is_better_than(&original, Compare::Size, 0.02));
+
+        let options = SyncOptions {
+            size_tolerance: Some(1.5),
+            ..SyncOptions::default()
+        };
+        assert!(options.validate().is_err());
+    }
+
+    #[test]
+    fn payload_comparison_ignores_added_artwork() {
+        let bare = SourceVariant {
+            format: "flac".to_string(),
+            bitrate: None,
+            size: 1_000_000,
+            payload: Some(999_000),
+            hash: None,
+        };
+        let with_art = SourceVariant {
+            size: 1_300_000,
+            payload: Some(999_100),
+            ..bare.clone()
+        };
+        assert!(with_art.is_better_than(&bare, Compare::Size, DEFAULT_SIZE_TOLERANCE));
+        assert!(!with_art.is_better_than(&bare, Compare::PayloadSize, DEFAULT_SIZE_TOLERANCE));
+
+        let unmeasured = SourceVariant {
+            payload: None,
+            ..bare.clone()
         };
-        assert!(flac.is_better_than(&mp3));
-        assert!(!mp3.is_better_than(&flac));
+        assert!(with_art.is_better_than(&unmeasured, Compare::PayloadSize, DEFAULT_SIZE_TOLERANCE));
     }
 
     #[test]
@@ -807,6 +2970,15 @@ mod tests {
         );
     }
 
+    #[test]
+    fn decomposed_names_match_their_composed_form() {
+        let composed = MediaMetadata::default();
+        assert_eq!(
+            metadata_id(&composed, "Caf\u{e9} \u{30d6}"),
+            metadata_id(&composed, "Cafe\u{301} \u{30d5}\u{3099}")
+        );
+    }
+
     #[test]
     fn image_type_accepts_jpeg_without_requiring_an_app_marker() {
         assert_eq!(image_mime_type(&[0xff, 0xd8, 0xff, 0xdb]), "image/jpeg");
@@ -840,6 +3012,104 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn ncm_tags_fill_in_from_the_decrypted_audio_without_blank_fields() -> Result<()> {
+        let info = NcmInfo {
+            name: "Song".to_string(),
+            id: 7,
+            album: " ".to_string(),
+            artist: vec![(String::new(), 1), ("Artist".to_string(), 2)],
+            bitrate: 320_000,
+            duration: 1_000,
+            format: "flac".to_string(),
+            mv_id: None,
+            alias: None,
+        };
+        let mut metadata = metadata_from_ncm(&info, None);
+        assert_eq!(metadata.artist.as_deref(), Some("Artist"));
+        assert_eq!(metadata.album, None);
+        metadata.fill_from(MediaMetadata {
+            title: Some("Embedded".to_string()),
+            album_artist: Some("Various".to_string()),
+            genre: Some("Pop".to_string()),
+            date: Some("2019-05-01".parse()?),
+            track: Some(3),
+            disc: Some(2),
+            ..MediaMetadata::default()
+        });
+        assert_eq!(metadata.title.as_deref(), Some("Song"));
+
+        let directory = tempdir()?;
+        let wav = directory.path().join("Song.wav");
+        write_test_wav(&wav)?;
+        write_metadata(&wav, "wav", &metadata, "ncm:7")?;
+        let (read, _) = read_regular_metadata(&wav, false)?;
+        assert_eq!(read.album, None);
+        assert_eq!(read.album_artist.as_deref(), Some("Various"));
+        assert_eq!(read.genre.as_deref(), Some("Pop"));
+        assert_eq!(read.date.map(|date| date.year), Some(2019));
+        assert_eq!((read.track, read.disc), (Some(3), Some(2)));
+
+        // A bare STREAMINFO block is enough for the Vorbis comment writer.
+        let flac = directory.path().join("Song.flac");
+        let mut bytes = b"fLaC\x80\x00\x00\x22".to_vec();
+        bytes.extend([0; 34]);
+        fs::write(&flac, bytes)?;
+        write_metadata(&flac, "flac", &metadata, "ncm:7")?;
+        let tag = metaflac::Tag::read_from_path(&flac)?;
+        let comments = tag.vorbis_comments().context("no Vorbis comments")?;
+        assert_eq!(
+            comments.get("ALBUMARTIST"),
+            Some(&vec!["Various".to_string()])
+        );
+        assert_eq!(comments.get("DATE"), Some(&vec!["2019-05-01".to_string()]));
+        assert_eq!(comments.get("DISCNUMBER"), Some(&vec!["2".to_string()]));
+        assert_eq!(comments.get("ALBUM"), None);
+        Ok(())
+    }
+
+    #[test]
+    fn sidecar_json_and_cue_sheets_fill_missing_tags() -> Result<()> {
+        let directory = tempdir()?;
+        let song = directory.path().join("Song.flac");
+        assert!(read_sidecar_metadata(&song)?.is_none());
+
+        fs::write(
+            directory.path().join("Song.cue"),
+            "\u{feff}REM GENRE Jazz\nREM DATE 1959\nPERFORMER \"Band\"\nTITLE \"Album\"\n\
+             FILE \"Song.flac\" WAVE\n  TRACK 04 AUDIO\n    TITLE \"Song\"\n    INDEX 01 00:00:00\n",
+        )?;
+        let cue = read_sidecar_metadata(&song)?.context("no cue metadata")?;
+        assert_eq!(cue.album.as_deref(), Some("Album"));
+        assert_eq!(cue.album_artist.as_deref(), Some("Band"));
+        assert_eq!(cue.genre.as_deref(), Some("Jazz"));
+        assert_eq!(cue.date.map(|date| date.year), Some(1959));
+        assert_eq!(cue.title.as_deref(), Some("Song"));
+        assert_eq!(cue.artist.as_deref(), Some("Band"));
+        assert_eq!(cue.track, Some(4));
+        let album_image =
+            parse_cue("TITLE \"Album\"\nTRACK 01 AUDIO\nTITLE \"A\"\nTRACK 02 AUDIO\n");
+        assert_eq!(album_image.album.as_deref(), Some("Album"));
+        assert_eq!(album_image.title, None);
+
+        // JSON wins over the cue sheet, and the source's own tags win over both.
+        fs::write(
+            directory.path().join("Song.json"),
+            r#"{"title": "Sidecar", "artist": "", "track": 7, "disc": 2}"#,
+        )?;
+        let mut metadata = MediaMetadata {
+            title: Some("Embedded".to_string()),
+            ..MediaMetadata::default()
+        };
+        metadata.fill_from(read_sidecar_metadata(&song)?.context("no JSON metadata")?);
+        assert_eq!(metadata.title.as_deref(), Some("Embedded"));
+        assert_eq!(metadata.artist, None);
+        assert_eq!((metadata.track, metadata.disc), (Some(7), Some(2)));
+        fs::write(directory.path().join("Song.json"), "not json")?;
+        assert!(read_sidecar_metadata(&song).is_err());
+        Ok(())
+    }
+
     fn write_test_wav(path: &Path) -> Result<()> {
         let sample_rate = 8_000_u32;
         let samples = vec![0_u8; sample_rate as usize * 2];
@@ -0,0 +1,88 @@
+use std::error::Error;
+
+use serde::Serialize;
+
+use crate::dump;
+
+/// Underlying cause of a [`SyncError`], with its own chain of sources.
+pub type Source = Box<dyn Error + Send + Sync + 'static>;
+
+/// Why a sync, or a single song within it, failed.
+///
+/// Per-song variants name the song by its display name and keep the lower-level error as
+/// their source, so `{:#}` on an `anyhow::Error` built from them prints the whole chain.
+#[derive(Debug, thiserror::Error)]
+pub enum SyncError {
+    #[error("failed to decrypt NCM file {song}")]
+    Ncm { song: String, source: Source },
+    #[error("failed to decrypt QMC file {song}")]
+    Qmc { song: String, source: Source },
+    #[error("failed to decrypt KGM file {song}")]
+    Kgm { song: String, source: Source },
+    #[error("FFmpeg could not transcode {song}")]
+    Transcode { song: String, source: Source },
+    #[error("failed to write tags for {song}")]
+    MetadataInject { song: String, source: Source },
+    #[error("output validation failed for {song}")]
+    Validation { song: String, source: Source },
+    /// Reading the source or writing, syncing or publishing the output failed.
+    #[error("failed to sync {song}")]
+    Io { song: String, source: Source },
+    /// Songs failed and `keep_going` is off; `errors` holds each processing failure, while
+    /// `failed` also counts sources that could not be inspected.
+    #[error("{failed} files could not be synchronized")]
+    Failed {
+        failed: usize,
+        errors: Vec<SyncError>,
+    },
+    #[error("operation cancelled")]
+    Cancelled,
+    /// Setting up or finishing the run failed, for example the output is locked.
+    #[error(transparent)]
+    Other(Source),
+}
+
+/// Which [`SyncError`] variant a failure was, without its payload, so results that are
+/// cloned or written to the JSON report can still be matched on.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SyncErrorKind {
+    Ncm,
+    Qmc,
+    Kgm,
+    Transcode,
+    MetadataInject,
+    Validation,
+    Io,
+    Failed,
+    Cancelled,
+    Other,
+}
+
+impl SyncError {
+    pub fn kind(&self) -> SyncErrorKind {
+        match self {
+            Self::Ncm { .. } => SyncErrorKind::Ncm,
+            Self::Qmc { .. } => SyncErrorKind::Qmc,
+            Self::Kgm { .. } => SyncErrorKind::Kgm,
+            Self::Transcode { .. } => SyncErrorKind::Transcode,
+            Self::MetadataInject { .. } => SyncErrorKind::MetadataInject,
+            Self::Validation { .. } => SyncErrorKind::Validation,
+            Self::Io { .. } => SyncErrorKind::Io,
+            Self::Failed { .. } => SyncErrorKind::Failed,
+            Self::Cancelled => SyncErrorKind::Cancelled,
+            Self::Other(_) => SyncErrorKind::Other,
+        }
+    }
+}
+
+impl From<anyhow::Error> for SyncError {
+    fn from(error: anyhow::Error) -> Self {
+        if dump::is_cancelled(&error) {
+            return Self::Cancelled;
+        }
+        error
+            .downcast::<SyncError>()
+            .unwrap_or_else(|error| Self::Other(error.into()))
+    }
+}
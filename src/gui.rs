@@ -14,11 +14,11 @@ use gpui::{
 };
 use lucide_icons::{Icon, LUCIDE_FONT_BYTES};
 
-use crate::config::{
+use w4dj::config::{
     EditableConfig, GuiTheme, MAX_WINDOW_OPACITY, MIN_WINDOW_OPACITY, Mode,
     normalize_window_opacity,
 };
-use crate::sync::{self, SyncEvent, SyncSummary};
+use w4dj::sync::{self, SyncEvent, SyncSummary};
 
 const ACCENT: u32 = 0x3a80db;
 const ACCENT_HOVER: u32 = 0x2f6fbe;
@@ -169,7 +169,8 @@ const ASCII_LOGO: [&str; 6] = [
 ];
 
 enum WorkerMessage {
-    Event(SyncEvent),
+    // Boxed because a finished run's summary dwarfs every other message.
+    Event(Box<SyncEvent>),
     Done(Result<(), String>),
 }
 
@@ -1013,10 +1014,10 @@ impl W4djGui {
                 let event_sender = worker_sender.clone();
                 let result =
                     sync::run_with_progress_cancellable(&config, &cancel_token, move |event| {
-                        let _ = event_sender.send(WorkerMessage::Event(event));
+                        let _ = event_sender.send(WorkerMessage::Event(Box::new(event)));
                     })
                     .map(|_| ())
-                    .map_err(|error| format!("{error:#}"));
+                    .map_err(|error| format!("{:#}", anyhow::Error::new(error)));
                 let _ = worker_sender.send(WorkerMessage::Done(result));
             });
 
@@ -1070,25 +1071,35 @@ impl W4djGui {
 
     fn apply_worker_message(&mut self, message: WorkerMessage) {
         match message {
-            WorkerMessage::Event(SyncEvent::Status(_)) => {}
-            WorkerMessage::Event(SyncEvent::Progress {
-                completed,
-                total,
-                current: _,
-            }) => {
-                if completed >= self.completed {
-                    self.completed = completed;
-                    self.total = total;
+            WorkerMessage::Event(event) => match *event {
+                SyncEvent::Status(_)
+                | SyncEvent::Planned { .. }
+                | SyncEvent::Removed(_)
+                | SyncEvent::Imported { .. }
+                | SyncEvent::StillWorking { .. }
+                | SyncEvent::Scanning { .. }
+                | SyncEvent::Plan(_)
+                | SyncEvent::Transferred { .. } => {}
+                SyncEvent::Progress {
+                    completed,
+                    total,
+                    failed: _,
+                    current: _,
+                } => {
+                    if completed >= self.completed {
+                        self.completed = completed;
+                        self.total = total;
+                    }
                 }
-            }
-            WorkerMessage::Event(SyncEvent::Finished(summary)) => {
-                self.error = summary.errors.first().cloned();
-                self.summary = Some(summary);
-            }
-            WorkerMessage::Event(SyncEvent::Cancelled(summary)) => {
-                self.error = None;
-                self.summary = Some(summary);
-            }
+                SyncEvent::Finished(summary) => {
+                    self.error = summary.errors.first().map(ToString::to_string);
+                    self.summary = Some(summary);
+                }
+                SyncEvent::Cancelled(summary) => {
+                    self.error = None;
+                    self.summary = Some(summary);
+                }
+            },
             WorkerMessage::Done(result) => {
                 self.syncing = false;
                 self.cancel_requested = false;
@@ -1483,47 +1494,54 @@ impl Render for W4djGui {
                                     .flex()
                                     .items_center()
                                     .children(
-                                        [Mode::Mp3, Mode::Wav, Mode::Original].into_iter().map(
-                                            |mode| {
-                                                let selected = self.config.mode == mode;
-                                                div()
-                                                    .id(SharedString::from(format!(
-                                                        "mode-{}",
-                                                        mode_label(mode)
-                                                    )))
-                                                    .h_8()
-                                                    .w(px(82.0))
-                                                    .rounded(px(4.0))
-                                                    .flex()
-                                                    .items_center()
-                                                    .justify_center()
-                                                    .text_size(px(UI_TEXT_SIZE))
-                                                    .font_weight(if selected {
-                                                        FontWeight::SEMIBOLD
-                                                    } else {
-                                                        FontWeight::NORMAL
-                                                    })
-                                                    .text_color(rgb(if selected {
-                                                        palette.ink
-                                                    } else {
-                                                        palette.muted
-                                                    }))
-                                                    .when(selected, |this| {
-                                                        this.bg(rgb(palette.surface_strong))
-                                                            .shadow_sm()
-                                                    })
-                                                    .when(!self.syncing, |this| {
-                                                        this.cursor_pointer()
-                                                .hover(|this| this.text_color(rgb(palette.ink)))
-                                                .on_click(cx.listener(
-                                                    move |this, _: &ClickEvent, _, cx| {
-                                                        this.select_mode(mode, cx)
-                                                    },
-                                                ))
-                                                    })
-                                                    .child(mode_label(mode))
-                                            },
-                                        ),
+                                        [
+                                            Mode::Mp3,
+                                            Mode::Aac,
+                                            Mode::Opus,
+                                            Mode::Wav,
+                                            Mode::Original,
+                                        ]
+                                        .into_iter()
+                                        .map(|mode| {
+                                            let selected = self.config.mode == mode;
+                                            div()
+                                                .id(SharedString::from(format!(
+                                                    "mode-{}",
+                                                    mode_label(mode)
+                                                )))
+                                                .h_8()
+                                                .w(px(64.0))
+                                                .rounded(px(4.0))
+                                                .flex()
+                                                .items_center()
+                                                .justify_center()
+                                                .text_size(px(UI_TEXT_SIZE))
+                                                .font_weight(if selected {
+                                                    FontWeight::SEMIBOLD
+                                                } else {
+                                                    FontWeight::NORMAL
+                                                })
+                                                .text_color(rgb(if selected {
+                                                    palette.ink
+                                                } else {
+                                                    palette.muted
+                                                }))
+                                                .when(selected, |this| {
+                                                    this.bg(rgb(palette.surface_strong)).shadow_sm()
+                                                })
+                                                .when(!self.syncing, |this| {
+                                                    this.cursor_pointer()
+                                                        .hover(|this| {
+                                                            this.text_color(rgb(palette.ink))
+                                                        })
+                                                        .on_click(cx.listener(
+                                                            move |this, _: &ClickEvent, _, cx| {
+                                                                this.select_mode(mode, cx)
+                                                            },
+                                                        ))
+                                                })
+                                                .child(mode_label(mode))
+                                        }),
                                     ),
                             )
                             .child(
@@ -1594,6 +1612,8 @@ fn mode_label(mode: Mode) -> &'static str {
         Mode::Original => "Ori",
         Mode::Mp3 => "MP3",
         Mode::Wav => "WAV",
+        Mode::Aac => "AAC",
+        Mode::Opus => "Opus",
     }
 }
 
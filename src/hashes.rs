@@ -0,0 +1,238 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::UNIX_EPOCH;
+
+use anyhow::{Context, Result};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use tempfile::NamedTempFile;
+use xxhash_rust::xxh3::Xxh3;
+
+const HASH_CACHE_NAME: &str = ".w4dj-hashes.json";
+
+/// A value read from a file, such as its content hash, together with the size and mtime it
+/// was computed for.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Fingerprint<T> {
+    pub path: PathBuf,
+    pub size: u64,
+    pub modified_secs: u64,
+    pub modified_nanos: u32,
+    pub value: T,
+}
+
+/// Remembers fingerprints between runs in a file in the output folder, so files whose size
+/// and mtime are unchanged are not read again.
+#[derive(Debug)]
+pub struct FingerprintCache<T> {
+    name: &'static str,
+    entries: HashMap<PathBuf, Fingerprint<T>>,
+    /// Fingerprints asked for this run; only these are saved.
+    seen: Mutex<BTreeMap<PathBuf, Fingerprint<T>>>,
+}
+
+impl<T: Clone + Serialize + DeserializeOwned> FingerprintCache<T> {
+    /// Loads the cache `name` stored in `output`; a missing or unreadable cache starts empty.
+    pub fn load(output: &Path, name: &'static str) -> Self {
+        let entries = fs::read(output.join(name))
+            .ok()
+            .and_then(|bytes| serde_json::from_slice::<Vec<Fingerprint<T>>>(&bytes).ok())
+            .unwrap_or_default()
+            .into_iter()
+            .map(|entry| (entry.path.clone(), entry))
+            .collect();
+        Self {
+            name,
+            entries,
+            seen: Mutex::default(),
+        }
+    }
+
+    /// Returns the cached fingerprint when size and mtime still match, otherwise computes it.
+    pub fn get(
+        &self,
+        path: &Path,
+        compute: impl FnOnce(&Path) -> Result<T>,
+    ) -> Result<Fingerprint<T>> {
+        let metadata =
+            fs::metadata(path).with_context(|| format!("failed to read {}", path.display()))?;
+        let modified = metadata
+            .modified()
+            .ok()
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .unwrap_or_default();
+        let size = metadata.len();
+        let fingerprint = match self.entries.get(path) {
+            Some(cached)
+                if cached.size == size
+                    && cached.modified_secs == modified.as_secs()
+                    && cached.modified_nanos == modified.subsec_nanos() =>
+            {
+                cached.clone()
+            }
+            _ => Fingerprint {
+                path: path.to_path_buf(),
+                size,
+                modified_secs: modified.as_secs(),
+                modified_nanos: modified.subsec_nanos(),
+                value: compute(path)?,
+            },
+        };
+        self.seen
+            .lock()
+            .unwrap()
+            .insert(path.to_path_buf(), fingerprint.clone());
+        Ok(fingerprint)
+    }
+
+    /// Replaces the cache in `output` with the fingerprints asked for since it was loaded,
+    /// dropping files that were not seen.
+    pub fn save(&self, output: &Path) -> Result<()> {
+        let entries = self
+            .seen
+            .lock()
+            .unwrap()
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+        let bytes = serde_json::to_vec(&entries).context("failed to serialize cache")?;
+        let mut temporary = NamedTempFile::new_in(output)
+            .with_context(|| format!("failed to create {} in {}", self.name, output.display()))?;
+        temporary.write_all(&bytes)?;
+        temporary
+            .persist(output.join(self.name))
+            .map_err(|error| error.error)
+            .with_context(|| format!("failed to save {}", self.name))?;
+        Ok(())
+    }
+}
+
+/// Content hashes of sources, for `compare = "hash"` and `dedupe_source = "hash"`.
+pub type HashCache = FingerprintCache<String>;
+
+impl HashCache {
+    pub fn load_hashes(output: &Path) -> Self {
+        Self::load(output, HASH_CACHE_NAME)
+    }
+
+    /// Returns the cached hash when size and mtime still match, otherwise hashes the file.
+    pub fn hash(&self, path: &Path) -> Result<Fingerprint<String>> {
+        self.get(path, hash_file)
+    }
+}
+
+/// Hashes everything written through it, matching [`hash_file`] for the same bytes.
+pub struct HashingWriter<W> {
+    inner: W,
+    hasher: Xxh3,
+}
+
+impl<W: Write> HashingWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            hasher: Xxh3::new(),
+        }
+    }
+
+    pub fn finish(&self) -> String {
+        format_hash(self.hasher.digest128())
+    }
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buffer: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buffer)?;
+        self.hasher.update(&buffer[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+pub fn hash_file(path: &Path) -> Result<String> {
+    let mut file =
+        File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+    let mut hasher = Xxh3::new();
+    let mut buffer = vec![0_u8; 1 << 16];
+    loop {
+        let read = file
+            .read(&mut buffer)
+            .with_context(|| format!("failed to hash {}", path.display()))?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(format_hash(hasher.digest128()))
+}
+
+fn format_hash(digest: u128) -> String {
+    format!("xxh3:{digest:032x}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cached_hashes_are_reused_until_the_file_changes() -> Result<()> {
+        let workspace = tempfile::tempdir()?;
+        let path = workspace.path().join("song.flac");
+        fs::write(&path, b"first")?;
+
+        let cache = HashCache::load_hashes(workspace.path());
+        let first = cache.hash(&path)?;
+        cache.save(workspace.path())?;
+        let cache = HashCache::load_hashes(workspace.path());
+        assert_eq!(cache.hash(&path)?, first);
+
+        let stale = Fingerprint {
+            value: "xxh3:stale".to_string(),
+            ..first.clone()
+        };
+        let mut cache = HashCache::load_hashes(workspace.path());
+        cache.entries = HashMap::from([(path.clone(), stale.clone())]);
+        assert_eq!(cache.hash(&path)?, stale);
+
+        fs::write(&path, b"second!")?;
+        assert_ne!(cache.hash(&path)?.value, first.value);
+        Ok(())
+    }
+
+    #[test]
+    fn caches_only_keep_the_files_seen_this_run() -> Result<()> {
+        let workspace = tempfile::tempdir()?;
+        let (kept, gone) = (workspace.path().join("kept"), workspace.path().join("gone"));
+        fs::write(&kept, b"kept")?;
+        fs::write(&gone, b"gone")?;
+        let cache = FingerprintCache::<u64>::load(workspace.path(), "lengths.json");
+        cache.get(&kept, |_| Ok(4))?;
+        cache.get(&gone, |_| Ok(4))?;
+        cache.save(workspace.path())?;
+
+        let cache = FingerprintCache::<u64>::load(workspace.path(), "lengths.json");
+        let fingerprint = cache.get(&kept, |_| panic!("unchanged files are not read again"))?;
+        assert_eq!(fingerprint.value, 4);
+        cache.save(workspace.path())?;
+        let cache = FingerprintCache::<u64>::load(workspace.path(), "lengths.json");
+        assert_eq!(cache.entries.keys().collect::<Vec<_>>(), [&kept]);
+        Ok(())
+    }
+
+    #[test]
+    fn hashing_writer_matches_the_file_hash() -> Result<()> {
+        let workspace = tempfile::tempdir()?;
+        let path = workspace.path().join("song.mp3");
+        let mut writer = HashingWriter::new(File::create(&path)?);
+        writer.write_all(b"decrypted audio")?;
+        writer.flush()?;
+        assert_eq!(writer.finish(), hash_file(&path)?);
+        Ok(())
+    }
+}
@@ -0,0 +1,220 @@
+use std::io::{self, Read, Seek, SeekFrom};
+
+use anyhow::{Context, Result, bail, ensure};
+use md5::{Digest, Md5};
+
+const KGM_MAGIC: [u8; 16] = [
+    0x7c, 0xd5, 0x32, 0xeb, 0x86, 0x02, 0x7f, 0x4b, 0xa8, 0xaf, 0xa6, 0x8e, 0x0f, 0xff, 0x99, 0x14,
+];
+const VPR_MAGIC: [u8; 16] = [
+    0x05, 0x28, 0xbc, 0x96, 0xe9, 0xe4, 0x5a, 0x43, 0x91, 0xaa, 0xbd, 0xd0, 0x7a, 0xf5, 0x36, 0x31,
+];
+/// Magic, audio offset, cipher version, key slot, test data and the file's own key.
+const HEADER_LEN: usize = 0x3c;
+/// Keys built into the Kugou player, by the slot number a file's header names.
+const SLOT_KEYS: [(u32, [u8; 4]); 1] = [(1, [0x6c, 0x2c, 0x2f, 0x27])];
+
+/// Decrypts the audio of a Kugou KGM or VPR file as it is read.
+///
+/// Both share one layout: a header holding a per-file key, then the audio XORed with a
+/// stream derived from that key, a key built into the player and the byte's position. Only
+/// cipher version 3 exists in the wild; the tables follow unlock-music's decoder.
+pub(crate) struct KgmReader<R> {
+    inner: R,
+    audio_offset: u64,
+    position: u64,
+    file_box: [u8; 17],
+    slot_box: [u8; 16],
+}
+
+impl<R: Read + Seek> KgmReader<R> {
+    /// Reads the header of `inner` and leaves it at the start of the audio.
+    pub(crate) fn new(mut inner: R) -> Result<Self> {
+        let mut header = [0_u8; HEADER_LEN];
+        inner
+            .read_exact(&mut header)
+            .context("the file is too short for a KGM header")?;
+        let magic = &header[..16];
+        ensure!(
+            magic == KGM_MAGIC || magic == VPR_MAGIC,
+            "the file does not start with a KGM or VPR header"
+        );
+        let word = |offset: usize| {
+            u32::from_le_bytes([
+                header[offset],
+                header[offset + 1],
+                header[offset + 2],
+                header[offset + 3],
+            ])
+        };
+        let (audio_offset, version, slot) = (word(0x10), word(0x14), word(0x18));
+        if version != 3 {
+            bail!("unsupported KGM cipher version {version}");
+        }
+        let slot_key = SLOT_KEYS
+            .iter()
+            .find(|(number, _)| *number == slot)
+            .map(|(_, key)| key)
+            .with_context(|| format!("unknown KGM key slot {slot}"))?;
+        ensure!(
+            audio_offset as usize >= HEADER_LEN,
+            "the KGM header claims its audio starts at byte {audio_offset}"
+        );
+        let mut file_box = [0x6b; 17];
+        file_box[..16].copy_from_slice(&kugou_md5(&header[0x2c..HEADER_LEN]));
+        let audio_offset = u64::from(audio_offset);
+        inner
+            .seek(SeekFrom::Start(audio_offset))
+            .context("failed to seek to the KGM audio")?;
+        Ok(Self {
+            inner,
+            audio_offset,
+            position: 0,
+            file_box,
+            slot_box: kugou_md5(slot_key),
+        })
+    }
+}
+
+impl<R> KgmReader<R> {
+    /// The keystream is symmetric up to the nibble mix, which undoes itself.
+    fn decrypt(&self, byte: u8, position: u64) -> u8 {
+        let mut byte = byte ^ self.file_box[(position % 17) as usize];
+        byte ^= byte << 4;
+        byte ^ self.slot_box[(position % 16) as usize] ^ collapse(position)
+    }
+
+    #[cfg(test)]
+    fn encrypt(&self, byte: u8, position: u64) -> u8 {
+        let mut byte = byte ^ self.slot_box[(position % 16) as usize] ^ collapse(position);
+        byte ^= byte << 4;
+        byte ^ self.file_box[(position % 17) as usize]
+    }
+}
+
+impl<R: Read> Read for KgmReader<R> {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buffer)?;
+        for (index, byte) in buffer[..read].iter_mut().enumerate() {
+            *byte = self.decrypt(*byte, self.position + index as u64);
+        }
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+impl<R: Seek> Seek for KgmReader<R> {
+    /// Seeks within the audio; offset 0 is its first byte, not the header's.
+    fn seek(&mut self, position: SeekFrom) -> io::Result<u64> {
+        let position = match position {
+            SeekFrom::Start(offset) => SeekFrom::Start(
+                self.audio_offset
+                    .checked_add(offset)
+                    .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidInput))?,
+            ),
+            relative => relative,
+        };
+        let absolute = self.inner.seek(position)?;
+        self.position = absolute.checked_sub(self.audio_offset).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "cannot seek into the KGM header",
+            )
+        })?;
+        Ok(self.position)
+    }
+}
+
+/// Kugou's MD5: the digest with its 2-byte pairs in reverse order.
+fn kugou_md5(data: &[u8]) -> [u8; 16] {
+    let digest = Md5::digest(data);
+    let mut key = [0_u8; 16];
+    for (pair, chunk) in key.chunks_exact_mut(2).zip(digest.chunks_exact(2).rev()) {
+        pair.copy_from_slice(chunk);
+    }
+    key
+}
+
+/// XOR of the four bytes of the position's low 32 bits.
+fn collapse(position: u64) -> u8 {
+    (position as u32)
+        .to_le_bytes()
+        .into_iter()
+        .fold(0, |folded, byte| folded ^ byte)
+}
+
+/// Builds a KGM file around `audio`, for tests that need an encrypted source.
+#[cfg(test)]
+pub(crate) fn encrypt(audio: &[u8], key: [u8; 16]) -> Result<Vec<u8>> {
+    let mut file = KGM_MAGIC.to_vec();
+    for word in [HEADER_LEN as u32, 3, 1] {
+        file.extend(word.to_le_bytes());
+    }
+    file.extend([0; 16]);
+    file.extend(key);
+    let reader = KgmReader::new(io::Cursor::new(file.clone()))?;
+    file.extend(
+        audio
+            .iter()
+            .enumerate()
+            .map(|(position, byte)| reader.encrypt(*byte, position as u64)),
+    );
+    Ok(file)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    #[test]
+    fn kgm_audio_decrypts_from_any_position() -> Result<()> {
+        let audio = (0..=255_u8).cycle().take(1000).collect::<Vec<_>>();
+        let file = encrypt(&audio, *b"0123456789abcdef")?;
+        assert_ne!(file[HEADER_LEN..], audio[..]);
+
+        let mut reader = KgmReader::new(Cursor::new(file))?;
+        let mut decrypted = Vec::new();
+        reader.read_to_end(&mut decrypted)?;
+        assert_eq!(decrypted, audio);
+
+        reader.seek(SeekFrom::Start(500))?;
+        let mut tail = [0_u8; 4];
+        reader.read_exact(&mut tail)?;
+        assert_eq!(tail[..], audio[500..504]);
+        assert!(reader.seek(SeekFrom::End(-2000)).is_err());
+
+        // VPR files differ from KGM only in their magic.
+        let mut file = encrypt(&audio, *b"0123456789abcdef")?;
+        file[..16].copy_from_slice(&VPR_MAGIC);
+        let mut decrypted = Vec::new();
+        KgmReader::new(Cursor::new(file))?.read_to_end(&mut decrypted)?;
+        assert_eq!(decrypted, audio);
+        Ok(())
+    }
+
+    #[test]
+    fn kgm_keystream_matches_its_reference_bytes() -> Result<()> {
+        // Computed independently from the published algorithm for this key and slot 1.
+        let file = encrypt(&[], *b"0123456789abcdef")?;
+        let reader = KgmReader::new(Cursor::new(file))?;
+        let stream = (0..8)
+            .map(|position| reader.decrypt(0, position))
+            .collect::<Vec<_>>();
+        assert_eq!(stream, [0xd8, 0x77, 0x05, 0xe6, 0xd1, 0xde, 0xf9, 0xc8]);
+        Ok(())
+    }
+
+    #[test]
+    fn unknown_headers_and_slots_are_refused() -> Result<()> {
+        assert!(KgmReader::new(Cursor::new(b"kugou".to_vec())).is_err());
+        let mut file = encrypt(&[], [0; 16])?;
+        file[0x18] = 2;
+        let error = KgmReader::new(Cursor::new(file))
+            .err()
+            .context("slot 2 was accepted")?;
+        assert!(error.to_string().contains("key slot 2"));
+        Ok(())
+    }
+}
@@ -0,0 +1,25 @@
+//! The w4dj sync engine: scans inputs for NCM, QMC, KGM and regular audio, decides what
+//! changed since the last run and writes a tagged library to the output directory.
+//!
+//! Build a [`Config`] with [`Config::from_paths`] and drive a run with [`run_with_progress`]
+//! or, to stop it from another thread, [`run_with_progress_cancellable`].
+
+pub mod cli;
+pub mod config;
+mod cover;
+pub mod doctor;
+pub mod dump;
+pub mod error;
+mod hashes;
+mod kgm;
+pub mod logging;
+pub mod probe;
+pub mod sync;
+pub mod watch;
+
+pub use config::{Compare, Config, Mode, SyncOptions};
+pub use error::{SyncError, SyncErrorKind};
+pub use sync::{
+    SyncAction, SyncActionKind, SyncEvent, SyncFailure, SyncSummary, run_with_progress,
+    run_with_progress_cancellable,
+};
@@ -0,0 +1,95 @@
+use std::io::Write;
+use std::sync::Mutex;
+
+use indicatif::MultiProgress;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+/// Command-line logger: summary lines go to stdout as plain text, everything else to
+/// stderr with a level prefix. While the progress bars are active, lines are printed above it
+/// instead of being drawn over it.
+struct CliLogger {
+    progress: Mutex<Option<MultiProgress>>,
+}
+
+static LOGGER: CliLogger = CliLogger {
+    progress: Mutex::new(None),
+};
+
+/// Installs the logger with the level selected by `-v` and `-q`.
+pub fn init(verbose: u8, quiet: u8) {
+    if log::set_logger(&LOGGER).is_ok() {
+        log::set_max_level(level_filter(verbose, quiet));
+    }
+}
+
+/// Info by default; each `-v` adds detail up to per-file decisions at trace, each `-q`
+/// removes it down to errors only.
+fn level_filter(verbose: u8, quiet: u8) -> LevelFilter {
+    match i16::from(verbose) - i16::from(quiet) {
+        ..=-2 => LevelFilter::Error,
+        -1 => LevelFilter::Warn,
+        0 => LevelFilter::Info,
+        1 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    }
+}
+
+/// Routes log lines through `progress` until it is cleared with `None`.
+pub fn set_progress(progress: Option<MultiProgress>) {
+    *LOGGER
+        .progress
+        .lock()
+        .unwrap_or_else(|error| error.into_inner()) = progress;
+}
+
+impl Log for CliLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        // Dependencies such as lofty log their own probing at debug level; `-v` is about
+        // w4dj's decisions, so detail below info is limited to this crate.
+        metadata.level() <= log::max_level()
+            && (metadata.level() <= Level::Info || metadata.target().starts_with("w4dj"))
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let line = match record.level() {
+            Level::Info => record.args().to_string(),
+            level => format!("{}: {}", level.as_str().to_ascii_lowercase(), record.args()),
+        };
+        let write = || {
+            if record.level() == Level::Info {
+                let _ = writeln!(std::io::stdout(), "{line}");
+            } else {
+                let _ = writeln!(std::io::stderr(), "{line}");
+            }
+        };
+        let progress = self
+            .progress
+            .lock()
+            .unwrap_or_else(|error| error.into_inner());
+        match progress.as_ref() {
+            Some(progress) => progress.suspend(write),
+            None => write(),
+        }
+    }
+
+    fn flush(&self) {
+        let _ = std::io::stdout().flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verbosity_flags_map_to_levels() {
+        assert_eq!(level_filter(0, 0), LevelFilter::Info);
+        assert_eq!(level_filter(0, 1), LevelFilter::Warn);
+        assert_eq!(level_filter(0, 3), LevelFilter::Error);
+        assert_eq!(level_filter(1, 0), LevelFilter::Debug);
+        assert_eq!(level_filter(2, 0), LevelFilter::Trace);
+    }
+}
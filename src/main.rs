@@ -1,15 +1,13 @@
-mod cli;
-mod config;
-mod doctor;
-mod dump;
 mod gui;
-mod sync;
 
-use anyhow::Result;
+use std::io::IsTerminal;
+
+use anyhow::{Result, bail};
 use clap::Parser;
 
-use crate::cli::{Cli, Command};
-use crate::config::Config;
+use w4dj::cli::{Cli, Command, FfmpegCommand};
+use w4dj::config::{self, Config};
+use w4dj::{doctor, logging, sync, watch};
 
 fn main() {
     if let Err(error) = run() {
@@ -25,19 +23,77 @@ fn run() -> Result<()> {
     }
 
     let mut cli = Cli::parse();
-    if let Some(Command::Doctor(args)) = cli.command.take() {
-        return doctor::run(args);
+    logging::init(cli.verbose, cli.quiet);
+    match cli.command.take() {
+        Some(Command::Init(args)) => {
+            if cli.config.len() > 1 {
+                bail!("init writes a single configuration file; pass --config once");
+            }
+            return config::init(cli.config.pop(), args.force);
+        }
+        Some(Command::Doctor(args)) => {
+            return doctor::run(args, config::Requirements::resolve(cli));
+        }
+        Some(Command::Ffmpeg(FfmpegCommand::Fetch(args))) => return doctor::fetch(args),
+        None => {}
+    }
+    let watch = cli.watch;
+    let banner = show_banner(cli.no_banner);
+    if cli.all {
+        return run_all_profiles(cli, banner);
     }
     let config = Config::resolve(cli)?;
 
-    println!("W4DJ");
-    println!("  inputs : {}", config.inputs.len());
-    println!("  output : {}", config.output.display());
-    println!("  profile: {}", config.mode.profile());
+    if banner {
+        print_banner(&config);
+    }
 
+    sync::install_interrupt_handler()?;
+    if watch {
+        return watch::run(&config);
+    }
     sync::run(&config)
 }
 
+/// Runs every `[[profiles]]` entry in turn; a failed profile does not stop the ones after it.
+fn run_all_profiles(cli: Cli, banner: bool) -> Result<()> {
+    let configs = Config::resolve_all(cli)?;
+    sync::install_interrupt_handler()?;
+    let mut failed = Vec::new();
+    for (name, config) in configs {
+        log::info!("Profile {name}");
+        if banner {
+            print_banner(&config);
+        }
+        if let Err(error) = sync::run(&config) {
+            log::error!("profile {name}: {error:#}");
+            failed.push(name);
+        }
+        if sync::stop_requested() {
+            break;
+        }
+    }
+    if !failed.is_empty() {
+        bail!("these profiles failed: {}", failed.join(", "));
+    }
+    Ok(())
+}
+
+fn print_banner(config: &Config) {
+    log::info!("W4DJ");
+    log::info!("  inputs : {}", config.inputs.len());
+    log::info!("  output : {}", config.output.display());
+    log::info!("  profile: {}", config.profile());
+}
+
+/// The header is only useful on an interactive terminal: it is skipped with `--no-banner`,
+/// when `W4DJ_NO_BANNER` is set to anything non-empty (like `NO_COLOR`), and when stdout
+/// is redirected to a file or a pipe.
+fn show_banner(no_banner: bool) -> bool {
+    let disabled_by_env = std::env::var_os("W4DJ_NO_BANNER").is_some_and(|value| !value.is_empty());
+    !no_banner && !disabled_by_env && std::io::stdout().is_terminal()
+}
+
 #[cfg(windows)]
 fn detach_console_for_gui() {
     #[link(name = "Kernel32")]
@@ -0,0 +1,172 @@
+//! Audio properties read with ffprobe, for decisions that tags and file sizes cannot answer.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Context, Result, bail};
+use serde::Deserialize;
+
+/// The first audio stream of a file as ffprobe reports it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AudioInfo {
+    pub duration: Option<Duration>,
+    /// Kilobits per second, like [`crate::dump::SourceVariant::bitrate`].
+    pub bitrate: Option<u64>,
+    pub codec: String,
+    pub sample_rate: Option<u32>,
+    pub channels: Option<u32>,
+}
+
+/// Remembers [`audio_info`] results by path and mtime, so each file is probed at most once
+/// per run even when several decisions ask for it.
+#[derive(Debug, Default)]
+pub struct AudioInfoCache {
+    entries: Mutex<HashMap<PathBuf, (SystemTime, AudioInfo)>>,
+}
+
+impl AudioInfoCache {
+    pub fn get(&self, ffprobe: &Path, path: &Path) -> Result<AudioInfo> {
+        let modified = fs::metadata(path)
+            .and_then(|metadata| metadata.modified())
+            .with_context(|| {
+                format!("failed to read the modification time of {}", path.display())
+            })?;
+        if let Some((stamp, info)) = self.entries.lock().unwrap().get(path)
+            && *stamp == modified
+        {
+            return Ok(info.clone());
+        }
+        let info = audio_info(ffprobe, path)?;
+        self.entries
+            .lock()
+            .unwrap()
+            .insert(path.to_path_buf(), (modified, info.clone()));
+        Ok(info)
+    }
+}
+
+/// Probes the first audio stream of `path`. Encrypted NCM and QMC files have to be
+/// decrypted first, since ffprobe cannot read them.
+pub fn audio_info(ffprobe: &Path, path: &Path) -> Result<AudioInfo> {
+    let output = Command::new(ffprobe)
+        .args(["-v", "error", "-select_streams", "a:0", "-show_entries"])
+        .arg("stream=codec_name,sample_rate,channels,bit_rate:format=duration,bit_rate")
+        .args(["-of", "json"])
+        .arg(path)
+        .stdin(Stdio::null())
+        .output()
+        .with_context(|| format!("failed to run ffprobe at {}", ffprobe.display()))?;
+    if !output.status.success() {
+        bail!(
+            "ffprobe could not read {}: {}",
+            path.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    parse_probe(&output.stdout).with_context(|| format!("failed to probe {}", path.display()))
+}
+
+#[derive(Deserialize)]
+struct Probe {
+    #[serde(default)]
+    streams: Vec<ProbeStream>,
+    #[serde(default)]
+    format: ProbeFormat,
+}
+
+#[derive(Deserialize)]
+struct ProbeStream {
+    codec_name: Option<String>,
+    sample_rate: Option<String>,
+    channels: Option<u32>,
+    bit_rate: Option<String>,
+}
+
+#[derive(Default, Deserialize)]
+struct ProbeFormat {
+    duration: Option<String>,
+    bit_rate: Option<String>,
+}
+
+fn parse_probe(json: &[u8]) -> Result<AudioInfo> {
+    let probe: Probe = serde_json::from_slice(json).context("ffprobe printed invalid JSON")?;
+    let Some(stream) = probe.streams.into_iter().next() else {
+        bail!("no audio stream");
+    };
+    // Containers such as FLAC only report the overall bitrate.
+    let bits_per_second = stream
+        .bit_rate
+        .or(probe.format.bit_rate)
+        .and_then(|value| value.parse::<u64>().ok());
+    Ok(AudioInfo {
+        duration: probe
+            .format
+            .duration
+            .and_then(|value| value.parse::<f64>().ok())
+            .filter(|seconds| seconds.is_finite() && *seconds >= 0.0)
+            .map(Duration::from_secs_f64),
+        bitrate: bits_per_second.map(|bits| (bits + 500) / 1000),
+        codec: stream.codec_name.unwrap_or_default(),
+        sample_rate: stream.sample_rate.and_then(|value| value.parse().ok()),
+        channels: stream.channels,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn probe_output_falls_back_to_the_container_bitrate() -> Result<()> {
+        let info = parse_probe(
+            br#"{
+                "programs": [],
+                "streams": [{"codec_name": "flac", "sample_rate": "44100", "channels": 2}],
+                "format": {"duration": "215.493878", "bit_rate": "912345"}
+            }"#,
+        )?;
+        assert_eq!(info.codec, "flac");
+        assert_eq!(info.bitrate, Some(912));
+        assert_eq!(info.sample_rate, Some(44_100));
+        assert_eq!(info.channels, Some(2));
+        assert_eq!(info.duration.map(|duration| duration.as_secs()), Some(215));
+        assert!(parse_probe(br#"{"streams": [], "format": {}}"#).is_err());
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn cached_files_are_probed_once_until_they_change() -> Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let workspace = tempfile::tempdir()?;
+        let song = workspace.path().join("Song.mp3");
+        fs::write(&song, b"ID3")?;
+        let calls = workspace.path().join("calls");
+        let ffprobe = workspace.path().join("ffprobe");
+        fs::write(
+            &ffprobe,
+            format!(
+                "#!/bin/sh\necho x >> '{}'\necho '{{\"streams\": [{{\"codec_name\": \"mp3\", \"bit_rate\": \"320000\"}}]}}'\n",
+                calls.display()
+            ),
+        )?;
+        fs::set_permissions(&ffprobe, fs::Permissions::from_mode(0o755))?;
+        let cache = AudioInfoCache::default();
+
+        assert_eq!(cache.get(&ffprobe, &song)?.bitrate, Some(320));
+        assert_eq!(cache.get(&ffprobe, &song)?.codec, "mp3");
+        assert_eq!(fs::read_to_string(&calls)?.lines().count(), 1);
+        fs::File::options()
+            .write(true)
+            .open(&song)?
+            .set_modified(SystemTime::now() + Duration::from_secs(60))?;
+        cache.get(&ffprobe, &song)?;
+        assert_eq!(fs::read_to_string(&calls)?.lines().count(), 2);
+        Ok(())
+    }
+}
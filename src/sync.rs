@@ -1,22 +1,68 @@
+use std::cell::{Cell, RefCell};
+use std::cmp::Reverse;
 use std::collections::{BTreeMap, HashMap, HashSet};
+use std::error::Error;
 use std::fs;
-use std::io::Write;
+use std::io::{self, IsTerminal, Write};
 use std::path::{Component, Path, PathBuf};
-use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::process;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, mpsc};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use anyhow::{Context, Result, bail};
-use indicatif::{ProgressBar, ProgressStyle};
+use clap::ValueEnum;
+use comfy_table::{Color, Table, presets};
+use directories::BaseDirs;
+use ignore::Match;
+use ignore::gitignore::Gitignore;
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use log::{debug, error, info, trace, warn};
 use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
 use tempfile::NamedTempFile;
+use unicode_normalization::UnicodeNormalization;
 use walkdir::{DirEntry, WalkDir};
+use xxhash_rust::xxh3::xxh3_64;
 
-use crate::config::Config;
+use crate::config::{
+    ByteSize, Compare, Config, DedupeKey, Link, MatchBy, Mode, ORGANIZE_FIELDS, PathFilter, Prefer,
+    SyncOptions,
+};
 use crate::doctor;
-use crate::dump::{self, Job, OutputIdentity, SourceItem, SourceVariant};
+use crate::dump::{
+    self, Job, JobAction, OutputDirectories, OutputIdentity, Processed, SourceItem, SourceVariant,
+    Staging, TranscodeSlots,
+};
+use crate::error::{SyncError, SyncErrorKind};
+use crate::hashes::{self, HashCache};
+use crate::logging;
 
 const MANIFEST_NAME: &str = ".w4dj-state.json";
 const MANIFEST_VERSION: u32 = 1;
+const LOCK_NAME: &str = ".w4dj.lock";
+/// Folder in the output where `keep_original` keeps untranscoded audio. It is never scanned
+/// as part of the output, so the originals cannot be mistaken for the songs they mirror; the
+/// reserved name keeps a user's own `originals` folder visible.
+pub(crate) const ORIGINALS_DIR: &str = ".w4dj-originals";
+/// Folder in the output that holds a `--staging` run's files until they are promoted.
+const STAGING_DIR: &str = ".w4dj-staging";
+/// Records which archive, at which size and modification time, an archive cache folder
+/// was extracted from.
+const ARCHIVE_STAMP_NAME: &str = ".w4dj-archive.json";
+const DEFAULT_WATCHDOG_SECS: u64 = 120;
+const RETRY_BACKOFF: Duration = Duration::from_millis(500);
+/// How often byte progress is published and the watchdog is checked.
+const MONITOR_INTERVAL: Duration = Duration::from_millis(250);
+const MAX_SANITIZED_NAME_CHARS: usize = 180;
+const IGNORE_FILE_NAME: &str = ".w4djignore";
+/// Rough disk throughput for copies and decryption, used to estimate dry runs.
+const ESTIMATED_COPY_BYTES_PER_SEC: f64 = 80.0 * 1024.0 * 1024.0;
+/// Rough FFmpeg time for one song, used to estimate dry runs.
+const ESTIMATED_TRANSCODE_SECS: f64 = 6.0;
+/// Files found between two scanning updates, so huge trees do not flood the listeners.
+const SCAN_REPORT_EVERY: usize = 100;
 
 #[derive(Debug, Deserialize, Serialize)]
 struct Manifest {
@@ -39,6 +85,32 @@ struct ManifestEntry {
     output: PathBuf,
     profile: String,
     source: SourceVariant,
+    /// Size and mtime of the output when it was last verified, so unchanged outputs are
+    /// trusted without reading their tags again.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    stamp: Option<OutputStamp>,
+    /// The untranscoded copy that `keep_original` saved with the output.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    original: Option<PathBuf>,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+struct OutputStamp {
+    size: u64,
+    modified_secs: u64,
+    modified_nanos: u32,
+}
+
+impl OutputStamp {
+    fn of(path: &Path) -> Option<Self> {
+        let metadata = fs::metadata(path).ok()?;
+        let modified = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+        Some(Self {
+            size: metadata.len(),
+            modified_secs: modified.as_secs(),
+            modified_nanos: modified.subsec_nanos(),
+        })
+    }
 }
 
 #[derive(Default)]
@@ -53,71 +125,842 @@ pub enum SyncEvent {
     Progress {
         completed: usize,
         total: usize,
+        /// Files that failed so far, included in `completed`.
+        failed: usize,
         current: Option<String>,
     },
+    /// A job that a dry run would have processed.
+    Planned {
+        source: PathBuf,
+        action: JobAction,
+        target: PathBuf,
+    },
+    /// An output whose source disappeared was deleted, or would be in a dry run.
+    Removed(PathBuf),
+    /// A file added to the output was copied into an input by `--two-way`, or would be in
+    /// a dry run.
+    Imported {
+        source: PathBuf,
+        target: PathBuf,
+    },
+    /// A single file has been processing for longer than the watchdog threshold.
+    StillWorking {
+        name: String,
+        elapsed: Duration,
+    },
+    /// Audio files found so far while walking the inputs and the output tree.
+    Scanning {
+        inputs: usize,
+        outputs: usize,
+    },
+    /// Planning is done and nothing has been written yet. Setting the cancel flag in
+    /// response ends the run here.
+    Plan(RunPlan),
+    /// Bytes of audio written so far out of the sources selected for this run.
+    Transferred {
+        bytes: u64,
+        total: u64,
+    },
     Finished(SyncSummary),
     Cancelled(SyncSummary),
 }
 
-#[derive(Clone, Debug, Default)]
+#[derive(Clone, Debug, Default, Serialize)]
 pub struct SyncSummary {
+    /// Audio files found in the inputs, including archive entries.
+    pub sources: usize,
+    /// Outputs tracked in the manifest once the run ended.
+    pub outputs: usize,
     pub processed: usize,
     pub skipped: usize,
     pub failed: usize,
-    pub errors: Vec<String>,
+    /// Files written to the staging folder of a `--staging` run that was not promoted.
+    pub staged: usize,
+    /// Files left for a later run because the byte budget was spent.
+    pub deferred: usize,
+    /// Files not started because the run was interrupted with Ctrl+C.
+    pub stopped: usize,
+    /// Sources found to be a copy of a better source by `dedupe_source`.
+    pub duplicates: usize,
+    /// Zero-byte sources, such as failed downloads, skipped with a warning.
+    pub empty: usize,
+    /// Files added directly to the output and copied into an input by `--two-way`.
+    pub imported: usize,
+    /// Songs that differ between the output and the inputs, left alone for `--prefer`.
+    pub conflicts: usize,
+    /// Outputs deleted because their source is gone.
+    pub removed: usize,
+    /// Sources deleted after syncing because of `--move`.
+    pub moved: usize,
+    /// NCM sources without embedded cover art, synced without one.
+    pub no_cover: Vec<PathBuf>,
+    /// Bytes of audio written to the output by this run.
+    pub bytes_written: u64,
+    /// Wall-clock time of the whole run.
+    pub elapsed_secs: f64,
+    /// Wall-clock time a dry run expects the real run to take over all workers.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub estimated_secs: Option<f64>,
+    /// Written files per kind of processing. A dry run counts the planned files, their
+    /// source bytes and an estimated busy time instead.
+    pub totals: BTreeMap<JobAction, ActionTotals>,
+    pub errors: Vec<SyncFailure>,
+    /// What happened to each song, in planning order.
+    pub actions: Vec<SyncAction>,
+}
+
+/// What a run is about to do, reported once before any file is written.
+#[derive(Clone, Debug)]
+pub struct RunPlan {
+    /// Files to write per kind of processing.
+    pub files: BTreeMap<JobAction, usize>,
+    /// Total size of the sources to process.
+    pub bytes: u64,
+    pub mode: Mode,
+    pub workers: usize,
+    pub ffmpeg: Option<PathBuf>,
+    /// Whether outputs whose source is gone will be deleted.
+    pub delete: bool,
+    /// Whether sources will be deleted once their output is written.
+    pub move_sources: bool,
+    /// How `--two-way` resolves conflicts, each side of which deletes a file.
+    pub two_way_prefer: Option<Prefer>,
+}
+
+impl RunPlan {
+    /// Whether the run deletes files, so the command line asks before starting it.
+    pub fn destructive(&self) -> bool {
+        self.delete || self.move_sources || self.two_way_prefer.is_some()
+    }
+
+    pub fn describe(&self) -> String {
+        let files = if self.files.is_empty() {
+            "nothing to write".to_string()
+        } else {
+            let parts = self
+                .files
+                .iter()
+                .map(|(action, files)| format!("{files} {action}"))
+                .collect::<Vec<_>>();
+            format!("{} ({})", parts.join(", "), ByteSize(self.bytes))
+        };
+        let mode = self
+            .mode
+            .to_possible_value()
+            .map(|value| value.get_name().to_string())
+            .unwrap_or_default();
+        let ffmpeg = match &self.ffmpeg {
+            Some(path) => format!("FFmpeg at {}", path.display()),
+            None => "no FFmpeg".to_string(),
+        };
+        let mut plan = format!(
+            "Plan: {files}; {mode} mode, {} workers, {ffmpeg}",
+            self.workers
+        );
+        if self.delete {
+            plan.push_str("; deletes outputs whose source is gone");
+        }
+        if self.move_sources {
+            plan.push_str("; deletes sources once they are written");
+        }
+        if let Some(prefer) = self.two_way_prefer {
+            plan.push_str(match prefer {
+                Prefer::Source => "; deletes conflicting files added to the output",
+                Prefer::Dest => "; replaces conflicting inputs with files from the output",
+                Prefer::Newer => "; replaces the older side of each conflict",
+            });
+        }
+        plan.push('.');
+        plan
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct ActionTotals {
+    pub files: usize,
+    pub bytes: u64,
+    /// Time spent on these files summed over all workers, so it can exceed the run time.
+    pub busy_secs: f64,
+}
+
+/// What a run did with one song, named in the JSON report like [`JobAction`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SyncActionKind {
+    Skip,
+    Delete,
+    /// `--two-way` copied a file added to the output into an input.
+    Import,
+    /// `--two-way` found an input that differs from a file added to the output.
+    Conflict,
+    #[serde(untagged)]
+    Write(JobAction),
+}
+
+impl std::fmt::Display for SyncActionKind {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Skip => formatter.write_str("skip"),
+            Self::Delete => formatter.write_str("delete"),
+            Self::Import => formatter.write_str("import"),
+            Self::Conflict => formatter.write_str("conflict"),
+            Self::Write(action) => action.fmt(formatter),
+        }
+    }
+}
+
+/// A file the run could not sync.
+#[derive(Clone, Debug, Serialize)]
+pub struct SyncFailure {
+    pub path: PathBuf,
+    pub kind: SyncErrorKind,
+    /// The error followed by its causes.
+    pub message: String,
+}
+
+impl SyncFailure {
+    fn new(path: &Path, error: &SyncError) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            kind: error.kind(),
+            message: describe(error),
+        }
+    }
+
+    /// A failure found before any job ran, such as a source that cannot be read.
+    fn inspection(path: &Path, error: anyhow::Error) -> Self {
+        let message = format!("{error:#}");
+        Self {
+            path: path.to_path_buf(),
+            kind: SyncError::from(error).kind(),
+            message,
+        }
+    }
+}
+
+impl std::fmt::Display for SyncFailure {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(formatter, "{}: {}", self.path.display(), self.message)
+    }
+}
+
+/// One line of the JSON run report.
+#[derive(Clone, Debug, Serialize)]
+pub struct SyncAction {
+    pub id: String,
+    pub action: SyncActionKind,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source: Option<PathBuf>,
+    pub target: PathBuf,
+    /// Size of the source when it was planned.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source_size: Option<u64>,
+    /// Size of the target when the job finished, or of the output it kept or deleted.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub output_size: Option<u64>,
+    /// Why a source was skipped.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<SyncFailure>,
+}
+
+#[derive(Serialize)]
+struct SyncReport<'a> {
+    dry_run: bool,
+    cancelled: bool,
+    #[serde(flatten)]
+    summary: &'a SyncSummary,
+}
+
+/// Caps the bytes a run may write; workers reserve a file's estimated output size before
+/// starting it, and a file that does not fit leaves room for smaller ones.
+struct ByteBudget {
+    limit: u64,
+    used: AtomicU64,
+}
+
+impl ByteBudget {
+    fn new(limit: u64) -> Self {
+        Self {
+            limit,
+            used: AtomicU64::new(0),
+        }
+    }
+
+    fn try_reserve(&self, bytes: u64) -> bool {
+        self.used
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+                used.checked_add(bytes).filter(|total| *total <= self.limit)
+            })
+            .is_ok()
+    }
+
+    fn settle(&self, reserved: u64, written: u64) {
+        if written >= reserved {
+            self.used.fetch_add(written - reserved, Ordering::Relaxed);
+        } else {
+            self.used.fetch_sub(reserved - written, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Spreads the writes of all workers over time so they stay under `max_mbps`. Each chunk
+/// books the next free slot on a shared schedule and waits for it to start.
+struct Throttle {
+    bytes_per_sec: f64,
+    next_free: Mutex<Instant>,
+}
+
+impl Throttle {
+    fn from_mbps(mbps: f64) -> Self {
+        Self {
+            bytes_per_sec: mbps * 1_000_000.0 / 8.0,
+            next_free: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Waits until `bytes` may be written, or until the run is cancelled.
+    fn wait(&self, bytes: u64, cancel: &AtomicBool) {
+        let start = {
+            let mut next_free = self.next_free.lock().unwrap();
+            let start = (*next_free).max(Instant::now());
+            *next_free = start + Duration::from_secs_f64(bytes as f64 / self.bytes_per_sec);
+            start
+        };
+        while !cancel.load(Ordering::Relaxed) {
+            let remaining = start.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                break;
+            }
+            thread::sleep(remaining.min(Duration::from_millis(100)));
+        }
+    }
+}
+
+/// Exclusive lock on an output directory, held until the run ends.
+///
+/// The operating system releases the lock when its process exits, so a lock file left
+/// behind by a crashed run is taken over directly; the PID inside only names the holder.
+struct RunLock {
+    _file: fs::File,
+}
+
+impl RunLock {
+    fn acquire(
+        output: &Path,
+        wait: bool,
+        cancel: &AtomicBool,
+        report: &impl Fn(SyncEvent),
+    ) -> Result<Self> {
+        let path = output.join(LOCK_NAME);
+        let mut file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .with_context(|| format!("failed to open lock file {}", path.display()))?;
+        let mut waiting = false;
+        loop {
+            match file.try_lock() {
+                Ok(()) => break,
+                Err(fs::TryLockError::WouldBlock) => {
+                    let holder = fs::read_to_string(&path)
+                        .ok()
+                        .and_then(|pid| pid.trim().parse::<u32>().ok())
+                        .map_or_else(String::new, |pid| format!(" (PID {pid})"));
+                    if !wait {
+                        bail!(
+                            "another w4dj run{holder} is syncing to {}; use --wait to start after it",
+                            output.display()
+                        );
+                    }
+                    if !waiting {
+                        report(SyncEvent::Status(format!(
+                            "Waiting for another w4dj run{holder} to finish..."
+                        )));
+                        waiting = true;
+                    }
+                    dump::ensure_not_cancelled(cancel)?;
+                    thread::sleep(Duration::from_millis(250));
+                }
+                Err(fs::TryLockError::Error(error)) => {
+                    return Err(error)
+                        .with_context(|| format!("failed to lock {}", path.display()));
+                }
+            }
+        }
+        file.set_len(0)
+            .and_then(|()| write!(file, "{}", process::id()))
+            .and_then(|()| file.flush())
+            .with_context(|| format!("failed to record the PID in {}", path.display()))?;
+        Ok(Self { _file: file })
+    }
+}
+
+/// Remembers when each in-flight file started so slow files can be reported as alive.
+struct Watchdog {
+    threshold: Duration,
+    active: Mutex<HashMap<usize, ActiveFile>>,
+}
+
+struct ActiveFile {
+    name: String,
+    started: Instant,
+    last_notice: Instant,
+}
+
+struct WatchdogGuard<'a> {
+    watchdog: &'a Watchdog,
+    index: usize,
+}
+
+impl Watchdog {
+    fn new(threshold: Duration) -> Self {
+        Self {
+            threshold,
+            active: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn track(&self, index: usize, name: &str) -> WatchdogGuard<'_> {
+        let now = Instant::now();
+        self.active
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .insert(
+                index,
+                ActiveFile {
+                    name: name.to_string(),
+                    started: now,
+                    last_notice: now,
+                },
+            );
+        WatchdogGuard {
+            watchdog: self,
+            index,
+        }
+    }
+
+    /// Returns files that passed another threshold interval since their last notice.
+    fn overdue(&self, now: Instant) -> Vec<(String, Duration)> {
+        let mut active = self
+            .active
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let mut overdue = active
+            .values_mut()
+            .filter(|file| now.saturating_duration_since(file.last_notice) >= self.threshold)
+            .map(|file| {
+                file.last_notice = now;
+                (
+                    file.name.clone(),
+                    now.saturating_duration_since(file.started),
+                )
+            })
+            .collect::<Vec<_>>();
+        overdue.sort();
+        overdue
+    }
+}
+
+impl Drop for WatchdogGuard<'_> {
+    fn drop(&mut self) {
+        self.watchdog
+            .active
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .remove(&self.index);
+    }
+}
+
+/// Formats a run time as `4m12s`, `1h03m` or `8.4s`.
+fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    if seconds >= 3600 {
+        format!("{}h{:02}m", seconds / 3600, seconds % 3600 / 60)
+    } else if seconds >= 60 {
+        format!("{}m{:02}s", seconds / 60, seconds % 60)
+    } else {
+        format!("{:.1}s", duration.as_secs_f64())
+    }
+}
+
+/// A per-file guess at how long a job keeps one worker busy: transcodes cost a roughly
+/// fixed time per song, copies and decryption are bound by disk throughput.
+fn estimated_busy_secs(action: JobAction, bytes: u64) -> f64 {
+    let copy = bytes as f64 / ESTIMATED_COPY_BYTES_PER_SEC;
+    match action {
+        JobAction::Copy | JobAction::Decrypt => copy,
+        JobAction::Transcode | JobAction::DecryptAndTranscode => ESTIMATED_TRANSCODE_SECS + copy,
+    }
+}
+
+/// `Plan: 210 copy (18.0 GiB), 95 transcode (3.1 GiB), about 31m00s`
+fn format_plan(summary: &SyncSummary) -> String {
+    let parts = summary
+        .totals
+        .iter()
+        .map(|(action, totals)| format!("{} {action} ({})", totals.files, ByteSize(totals.bytes)))
+        .collect::<Vec<_>>();
+    let estimate = Duration::from_secs_f64(summary.estimated_secs.unwrap_or(0.0));
+    format!(
+        "Plan: {}, about {} estimated.",
+        parts.join(", "),
+        format_duration(estimate)
+    )
+}
+
+/// One row per file of a finished run: failures first, then written and deleted files,
+/// then skipped ones when `list_skipped` is set, each group sorted by name.
+fn results_table(actions: &[SyncAction], list_skipped: bool) -> Table {
+    let mut rows = actions
+        .iter()
+        .filter_map(|action| {
+            let (rank, status, color) = if action.error.is_some() {
+                (0, "failed", Color::Red)
+            } else if matches!(
+                action.action,
+                SyncActionKind::Skip | SyncActionKind::Conflict
+            ) {
+                if !list_skipped {
+                    return None;
+                }
+                (2, "skipped", Color::DarkGrey)
+            } else {
+                (1, "ok", Color::Green)
+            };
+            let name = action
+                .source
+                .as_deref()
+                .unwrap_or(&action.target)
+                .file_stem()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .into_owned();
+            let size = |size: Option<u64>| {
+                size.map_or_else(|| "-".to_string(), |size| ByteSize(size).to_string())
+            };
+            let sizes = format!(
+                "{} → {}",
+                size(action.source_size),
+                size(action.output_size)
+            );
+            Some((rank, name, action.action.to_string(), sizes, status, color))
+        })
+        .collect::<Vec<_>>();
+    rows.sort_by(|left, right| {
+        (left.0, left.1.to_lowercase()).cmp(&(right.0, right.1.to_lowercase()))
+    });
+
+    let mut table = Table::new();
+    table
+        .load_style(presets::UTF8_FULL_CONDENSED)
+        .set_header(["File", "Action", "Size", "Status"]);
+    for (_, name, action, sizes, status, color) in rows {
+        table.add_row([
+            comfy_table::Cell::new(name),
+            comfy_table::Cell::new(action),
+            comfy_table::Cell::new(sizes),
+            comfy_table::Cell::new(status).fg(color),
+        ]);
+    }
+    table
+}
+
+/// Asks a yes/no question on the terminal; anything but `y` or `yes` declines.
+fn confirm(question: &str) -> bool {
+    print!("{question}");
+    let _ = io::stdout().flush();
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer).is_ok()
+        && matches!(answer.trim().to_ascii_lowercase().as_str(), "y" | "yes")
+}
+
+/// Raised by the first Ctrl+C: files in progress finish, nothing new starts and the rest
+/// is left for the next run.
+static STOP_REQUESTED: AtomicBool = AtomicBool::new(false);
+/// Whether [`run`] is syncing, so a Ctrl+C while idle can exit straight away.
+static RUNNING: AtomicBool = AtomicBool::new(false);
+
+/// Installs the command line's Ctrl+C handling: the first press stops the sync after the
+/// files in progress, so no output is left half-written; a second press exits at once.
+pub fn install_interrupt_handler() -> Result<()> {
+    ctrlc::set_handler(|| {
+        if STOP_REQUESTED.swap(true, Ordering::SeqCst) || !RUNNING.load(Ordering::SeqCst) {
+            process::exit(130);
+        }
+        warn!("Stopping after the files in progress; press Ctrl+C again to quit now.");
+    })
+    .context("failed to install the Ctrl+C handler")
+}
+
+/// Whether Ctrl+C stopped a run, so callers like `--watch` end instead of continuing.
+pub fn stop_requested() -> bool {
+    STOP_REQUESTED.load(Ordering::SeqCst)
 }
 
 pub fn run(config: &Config) -> Result<()> {
-    let bar = ProgressBar::new(0);
-    bar.set_style(
+    RUNNING.store(true, Ordering::SeqCst);
+    let result = run_with_bars(config);
+    RUNNING.store(false, Ordering::SeqCst);
+    result
+}
+
+fn run_with_bars(config: &Config) -> Result<()> {
+    let progress = if log::log_enabled!(log::Level::Info) {
+        MultiProgress::new()
+    } else {
+        MultiProgress::with_draw_target(ProgressDrawTarget::hidden())
+    };
+    let bar = progress.add(ProgressBar::new(0));
+    bar.set_style(config.options.progress_style()?);
+    // Files only tick the bar above when they finish; this one moves while a large file
+    // is still being copied.
+    let bytes_bar = progress.add(ProgressBar::new(0));
+    bytes_bar.set_style(
         ProgressStyle::with_template(
-            "{spinner:.green} [{elapsed_precise}] [{bar:36.cyan/blue}] {pos}/{len} {msg}",
+            "               [{bar:36.green/white}] {binary_bytes}/{binary_total_bytes} ({binary_bytes_per_sec})",
         )
         .expect("valid progress template"),
     );
+    // Walking a huge tree takes a while before the bar has anything to count, so a spinner
+    // above it shows how many files were found until the walk is done.
+    let spinner = ProgressBar::new_spinner();
+    let scanning = AtomicBool::new(false);
+    logging::set_progress(Some(progress.clone()));
 
-    run_with_progress(config, |event| match event {
-        SyncEvent::Status(status) => bar.set_message(status),
+    let dry_run = config.options.dry_run;
+    let cancel = AtomicBool::new(false);
+    let finish_scan = || {
+        if scanning.swap(false, Ordering::Relaxed) {
+            spinner.finish_and_clear();
+        }
+    };
+    let result = run_until(config, &cancel, &STOP_REQUESTED, |event| match event {
+        SyncEvent::Status(status) => {
+            finish_scan();
+            debug!("{status}");
+            bar.set_message(status);
+        }
+        SyncEvent::Scanning { inputs, outputs } => {
+            if !scanning.swap(true, Ordering::Relaxed) {
+                progress.insert(0, spinner.clone());
+                spinner.enable_steady_tick(Duration::from_millis(100));
+            }
+            if outputs == 0 {
+                spinner.set_message(format!("Scanning: {inputs} input files found"));
+            } else {
+                spinner.set_message(format!(
+                    "Scanning: {inputs} input files, {outputs} output files found"
+                ));
+            }
+        }
         SyncEvent::Progress {
             completed,
             total,
+            failed,
             current,
         } => {
+            finish_scan();
             bar.set_length(total as u64);
             bar.set_position(completed as u64);
             if let Some(current) = current {
-                bar.set_message(current);
+                if failed == 0 {
+                    bar.set_message(current);
+                } else {
+                    bar.set_message(format!("{current} ({failed} failed)"));
+                }
+            }
+        }
+        SyncEvent::Planned {
+            source,
+            action,
+            target,
+        } => {
+            info!("{action:<17} {} -> {}", source.display(), target.display());
+        }
+        SyncEvent::Removed(path) => {
+            let verb = if dry_run { "would delete" } else { "delete" };
+            info!("{verb:<17} {}", path.display());
+        }
+        SyncEvent::Imported { source, target } => {
+            let verb = if dry_run { "would import" } else { "import" };
+            info!("{verb:<17} {} -> {}", source.display(), target.display());
+        }
+        SyncEvent::StillWorking { name, elapsed } => {
+            warn!(
+                "still working on {name} ({} elapsed)",
+                format_duration(elapsed)
+            );
+        }
+        SyncEvent::Plan(plan) => {
+            finish_scan();
+            info!("{}", plan.describe());
+            let interactive = io::stdin().is_terminal() && io::stdout().is_terminal();
+            if plan.destructive()
+                && !config.options.yes
+                && interactive
+                && !progress.suspend(|| confirm("Continue? [y/N] "))
+            {
+                info!("Nothing was written.");
+                // Like Ctrl+C, so `--watch` ends instead of asking again on the next change.
+                STOP_REQUESTED.store(true, Ordering::SeqCst);
+                cancel.store(true, Ordering::SeqCst);
             }
         }
+        SyncEvent::Transferred { bytes, total } => {
+            bytes_bar.set_length(total);
+            bytes_bar.set_position(bytes);
+        }
         SyncEvent::Finished(summary) => {
-            if summary.failed == 0 {
+            finish_scan();
+            bytes_bar.finish_and_clear();
+            if summary.stopped > 0 {
+                bar.abandon_with_message("sync interrupted");
+            } else if summary.failed == 0 {
                 bar.finish_and_clear();
             } else {
-                bar.abandon_with_message("some files failed");
+                bar.abandon_with_message(format!(
+                    "{} succeeded, {} failed",
+                    summary.processed, summary.failed
+                ));
             }
-            println!(
-                "Sync complete: {} processed, {} skipped, {} failed.",
-                summary.processed, summary.skipped, summary.failed
-            );
-            for error in summary.errors {
-                eprintln!("  {error}");
+            if !dry_run
+                && summary
+                    .actions
+                    .iter()
+                    .any(|action| action.action != SyncActionKind::Skip)
+            {
+                let list_skipped = log::log_enabled!(log::Level::Debug);
+                let mut table = results_table(&summary.actions, list_skipped);
+                if !io::stdout().is_terminal() {
+                    table.load_style(presets::NOTHING).force_no_tty();
+                }
+                info!("{table}");
+                if !list_skipped && summary.skipped > 0 {
+                    info!(
+                        "{} skipped files are not listed; pass -v to list them.",
+                        summary.skipped
+                    );
+                }
+            }
+            if dry_run {
+                info!(
+                    "Dry run: {} would be processed, {} skipped; nothing was written.",
+                    summary.processed, summary.skipped
+                );
+                if !summary.totals.is_empty() {
+                    info!("{}", format_plan(&summary));
+                }
+            } else {
+                info!(
+                    "Sync complete: {} processed, {} skipped, {} failed.",
+                    summary.processed, summary.skipped, summary.failed
+                );
+            }
+            if summary.removed > 0 {
+                let verb = if dry_run { "Would delete" } else { "Deleted" };
+                info!("{verb} {} outputs whose source is gone.", summary.removed);
+            }
+            if !dry_run && summary.processed > 0 {
+                let elapsed = Duration::from_secs_f64(summary.elapsed_secs);
+                let rate = summary.bytes_written as f64 / summary.elapsed_secs.max(0.001);
+                info!(
+                    "Synced {} files, {}, in {} ({}/s).",
+                    summary.processed,
+                    ByteSize(summary.bytes_written),
+                    format_duration(elapsed),
+                    ByteSize(rate as u64)
+                );
+                for (action, totals) in &summary.totals {
+                    info!(
+                        "  {action:<17} {} files, {}, {} busy",
+                        totals.files,
+                        ByteSize(totals.bytes),
+                        format_duration(Duration::from_secs_f64(totals.busy_secs))
+                    );
+                }
+            }
+            if summary.moved > 0 {
+                info!("Removed {} sources after syncing them.", summary.moved);
+            }
+            if summary.imported > 0 {
+                let verb = if dry_run { "Would copy" } else { "Copied" };
+                info!(
+                    "{verb} {} files added on the output back into the inputs.",
+                    summary.imported
+                );
+            }
+            if summary.conflicts > 0 {
+                warn!(
+                    "{} songs differ between the output and the inputs; pass --prefer to resolve them.",
+                    summary.conflicts
+                );
+            }
+            if summary.duplicates > 0 {
+                info!(
+                    "Found {} duplicate sources; see the warnings above.",
+                    summary.duplicates
+                );
+            }
+            if !summary.no_cover.is_empty() {
+                for path in &summary.no_cover {
+                    debug!("No embedded cover art: {}", path.display());
+                }
+                info!(
+                    "{} tracks had no embedded cover art.",
+                    summary.no_cover.len()
+                );
+            }
+            if summary.empty > 0 {
+                warn!(
+                    "Skipped {} empty source files; see the warnings above.",
+                    summary.empty
+                );
+            }
+            if summary.staged > 0 {
+                info!(
+                    "Staged {} files; the output is unchanged until a run completes.",
+                    summary.staged
+                );
+            }
+            if summary.deferred > 0 {
+                info!(
+                    "Stopped at budget: {} files left for the next run.",
+                    summary.deferred
+                );
+            }
+            if summary.stopped > 0 {
+                info!(
+                    "Interrupted: {} files left for the next run.",
+                    summary.stopped
+                );
+            }
+            for failure in summary.errors {
+                error!("{failure}");
             }
         }
         SyncEvent::Cancelled(summary) => {
+            finish_scan();
+            bytes_bar.finish_and_clear();
             bar.abandon_with_message("sync cancelled");
-            println!(
+            info!(
                 "Sync cancelled: {} processed, {} skipped, {} failed.",
                 summary.processed, summary.skipped, summary.failed
             );
         }
-    })
-    .map(|_| ())
+    });
+    logging::set_progress(None);
+    result?;
+    Ok(())
 }
 
 pub fn run_with_progress(
     config: &Config,
     report: impl Fn(SyncEvent) + Sync,
-) -> Result<SyncSummary> {
+) -> Result<SyncSummary, SyncError> {
     let cancel = AtomicBool::new(false);
     run_with_progress_cancellable(config, &cancel, report)
 }
@@ -126,9 +969,20 @@ pub fn run_with_progress_cancellable(
     config: &Config,
     cancel: &AtomicBool,
     report: impl Fn(SyncEvent) + Sync,
-) -> Result<SyncSummary> {
-    match run_with_progress_inner(config, cancel, &report) {
-        Err(error) if dump::is_cancelled(&error) => {
+) -> Result<SyncSummary, SyncError> {
+    run_until(config, cancel, &AtomicBool::new(false), report)
+}
+
+/// Runs a sync that aborts work in progress once `cancel` is set and starts no new files
+/// once `stop` is set.
+fn run_until(
+    config: &Config,
+    cancel: &AtomicBool,
+    stop: &AtomicBool,
+    report: impl Fn(SyncEvent) + Sync,
+) -> Result<SyncSummary, SyncError> {
+    match run_with_progress_inner(config, cancel, stop, &report).map_err(SyncError::from) {
+        Err(SyncError::Cancelled) => {
             let summary = SyncSummary::default();
             report(SyncEvent::Cancelled(summary.clone()));
             Ok(summary)
@@ -140,14 +994,141 @@ pub fn run_with_progress_cancellable(
 fn run_with_progress_inner(
     config: &Config,
     cancel: &AtomicBool,
+    stop: &AtomicBool,
     report: &(impl Fn(SyncEvent) + Sync),
 ) -> Result<SyncSummary> {
+    let run_started = Instant::now();
     let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(config.options.jobs.unwrap_or(0))
         .build()
         .context("failed to create the worker pool")?;
 
+    // Locate FFmpeg before touching the output so a missing encoder fails the run cleanly.
+    let ffmpeg = if config.needs_ffmpeg() {
+        Some(
+            doctor::find_ffmpeg(config.options.ffmpeg_path.as_deref()).context(
+                "FFmpeg was not found next to w4dj or in PATH; it is required by the selected \
+                 output mode, verify_flac and replaygain (run `w4dj doctor` or set ffmpeg_path)",
+            )?,
+        )
+    } else {
+        None
+    };
+    let transcode_slots = config
+        .options
+        .max_transcodes
+        .map(|limit| Arc::new(TranscodeSlots::new(limit)));
+    let output_dirs = Arc::new(OutputDirectories::default());
+    let dry_run = config.options.dry_run;
+    let _lock = if dry_run {
+        None
+    } else {
+        Some(RunLock::acquire(
+            &config.output,
+            config.options.wait_for_lock.unwrap_or(false),
+            cancel,
+            report,
+        )?)
+    };
+    let staging = if config.options.staging && !dry_run {
+        let staging = Staging::new(&config.output, config.output.join(STAGING_DIR));
+        // Held by the run lock, so anything there was left by an earlier failed run. A
+        // promotion that stopped halfway holds files the output is missing, so it is kept.
+        if staging.interrupted() {
+            bail!(
+                "an earlier run stopped while moving staged files into place; move the files in {} into the output or delete the folder, then run again",
+                staging.root().display()
+            );
+        }
+        if staging.root().exists() {
+            warn!(
+                "discarding staged files left by an earlier run in {}",
+                staging.root().display()
+            );
+            fs::remove_dir_all(staging.root()).with_context(|| {
+                format!(
+                    "failed to clear staging folder {}",
+                    staging.root().display()
+                )
+            })?;
+        }
+        Some(Arc::new(staging))
+    } else {
+        None
+    };
+    let compare = config.options.compare.unwrap_or_default();
+    let match_by = config.options.match_by.unwrap_or_default();
+    let size_tolerance = config.options.size_tolerance();
     dump::ensure_not_cancelled(cancel)?;
-    let source_paths = scan_inputs(&config.inputs, &config.output, cancel)?;
+    let filter = PathFilter::new(&config.options)?;
+    let manifest_path = config.output.join(MANIFEST_NAME);
+    let manifest = load_manifest(&manifest_path)?;
+    let manifest_was_empty = manifest.entries.is_empty();
+    let found_inputs = AtomicUsize::new(0);
+    let found_outputs = AtomicUsize::new(0);
+    let report_found = |counter: &AtomicUsize, count: usize| {
+        counter.store(count, Ordering::Relaxed);
+        if count.is_multiple_of(SCAN_REPORT_EVERY) {
+            report(SyncEvent::Scanning {
+                inputs: found_inputs.load(Ordering::Relaxed),
+                outputs: found_outputs.load(Ordering::Relaxed),
+            });
+        }
+    };
+    let found_input = |count| report_found(&found_inputs, count);
+    let found_output = |count| report_found(&found_outputs, count);
+    // Without a manifest every source has to be looked up in the output tree, so that walk
+    // runs alongside the input scan instead of after it; both are slow on network drives.
+    let (scanned_inputs, output_paths) = pool.join(
+        || {
+            scan_inputs(
+                &config.inputs,
+                &config.output,
+                &filter,
+                &config.options,
+                cancel,
+                &found_input,
+            )
+        },
+        || {
+            (manifest_was_empty && config.output.exists())
+                .then(|| walk_output(&config.output, cancel, &found_output))
+        },
+    );
+    let (archives, mut source_paths) = scanned_inputs?
+        .into_iter()
+        .partition::<Vec<_>, _>(|path| is_archive(path));
+    let mut inspection_errors = Vec::new();
+    // Archive entries are kept between runs, so an unchanged archive is not extracted again.
+    let archive_cache = if archives.is_empty() {
+        None
+    } else {
+        Some(archive_cache_root(&config.options)?)
+    };
+    if let Some(cache) = &archive_cache {
+        report(SyncEvent::Status(format!(
+            "Extracting audio from {} archives...",
+            archives.len()
+        )));
+        prune_archive_cache(cache);
+        for archive in &archives {
+            // Entries of an archive found in an input folder are filtered by their path
+            // below that folder, as if the archive were a folder itself.
+            let relative = input_root(&config.inputs, archive)
+                .filter(|root| *root != archive)
+                .and_then(|root| archive.strip_prefix(root).ok());
+            let allows = |entry: &Path| {
+                filter.allows_extension(entry)
+                    && relative.is_none_or(|relative| filter.allows(&relative.join(entry)))
+            };
+            match extract_archive(archive, cache, &allows, cancel) {
+                Ok(paths) => source_paths.extend(paths),
+                Err(error) if dump::is_cancelled(&error) => return Err(error),
+                Err(error) => inspection_errors.push(SyncFailure::inspection(archive, error)),
+            }
+        }
+    }
+    let scanned = source_paths.len();
     report(SyncEvent::Status(format!(
         "Scanning metadata for {} input files...",
         source_paths.len()
@@ -159,33 +1140,133 @@ fn run_with_progress_inner(
                 if cancel.load(Ordering::Relaxed) {
                     None
                 } else {
-                    Some((path, dump::inspect_source(path)))
+                    let inspected = dump::inspect_source(path, compare).map(|mut source| {
+                        if let Some(key) =
+                            match_key(match_by, input_root(&config.inputs, path), path)
+                        {
+                            if source.id == source.fallback_id {
+                                source.id = key.clone();
+                            }
+                            source.fallback_id = key;
+                        }
+                        source
+                    });
+                    Some((path, inspected))
                 }
             })
             .collect::<Vec<_>>()
     });
     dump::ensure_not_cancelled(cancel)?;
 
-    let mut inspection_errors = Vec::new();
     let mut sources = BTreeMap::<String, SourceItem>::new();
+    let mut empty = 0_usize;
     for (path, result) in inspections {
         match result {
-            Ok(source) => select_best_source(&mut sources, source),
-            Err(error) => inspection_errors.push(format!("{}: {error:#}", path.display())),
+            Ok(source) => {
+                let previous = sources
+                    .get(&source.id)
+                    .filter(|current| {
+                        input_root(&config.inputs, &current.path)
+                            != input_root(&config.inputs, &source.path)
+                    })
+                    .map(|current| current.path.clone());
+                let id = source.id.clone();
+                let path = source.path.clone();
+                let dropped = select_best_source(&mut sources, source, compare, size_tolerance);
+                if let Some(dropped) = dropped {
+                    explain_skip(
+                        &config.options,
+                        &dropped.path,
+                        &format!(
+                            "{} is a better copy of the same song",
+                            sources[&id].path.display()
+                        ),
+                    );
+                }
+                if let Some(previous) = previous {
+                    warn!(
+                        "duplicate track: {} and {} are both {id}; using {}",
+                        previous.display(),
+                        path.display(),
+                        sources[&id].path.display()
+                    );
+                }
+            }
+            Err(error) if dump::is_empty_source(&error) => {
+                warn!("skipping {}: {error}", path.display());
+                empty += 1;
+            }
+            Err(error) => inspection_errors.push(SyncFailure::inspection(path, error)),
         }
     }
 
-    let manifest_path = config.output.join(MANIFEST_NAME);
-    let manifest = load_manifest(&manifest_path)?;
-    let manifest_was_empty = manifest.entries.is_empty();
-    let mut entries = manifest
-        .entries
-        .into_iter()
-        .map(|entry| (entry.id.clone(), entry))
-        .collect::<BTreeMap<_, _>>();
-
-    let mut located = HashMap::<String, PathBuf>::new();
-    let mut unresolved = Vec::new();
+    let dedupe = config.options.dedupe_source;
+    if compare == Compare::Hash || dedupe == Some(DedupeKey::Hash) {
+        report(SyncEvent::Status(format!(
+            "Hashing {} input files...",
+            sources.len()
+        )));
+        let cache = HashCache::load_hashes(&config.output);
+        let hashed = pool.install(|| {
+            sources
+                .par_iter_mut()
+                .map(|(_, source)| {
+                    let result = cache.hash(&source.path);
+                    if let Ok(cached) = &result {
+                        source.variant.hash = Some(cached.value.clone());
+                    }
+                    (source.path.clone(), result)
+                })
+                .collect::<Vec<_>>()
+        });
+        for (path, result) in hashed {
+            if let Err(error) = result {
+                inspection_errors.push(SyncFailure::inspection(&path, error));
+            }
+        }
+        if !dry_run && config.output.exists() {
+            cache.save(&config.output)?;
+        }
+    }
+
+    let mut duplicates = 0_usize;
+    if let Some(key) = dedupe {
+        let skip = config.options.skip_duplicates.unwrap_or(false);
+        for group in duplicate_groups(&sources, key) {
+            let names = group[1..]
+                .iter()
+                .map(|id| sources[id].path.display().to_string())
+                .collect::<Vec<_>>()
+                .join(", ");
+            let verb = if skip { "skipping" } else { "also found" };
+            warn!(
+                "duplicate song: keeping {}; {verb} {names}",
+                sources[&group[0]].path.display()
+            );
+            duplicates += group.len() - 1;
+            if skip {
+                let best = sources[&group[0]].path.clone();
+                for id in &group[1..] {
+                    if let Some(duplicate) = sources.remove(id) {
+                        explain_skip(
+                            &config.options,
+                            &duplicate.path,
+                            &format!("duplicate of {}", best.display()),
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    let mut entries = manifest
+        .entries
+        .into_iter()
+        .map(|entry| (entry.id.clone(), entry))
+        .collect::<BTreeMap<_, _>>();
+
+    let mut located = HashMap::<String, PathBuf>::new();
+    let mut unresolved = Vec::new();
     for source in sources.values() {
         dump::ensure_not_cancelled(cancel)?;
         let Some(entry) = entries.get(&source.id) else {
@@ -195,20 +1276,23 @@ fn run_with_progress_inner(
             continue;
         };
         let path = config.output.join(&entry.output);
-        if output_matches(&path, source) {
+        let unchanged = entry.stamp.is_some() && entry.stamp == OutputStamp::of(&path);
+        if unchanged || output_matches(&path, source) {
             located.insert(source.id.clone(), path);
         } else {
             unresolved.push(source.id.clone());
         }
     }
 
-    let output_index = if unresolved.is_empty() {
+    let output_index = if unresolved.is_empty() || !config.output.exists() {
         None
     } else {
         report(SyncEvent::Status(
             "Searching the output tree for moved files...".to_string(),
         ));
-        Some(build_output_index(&config.output, &pool, cancel))
+        let paths =
+            output_paths.unwrap_or_else(|| walk_output(&config.output, cancel, &found_output));
+        Some(build_output_index(paths, config, &pool, cancel))
     };
     dump::ensure_not_cancelled(cancel)?;
 
@@ -235,11 +1319,13 @@ fn run_with_progress_inner(
 
     let mut claims = build_claims(&entries, &config.output);
     let mut jobs = Vec::new();
+    let mut actions = Vec::new();
     let mut skipped = 0_usize;
-    let profile = config.mode.profile().to_string();
-
+    let tag_cache = (compare == Compare::Tags).then(|| dump::TagCache::load_tags(&config.output));
     for source in sources.values() {
         dump::ensure_not_cancelled(cancel)?;
+        let mode = source_mode(config, source);
+        let profile = config.profile_for(mode);
         let previous = entries.get(&source.id).cloned();
         let existing = located.get(&source.id).cloned();
 
@@ -248,7 +1334,8 @@ fn run_with_progress_inner(
             let relative = relative_output(&config.output, existing)?;
             if let Some(entry) = entries.get_mut(&source.id) {
                 entry.output = relative;
-            } else if output_extension_matches(config, source, existing) {
+                entry.stamp = OutputStamp::of(existing);
+            } else if !config.options.force && output_extension_matches(mode, source, existing) {
                 entries.insert(
                     source.id.clone(),
                     ManifestEntry {
@@ -256,26 +1343,72 @@ fn run_with_progress_inner(
                         output: relative,
                         profile: profile.clone(),
                         source: source.variant.clone(),
+                        stamp: OutputStamp::of(existing),
+                        // An original left by an earlier run still belongs to the output.
+                        original: original_path(config, mode, existing, &source.variant.format)?
+                            .filter(|original| original.is_file())
+                            .map(|original| relative_output(&config.output, &original))
+                            .transpose()?,
                     },
                 );
+                let reason = format!("adopted the existing output {}", existing.display());
+                explain_skip(&config.options, &source.path, &reason);
+                let output_size = entries[&source.id].stamp.map(|stamp| stamp.size);
+                actions.push(skip_action(source, existing, output_size, reason));
                 skipped += 1;
                 continue;
             }
         }
 
-        let needs_processing = match (&previous, &existing) {
-            (_, None) => true,
-            (None, Some(_)) => true,
-            (Some(entry), Some(_)) => {
-                entry.profile != profile || source.variant.is_better_than(&entry.source)
+        let reason = resync_reason(
+            &source.variant,
+            previous.as_ref(),
+            existing.as_deref(),
+            &profile,
+            &config.options,
+            |output| {
+                // Tags that cannot be read say nothing about a change; resyncing would fail
+                // the same way on every run, so the output is kept.
+                tag_cache.as_ref().is_none_or(|cache| {
+                    dump::tags_differ(source, output, cache).unwrap_or_else(|error| {
+                        warn!(
+                            "keeping {}: cannot compare tags: {error:#}",
+                            output.display()
+                        );
+                        false
+                    })
+                })
+            },
+        );
+        let Some(reason) = reason else {
+            let why = match &previous {
+                Some(entry) => up_to_date_reason(&entry.source, &source.variant, size_tolerance),
+                None => "the output is up to date".to_string(),
+            };
+            explain_skip(&config.options, &source.path, &why);
+            // Outputs synced before hashing was enabled adopt the current hash as their baseline.
+            if let Some(entry) = entries.get_mut(&source.id)
+                && entry.source.hash.is_none()
+            {
+                entry.source.hash = source.variant.hash.clone();
+            }
+            if let Some(existing) = &existing {
+                let output_size = entries
+                    .get(&source.id)
+                    .and_then(|entry| entry.stamp)
+                    .map(|stamp| stamp.size);
+                actions.push(skip_action(source, existing, output_size, why));
             }
-        };
-        if !needs_processing {
             skipped += 1;
             continue;
-        }
+        };
+        trace!(
+            "{}: {reason}, processing {}",
+            source.id,
+            source.path.display()
+        );
 
-        let desired_extension = config.mode.extension(&source.variant.format);
+        let desired_extension = mode.extension(&source.variant.format);
         let base_target = if let Some(path) = &existing {
             path.with_extension(desired_extension)
         } else if let Some(entry) = &previous {
@@ -283,132 +1416,1089 @@ fn run_with_progress_inner(
                 .output
                 .join(&entry.output)
                 .with_extension(desired_extension)
+        } else if let Some(template) = &config.options.organize {
+            append_extension(
+                config.output.join(organized_path(template, source)),
+                desired_extension,
+            )
         } else {
-            config
-                .output
-                .join(&source.display_name)
-                .with_extension(desired_extension)
+            let directory = if config.options.preserve_structure.unwrap_or(false) {
+                source_subdirectory(&config.inputs, &source.path)
+            } else {
+                PathBuf::new()
+            };
+            let (directory, name) = if config.options.sanitize_filenames.unwrap_or(false) {
+                (
+                    directory
+                        .iter()
+                        .map(|component| sanitize_file_name(&component.to_string_lossy()))
+                        .collect(),
+                    sanitize_file_name(&source.display_name),
+                )
+            } else {
+                (directory, source.display_name.clone())
+            };
+            append_extension(config.output.join(directory).join(name), desired_extension)
         };
         let target = reserve_target(base_target, source, &mut claims);
+        let original = original_path(config, mode, &target, &source.variant.format)?;
+        // Archive entries are extracted copies; the archive itself is left alone.
+        let extracted = archive_cache
+            .as_ref()
+            .is_some_and(|cache| source.path.starts_with(cache));
         jobs.push(Job {
             source: source.clone(),
             target,
             old_output: existing,
-            mode: config.mode,
-            ffmpeg: None,
+            mode,
+            ffmpeg: ffmpeg.clone(),
+            transcode_slots: transcode_slots.clone(),
+            output_dirs: output_dirs.clone(),
+            original,
+            staging: staging.clone(),
+            move_source: config.options.move_sources
+                && !dry_run
+                && !extracted
+                && !source.path.starts_with(&config.output),
         });
     }
 
-    if config.mode.needs_ffmpeg() && !jobs.is_empty() {
-        let ffmpeg = doctor::find_ffmpeg().context(
-            "FFmpeg was not found next to w4dj or in PATH; it is required for mp3 and wav modes",
-        )?;
-        for job in &mut jobs {
-            job.ffmpeg = Some(ffmpeg.clone());
+    if let Some(cache) = &tag_cache
+        && !dry_run
+        && config.output.exists()
+    {
+        cache.save(&config.output)?;
+    }
+    check_free_space(config, &jobs)?;
+    warn_untouched_lossless(config, &jobs);
+    warn_hard_links(config, &jobs);
+    if !dry_run {
+        let mut files = BTreeMap::<JobAction, usize>::new();
+        for job in &jobs {
+            *files.entry(job.action()).or_default() += 1;
         }
+        report(SyncEvent::Plan(RunPlan {
+            files,
+            bytes: jobs.iter().map(|job| job.source.variant.size).sum(),
+            mode: config.mode,
+            workers: pool.current_num_threads(),
+            ffmpeg: ffmpeg.clone(),
+            delete: config.options.delete,
+            move_sources: config.options.move_sources,
+            two_way_prefer: config.options.prefer.filter(|_| config.options.two_way),
+        }));
+        dump::ensure_not_cancelled(cancel)?;
     }
+    // Until the first run has recorded the library, files in the output cannot be told
+    // apart from ones added on the device, so nothing is imported yet. Imports can replace
+    // inputs, so they wait for the plan to be confirmed; imported files are synced by the
+    // next run.
+    let device_import = if config.options.two_way && !manifest_was_empty {
+        report(SyncEvent::Status(
+            "Looking for files added to the output...".to_string(),
+        ));
+        import_device_files(config, &entries, cancel, report)?
+    } else {
+        DeviceImport::default()
+    };
+    inspection_errors.extend(device_import.errors);
+    actions.splice(0..0, device_import.actions);
 
     let total = jobs.len();
     report(SyncEvent::Progress {
         completed: 0,
         total,
+        failed: 0,
         current: None,
     });
+    let budget = config
+        .options
+        .max_bytes
+        .map(|limit| ByteBudget::new(limit.0));
+    let throttle = config.options.max_mbps.map(Throttle::from_mbps);
+    let deferred = AtomicUsize::new(0);
+    let stopped = AtomicUsize::new(0);
+    let total_bytes = AtomicU64::new(jobs.iter().map(|job| job.source.variant.size).sum());
+    let copied = AtomicU64::new(0);
     let completed = AtomicUsize::new(0);
-    let results = pool.install(|| {
-        jobs.par_iter()
-            .map(|job| {
-                let result = dump::process_with_cancel(job, cancel);
-                if !result.as_ref().is_err_and(dump::is_cancelled) {
-                    let completed = completed.fetch_add(1, Ordering::Relaxed) + 1;
-                    report(SyncEvent::Progress {
-                        completed,
-                        total,
-                        current: Some(job.source.display_name.clone()),
+    let failed = AtomicUsize::new(0);
+    let watchdog = match config
+        .options
+        .watchdog_secs
+        .unwrap_or(DEFAULT_WATCHDOG_SECS)
+    {
+        0 => None,
+        seconds => Some(Watchdog::new(Duration::from_secs(seconds))),
+    };
+    let (stop_monitor, monitor_stopped) = mpsc::channel::<()>();
+    let results = thread::scope(|scope| {
+        let (watchdog, total_bytes, copied) = (watchdog.as_ref(), &total_bytes, &copied);
+        scope.spawn(move || {
+            let mut reported = None;
+            while let Err(mpsc::RecvTimeoutError::Timeout) =
+                monitor_stopped.recv_timeout(MONITOR_INTERVAL)
+            {
+                if let Some(watchdog) = watchdog {
+                    for (name, elapsed) in watchdog.overdue(Instant::now()) {
+                        report(SyncEvent::StillWorking { name, elapsed });
+                    }
+                }
+                let bytes = copied.load(Ordering::Relaxed);
+                if !dry_run && reported != Some(bytes) {
+                    reported = Some(bytes);
+                    report(SyncEvent::Transferred {
+                        bytes,
+                        total: total_bytes.load(Ordering::Relaxed),
                     });
                 }
-                (job, result)
-            })
-            .collect::<Vec<_>>()
+            }
+        });
+        let results = pool.install(|| {
+            jobs.par_iter()
+                .enumerate()
+                .filter_map(|(index, job)| {
+                    let estimate = job.source.variant.size;
+                    if stop.load(Ordering::Relaxed) {
+                        stopped.fetch_add(1, Ordering::Relaxed);
+                        total_bytes.fetch_sub(estimate, Ordering::Relaxed);
+                        return None;
+                    }
+                    let reserved = estimated_output_size(job);
+                    if let Some(budget) = &budget
+                        && !budget.try_reserve(reserved)
+                    {
+                        deferred.fetch_add(1, Ordering::Relaxed);
+                        total_bytes.fetch_sub(estimate, Ordering::Relaxed);
+                        return None;
+                    }
+                    if dry_run {
+                        report(SyncEvent::Planned {
+                            source: job.source.path.clone(),
+                            action: job.action(),
+                            target: job.target.clone(),
+                        });
+                        let completed = completed.fetch_add(1, Ordering::Relaxed) + 1;
+                        report(SyncEvent::Progress {
+                            completed,
+                            total,
+                            failed: 0,
+                            current: Some(job.source.display_name.clone()),
+                        });
+                        return Some((job, Ok(Processed::default()), Duration::ZERO, None));
+                    }
+                    let _tracked = watchdog
+                        .as_ref()
+                        .map(|watchdog| watchdog.track(index, &job.source.display_name));
+                    let started = Instant::now();
+                    let job_copied = Cell::new(0_u64);
+                    let result = process_with_retries(job, config, cancel, &|bytes| {
+                        job_copied.set(job_copied.get() + bytes);
+                        copied.fetch_add(bytes, Ordering::Relaxed);
+                        if let Some(throttle) = &throttle {
+                            throttle.wait(bytes, cancel);
+                        }
+                    });
+                    // Transcoded sources are read by FFmpeg rather than the copy loop; count
+                    // whatever was not reported once the file is done so the total adds up.
+                    copied.fetch_add(estimate.saturating_sub(job_copied.get()), Ordering::Relaxed);
+                    // A source renamed into place later is its output as it stands.
+                    let output_size = result.as_ref().ok().and_then(|processed| {
+                        if processed.rename_source {
+                            Some(job.source.variant.size)
+                        } else {
+                            fs::metadata(job.write_path(&job.target))
+                                .ok()
+                                .map(|metadata| metadata.len())
+                        }
+                    });
+                    let written = match &result {
+                        Ok(_) => output_size.unwrap_or(estimate),
+                        Err(_) => 0,
+                    };
+                    if let Some(budget) = &budget {
+                        budget.settle(reserved, written);
+                    }
+                    // FFmpeg writes its output without the copy loop, so a transcode is paid
+                    // for once it is done and holds back the writes that follow.
+                    if let Some(throttle) = &throttle
+                        && job.mode.needs_ffmpeg()
+                    {
+                        throttle.wait(written, cancel);
+                    }
+                    if !matches!(result, Err(SyncError::Cancelled)) {
+                        let failed = if result.is_err() {
+                            failed.fetch_add(1, Ordering::Relaxed) + 1
+                        } else {
+                            failed.load(Ordering::Relaxed)
+                        };
+                        let completed = completed.fetch_add(1, Ordering::Relaxed) + 1;
+                        report(SyncEvent::Progress {
+                            completed,
+                            total,
+                            failed,
+                            current: Some(job.source.display_name.clone()),
+                        });
+                    }
+                    Some((job, result, started.elapsed(), output_size))
+                })
+                .collect::<Vec<_>>()
+        });
+        drop(stop_monitor);
+        results
     });
+    if !dry_run {
+        report(SyncEvent::Transferred {
+            bytes: copied.into_inner(),
+            total: total_bytes.into_inner(),
+        });
+    }
 
+    // A staged run only changes the real output once every file made it.
+    let promoted = match &staging {
+        Some(staging)
+            if inspection_errors.is_empty()
+                && !cancel.load(Ordering::Relaxed)
+                && stopped.load(Ordering::Relaxed) == 0
+                && deferred.load(Ordering::Relaxed) == 0
+                && results.iter().all(|(_, result, _, _)| result.is_ok()) =>
+        {
+            let count = staging.promote()?;
+            report(SyncEvent::Status(format!(
+                "Moved {count} staged files into place"
+            )));
+            true
+        }
+        Some(staging) => {
+            warn!(
+                "the run did not complete, so the output is unchanged; its files are left in {}",
+                staging.root().display()
+            );
+            false
+        }
+        None => true,
+    };
     let mut process_errors = Vec::new();
+    let mut failures = Vec::new();
     let mut processed = 0_usize;
-    for (job, result) in results {
+    let mut staged = 0_usize;
+    let mut no_cover = Vec::new();
+    let mut to_move = Vec::new();
+    let mut to_rename = Vec::new();
+    let mut totals = BTreeMap::<JobAction, ActionTotals>::new();
+    for (job, result, busy, output_size) in results {
+        let failure = result
+            .as_ref()
+            .err()
+            .filter(|error| !matches!(error, SyncError::Cancelled))
+            .map(|error| SyncFailure::new(&job.source.path, error));
+        actions.push(SyncAction {
+            id: job.source.id.clone(),
+            action: SyncActionKind::Write(job.action()),
+            source: Some(job.source.path.clone()),
+            target: job.target.clone(),
+            source_size: Some(job.source.variant.size),
+            output_size,
+            error: failure.clone(),
+            reason: None,
+        });
         match result {
-            Ok(()) => {
+            Ok(outcome) => {
+                if outcome.missing_cover {
+                    no_cover.push(job.source.path.clone());
+                }
+                if !promoted {
+                    staged += 1;
+                    continue;
+                }
                 processed += 1;
+                if staging.is_some() {
+                    dump::remove_superseded(job, &config.options);
+                }
+                let totals = totals.entry(job.action()).or_default();
+                totals.files += 1;
+                if dry_run {
+                    totals.bytes += job.source.variant.size;
+                    totals.busy_secs += estimated_busy_secs(job.action(), job.source.variant.size);
+                } else {
+                    totals.bytes += output_size.unwrap_or(0);
+                    totals.busy_secs += busy.as_secs_f64();
+                }
+                // A renamed source is stamped as it is now; the rename keeps size and time.
+                let stamp = if outcome.rename_source {
+                    to_rename.push((job.source.path.clone(), job.target.clone()));
+                    OutputStamp::of(&job.source.path)
+                } else {
+                    if job.move_source {
+                        to_move.push(job.source.path.clone());
+                    }
+                    OutputStamp::of(&job.target)
+                };
+                let original = job
+                    .original
+                    .as_deref()
+                    .map(|original| relative_output(&config.output, original))
+                    .transpose()?;
+                if !dry_run {
+                    remove_replaced_original(config, entries.get(&job.source.id), &original);
+                }
                 entries.insert(
                     job.source.id.clone(),
                     ManifestEntry {
                         id: job.source.id.clone(),
                         output: relative_output(&config.output, &job.target)?,
-                        profile: profile.clone(),
+                        profile: config.profile_for(job.mode),
                         source: job.source.variant.clone(),
+                        stamp,
+                        original,
                     },
                 );
             }
-            Err(error) if dump::is_cancelled(&error) => {}
-            Err(error) => process_errors.push(format!("{}: {error:#}", job.source.path.display())),
+            Err(SyncError::Cancelled) => {}
+            Err(error) => {
+                process_errors.extend(failure);
+                failures.push(error);
+            }
+        }
+    }
+    let mut removed = 0_usize;
+    if config.options.delete {
+        if !inspection_errors.is_empty() || !process_errors.is_empty() {
+            report(SyncEvent::Status(
+                "Skipping deletions because some files failed".to_string(),
+            ));
+        } else if empty > 0 {
+            // An empty file has no readable ID, so a source truncated by a failed download
+            // would otherwise take its good output with it.
+            report(SyncEvent::Status(
+                "Skipping deletions because some sources are empty".to_string(),
+            ));
+        } else if !cancel.load(Ordering::Relaxed) {
+            let deletions = remove_orphaned_outputs(config, &sources, &mut entries, report)?;
+            removed = deletions.len();
+            actions.extend(deletions);
+        }
+    }
+    if !dry_run {
+        // Entries whose output is gone can never be trusted again; dropping them keeps the
+        // manifest from growing with files removed outside of w4dj.
+        let renamed = to_rename
+            .iter()
+            .map(|(_, target)| target)
+            .collect::<HashSet<_>>();
+        entries.retain(|_, entry| {
+            let output = config.output.join(&entry.output);
+            output.is_file() || renamed.contains(&output)
+        });
+    }
+    let outputs = entries.len();
+    let tracked_outputs = entries
+        .values()
+        .map(|entry| config.output.join(&entry.output))
+        .collect::<Vec<_>>();
+    if !dry_run {
+        save_manifest(
+            &manifest_path,
+            Manifest {
+                version: MANIFEST_VERSION,
+                entries: entries.into_values().collect(),
+            },
+        )?;
+    }
+    // Sources go only once the manifest records their outputs, so an interrupted run can
+    // never lose a song that is not tracked yet. One that stops before a rename leaves the
+    // source where it was, and the next run finds its output missing and writes it again.
+    let mut moved = 0_usize;
+    for (source, target) in to_rename {
+        match dump::move_source(&source, &target, &config.options) {
+            Ok(()) => moved += 1,
+            Err(error) => warn!("{error:#}"),
+        }
+    }
+    for source in to_move {
+        match fs::remove_file(&source) {
+            Ok(()) => moved += 1,
+            // Removed by hand while the run was busy; its output is written all the same.
+            Err(error) if error.kind() == io::ErrorKind::NotFound => moved += 1,
+            Err(error) => warn!(
+                "failed to remove moved source {}: {error}",
+                source.display()
+            ),
         }
     }
-    save_manifest(
-        &manifest_path,
-        Manifest {
-            version: MANIFEST_VERSION,
-            entries: entries.into_values().collect(),
-        },
-    )?;
 
     let errors = inspection_errors
         .into_iter()
         .chain(process_errors)
         .collect::<Vec<_>>();
     let summary = SyncSummary {
+        sources: scanned,
+        outputs,
         processed,
         skipped,
         failed: errors.len(),
+        staged,
+        deferred: deferred.into_inner(),
+        stopped: stopped.into_inner(),
+        duplicates,
+        empty,
+        imported: device_import.imported,
+        conflicts: device_import.conflicts,
+        removed,
+        moved,
+        no_cover,
+        bytes_written: if dry_run {
+            0
+        } else {
+            totals.values().map(|totals| totals.bytes).sum()
+        },
+        elapsed_secs: run_started.elapsed().as_secs_f64(),
+        estimated_secs: dry_run.then(|| {
+            let busy = totals.values().map(|totals| totals.busy_secs).sum::<f64>();
+            busy / pool.current_num_threads().max(1) as f64
+        }),
+        totals,
         errors,
+        actions,
     };
-    if cancel.load(Ordering::Relaxed) {
+    let cancelled = cancel.load(Ordering::Relaxed);
+    if let Some(path) = &config.options.report {
+        write_report(path, &summary, dry_run, cancelled)?;
+    }
+    if cancelled {
         report(SyncEvent::Cancelled(summary.clone()));
         return Ok(summary);
     }
+    let succeeded = summary.failed == 0 || config.options.keep_going.unwrap_or(false);
+    if succeeded
+        && !dry_run
+        && let Some(path) = &config.options.playlist
+    {
+        let tracks = if config.options.playlist_new_only.unwrap_or(false) {
+            summary
+                .actions
+                .iter()
+                .filter(|action| {
+                    action.error.is_none() && matches!(action.action, SyncActionKind::Write(_))
+                })
+                .map(|action| action.target.clone())
+                .collect()
+        } else {
+            tracked_outputs
+        };
+        write_playlist(path, tracks)?;
+    }
     report(SyncEvent::Finished(summary.clone()));
-    if summary.failed > 0 {
-        bail!("{} files could not be synchronized", summary.failed);
+    if !succeeded {
+        return Err(SyncError::Failed {
+            failed: summary.failed,
+            errors: failures,
+        }
+        .into());
     }
     Ok(summary)
 }
 
-fn scan_inputs(inputs: &[PathBuf], output: &Path, cancel: &AtomicBool) -> Result<Vec<PathBuf>> {
+/// Refuses to start when the planned outputs clearly do not fit on the output drive, so a
+/// full disk cannot strand a run halfway, and warns when little room would be left.
+fn check_free_space(config: &Config, jobs: &[Job]) -> Result<()> {
+    let needed = jobs
+        .iter()
+        .map(|job| {
+            let replaced = job
+                .old_output
+                .as_ref()
+                .and_then(|path| fs::metadata(path).ok())
+                .map_or(0, |metadata| metadata.len());
+            estimated_output_size(job).saturating_sub(replaced)
+        })
+        .fold(0_u64, u64::saturating_add);
+    let needed = config
+        .options
+        .max_bytes
+        .map_or(needed, |limit| needed.min(limit.0));
+    let Some(location) = config.output.ancestors().find(|path| path.exists()) else {
+        return Ok(());
+    };
+    if needed == 0 {
+        return Ok(());
+    }
+    let available = match fs2::available_space(location) {
+        Ok(available) => available,
+        Err(error) => {
+            debug!(
+                "could not read the free space of {}: {error}",
+                location.display()
+            );
+            return Ok(());
+        }
+    };
+    if needed > available {
+        let message = format!(
+            "not enough free space for {}: the run needs about {} but only {} is available; \
+             free up space or set max_bytes",
+            config.output.display(),
+            ByteSize(needed),
+            ByteSize(available)
+        );
+        if config.options.dry_run {
+            warn!("{message}");
+            return Ok(());
+        }
+        bail!("{message}");
+    }
+    let left = available - needed;
+    if config
+        .options
+        .free_space_margin
+        .is_some_and(|margin| left < margin.0)
+    {
+        warn!(
+            "only about {} will be left free for {} after this run",
+            ByteSize(left),
+            config.output.display()
+        );
+    }
+    Ok(())
+}
+
+/// Rough size of a job's output. Copies, decrypted files and lossy encodes stay close to
+/// the source size, while WAV expands compressed audio.
+fn estimated_output_size(job: &Job) -> u64 {
+    let size = job.source.variant.size;
+    match (job.mode, job.source.variant.format.as_str()) {
+        (Mode::Wav, "wav") => size,
+        (Mode::Wav, "flac") => size.saturating_mul(2),
+        (Mode::Wav, _) => size.saturating_mul(10),
+        _ => size,
+    }
+}
+
+/// Writes an extended M3U playlist whose entries are relative to the playlist's folder.
+fn write_playlist(path: &Path, mut tracks: Vec<PathBuf>) -> Result<()> {
+    let base = path
+        .parent()
+        .context("playlist path has no parent directory")?;
+    tracks.sort();
+    let mut contents = String::from("#EXTM3U\n");
+    for track in tracks {
+        let entry = relative_to(base, &track).unwrap_or(track);
+        let entry = entry
+            .components()
+            .map(|component| component.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+        contents.push_str(&entry);
+        contents.push('\n');
+    }
+    fs::write(path, contents)
+        .with_context(|| format!("failed to write playlist {}", path.display()))
+}
+
+/// Expresses `path` relative to `base`, or `None` when they share no root (another drive).
+fn relative_to(base: &Path, path: &Path) -> Option<PathBuf> {
+    let base = base.components().collect::<Vec<_>>();
+    let target = path.components().collect::<Vec<_>>();
+    let common = base
+        .iter()
+        .zip(&target)
+        .take_while(|(left, right)| left == right)
+        .count();
+    if common == 0 {
+        return None;
+    }
+    let mut relative = PathBuf::new();
+    for _ in common..base.len() {
+        relative.push("..");
+    }
+    relative.extend(&target[common..]);
+    Some(relative)
+}
+
+/// `error` followed by each of its sources.
+fn causes(error: &SyncError) -> impl Iterator<Item = &(dyn Error + 'static)> {
+    std::iter::successors(Some(error as &(dyn Error + 'static)), |cause| {
+        (*cause).source()
+    })
+}
+
+/// A failed song's error with its causes on one line, like `{:#}` on an `anyhow::Error`.
+fn describe(error: &SyncError) -> String {
+    causes(error)
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(": ")
+}
+
+/// Processes a job, retrying transient I/O failures with exponential backoff.
+fn process_with_retries(
+    job: &Job,
+    config: &Config,
+    cancel: &AtomicBool,
+    on_copied: &dyn Fn(u64),
+) -> Result<Processed, SyncError> {
+    let retries = config.options.retries.unwrap_or(0);
+    let mut attempt = 0;
+    loop {
+        match dump::process_with_cancel(job, &config.options, cancel, on_copied) {
+            Err(error) if attempt < retries && is_transient(&error) => {
+                thread::sleep(RETRY_BACKOFF * 2_u32.saturating_pow(attempt));
+                if cancel.load(Ordering::Relaxed) {
+                    return Err(SyncError::Cancelled);
+                }
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// I/O failures are worth retrying unless they say the input is missing or malformed.
+fn is_transient(error: &SyncError) -> bool {
+    causes(error)
+        .find_map(|cause| cause.downcast_ref::<io::Error>())
+        .is_some_and(|error| {
+            !matches!(
+                error.kind(),
+                io::ErrorKind::NotFound
+                    | io::ErrorKind::InvalidData
+                    | io::ErrorKind::InvalidInput
+                    | io::ErrorKind::PermissionDenied
+                    | io::ErrorKind::Unsupported
+            )
+        })
+}
+
+/// Deletes tracked outputs whose IDs were not found in this run's inputs.
+///
+/// Only files recorded in the manifest with a music extension are considered, and each
+/// one must still carry the recorded ID, so untracked or user files are never touched.
+fn remove_orphaned_outputs(
+    config: &Config,
+    sources: &BTreeMap<String, SourceItem>,
+    entries: &mut BTreeMap<String, ManifestEntry>,
+    report: &impl Fn(SyncEvent),
+) -> Result<Vec<SyncAction>> {
+    let orphaned = entries
+        .keys()
+        .filter(|id| !sources.contains_key(*id))
+        .cloned()
+        .collect::<Vec<_>>();
+    let mut removed = Vec::new();
+    for id in orphaned {
+        let path = config.output.join(&entries[&id].output);
+        let owned = is_supported(&path)
+            && path.is_file()
+            && dump::inspect_output(&path).is_ok_and(|identity| identity.id == id);
+        if !owned {
+            continue;
+        }
+        report(SyncEvent::Removed(path.clone()));
+        let output_size = fs::metadata(&path).ok().map(|metadata| metadata.len());
+        if !config.options.dry_run {
+            dump::remove_output(&path, config.options.use_trash.unwrap_or(false))
+                .with_context(|| format!("failed to delete {}", path.display()))?;
+            if let Some(original) = &entries[&id].original {
+                let original = config.output.join(original);
+                if original.is_file() {
+                    dump::remove_output(&original, config.options.use_trash.unwrap_or(false))
+                        .with_context(|| format!("failed to delete {}", original.display()))?;
+                }
+            }
+            let lyrics = path.with_extension("lrc");
+            if lyrics.is_file() {
+                dump::remove_output(&lyrics, config.options.use_trash.unwrap_or(false))
+                    .with_context(|| format!("failed to delete {}", lyrics.display()))?;
+            }
+            entries.remove(&id);
+        }
+        removed.push(SyncAction {
+            id,
+            action: SyncActionKind::Delete,
+            source: None,
+            target: path,
+            source_size: None,
+            output_size,
+            reason: None,
+            error: None,
+        });
+    }
+    Ok(removed)
+}
+
+/// Files found in the output that no input produced, and what `--two-way` did with them.
+#[derive(Default)]
+struct DeviceImport {
+    actions: Vec<SyncAction>,
+    imported: usize,
+    conflicts: usize,
+    errors: Vec<SyncFailure>,
+}
+
+/// Copies audio that was added directly to the output back into the first input folder,
+/// keeping its path relative to the output.
+///
+/// Outputs tracked in the manifest or tagged with a w4dj track ID came from an input and
+/// are left alone. When an input with the same name already exists and differs, the song
+/// is a conflict: it is reported, or resolved by `prefer`.
+fn import_device_files(
+    config: &Config,
+    entries: &BTreeMap<String, ManifestEntry>,
+    cancel: &AtomicBool,
+    report: &impl Fn(SyncEvent),
+) -> Result<DeviceImport> {
+    let mut import = DeviceImport::default();
+    let Some(root) = config.inputs.iter().find(|input| input.is_dir()) else {
+        warn!("--two-way needs an input folder to copy files from the output into");
+        return Ok(import);
+    };
+    let tracked = entries
+        .values()
+        .map(|entry| path_key(&config.output.join(&entry.output)))
+        .collect::<HashSet<_>>();
+    for path in walk_output(&config.output, cancel, &|_| {}) {
+        dump::ensure_not_cancelled(cancel)?;
+        if tracked.contains(&path_key(&path))
+            || dump::inspect_output(&path).is_ok_and(|identity| identity.has_embedded_id)
+        {
+            continue;
+        }
+        let Ok(relative) = path.strip_prefix(&config.output) else {
+            continue;
+        };
+        let target = root.join(relative);
+        match import_device_file(config, &path, &target, report) {
+            Ok(Some(action)) => {
+                match action.action {
+                    SyncActionKind::Import => import.imported += 1,
+                    SyncActionKind::Conflict => import.conflicts += 1,
+                    _ => {}
+                }
+                import.actions.push(action);
+            }
+            Ok(None) => {}
+            Err(error) => import.errors.push(SyncFailure::inspection(&path, error)),
+        }
+    }
+    Ok(import)
+}
+
+fn import_device_file(
+    config: &Config,
+    path: &Path,
+    target: &Path,
+    report: &impl Fn(SyncEvent),
+) -> Result<Option<SyncAction>> {
+    let existing = same_song_inputs(target)?;
+    for input in &existing {
+        if same_content(path, input)? {
+            return Ok(None);
+        }
+    }
+    let size = |path: &Path| fs::metadata(path).ok().map(|metadata| metadata.len());
+    let device_size = size(path);
+    let action = |action: SyncActionKind, target: &Path, output_size: Option<u64>| SyncAction {
+        id: String::new(),
+        action,
+        source: Some(path.to_path_buf()),
+        target: target.to_path_buf(),
+        source_size: device_size,
+        output_size,
+        reason: None,
+        error: None,
+    };
+    let device_wins = match (existing.is_empty(), config.options.prefer) {
+        (true, _) => true,
+        (false, None) => {
+            let names = existing
+                .iter()
+                .map(|input| input.display().to_string())
+                .collect::<Vec<_>>()
+                .join(", ");
+            warn!(
+                "conflict: {} differs from {names}; pass --prefer to choose a side",
+                path.display()
+            );
+            return Ok(Some(action(
+                SyncActionKind::Conflict,
+                &existing[0],
+                size(&existing[0]),
+            )));
+        }
+        (false, Some(Prefer::Dest)) => true,
+        (false, Some(Prefer::Source)) => false,
+        (false, Some(Prefer::Newer)) => {
+            let modified =
+                |path: &Path| fs::metadata(path).and_then(|metadata| metadata.modified());
+            let newest_input = existing
+                .iter()
+                .filter_map(|input| modified(input).ok())
+                .max();
+            modified(path).ok() > newest_input
+        }
+    };
+    let dry_run = config.options.dry_run;
+    let use_trash = config.options.use_trash.unwrap_or(false);
+    if !device_wins {
+        report(SyncEvent::Removed(path.to_path_buf()));
+        if !dry_run {
+            dump::remove_output(path, use_trash)
+                .with_context(|| format!("failed to delete {}", path.display()))?;
+        }
+        return Ok(Some(action(SyncActionKind::Delete, path, None)));
+    }
+
+    report(SyncEvent::Imported {
+        source: path.to_path_buf(),
+        target: target.to_path_buf(),
+    });
+    if !dry_run {
+        for input in existing.iter().filter(|input| *input != target) {
+            dump::remove_output(input, use_trash)
+                .with_context(|| format!("failed to delete {}", input.display()))?;
+        }
+        copy_into_place(path, target)?;
+    }
+    Ok(Some(action(SyncActionKind::Import, target, device_size)))
+}
+
+/// Inputs next to `target` with its name and extension, which `--prefer` may replace. A
+/// file in another format is a different song to import next to it, so a device MP3 never
+/// costs the FLAC master it came from.
+fn same_song_inputs(target: &Path) -> Result<Vec<PathBuf>> {
+    let (Some(directory), Some(name)) = (target.parent(), target.file_name()) else {
+        return Ok(Vec::new());
+    };
+    let name = path_key(Path::new(name));
+    let entries = match fs::read_dir(directory) {
+        Ok(entries) => entries,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(error) => {
+            return Err(error).with_context(|| format!("failed to read {}", directory.display()));
+        }
+    };
+    let mut inputs = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.is_file()
+                && is_supported(path)
+                && !is_temporary(path)
+                && path
+                    .file_name()
+                    .is_some_and(|file_name| path_key(Path::new(file_name)) == name)
+        })
+        .collect::<Vec<_>>();
+    inputs.sort();
+    Ok(inputs)
+}
+
+fn same_content(first: &Path, second: &Path) -> Result<bool> {
+    let size = |path: &Path| {
+        fs::metadata(path)
+            .map(|metadata| metadata.len())
+            .with_context(|| format!("failed to read metadata for {}", path.display()))
+    };
+    Ok(size(first)? == size(second)? && hashes::hash_file(first)? == hashes::hash_file(second)?)
+}
+
+/// Copies `source` to `target` through a temporary file in the target folder.
+fn copy_into_place(source: &Path, target: &Path) -> Result<()> {
+    let directory = target
+        .parent()
+        .context("import target has no parent directory")?;
+    fs::create_dir_all(directory)
+        .with_context(|| format!("failed to create {}", directory.display()))?;
+    let mut temporary = tempfile::Builder::new()
+        .prefix(".w4dj-")
+        .tempfile_in(directory)
+        .with_context(|| {
+            format!(
+                "failed to create a temporary file in {}",
+                directory.display()
+            )
+        })?;
+    let mut input =
+        fs::File::open(source).with_context(|| format!("failed to open {}", source.display()))?;
+    io::copy(&mut input, temporary.as_file_mut())
+        .and_then(|_| temporary.as_file().sync_all())
+        .with_context(|| format!("failed to copy {}", source.display()))?;
+    temporary
+        .persist(target)
+        .map_err(|error| error.error)
+        .with_context(|| format!("failed to write {}", target.display()))?;
+    Ok(())
+}
+
+fn skip_action(
+    source: &SourceItem,
+    existing: &Path,
+    output_size: Option<u64>,
+    reason: String,
+) -> SyncAction {
+    SyncAction {
+        id: source.id.clone(),
+        action: SyncActionKind::Skip,
+        source: Some(source.path.clone()),
+        target: existing.to_path_buf(),
+        source_size: Some(source.variant.size),
+        output_size,
+        reason: Some(reason),
+        error: None,
+    }
+}
+
+/// Logs why a source is not synced: at info level with `--explain`, otherwise with -vv.
+fn explain_skip(options: &SyncOptions, source: &Path, reason: &str) {
+    if options.explain {
+        info!("skip {}: {reason}", source.display());
+    } else {
+        trace!("skip {}: {reason}", source.display());
+    }
+}
+
+/// Why a source has to be synced, or `None` when its output is up to date. `previous` is
+/// the source's manifest entry and `output` the file found for it; `tags_differ` reads both
+/// files, so it is only asked with `compare = "tags"`.
+fn resync_reason(
+    source: &SourceVariant,
+    previous: Option<&ManifestEntry>,
+    output: Option<&Path>,
+    profile: &str,
+    options: &SyncOptions,
+    tags_differ: impl FnOnce(&Path) -> bool,
+) -> Option<&'static str> {
+    let compare = options.compare.unwrap_or_default();
+    match (previous, output) {
+        (_, None) => Some("no output yet"),
+        _ if options.force => Some("--force"),
+        (None, Some(_)) => Some("output is not tracked"),
+        (Some(entry), Some(output)) => {
+            if entry.profile != profile {
+                Some("output profile changed")
+            } else if source.is_better_than(&entry.source, compare, options.size_tolerance()) {
+                Some("better source variant")
+            } else if compare == Compare::Hash
+                && entry.source.hash.is_some()
+                && entry.source.hash != source.hash
+            {
+                Some("source content changed")
+            } else if compare == Compare::Tags && tags_differ(output) {
+                Some("tags differ from the output")
+            } else {
+                None
+            }
+        }
+    }
+}
+
+/// Why a source whose output is up to date did not replace it.
+fn up_to_date_reason(
+    synced: &SourceVariant,
+    current: &SourceVariant,
+    size_tolerance: f64,
+) -> String {
+    let same_encoding = synced.format == current.format && synced.bitrate == current.bitrate;
+    if same_encoding && synced.size == current.size {
+        return "the output is up to date and the source is unchanged".to_string();
+    }
+    if same_encoding && current.size > synced.size {
+        return format!(
+            "{} larger than the synced copy, which is within size_tolerance ({:.0}%)",
+            ByteSize(current.size - synced.size),
+            size_tolerance * 100.0
+        );
+    }
+    format!(
+        "the synced copy ({}) is at least as good as this one ({})",
+        describe_variant(synced),
+        describe_variant(current)
+    )
+}
+
+fn describe_variant(variant: &SourceVariant) -> String {
+    match variant.bitrate {
+        Some(kbps) => format!(
+            "{} {kbps} kbit/s, {}",
+            variant.format,
+            ByteSize(variant.size)
+        ),
+        None => format!("{}, {}", variant.format, ByteSize(variant.size)),
+    }
+}
+
+fn write_report(path: &Path, summary: &SyncSummary, dry_run: bool, cancelled: bool) -> Result<()> {
+    let report = SyncReport {
+        dry_run,
+        cancelled,
+        summary,
+    };
+    let bytes = serde_json::to_vec_pretty(&report).context("failed to serialize the run report")?;
+    fs::write(path, bytes).with_context(|| format!("failed to write run report {}", path.display()))
+}
+
+fn scan_inputs(
+    inputs: &[PathBuf],
+    output: &Path,
+    filter: &PathFilter,
+    options: &SyncOptions,
+    cancel: &AtomicBool,
+    found: &(dyn Fn(usize) + Sync),
+) -> Result<Vec<PathBuf>> {
+    let follow_symlinks = options.follow_symlinks.unwrap_or(false);
+    let skip_hidden = options.skip_hidden.unwrap_or(false);
+    let cutoff = options.since.map(|since| since.cutoff(SystemTime::now()));
     let mut files = HashSet::new();
+    let mut visited = HashSet::new();
     for input in inputs {
         dump::ensure_not_cancelled(cancel)?;
         if input.is_file() {
-            if is_supported(input) {
-                files.insert(input.clone());
-            } else {
+            if !(is_supported(input) || is_archive(input)) {
                 bail!("unsupported input file: {}", input.display());
             }
+            if modified_since(input, cutoff) && files.insert(input.clone()) {
+                found(files.len());
+            }
             continue;
         }
 
+        let ignores = IgnoreFiles::default();
         let walker = WalkDir::new(input)
-            .follow_links(false)
+            .follow_links(follow_symlinks)
             .into_iter()
-            .filter_entry(|entry| should_enter(entry, output));
+            .filter_entry(|entry| {
+                should_enter(entry, output)
+                    && !(skip_hidden && is_hidden(entry))
+                    && !ignores
+                        .matched(input, entry.path(), entry.file_type().is_dir())
+                        .is_ignore()
+                    && (!follow_symlinks || first_visit(entry, output, &mut visited))
+            });
         for entry in walker {
             dump::ensure_not_cancelled(cancel)?;
             match entry {
-                Ok(entry) if entry.file_type().is_file() && is_supported(entry.path()) => {
+                Ok(entry)
+                    if entry.file_type().is_file()
+                        && (is_supported(entry.path()) || is_archive(entry.path()))
+                        && filter.allows_extension(entry.path())
+                        && (ignores.matched(input, entry.path(), false).is_whitelist()
+                            || entry
+                                .path()
+                                .strip_prefix(input)
+                                .is_ok_and(|relative| filter.allows(relative)))
+                        && modified_since(entry.path(), cutoff) =>
+                {
                     let path = fs::canonicalize(entry.path()).with_context(|| {
                         format!("failed to resolve input file {}", entry.path().display())
                     })?;
-                    files.insert(path);
+                    if files.insert(path) {
+                        found(files.len());
+                    }
                 }
                 Ok(_) => {}
-                Err(error) => eprintln!("scan warning: {error}"),
+                // A link back to one of its own parents; everything below it is scanned already.
+                Err(error) if error.loop_ancestor().is_some() => {
+                    debug!("skipping symlink loop: {error}");
+                }
+                Err(error) => warn!("failed to scan input: {error}"),
             }
         }
     }
@@ -417,23 +2507,390 @@ fn scan_inputs(inputs: &[PathBuf], output: &Path, cancel: &AtomicBool) -> Result
     Ok(files)
 }
 
-fn should_enter(entry: &DirEntry, output: &Path) -> bool {
-    entry.depth() == 0 || !entry.path().starts_with(output)
+/// Whether a file was modified at or after `cutoff`; files without a readable time are
+/// kept so that `--since` never hides a song by accident.
+fn modified_since(path: &Path, cutoff: Option<SystemTime>) -> bool {
+    let Some(cutoff) = cutoff else {
+        return true;
+    };
+    match fs::metadata(path).and_then(|metadata| metadata.modified()) {
+        Ok(modified) => modified >= cutoff,
+        Err(_) => true,
+    }
 }
 
-fn select_best_source(sources: &mut BTreeMap<String, SourceItem>, candidate: SourceItem) {
+/// Where extracted archive entries are kept: `archive_cache`, or the system cache folder.
+fn archive_cache_root(options: &SyncOptions) -> Result<PathBuf> {
+    match &options.archive_cache {
+        Some(path) => Ok(path.clone()),
+        None => Ok(BaseDirs::new()
+            .context("failed to locate the platform cache directory")?
+            .cache_dir()
+            .join("w4dj")
+            .join("archives")),
+    }
+}
+
+/// The archive an archive cache folder holds the entries of, and its stamp when they were
+/// extracted.
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+struct ArchiveStamp {
+    archive: PathBuf,
+    stamp: Option<OutputStamp>,
+}
+
+impl ArchiveStamp {
+    fn of(archive: &Path) -> Self {
+        Self {
+            archive: archive.to_path_buf(),
+            stamp: OutputStamp::of(archive),
+        }
+    }
+
+    fn load(folder: &Path) -> Option<Self> {
+        serde_json::from_slice(&fs::read(folder.join(ARCHIVE_STAMP_NAME)).ok()?).ok()
+    }
+}
+
+/// Removes the cached entries of archives that no longer exist.
+fn prune_archive_cache(cache: &Path) {
+    let Ok(folders) = fs::read_dir(cache) else {
+        return;
+    };
+    for folder in folders.flatten().map(|entry| entry.path()) {
+        if let Some(stamp) = ArchiveStamp::load(&folder)
+            && !stamp.archive.exists()
+            && let Err(error) = fs::remove_dir_all(&folder)
+        {
+            warn!("failed to remove {}: {error}", folder.display());
+        }
+    }
+}
+
+/// Extracts the supported audio entries of a zip archive that `allows` accepts into a
+/// folder of `cache` named after the archive's path.
+///
+/// Each entry gets its own directory so entries that share a file name keep their
+/// original stems, which become the display names of the extracted sources. While the
+/// archive's size and modification time stay the same, entries already extracted are
+/// reused rather than read from the archive again.
+fn extract_archive(
+    archive: &Path,
+    cache: &Path,
+    allows: &dyn Fn(&Path) -> bool,
+    cancel: &AtomicBool,
+) -> Result<Vec<PathBuf>> {
+    let destination = cache.join(format!(
+        "{:016x}",
+        xxh3_64(archive.as_os_str().as_encoded_bytes())
+    ));
+    let stamp = ArchiveStamp::of(archive);
+    let unchanged = stamp.stamp.is_some()
+        && ArchiveStamp::load(&destination).is_some_and(|cached| cached == stamp);
+    if !unchanged && destination.exists() {
+        fs::remove_dir_all(&destination)
+            .with_context(|| format!("failed to clear {}", destination.display()))?;
+    }
+    let file = fs::File::open(archive)
+        .with_context(|| format!("failed to open archive {}", archive.display()))?;
+    let mut zip = zip::ZipArchive::new(file).context("invalid zip archive")?;
+    let mut paths = Vec::new();
+    for index in 0..zip.len() {
+        dump::ensure_not_cancelled(cancel)?;
+        let mut entry = zip
+            .by_index(index)
+            .with_context(|| format!("failed to read archive entry {index}"))?;
+        let Some(name) = entry.enclosed_name() else {
+            continue;
+        };
+        let Some(file_name) = name.file_name().map(PathBuf::from) else {
+            continue;
+        };
+        if !entry.is_file() || !is_supported(&file_name) || !allows(&name) {
+            continue;
+        }
+        let directory = destination.join(index.to_string());
+        let path = directory.join(&file_name);
+        if unchanged && path.is_file() {
+            paths.push(path);
+            continue;
+        }
+        fs::create_dir_all(&directory)
+            .with_context(|| format!("failed to create {}", directory.display()))?;
+        // Extracted through a temporary file, so an interrupted run never leaves a
+        // truncated entry behind for the next one to reuse.
+        let mut output = NamedTempFile::new_in(&directory)
+            .with_context(|| format!("failed to create a file in {}", directory.display()))?;
+        std::io::copy(&mut entry, &mut output)
+            .with_context(|| format!("failed to extract {}", file_name.display()))?;
+        output
+            .persist(&path)
+            .with_context(|| format!("failed to create {}", path.display()))?;
+        paths.push(path);
+    }
+    if !unchanged {
+        fs::create_dir_all(&destination)
+            .with_context(|| format!("failed to create {}", destination.display()))?;
+        fs::write(
+            destination.join(ARCHIVE_STAMP_NAME),
+            serde_json::to_vec(&stamp).context("failed to serialize the archive stamp")?,
+        )
+        .with_context(|| format!("failed to write the stamp of {}", destination.display()))?;
+    }
+    Ok(paths)
+}
+
+/// Returns the most specific input that contains `path`.
+fn input_root<'a>(inputs: &'a [PathBuf], path: &Path) -> Option<&'a Path> {
+    inputs
+        .iter()
+        .filter(|input| path.starts_with(input))
+        .max_by_key(|input| input.components().count())
+        .map(PathBuf::as_path)
+}
+
+/// Returns the folder of `path` relative to its input, or an empty path for direct inputs.
+fn source_subdirectory(inputs: &[PathBuf], path: &Path) -> PathBuf {
+    input_root(inputs, path)
+        .and_then(|root| path.parent()?.strip_prefix(root).ok())
+        .map(Path::to_path_buf)
+        .unwrap_or_default()
+}
+
+/// Fills an `organize` template with the source's tags. A missing tag uses the file name,
+/// and every path component is sanitized because tags may contain separators.
+fn organized_path(template: &str, source: &SourceItem) -> PathBuf {
+    let tags = &source.tags;
+    template
+        .split(['/', '\\'])
+        .filter(|part| !part.is_empty())
+        .map(|part| {
+            let filled = ORGANIZE_FIELDS
+                .iter()
+                .fold(part.to_string(), |filled, field| {
+                    let value = match *field {
+                        "artist" => tags.artist.as_deref(),
+                        "album" => tags.album.as_deref(),
+                        _ => tags.title.as_deref(),
+                    };
+                    filled.replace(
+                        &format!("{{{field}}}"),
+                        value.unwrap_or(&source.display_name),
+                    )
+                });
+            sanitize_file_name(&filled)
+        })
+        .collect()
+}
+
+/// Adds `.{extension}` to the file name of `path`. Unlike `with_extension`, this keeps
+/// whatever follows a dot in the name itself, such as the "2" of "Pt. 2".
+fn append_extension(path: PathBuf, extension: &str) -> PathBuf {
+    if extension.is_empty() {
+        return path;
+    }
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".");
+    name.push(extension);
+    path.with_file_name(name)
+}
+
+/// Makes a file or folder name safe for FAT32 and exFAT: characters those file systems
+/// reject become `_`, trailing dots and spaces are dropped and long names are shortened,
+/// leaving room for an ID suffix and the extension.
+fn sanitize_file_name(name: &str) -> String {
+    let replaced = name
+        .chars()
+        .map(|character| {
+            if character.is_control() || r#"<>:"/\|?*"#.contains(character) {
+                '_'
+            } else {
+                character
+            }
+        })
+        .take(MAX_SANITIZED_NAME_CHARS)
+        .collect::<String>();
+    let trimmed = replaced.trim_end_matches(['.', ' ']);
+    if trimmed.is_empty() {
+        "track".to_string()
+    } else {
+        trimmed.to_string()
+    }
+}
+
+fn should_enter(entry: &DirEntry, output: &Path) -> bool {
+    entry.depth() == 0 || !entry.path().starts_with(output)
+}
+
+/// Dot-prefixed entries below an input, such as `.DS_Store`, `.stfolder` or Syncthing's
+/// `.name.sync-conflict-*` copies. Hidden folders are pruned with everything inside them.
+fn is_hidden(entry: &DirEntry) -> bool {
+    entry.depth() > 0 && entry.file_name().to_string_lossy().starts_with('.')
+}
+
+/// `max_sample_rate` and `max_bit_depth` only shape what FFmpeg writes; lossless sources
+/// that are copied or decrypted keep their original resolution.
+fn warn_untouched_lossless(config: &Config, jobs: &[Job]) {
+    if config.options.max_sample_rate.is_none() && config.options.max_bit_depth.is_none() {
+        return;
+    }
+    let untouched = jobs
+        .iter()
+        .filter(|job| {
+            !job.mode.needs_ffmpeg() && matches!(job.source.variant.format.as_str(), "flac" | "wav")
+        })
+        .count();
+    if untouched > 0 {
+        warn!(
+            "max_sample_rate and max_bit_depth only apply to transcodes; {untouched} lossless \
+             sources are copied unchanged"
+        );
+    }
+}
+
+/// A hard-linked output is the source file itself, so editing either side later changes
+/// both.
+fn warn_hard_links(config: &Config, jobs: &[Job]) {
+    if config.options.link != Some(Link::Hard) || config.options.dry_run {
+        return;
+    }
+    let linked = jobs
+        .iter()
+        .filter(|job| job.action() == JobAction::Copy)
+        .count();
+    if linked > 0 {
+        warn!(
+            "link = \"hard\": up to {linked} outputs will share their data with their sources; \
+             a later edit to either file shows up in both"
+        );
+    }
+}
+
+/// `.w4djignore` files met during an input walk. Each one uses gitignore syntax, applies to
+/// its own directory and below, and overrides `include` and `exclude` there.
+#[derive(Default)]
+struct IgnoreFiles {
+    loaded: RefCell<HashMap<PathBuf, Option<Gitignore>>>,
+}
+
+impl IgnoreFiles {
+    /// The verdict of the deepest ignore file between `root` and `path` that mentions
+    /// `path`, so nested files refine their parents like nested `.gitignore` files do.
+    fn matched(&self, root: &Path, path: &Path, is_dir: bool) -> Match<()> {
+        let mut loaded = self.loaded.borrow_mut();
+        for directory in path.ancestors().skip(1) {
+            if !directory.starts_with(root) {
+                break;
+            }
+            let ignore = loaded
+                .entry(directory.to_path_buf())
+                .or_insert_with(|| load_ignore_file(directory));
+            match ignore.as_ref().map(|ignore| ignore.matched(path, is_dir)) {
+                Some(Match::Ignore(_)) => return Match::Ignore(()),
+                Some(Match::Whitelist(_)) => return Match::Whitelist(()),
+                Some(Match::None) | None => {}
+            }
+        }
+        Match::None
+    }
+}
+
+fn load_ignore_file(directory: &Path) -> Option<Gitignore> {
+    let path = directory.join(IGNORE_FILE_NAME);
+    if !path.is_file() {
+        return None;
+    }
+    let (ignore, error) = Gitignore::new(&path);
+    if let Some(error) = error {
+        warn!("{}: {error}", path.display());
+    }
+    Some(ignore)
+}
+
+/// With symlinks followed, enters each real directory once, so several links into a shared
+/// library or a link pointing back up the tree do not scan the same files again, and a link
+/// into the output is never scanned.
+fn first_visit(entry: &DirEntry, output: &Path, visited: &mut HashSet<PathBuf>) -> bool {
+    if !entry.file_type().is_dir() {
+        return true;
+    }
+    match fs::canonicalize(entry.path()) {
+        Ok(directory) => {
+            (entry.depth() == 0 || !directory.starts_with(output)) && visited.insert(directory)
+        }
+        Err(_) => true,
+    }
+}
+
+/// Keeps the better of `candidate` and the source already found with its ID, returning
+/// the one that lost.
+fn select_best_source(
+    sources: &mut BTreeMap<String, SourceItem>,
+    candidate: SourceItem,
+    compare: Compare,
+    size_tolerance: f64,
+) -> Option<SourceItem> {
     match sources.get(&candidate.id) {
-        Some(current) if !candidate.variant.is_better_than(&current.variant) => {}
-        _ => {
-            sources.insert(candidate.id.clone(), candidate);
+        Some(current)
+            if !candidate
+                .variant
+                .is_better_than(&current.variant, compare, size_tolerance) =>
+        {
+            Some(candidate)
+        }
+        _ => sources.insert(candidate.id.clone(), candidate),
+    }
+}
+
+/// Groups the IDs of sources that are the same song under `key`, best copy first: lossless
+/// before lossy, then the higher bitrate, then the larger file. Sources without the data the
+/// key needs are never grouped.
+fn duplicate_groups(sources: &BTreeMap<String, SourceItem>, key: DedupeKey) -> Vec<Vec<String>> {
+    let mut groups = BTreeMap::<String, Vec<&SourceItem>>::new();
+    for source in sources.values() {
+        let group_key = match key {
+            DedupeKey::Hash => source.variant.hash.clone(),
+            DedupeKey::Tags => match (&source.tags.artist, &source.tags.title) {
+                (Some(artist), Some(title)) => Some(format!(
+                    "{}|{}",
+                    dump::normalize(artist),
+                    dump::normalize(title)
+                )),
+                _ => None,
+            },
+        };
+        if let Some(group_key) = group_key {
+            groups.entry(group_key).or_default().push(source);
         }
     }
+    groups
+        .into_values()
+        .filter(|group| group.len() > 1)
+        .map(|mut group| {
+            // `is_better_than` falls back to sizes when only one side has a bitrate, which is
+            // not a total order, so the best copy is ranked by an explicit key instead.
+            group.sort_by_key(|source| {
+                (
+                    Reverse(dump::format_rank(&source.variant.format)),
+                    Reverse(source.variant.bitrate.unwrap_or(0)),
+                    Reverse(source.variant.size),
+                    &source.path,
+                )
+            });
+            group.into_iter().map(|source| source.id.clone()).collect()
+        })
+        .collect()
 }
 
-fn build_output_index(output: &Path, pool: &rayon::ThreadPool, cancel: &AtomicBool) -> OutputIndex {
-    let paths = WalkDir::new(output)
+/// Lists the audio files in the output tree, skipping w4dj's temporary files. `found`
+/// gets the number of files listed so far.
+fn walk_output(output: &Path, cancel: &AtomicBool, found: &(dyn Fn(usize) + Sync)) -> Vec<PathBuf> {
+    let mut count = 0;
+    let (originals, staging) = (output.join(ORIGINALS_DIR), output.join(STAGING_DIR));
+    WalkDir::new(output)
         .follow_links(false)
         .into_iter()
+        .filter_entry(|entry| entry.path() != originals && entry.path() != staging)
         .filter_map(|entry| {
             if cancel.load(Ordering::Relaxed) {
                 return None;
@@ -444,16 +2901,27 @@ fn build_output_index(output: &Path, pool: &rayon::ThreadPool, cancel: &AtomicBo
                         && is_supported(entry.path())
                         && !is_temporary(entry.path()) =>
                 {
+                    count += 1;
+                    found(count);
                     Some(entry.path().to_path_buf())
                 }
                 Ok(_) => None,
                 Err(error) => {
-                    eprintln!("output scan warning: {error}");
+                    warn!("failed to scan output: {error}");
                     None
                 }
             }
         })
-        .collect::<Vec<_>>();
+        .collect()
+}
+
+fn build_output_index(
+    paths: Vec<PathBuf>,
+    config: &Config,
+    pool: &rayon::ThreadPool,
+    cancel: &AtomicBool,
+) -> OutputIndex {
+    let match_by = config.options.match_by.unwrap_or_default();
     let identities = pool.install(|| {
         paths
             .par_iter()
@@ -462,9 +2930,22 @@ fn build_output_index(output: &Path, pool: &rayon::ThreadPool, cancel: &AtomicBo
                     None
                 } else {
                     match dump::inspect_output(path) {
-                        Ok(identity) => Some((path.clone(), identity)),
+                        Ok(mut identity) => {
+                            if !identity.has_embedded_id
+                                && let Some(key) = match_key(match_by, Some(&config.output), path)
+                            {
+                                if identity.id == identity.fallback_id {
+                                    identity.id = key.clone();
+                                }
+                                identity.fallback_id = key;
+                            }
+                            Some((path.clone(), identity))
+                        }
                         Err(error) => {
-                            eprintln!("output metadata warning for {}: {error:#}", path.display());
+                            warn!(
+                                "failed to read output metadata for {}: {error:#}",
+                                path.display()
+                            );
                             None
                         }
                     }
@@ -491,13 +2972,35 @@ fn build_output_index(output: &Path, pool: &rayon::ThreadPool, cancel: &AtomicBo
     index
 }
 
+/// The ID of a song without a platform or W4DJ ID under `match_by`, computed from its path
+/// below `root` (an input or the output); `None` keeps the tag-based ID.
+fn match_key(match_by: MatchBy, root: Option<&Path>, path: &Path) -> Option<String> {
+    match match_by {
+        MatchBy::Tags => None,
+        MatchBy::Stem => Some(dump::name_id("stem", &path.file_stem()?.to_string_lossy())),
+        MatchBy::Relpath => {
+            // Archive entries have no input folder; their file name is all there is.
+            let relative = root
+                .and_then(|root| path.strip_prefix(root).ok())
+                .unwrap_or(Path::new(path.file_name()?))
+                .with_extension("");
+            let name = relative
+                .components()
+                .map(|component| component.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/");
+            Some(dump::name_id("path", &name))
+        }
+    }
+}
+
 fn find_indexed_output(index: &OutputIndex, source: &SourceItem) -> Option<PathBuf> {
     if let Some(paths) = index.by_id.get(&source.id)
         && let Some(path) = paths.iter().min()
     {
         if paths.len() > 1 {
-            eprintln!(
-                "output warning: {} files carry track ID {}; using {}",
+            warn!(
+                "{} files carry track ID {}; using {}",
                 paths.len(),
                 source.id,
                 path.display()
@@ -529,15 +3032,49 @@ fn identity_matches(identity: &OutputIdentity, source: &SourceItem) -> bool {
         || (!identity.has_embedded_id && identity.fallback_id == source.fallback_id)
 }
 
-fn output_extension_matches(config: &Config, source: &SourceItem, output: &Path) -> bool {
+fn output_extension_matches(mode: Mode, source: &SourceItem, output: &Path) -> bool {
     output
         .extension()
         .and_then(|extension| extension.to_str())
         .is_some_and(|extension| {
-            extension.eq_ignore_ascii_case(config.mode.extension(&source.variant.format))
+            extension.eq_ignore_ascii_case(mode.extension(&source.variant.format))
         })
 }
 
+/// The mode a source is synced with. MP3 mode copies MP3 sources at or below
+/// `transcode_above_bitrate`, since re-encoding them only loses quality; sources without a
+/// known bitrate are transcoded as usual.
+fn source_mode(config: &Config, source: &SourceItem) -> Mode {
+    let mode = config.mode_for(&source.path);
+    let Some(threshold) = config.options.transcode_above_bitrate else {
+        return mode;
+    };
+    if mode != Mode::Mp3 || source.variant.format != "mp3" {
+        return mode;
+    }
+    match source.variant.bitrate {
+        Some(kbps) if kbps <= u64::from(threshold.0) => {
+            debug!(
+                "{}: {kbps} kbit/s is at or below {}k, copying",
+                source.path.display(),
+                threshold.0
+            );
+            Mode::Original
+        }
+        bitrate => {
+            debug!(
+                "{}: {} is above {}k, transcoding",
+                source.path.display(),
+                bitrate.map_or("unknown bitrate".to_string(), |kbps| format!(
+                    "{kbps} kbit/s"
+                )),
+                threshold.0
+            );
+            mode
+        }
+    }
+}
+
 fn build_claims(
     entries: &BTreeMap<String, ManifestEntry>,
     output_root: &Path,
@@ -575,7 +3112,7 @@ fn reserve_target(
         } else {
             format!("{stem} [{suffix}-{number}]")
         };
-        let path = parent.join(name).with_extension(extension);
+        let path = append_extension(parent.join(name), extension);
         if target_available(&path, source, claims) {
             claims.insert(path_key(&path), source.id.clone());
             return path;
@@ -613,11 +3150,62 @@ fn id_suffix(id: &str) -> String {
 }
 
 fn path_key(path: &Path) -> String {
-    let value = path.to_string_lossy();
-    if cfg!(windows) {
+    // macOS stores names decomposed, so they are composed first to match the same name
+    // from elsewhere.
+    let value = path.to_string_lossy().nfc().collect::<String>();
+    if cfg!(any(windows, target_os = "macos")) {
         value.to_lowercase()
     } else {
-        value.into_owned()
+        value
+    }
+}
+
+/// Where `keep_original` keeps the untranscoded audio of `target`: the same path under
+/// [`ORIGINALS_DIR`], with the extension of the source format. `None` unless the option is
+/// set and `mode` transcodes.
+fn original_path(
+    config: &Config,
+    mode: Mode,
+    target: &Path,
+    format: &str,
+) -> Result<Option<PathBuf>> {
+    if config.options.keep_original != Some(true) || !mode.needs_ffmpeg() {
+        return Ok(None);
+    }
+    // `target` always ends in its own extension, so only that one is swapped for the
+    // source format; dots inside the name stay.
+    let relative = relative_output(&config.output, target)?;
+    let stem = relative.file_stem().unwrap_or_default().to_os_string();
+    Ok(Some(append_extension(
+        config
+            .output
+            .join(ORIGINALS_DIR)
+            .join(relative.with_file_name(stem)),
+        format,
+    )))
+}
+
+/// Deletes the original a re-synced song kept before, when this run kept none or kept it
+/// under another name.
+fn remove_replaced_original(
+    config: &Config,
+    previous: Option<&ManifestEntry>,
+    original: &Option<PathBuf>,
+) {
+    let Some(old) = previous.and_then(|entry| entry.original.as_ref()) else {
+        return;
+    };
+    if Some(old) == original.as_ref() {
+        return;
+    }
+    let path = config.output.join(old);
+    if path.is_file()
+        && let Err(error) = dump::remove_output(&path, config.options.use_trash.unwrap_or(false))
+    {
+        warn!(
+            "failed to delete replaced original {}: {error}",
+            path.display()
+        );
     }
 }
 
@@ -684,18 +3272,28 @@ fn safe_relative_path(path: &Path) -> bool {
             .all(|component| matches!(component, Component::Normal(_)))
 }
 
-fn is_supported(path: &Path) -> bool {
+/// Plain audio formats and NCM, besides the encrypted extensions in `dump`.
+pub(crate) const AUDIO_EXTENSIONS: [&str; 7] = ["mp3", "flac", "wav", "m4a", "ogg", "opus", "ncm"];
+
+pub(crate) fn is_supported(path: &Path) -> bool {
     path.extension()
         .and_then(|extension| extension.to_str())
         .is_some_and(|extension| {
-            matches!(
-                extension.to_ascii_lowercase().as_str(),
-                "ncm" | "mp3" | "flac" | "wav"
-            )
+            let extension = extension.to_ascii_lowercase();
+            AUDIO_EXTENSIONS.contains(&extension.as_str())
+                || dump::QMC_EXTENSIONS.contains(&extension.as_str())
+                || dump::KGM_EXTENSIONS.contains(&extension.as_str())
+                || dump::UNSUPPORTED_ENCRYPTED_EXTENSIONS.contains(&extension.as_str())
         })
 }
 
-fn is_temporary(path: &Path) -> bool {
+pub(crate) fn is_archive(path: &Path) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("zip"))
+}
+
+pub(crate) fn is_temporary(path: &Path) -> bool {
     path.file_name()
         .and_then(|name| name.to_str())
         .is_some_and(|name| name.starts_with(".w4dj-"))
@@ -711,6 +3309,8 @@ mod tests {
     use tempfile::tempdir;
 
     use super::*;
+    use crate::config::{Bitrate, ByteSize};
+    use crate::dump::SourceTags;
 
     #[test]
     fn pre_cancelled_sync_reports_cancellation() -> Result<()> {
@@ -723,6 +3323,7 @@ mod tests {
             inputs: vec![input],
             output: output.clone(),
             mode: crate::config::Mode::Original,
+            options: SyncOptions::default(),
         };
         let cancel = AtomicBool::new(true);
         let cancelled = Mutex::new(false);
@@ -751,6 +3352,7 @@ mod tests {
             inputs: vec![input],
             output: output.clone(),
             mode: crate::config::Mode::Original,
+            options: SyncOptions::default(),
         };
         let cancel = AtomicBool::new(false);
         let cancelled = Mutex::new(false);
@@ -771,33 +3373,1915 @@ mod tests {
         Ok(())
     }
 
-    fn source(id: &str) -> SourceItem {
-        SourceItem {
-            path: PathBuf::from("source.ncm"),
-            id: id.to_string(),
-            fallback_id: "meta:v1:test".to_string(),
-            display_name: "Song".to_string(),
-            variant: SourceVariant {
-                format: "flac".to_string(),
-                bitrate: Some(900),
-                size: 100,
+    #[test]
+    fn watchdog_repeats_notices_once_per_threshold_until_the_file_finishes() {
+        let watchdog = Watchdog::new(Duration::from_secs(60));
+        let guard = watchdog.track(0, "Long Mix");
+        let started = watchdog.active.lock().unwrap()[&0].started;
+
+        assert!(
+            watchdog
+                .overdue(started + Duration::from_secs(59))
+                .is_empty()
+        );
+        assert_eq!(
+            watchdog.overdue(started + Duration::from_secs(61)),
+            vec![("Long Mix".to_string(), Duration::from_secs(61))]
+        );
+        assert!(
+            watchdog
+                .overdue(started + Duration::from_secs(90))
+                .is_empty()
+        );
+        assert_eq!(
+            watchdog.overdue(started + Duration::from_secs(125)).len(),
+            1
+        );
+
+        drop(guard);
+        assert!(
+            watchdog
+                .overdue(started + Duration::from_secs(600))
+                .is_empty()
+        );
+        assert_eq!(format_duration(Duration::from_secs(125)), "2m05s");
+    }
+
+    #[test]
+    fn throttle_spreads_writes_over_the_configured_rate() {
+        // 8 Mbit/s is one megabyte per second.
+        let throttle = Throttle::from_mbps(8.0);
+        let cancel = AtomicBool::new(false);
+        let started = Instant::now();
+
+        throttle.wait(100_000, &cancel);
+        assert!(started.elapsed() < Duration::from_millis(50));
+        throttle.wait(100_000, &cancel);
+        throttle.wait(100_000, &cancel);
+        assert!(started.elapsed() >= Duration::from_millis(200));
+
+        let cancelled = AtomicBool::new(true);
+        let waited = Instant::now();
+        throttle.wait(10_000_000, &cancelled);
+        throttle.wait(1, &cancelled);
+        assert!(waited.elapsed() < Duration::from_millis(50));
+    }
+
+    #[test]
+    fn byte_budget_still_fits_smaller_files_after_a_refusal() {
+        let budget = ByteBudget::new(100);
+        assert!(budget.try_reserve(80));
+        assert!(!budget.try_reserve(30));
+        assert!(budget.try_reserve(20));
+        assert!(!budget.try_reserve(1));
+        budget.settle(80, 50);
+        assert!(budget.try_reserve(30));
+    }
+
+    #[test]
+    fn byte_budget_defers_files_for_the_next_run() -> Result<()> {
+        let workspace = tempdir()?;
+        let input = workspace.path().join("input");
+        let output = workspace.path().join("output");
+        fs::create_dir_all(&input)?;
+        fs::create_dir_all(&output)?;
+        write_test_wav(&input.join("First.wav"), Some("ncm:1"))?;
+        write_test_wav(&input.join("Second.wav"), Some("ncm:2"))?;
+        let mut config = Config {
+            inputs: vec![input],
+            output: output.clone(),
+            mode: crate::config::Mode::Original,
+            options: SyncOptions {
+                max_bytes: Some(ByteSize(20_000)),
+                ..SyncOptions::default()
             },
-        }
+        };
+
+        let summary = run_with_progress(&config, |_| {})?;
+        assert_eq!(summary.processed, 1);
+        assert_eq!(summary.deferred, 1);
+
+        config.options.max_bytes = None;
+        let summary = run_with_progress(&config, |_| {})?;
+        assert_eq!(summary.processed, 1);
+        assert_eq!(summary.deferred, 0);
+        Ok(())
     }
 
     #[test]
-    fn different_ids_receive_stable_filename_suffixes() {
-        let mut claims = HashMap::new();
-        claims.insert("song.mp3".to_string(), "ncm:1".to_string());
-        let target = reserve_target(PathBuf::from("Song.mp3"), &source("ncm:2"), &mut claims);
-        assert_eq!(target, PathBuf::from("Song [ncm-2].mp3"));
+    fn a_stop_request_finishes_started_files_and_leaves_the_rest() -> Result<()> {
+        let workspace = tempdir()?;
+        let input = workspace.path().join("input");
+        let output = workspace.path().join("output");
+        fs::create_dir_all(&input)?;
+        fs::create_dir_all(&output)?;
+        write_test_wav(&input.join("First.wav"), Some("ncm:1"))?;
+        write_test_wav(&input.join("Second.wav"), Some("ncm:2"))?;
+        let config = Config {
+            inputs: vec![input],
+            output: output.clone(),
+            mode: crate::config::Mode::Original,
+            options: SyncOptions {
+                jobs: Some(1),
+                ..SyncOptions::default()
+            },
+        };
+        let stop = AtomicBool::new(false);
+
+        let summary = run_until(&config, &AtomicBool::new(false), &stop, |event| {
+            if let SyncEvent::Progress { completed: 1, .. } = event {
+                stop.store(true, Ordering::Relaxed);
+            }
+        })?;
+
+        assert_eq!(summary.processed, 1);
+        assert_eq!(summary.stopped, 1);
+        assert_eq!(summary.outputs, 1);
+        let leftovers = fs::read_dir(&output)?
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| is_temporary(&entry.path()) && is_supported(&entry.path()))
+            .count();
+        assert_eq!(leftovers, 0);
+        Ok(())
     }
 
     #[test]
-    fn manifest_paths_cannot_escape_the_output_directory() {
-        assert!(safe_relative_path(Path::new("artist/song.mp3")));
-        assert!(!safe_relative_path(Path::new("../song.mp3")));
-        assert!(!safe_relative_path(Path::new("C:/song.mp3")));
+    fn byte_progress_adds_up_to_the_selected_sources() -> Result<()> {
+        let workspace = tempdir()?;
+        let input = workspace.path().join("input");
+        let output = workspace.path().join("output");
+        fs::create_dir_all(&input)?;
+        fs::create_dir_all(&output)?;
+        write_test_wav(&input.join("First.wav"), Some("ncm:1"))?;
+        write_test_wav(&input.join("Second.wav"), Some("ncm:2"))?;
+        let expected = fs::metadata(input.join("First.wav"))?.len()
+            + fs::metadata(input.join("Second.wav"))?.len();
+        let config = Config {
+            inputs: vec![input],
+            output,
+            mode: crate::config::Mode::Original,
+            options: SyncOptions::default(),
+        };
+
+        let transferred = std::sync::Mutex::new(Vec::new());
+        run_with_progress(&config, |event| {
+            if let SyncEvent::Transferred { bytes, total } = event {
+                transferred.lock().unwrap().push((bytes, total));
+            }
+        })?;
+
+        let transferred = transferred.into_inner().unwrap();
+        assert!(transferred.windows(2).all(|pair| pair[0].0 <= pair[1].0));
+        assert_eq!(transferred.last(), Some(&(expected, expected)));
+        Ok(())
+    }
+
+    #[test]
+    fn scanning_reports_the_files_found_so_far() -> Result<()> {
+        let workspace = tempdir()?;
+        let input = workspace.path().join("input");
+        fs::create_dir_all(&input)?;
+        for index in 0..SCAN_REPORT_EVERY + 1 {
+            write_test_wav(&input.join(format!("{index}.wav")), None)?;
+        }
+        let config = Config {
+            inputs: vec![input],
+            output: workspace.path().join("output"),
+            mode: crate::config::Mode::Original,
+            options: SyncOptions {
+                dry_run: true,
+                ..SyncOptions::default()
+            },
+        };
+
+        let scanning = std::sync::Mutex::new(Vec::new());
+        run_with_progress(&config, |event| {
+            if let SyncEvent::Scanning { inputs, outputs } = event {
+                scanning.lock().unwrap().push((inputs, outputs));
+            }
+        })?;
+
+        assert_eq!(scanning.into_inner().unwrap(), vec![(SCAN_REPORT_EVERY, 0)]);
+        Ok(())
+    }
+
+    #[test]
+    fn summary_totals_written_bytes_per_action() -> Result<()> {
+        let workspace = tempdir()?;
+        let input = workspace.path().join("input");
+        let output = workspace.path().join("output");
+        fs::create_dir_all(&input)?;
+        fs::create_dir_all(&output)?;
+        write_test_wav(&input.join("First.wav"), Some("ncm:1"))?;
+        write_test_wav(&input.join("Second.wav"), Some("ncm:2"))?;
+        let config = Config {
+            inputs: vec![input],
+            output: output.clone(),
+            mode: crate::config::Mode::Original,
+            options: SyncOptions::default(),
+        };
+
+        let summary = run_with_progress(&config, |_| {})?;
+
+        let written = fs::metadata(output.join("First.wav"))?.len()
+            + fs::metadata(output.join("Second.wav"))?.len();
+        assert_eq!(summary.bytes_written, written);
+        let copies = &summary.totals[&JobAction::Copy];
+        assert_eq!((copies.files, copies.bytes), (2, written));
+        assert_eq!(summary.totals.len(), 1);
+        assert_eq!(format_duration(Duration::from_secs(252)), "4m12s");
+        assert_eq!(format_duration(Duration::from_secs(3780)), "1h03m");
+        Ok(())
+    }
+
+    #[test]
+    fn a_second_run_on_the_same_output_is_refused_or_waits() -> Result<()> {
+        let workspace = tempdir()?;
+        let input = workspace.path().join("input");
+        let output = workspace.path().join("output");
+        fs::create_dir_all(&input)?;
+        fs::create_dir_all(&output)?;
+        write_test_wav(&input.join("Song.wav"), Some("ncm:1"))?;
+        let mut config = Config {
+            inputs: vec![input],
+            output: output.clone(),
+            mode: crate::config::Mode::Original,
+            options: SyncOptions::default(),
+        };
+
+        let cancel = AtomicBool::new(false);
+        let held = RunLock::acquire(&output, false, &cancel, &|_| {})?;
+        let pid = fs::read_to_string(output.join(LOCK_NAME))?;
+        assert_eq!(pid, process::id().to_string());
+        let error = run_with_progress(&config, |_| {}).unwrap_err();
+        assert!(format!("{error:#}").contains(&format!("PID {pid}")));
+
+        config.options.wait_for_lock = Some(true);
+        let summary = thread::scope(|scope| {
+            scope.spawn(move || {
+                thread::sleep(Duration::from_millis(300));
+                drop(held);
+            });
+            run_with_progress(&config, |_| {})
+        })?;
+        assert_eq!(summary.processed, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn dry_run_plans_jobs_without_creating_the_output() -> Result<()> {
+        let workspace = tempdir()?;
+        let input = workspace.path().join("input");
+        let output = workspace.path().join("output");
+        fs::create_dir_all(&input)?;
+        write_test_wav(&input.join("Song.wav"), Some("ncm:1"))?;
+        let config = Config::from_paths(
+            vec![input.clone()],
+            output.clone(),
+            crate::config::Mode::Original,
+            SyncOptions {
+                dry_run: true,
+                ..SyncOptions::default()
+            },
+        )?;
+
+        let planned = std::sync::Mutex::new(Vec::new());
+        let summary = run_with_progress(&config, |event| {
+            if let SyncEvent::Planned {
+                source,
+                action,
+                target,
+            } = event
+            {
+                planned.lock().unwrap().push((source, action, target));
+            }
+        })?;
+
+        assert_eq!(summary.processed, 1);
+        assert_eq!(summary.bytes_written, 0);
+        assert_eq!(summary.totals[&JobAction::Copy].files, 1);
+        assert_eq!(
+            summary.totals[&JobAction::Copy].bytes,
+            fs::metadata(input.join("Song.wav"))?.len()
+        );
+        assert!(format_plan(&summary).starts_with("Plan: 1 copy ("));
+        assert!(summary.estimated_secs.is_some());
+        assert_eq!(
+            planned.into_inner().unwrap(),
+            vec![(
+                fs::canonicalize(input.join("Song.wav"))?,
+                JobAction::Copy,
+                output.join("Song.wav")
+            )]
+        );
+        assert!(!output.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn delete_removes_only_tracked_outputs_whose_source_is_gone() -> Result<()> {
+        let workspace = tempdir()?;
+        let input = workspace.path().join("input");
+        let output = workspace.path().join("output");
+        fs::create_dir_all(&input)?;
+        fs::create_dir_all(&output)?;
+        write_test_wav(&input.join("Keep.wav"), Some("ncm:1"))?;
+        write_test_wav(&input.join("Gone.wav"), Some("ncm:2"))?;
+        write_test_wav(&output.join("Untracked.wav"), Some("ncm:3"))?;
+        let mut config = Config {
+            inputs: vec![input.clone()],
+            output: output.clone(),
+            mode: crate::config::Mode::Original,
+            options: SyncOptions::default(),
+        };
+        run_with_progress(&config, |_| {})?;
+        fs::remove_file(input.join("Gone.wav"))?;
+
+        config.options.delete = true;
+        config.options.dry_run = true;
+        let summary = run_with_progress(&config, |_| {})?;
+        assert_eq!(summary.removed, 1);
+        assert!(output.join("Gone.wav").is_file());
+
+        config.options.dry_run = false;
+        let summary = run_with_progress(&config, |_| {})?;
+        assert_eq!(summary.removed, 1);
+        assert!(!output.join("Gone.wav").exists());
+        assert!(output.join("Keep.wav").is_file());
+        assert!(output.join("Untracked.wav").is_file());
+
+        let summary = run_with_progress(&config, |_| {})?;
+        assert_eq!(summary.removed, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn unchanged_outputs_are_trusted_and_missing_ones_are_forgotten() -> Result<()> {
+        let workspace = tempdir()?;
+        let input = workspace.path().join("input");
+        let output = workspace.path().join("output");
+        fs::create_dir_all(&input)?;
+        fs::create_dir_all(&output)?;
+        write_test_wav(&input.join("Song.wav"), Some("ncm:1"))?;
+        write_test_wav(&input.join("Other.wav"), Some("ncm:2"))?;
+        let config = Config {
+            inputs: vec![input.clone()],
+            output: output.clone(),
+            mode: crate::config::Mode::Original,
+            options: SyncOptions::default(),
+        };
+        run_with_progress(&config, |_| {})?;
+        let manifest = load_manifest(&output.join(MANIFEST_NAME))?;
+        assert!(manifest.entries.iter().all(|entry| entry.stamp.is_some()));
+
+        // The stamp matches, so the tag reader never sees the now unreadable output.
+        let song = output.join("Song.wav");
+        let modified = fs::metadata(&song)?.modified()?;
+        let length = fs::metadata(&song)?.len() as usize;
+        fs::write(&song, vec![0_u8; length])?;
+        fs::File::options()
+            .write(true)
+            .open(&song)?
+            .set_modified(modified)?;
+        fs::remove_file(input.join("Other.wav"))?;
+        fs::remove_file(output.join("Other.wav"))?;
+        let summary = run_with_progress(&config, |_| {})?;
+        assert_eq!(summary.skipped, 1);
+        assert_eq!(summary.processed, 0);
+
+        let manifest = load_manifest(&output.join(MANIFEST_NAME))?;
+        assert_eq!(manifest.entries.len(), 1);
+        assert_eq!(manifest.entries[0].id, "ncm:1");
+        Ok(())
+    }
+
+    #[test]
+    fn sanitized_names_are_safe_on_fat_file_systems() {
+        assert_eq!(sanitize_file_name("A: B? <C>*"), "A_ B_ _C__");
+        assert_eq!(sanitize_file_name("晴天..."), "晴天");
+        assert_eq!(sanitize_file_name(" . "), "track");
+        let long = "长".repeat(400);
+        assert_eq!(
+            sanitize_file_name(&long).chars().count(),
+            MAX_SANITIZED_NAME_CHARS
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn followed_symlinks_are_scanned_once_and_loops_end() -> Result<()> {
+        use std::os::unix::fs::symlink;
+
+        let workspace = tempdir()?;
+        let input = workspace.path().join("input");
+        let shared = workspace.path().join("shared").join("Album");
+        let output = workspace.path().join("output");
+        fs::create_dir_all(&input)?;
+        fs::create_dir_all(&shared)?;
+        write_test_wav(&input.join("Local.wav"), Some("ncm:1"))?;
+        write_test_wav(&shared.join("Shared.wav"), Some("ncm:2"))?;
+        symlink(&shared, input.join("Album"))?;
+        symlink(&shared, input.join("Same Album"))?;
+        symlink(&input, input.join("Loop"))?;
+        let options = SyncOptions::default();
+        let filter = PathFilter::new(&options)?;
+        let cancel = AtomicBool::new(false);
+        let input = fs::canonicalize(&input)?;
+        let inputs = [input.clone()];
+
+        let skipped = scan_inputs(&inputs, &output, &filter, &options, &cancel, &|_| {})?;
+        assert_eq!(skipped, vec![input.join("Local.wav")]);
+
+        let options = SyncOptions {
+            follow_symlinks: Some(true),
+            ..SyncOptions::default()
+        };
+        let followed = scan_inputs(&inputs, &output, &filter, &options, &cancel, &|_| {})?;
+        assert_eq!(
+            followed,
+            vec![
+                input.join("Local.wav"),
+                fs::canonicalize(shared.join("Shared.wav"))?,
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn skip_hidden_prunes_dot_folders_and_dot_files() -> Result<()> {
+        let workspace = tempdir()?;
+        let input = fs::canonicalize(workspace.path())?.join("input");
+        let output = workspace.path().join("output");
+        fs::create_dir_all(input.join(".stfolder"))?;
+        write_test_wav(&input.join("Song.wav"), Some("ncm:1"))?;
+        write_test_wav(&input.join(".stfolder").join("Song.wav"), Some("ncm:2"))?;
+        write_test_wav(
+            &input.join(".Song.sync-conflict-20240101.wav"),
+            Some("ncm:3"),
+        )?;
+        let options = SyncOptions {
+            skip_hidden: Some(true),
+            ..SyncOptions::default()
+        };
+        let filter = PathFilter::new(&options)?;
+        let cancel = AtomicBool::new(false);
+        let inputs = [input.clone()];
+
+        let files = scan_inputs(&inputs, &output, &filter, &options, &cancel, &|_| {})?;
+
+        assert_eq!(files, vec![input.join("Song.wav")]);
+        Ok(())
+    }
+
+    #[test]
+    fn extension_allowlists_limit_the_scan_and_reject_unknown_formats() -> Result<()> {
+        let workspace = tempdir()?;
+        let input = fs::canonicalize(workspace.path())?.join("input");
+        let output = workspace.path().join("output");
+        fs::create_dir_all(&input)?;
+        for name in ["Song.mp3", "Song.flac", "Other.FLAC", "Song.wav"] {
+            fs::write(input.join(name), b"audio")?;
+        }
+        fs::write(input.join(IGNORE_FILE_NAME), "!Song.mp3\n")?;
+        let options = SyncOptions {
+            extensions: vec![".flac".to_string()],
+            ..SyncOptions::default()
+        };
+        options.validate()?;
+        let filter = PathFilter::new(&options)?;
+        let cancel = AtomicBool::new(false);
+        let inputs = [input.clone()];
+
+        let mut files = scan_inputs(&inputs, &output, &filter, &options, &cancel, &|_| {})?;
+        files.sort();
+
+        assert_eq!(
+            files,
+            vec![input.join("Other.FLAC"), input.join("Song.flac")]
+        );
+        for extension in ["txt", "mflac"] {
+            let options = SyncOptions {
+                extensions: vec![extension.to_string()],
+                ..SyncOptions::default()
+            };
+            assert!(options.validate().is_err(), "{extension}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn ignore_files_apply_to_their_folder_and_override_exclude() -> Result<()> {
+        let workspace = tempdir()?;
+        let input = fs::canonicalize(workspace.path())?.join("input");
+        let output = workspace.path().join("output");
+        let album = input.join("Album");
+        fs::create_dir_all(album.join("Bonus"))?;
+        fs::create_dir_all(input.join("Podcasts"))?;
+        fs::write(input.join(IGNORE_FILE_NAME), "Podcasts/\n")?;
+        fs::write(album.join(IGNORE_FILE_NAME), "*.wav\n!Keep.wav\n")?;
+        write_test_wav(&input.join("Podcasts").join("Episode.wav"), Some("ncm:1"))?;
+        write_test_wav(&album.join("Drop.wav"), Some("ncm:2"))?;
+        write_test_wav(&album.join("Keep.wav"), Some("ncm:3"))?;
+        write_test_wav(&album.join("Bonus").join("Demo.wav"), Some("ncm:4"))?;
+        write_test_wav(&input.join("Single.wav"), Some("ncm:5"))?;
+        write_test_wav(&input.join("Keep.wav"), Some("ncm:6"))?;
+        let options = SyncOptions {
+            exclude: vec!["**/Keep.wav".to_string()],
+            ..SyncOptions::default()
+        };
+        let filter = PathFilter::new(&options)?;
+        let cancel = AtomicBool::new(false);
+        let inputs = [input.clone()];
+
+        let files = scan_inputs(&inputs, &output, &filter, &options, &cancel, &|_| {})?;
+
+        assert_eq!(
+            files,
+            vec![album.join("Keep.wav"), input.join("Single.wav")]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn preserve_structure_mirrors_input_folders() -> Result<()> {
+        let workspace = tempdir()?;
+        let input = workspace.path().join("input");
+        let output = workspace.path().join("output");
+        let album = input.join("Artist").join("Album");
+        fs::create_dir_all(&album)?;
+        fs::create_dir_all(&output)?;
+        write_test_wav(&album.join("Track.wav"), Some("ncm:1"))?;
+        write_test_wav(&input.join("Loose.wav"), Some("ncm:2"))?;
+        let config = Config::from_paths(
+            vec![input],
+            output.clone(),
+            crate::config::Mode::Original,
+            SyncOptions {
+                preserve_structure: Some(true),
+                ..SyncOptions::default()
+            },
+        )?;
+
+        let summary = run_with_progress(&config, |_| {})?;
+
+        assert_eq!(summary.processed, 2);
+        assert!(output.join("Artist/Album/Track.wav").is_file());
+        assert!(output.join("Loose.wav").is_file());
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn kept_originals_are_skipped_by_scans_and_deleted_with_their_output() -> Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let workspace = tempdir()?;
+        let input = workspace.path().join("input");
+        let output = workspace.path().join("output");
+        fs::create_dir_all(&input)?;
+        fs::create_dir_all(&output)?;
+        let source = input.join("Suite Pt. 2.wav");
+        write_test_wav(&source, Some("ncm:1"))?;
+        // Stands in for FFmpeg by copying the input to the last argument.
+        let ffmpeg = workspace.path().join("ffmpeg");
+        fs::write(
+            &ffmpeg,
+            "#!/bin/sh
+while [ \"$1\" != -i ]; do shift; done
+in=$2
+for last; do :; done
+cp \"$in\" \"$last\"
+",
+        )?;
+        fs::set_permissions(&ffmpeg, fs::Permissions::from_mode(0o755))?;
+        let mut config = Config::from_paths(
+            vec![input],
+            output.clone(),
+            crate::config::Mode::Wav,
+            SyncOptions {
+                keep_original: Some(true),
+                ffmpeg_path: Some(ffmpeg),
+                ..SyncOptions::default()
+            },
+        )?;
+
+        assert_eq!(run_with_progress(&config, |_| {})?.processed, 1);
+        let original = output.join(ORIGINALS_DIR).join("Suite Pt. 2.wav");
+        assert!(output.join("Suite Pt. 2.wav").is_file());
+        assert!(original.is_file());
+        // Without a manifest the output is searched again, and the original must not be
+        // taken for the output of the same song.
+        fs::remove_file(output.join(MANIFEST_NAME))?;
+        let summary = run_with_progress(&config, |_| {})?;
+        assert_eq!((summary.processed, summary.skipped), (0, 1));
+
+        fs::remove_file(&source)?;
+        config.options.delete = true;
+        assert_eq!(run_with_progress(&config, |_| {})?.removed, 1);
+        assert!(!output.join("Suite Pt. 2.wav").exists());
+        assert!(!original.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn organize_lays_out_new_outputs_by_tags() -> Result<()> {
+        let workspace = tempdir()?;
+        let input = workspace.path().join("input");
+        let output = workspace.path().join("output");
+        fs::create_dir_all(&input)?;
+        fs::create_dir_all(&output)?;
+        write_test_wav(&input.join("Tagged.wav"), Some("ncm:1"))?;
+        let untagged = input.join("Untagged.wav");
+        write_test_wav(&untagged, Some("ncm:2"))?;
+        let mut tag = id3::Tag::read_from_path(&untagged)?;
+        tag.remove_album();
+        tag.set_artist("AC/DC");
+        tag.write_to_path(&untagged, Version::Id3v24)?;
+        let dotted = input.join("Dotted.wav");
+        write_test_wav(&dotted, Some("ncm:3"))?;
+        let mut tag = id3::Tag::read_from_path(&dotted)?;
+        tag.set_title("Suite Pt. 2");
+        tag.write_to_path(&dotted, Version::Id3v24)?;
+        let config = Config::from_paths(
+            vec![input],
+            output.clone(),
+            crate::config::Mode::Original,
+            SyncOptions {
+                organize: Some("{artist}/{album}/{title}".to_string()),
+                ..SyncOptions::default()
+            },
+        )?;
+
+        let summary = run_with_progress(&config, |_| {})?;
+
+        assert_eq!(summary.processed, 3);
+        assert!(
+            output
+                .join("Same Artist/Same Album/Same Song.wav")
+                .is_file()
+        );
+        assert!(output.join("AC_DC/Untagged/Same Song.wav").is_file());
+        assert!(
+            output
+                .join("Same Artist/Same Album/Suite Pt. 2.wav")
+                .is_file()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn force_reprocesses_up_to_date_outputs() -> Result<()> {
+        let workspace = tempdir()?;
+        let input = workspace.path().join("input");
+        let output = workspace.path().join("output");
+        fs::create_dir_all(&input)?;
+        fs::create_dir_all(&output)?;
+        write_test_wav(&input.join("Song.wav"), Some("ncm:1"))?;
+        let mut config = Config {
+            inputs: vec![input],
+            output: output.clone(),
+            mode: crate::config::Mode::Original,
+            options: SyncOptions::default(),
+        };
+        assert_eq!(run_with_progress(&config, |_| {})?.processed, 1);
+        assert_eq!(run_with_progress(&config, |_| {})?.skipped, 1);
+
+        config.options.force = true;
+        let summary = run_with_progress(&config, |_| {})?;
+        assert_eq!(summary.processed, 1);
+        assert_eq!(summary.skipped, 0);
+        assert!(output.join("Song.wav").is_file());
+        Ok(())
+    }
+
+    #[test]
+    fn relpath_matching_keeps_equal_names_in_different_folders_apart() -> Result<()> {
+        let workspace = tempdir()?;
+        let input = workspace.path().join("input");
+        fs::create_dir_all(input.join("First"))?;
+        fs::create_dir_all(input.join("Second"))?;
+        write_test_wav(&input.join("First").join("01 Intro.wav"), None)?;
+        write_test_wav(&input.join("Second").join("01 Intro.wav"), None)?;
+        let run = |match_by, output: &str| -> Result<SyncSummary> {
+            let config = Config::from_paths(
+                vec![input.clone()],
+                workspace.path().join(output),
+                crate::config::Mode::Original,
+                SyncOptions {
+                    match_by: Some(match_by),
+                    preserve_structure: Some(true),
+                    ..SyncOptions::default()
+                },
+            )?;
+            Ok(run_with_progress(&config, |_| {})?)
+        };
+
+        assert_eq!(run(MatchBy::Tags, "by-tags")?.processed, 1);
+        assert_eq!(run(MatchBy::Relpath, "by-path")?.processed, 2);
+        let again = run(MatchBy::Relpath, "by-path")?;
+        assert_eq!((again.processed, again.skipped), (0, 2));
+        Ok(())
+    }
+
+    #[test]
+    fn empty_sources_are_skipped_without_failing_the_run() -> Result<()> {
+        let workspace = tempdir()?;
+        let input = workspace.path().join("input");
+        let output = workspace.path().join("output");
+        fs::create_dir_all(&input)?;
+        write_test_wav(&input.join("Song.wav"), Some("ncm:1"))?;
+        fs::write(input.join("Broken.ncm"), b"")?;
+        let config = Config::from_paths(
+            vec![input.clone()],
+            output.clone(),
+            crate::config::Mode::Original,
+            SyncOptions {
+                delete: true,
+                ..SyncOptions::default()
+            },
+        )?;
+
+        let summary = run_with_progress(&config, |_| {})?;
+        assert_eq!(summary.processed, 1);
+        assert_eq!(summary.empty, 1);
+        assert!(summary.errors.is_empty());
+        assert!(!output.join("Broken.ncm").exists());
+
+        // A source truncated to nothing still owns its output.
+        fs::write(input.join("Song.wav"), b"")?;
+        let summary = run_with_progress(&config, |_| {})?;
+        assert_eq!((summary.empty, summary.removed), (2, 0));
+        assert!(output.join("Song.wav").is_file());
+        Ok(())
+    }
+
+    #[test]
+    fn since_skips_sources_modified_before_the_cutoff() -> Result<()> {
+        let workspace = tempdir()?;
+        let input = workspace.path().join("input");
+        let output = workspace.path().join("output");
+        fs::create_dir_all(&input)?;
+        fs::create_dir_all(&output)?;
+        write_test_wav(&input.join("Old.wav"), Some("ncm:1"))?;
+        write_test_wav(&input.join("New.wav"), Some("ncm:2"))?;
+        fs::File::options()
+            .write(true)
+            .open(input.join("Old.wav"))?
+            .set_modified(SystemTime::now() - Duration::from_secs(3 * 86_400))?;
+        let config = Config {
+            inputs: vec![input],
+            output: output.clone(),
+            mode: crate::config::Mode::Original,
+            options: SyncOptions {
+                since: Some("1d".parse()?),
+                ..SyncOptions::default()
+            },
+        };
+
+        assert_eq!(run_with_progress(&config, |_| {})?.processed, 1);
+        assert!(output.join("New.wav").is_file());
+        assert!(!output.join("Old.wav").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn duplicates_are_reported_and_optionally_skipped() -> Result<()> {
+        let workspace = tempdir()?;
+        let input = workspace.path().join("input");
+        let output = workspace.path().join("output");
+        fs::create_dir_all(&input)?;
+        fs::create_dir_all(&output)?;
+        write_test_wav(&input.join("Download.wav"), Some("ncm:1"))?;
+        write_test_wav(&input.join("Download (1).wav"), Some("ncm:2"))?;
+        let mut config = Config {
+            inputs: vec![input],
+            output: output.clone(),
+            mode: crate::config::Mode::Original,
+            options: SyncOptions {
+                dedupe_source: Some(DedupeKey::Tags),
+                dry_run: true,
+                ..SyncOptions::default()
+            },
+        };
+
+        let summary = run_with_progress(&config, |_| {})?;
+        assert_eq!(summary.duplicates, 1);
+        assert_eq!(summary.processed, 2);
+
+        config.options.skip_duplicates = Some(true);
+        config.options.dry_run = false;
+        let summary = run_with_progress(&config, |_| {})?;
+        assert_eq!(summary.duplicates, 1);
+        assert_eq!(summary.processed, 1);
+        assert_eq!(summary.sources, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn hash_comparison_resyncs_same_size_edits() -> Result<()> {
+        let workspace = tempdir()?;
+        let input = workspace.path().join("input");
+        let output = workspace.path().join("output");
+        fs::create_dir_all(&input)?;
+        fs::create_dir_all(&output)?;
+        let song = input.join("Song.wav");
+        write_test_wav(&song, Some("ncm:1"))?;
+        let config = Config {
+            inputs: vec![input],
+            output: output.clone(),
+            mode: crate::config::Mode::Original,
+            options: SyncOptions {
+                compare: Some(Compare::Hash),
+                ..SyncOptions::default()
+            },
+        };
+        assert_eq!(run_with_progress(&config, |_| {})?.processed, 1);
+        assert_eq!(run_with_progress(&config, |_| {})?.skipped, 1);
+
+        let mut bytes = fs::read(&song)?;
+        bytes[100] ^= 0xff;
+        fs::write(&song, bytes)?;
+        let summary = run_with_progress(&config, |_| {})?;
+        assert_eq!(summary.processed, 1);
+        assert_eq!(run_with_progress(&config, |_| {})?.skipped, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn tag_comparison_resyncs_retagged_sources() -> Result<()> {
+        let workspace = tempdir()?;
+        let input = workspace.path().join("input");
+        let output = workspace.path().join("output");
+        fs::create_dir_all(&input)?;
+        fs::create_dir_all(&output)?;
+        let song = input.join("Song.wav");
+        write_test_wav(&song, Some("ncm:1"))?;
+        let mut config = Config {
+            inputs: vec![input],
+            output: output.clone(),
+            mode: crate::config::Mode::Original,
+            options: SyncOptions::default(),
+        };
+        assert_eq!(run_with_progress(&config, |_| {})?.processed, 1);
+
+        let mut tag = id3::Tag::read_from_path(&song)?;
+        tag.set_artist("Some Artist");
+        tag.write_to_path(&song, Version::Id3v24)?;
+        assert_eq!(run_with_progress(&config, |_| {})?.skipped, 1);
+
+        config.options.compare = Some(Compare::Tags);
+        assert_eq!(run_with_progress(&config, |_| {})?.processed, 1);
+        assert_eq!(run_with_progress(&config, |_| {})?.skipped, 1);
+        let retagged = id3::Tag::read_from_path(output.join("Song.wav"))?;
+        assert_eq!(retagged.artist(), Some("Some Artist"));
+
+        // Unchanged files are judged by their cached summaries, not read again.
+        let cache_path = output.join(".w4dj-tags.json");
+        let mut cache: serde_json::Value = serde_json::from_slice(&fs::read(&cache_path)?)?;
+        let entries = cache.as_array_mut().context("tag cache is not a list")?;
+        assert_eq!(entries.len(), 2);
+        for entry in entries {
+            if entry["path"] == serde_json::json!(output.join("Song.wav")) {
+                entry["value"]["artist"] = serde_json::json!("someone else");
+            }
+        }
+        fs::write(&cache_path, serde_json::to_vec(&cache)?)?;
+        assert_eq!(run_with_progress(&config, |_| {})?.processed, 1);
+
+        // An output whose tags cannot be read is kept rather than resynced on every run.
+        let synced = output.join("Song.wav");
+        let metadata = fs::metadata(&synced)?;
+        fs::write(&synced, vec![0_u8; metadata.len() as usize])?;
+        fs::File::options()
+            .write(true)
+            .open(&synced)?
+            .set_modified(metadata.modified()?)?;
+        assert_eq!(run_with_progress(&config, |_| {})?.skipped, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn staged_runs_only_touch_the_output_when_everything_succeeded() -> Result<()> {
+        let workspace = tempdir()?;
+        let input = workspace.path().join("input");
+        let output = workspace.path().join("output");
+        fs::create_dir_all(&input)?;
+        fs::create_dir_all(&output)?;
+        write_test_wav(&input.join("First.wav"), Some("ncm:1"))?;
+        let mut config = Config {
+            inputs: vec![input.clone()],
+            output: output.clone(),
+            mode: crate::config::Mode::Original,
+            options: SyncOptions {
+                staging: true,
+                keep_going: Some(true),
+                ..SyncOptions::default()
+            },
+        };
+        let staging = output.join(STAGING_DIR);
+
+        assert_eq!(run_with_progress(&config, |_| {})?.processed, 1);
+        assert!(output.join("First.wav").is_file());
+        assert!(!staging.exists());
+
+        write_test_wav(&input.join("Second.wav"), Some("ncm:2"))?;
+        fs::write(input.join("Broken.flac"), b"not audio")?;
+        let summary = run_with_progress(&config, |_| {})?;
+        assert_eq!(
+            (summary.processed, summary.staged, summary.failed),
+            (0, 1, 1)
+        );
+        assert!(!output.join("Second.wav").exists());
+        assert!(staging.join("Second.wav").is_file());
+
+        // A promotion that stopped halfway is never discarded automatically.
+        fs::write(staging.join(".w4dj-promoting"), b"")?;
+        let error = run_with_progress(&config, |_| {}).unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .contains("stopped while moving staged files")
+        );
+        assert!(staging.join("Second.wav").is_file());
+        fs::remove_file(staging.join(".w4dj-promoting"))?;
+
+        fs::remove_file(input.join("Broken.flac"))?;
+        config.options.keep_going = None;
+
+        // Files left behind by Ctrl+C or held back by max_bytes keep the run partial too.
+        write_test_wav(&input.join("Third.wav"), Some("ncm:3"))?;
+        config.options.jobs = Some(1);
+        let stop = AtomicBool::new(false);
+        let summary = run_until(&config, &AtomicBool::new(false), &stop, |event| {
+            if let SyncEvent::Progress { completed: 1, .. } = event {
+                stop.store(true, Ordering::Relaxed);
+            }
+        })?;
+        assert_eq!((summary.processed, summary.stopped), (0, 1));
+        assert!(!output.join("Second.wav").exists());
+        assert!(!output.join("Third.wav").exists());
+
+        config.options.max_bytes =
+            Some(ByteSize(fs::metadata(input.join("Second.wav"))?.len() + 1));
+        let summary = run_with_progress(&config, |_| {})?;
+        assert_eq!((summary.processed, summary.deferred), (0, 1));
+        assert!(!output.join("Second.wav").exists());
+        assert!(!output.join("Third.wav").exists());
+
+        config.options.max_bytes = None;
+        assert_eq!(run_with_progress(&config, |_| {})?.processed, 2);
+        assert!(output.join("Second.wav").is_file());
+        assert!(output.join("Third.wav").is_file());
+        assert!(!staging.exists());
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn hard_linked_outputs_share_the_source_file() -> Result<()> {
+        use std::os::unix::fs::MetadataExt;
+
+        let workspace = tempdir()?;
+        let input = workspace.path().join("input");
+        let output = workspace.path().join("output");
+        fs::create_dir_all(&input)?;
+        fs::create_dir_all(&output)?;
+        let source = input.join("Song.wav");
+        write_test_wav(&source, Some("ncm:1"))?;
+        let original = fs::read(&source)?;
+        let mut config = Config {
+            inputs: vec![input],
+            output: output.clone(),
+            mode: crate::config::Mode::Original,
+            options: SyncOptions {
+                link: Some(Link::Hard),
+                ..SyncOptions::default()
+            },
+        };
+
+        assert_eq!(run_with_progress(&config, |_| {})?.processed, 1);
+        let target = output.join("Song.wav");
+        assert_eq!(fs::metadata(&target)?.ino(), fs::metadata(&source)?.ino());
+        assert_eq!(dump::inspect_output(&target)?.id, "ncm:1");
+        assert_eq!(fs::read(&source)?, original);
+        assert_eq!(run_with_progress(&config, |_| {})?.processed, 0);
+
+        // Stripping rewrites the output's tags, so it gets a copy of its own.
+        config.options.strip_metadata = Some(true);
+        config.options.force = true;
+        assert_eq!(run_with_progress(&config, |_| {})?.processed, 1);
+        assert_ne!(fs::metadata(&target)?.ino(), fs::metadata(&source)?.ino());
+        assert_eq!(fs::read(&source)?, original);
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn replaygain_never_tags_a_linked_source() -> Result<()> {
+        use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+        let workspace = tempdir()?;
+        let input = workspace.path().join("input");
+        let output = workspace.path().join("output");
+        fs::create_dir_all(&input)?;
+        fs::create_dir_all(&output)?;
+        let source = input.join("Song.wav");
+        write_test_wav(&source, Some("ncm:1"))?;
+        let original = fs::read(&source)?;
+        let ffmpeg = workspace.path().join("ffmpeg");
+        fs::write(
+            &ffmpeg,
+            "#!/bin/sh\nprintf 'Summary:\\n  I: -11.5 LUFS\\n  Peak: 0.4 dBFS\\n' >&2\n",
+        )?;
+        fs::set_permissions(&ffmpeg, fs::Permissions::from_mode(0o755))?;
+        let config = Config {
+            inputs: vec![input],
+            output: output.clone(),
+            mode: crate::config::Mode::Original,
+            options: SyncOptions {
+                link: Some(Link::Hard),
+                replaygain: Some(true),
+                ffmpeg_path: Some(ffmpeg),
+                ..SyncOptions::default()
+            },
+        };
+
+        assert_eq!(run_with_progress(&config, |_| {})?.processed, 1);
+        let target = output.join("Song.wav");
+        assert_ne!(fs::metadata(&target)?.ino(), fs::metadata(&source)?.ino());
+        assert_eq!(fs::read(&source)?, original);
+        assert_ne!(fs::read(&target)?, original);
+        Ok(())
+    }
+
+    #[test]
+    fn declining_the_plan_cancels_the_run_before_anything_is_written() -> Result<()> {
+        let workspace = tempdir()?;
+        let input = workspace.path().join("input");
+        let output = workspace.path().join("output");
+        fs::create_dir_all(&input)?;
+        fs::create_dir_all(&output)?;
+        write_test_wav(&input.join("First.wav"), Some("ncm:1"))?;
+        write_test_wav(&input.join("Second.wav"), Some("ncm:2"))?;
+        let config = Config {
+            inputs: vec![input],
+            output: output.clone(),
+            mode: crate::config::Mode::Original,
+            options: SyncOptions {
+                jobs: Some(2),
+                delete: true,
+                ..SyncOptions::default()
+            },
+        };
+        let plans = Mutex::new(Vec::new());
+        let cancel = AtomicBool::new(false);
+
+        run_with_progress_cancellable(&config, &cancel, |event| {
+            if let SyncEvent::Plan(plan) = event {
+                plans.lock().unwrap().push(plan);
+                cancel.store(true, Ordering::SeqCst);
+            }
+        })?;
+
+        let plans = plans.into_inner().unwrap();
+        assert_eq!(plans.len(), 1);
+        assert_eq!(plans[0].files, BTreeMap::from([(JobAction::Copy, 2)]));
+        assert_eq!(plans[0].workers, 2);
+        assert!(plans[0].destructive());
+        assert!(plans[0].describe().starts_with("Plan: 2 copy ("));
+        assert!(!output.join("First.wav").exists());
+        assert!(!output.join("Second.wav").exists());
+
+        let two_way = RunPlan {
+            delete: false,
+            two_way_prefer: Some(Prefer::Dest),
+            ..plans[0].clone()
+        };
+        assert!(two_way.destructive());
+        assert!(two_way.describe().contains("replaces conflicting inputs"));
+        Ok(())
+    }
+
+    #[test]
+    fn keep_going_reports_every_failure_without_failing_the_run() -> Result<()> {
+        let workspace = tempdir()?;
+        let input = workspace.path().join("input");
+        let output = workspace.path().join("output");
+        fs::create_dir_all(&input)?;
+        fs::create_dir_all(&output)?;
+        write_test_wav(&input.join("Good.wav"), Some("ncm:1"))?;
+        fs::write(input.join("Broken.flac"), b"not audio")?;
+        fs::write(input.join("Broken.mp3"), b"not audio either")?;
+        let mut config = Config {
+            inputs: vec![input],
+            output: output.clone(),
+            mode: crate::config::Mode::Original,
+            options: SyncOptions::default(),
+        };
+
+        let error = run_with_progress(&config, |_| {}).unwrap_err();
+        assert!(error.to_string().contains("2 files"));
+        assert!(matches!(error, SyncError::Failed { failed: 2, .. }));
+
+        config.options.keep_going = Some(true);
+        let summary = run_with_progress(&config, |_| {})?;
+        assert_eq!(summary.failed, 2);
+        assert_eq!(summary.errors.len(), 2);
+        assert!(output.join("Good.wav").is_file());
+        Ok(())
+    }
+
+    #[test]
+    fn move_removes_only_sources_whose_output_was_written() -> Result<()> {
+        let workspace = tempdir()?;
+        let input = workspace.path().join("input");
+        let output = workspace.path().join("output");
+        fs::create_dir_all(&input)?;
+        write_test_wav(&input.join("Good.wav"), Some("ncm:1"))?;
+        fs::write(input.join("Broken.flac"), b"not audio")?;
+        #[cfg(unix)]
+        let inode = std::os::unix::fs::MetadataExt::ino(&fs::metadata(input.join("Good.wav"))?);
+        let options = SyncOptions {
+            move_sources: true,
+            keep_going: Some(true),
+            ..SyncOptions::default()
+        };
+        let config = Config::from_paths(
+            vec![input.clone()],
+            output.clone(),
+            crate::config::Mode::Original,
+            options.clone(),
+        )?;
+
+        let summary = run_with_progress(&config, |_| {})?;
+
+        assert_eq!(summary.moved, 1);
+        assert!(output.join("Good.wav").is_file());
+        assert!(!input.join("Good.wav").exists());
+        // The source needed no new tags and shares the drive, so it was renamed into place.
+        #[cfg(unix)]
+        assert_eq!(
+            std::os::unix::fs::MetadataExt::ino(&fs::metadata(output.join("Good.wav"))?),
+            inode
+        );
+        // Sizes are recorded as the job finishes, so a moved source still has one.
+        let good = summary
+            .actions
+            .iter()
+            .find(|action| action.error.is_none())
+            .context("no action for the synced source")?;
+        let size = fs::metadata(output.join("Good.wav"))?.len();
+        assert_eq!(
+            (good.source_size, good.output_size),
+            (Some(size), Some(size))
+        );
+        assert!(input.join("Broken.flac").is_file());
+        // The manifest stamped the renamed file, so it is up to date on the next run.
+        fs::remove_file(input.join("Broken.flac"))?;
+        let summary = run_with_progress(&config, |_| {})?;
+        assert_eq!((summary.processed, summary.outputs), (0, 1));
+
+        let nested = Config::from_paths(
+            vec![workspace.path().to_path_buf()],
+            output,
+            crate::config::Mode::Original,
+            options.clone(),
+        );
+        assert!(nested.is_err());
+        let with_delete = SyncOptions {
+            delete: true,
+            ..options
+        };
+        assert!(with_delete.validate().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn json_report_is_written_even_when_files_fail() -> Result<()> {
+        let workspace = tempdir()?;
+        let input = workspace.path().join("input");
+        let output = workspace.path().join("output");
+        let report_path = workspace.path().join("report.json");
+        fs::create_dir_all(&input)?;
+        fs::create_dir_all(&output)?;
+        write_test_wav(&input.join("Good.wav"), Some("ncm:1"))?;
+        fs::write(input.join("Broken.flac"), b"not audio")?;
+        let config = Config {
+            inputs: vec![input.clone()],
+            output: output.clone(),
+            mode: crate::config::Mode::Original,
+            options: SyncOptions {
+                report: Some(report_path.clone()),
+                ..SyncOptions::default()
+            },
+        };
+
+        assert!(run_with_progress(&config, |_| {}).is_err());
+        let report: serde_json::Value = serde_json::from_slice(&fs::read(&report_path)?)?;
+        assert_eq!(report["sources"], 2);
+        assert_eq!(report["processed"], 1);
+        assert_eq!(report["failed"], 1);
+        assert_eq!(report["errors"][0]["kind"], "other");
+        assert_eq!(
+            report["errors"][0]["path"],
+            input.join("Broken.flac").to_string_lossy().as_ref()
+        );
+        assert_eq!(report["actions"][0]["action"], "copy");
+        assert_eq!(report["actions"][0]["id"], "ncm:1");
+
+        fs::remove_file(input.join("Broken.flac"))?;
+        run_with_progress(&config, |_| {})?;
+        let report: serde_json::Value = serde_json::from_slice(&fs::read(&report_path)?)?;
+        assert_eq!(report["outputs"], 1);
+        assert_eq!(report["actions"][0]["action"], "skip");
+        assert_eq!(
+            report["actions"][0]["reason"],
+            "the output is up to date and the source is unchanged"
+        );
+        assert_eq!(
+            report["actions"][0]["target"],
+            output.join("Good.wav").to_string_lossy().as_ref()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn results_table_lists_failures_first_and_hides_skips_by_default() {
+        let action = |name: &str, kind: SyncActionKind, error: Option<&str>| SyncAction {
+            id: name.to_string(),
+            action: kind,
+            source: Some(PathBuf::from(format!("/missing/in/{name}.flac"))),
+            target: PathBuf::from(format!("/missing/out/{name}.flac")),
+            source_size: error.is_none().then_some(2048),
+            output_size: (kind == SyncActionKind::Write(JobAction::Copy)).then_some(1024),
+            reason: None,
+            error: error.map(|message| SyncFailure {
+                path: PathBuf::from(format!("/missing/in/{name}.flac")),
+                kind: SyncErrorKind::Transcode,
+                message: message.to_string(),
+            }),
+        };
+        let copy = SyncActionKind::Write(JobAction::Copy);
+        let actions = [
+            action("b-copied", copy, None),
+            action("c-skipped", SyncActionKind::Skip, None),
+            action("a-copied", copy, None),
+            action(
+                "z-broken",
+                SyncActionKind::Write(JobAction::Transcode),
+                Some("invalid data"),
+            ),
+        ];
+        let render = |list_skipped| {
+            let mut table = results_table(&actions, list_skipped);
+            table.load_style(presets::NOTHING).force_no_tty();
+            table
+                .lines()
+                .skip(1)
+                .map(|line| line.split_whitespace().collect::<Vec<_>>().join(" "))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            render(false),
+            [
+                "z-broken transcode - → - failed",
+                "a-copied copy 2.0 KiB → 1.0 KiB ok",
+                "b-copied copy 2.0 KiB → 1.0 KiB ok",
+            ]
+        );
+        assert_eq!(
+            render(true).last().unwrap(),
+            "c-skipped skip 2.0 KiB → - skipped"
+        );
+    }
+
+    #[test]
+    fn skip_reasons_name_the_tolerance_or_the_better_synced_copy() {
+        let variant = |format: &str, bitrate, size| SourceVariant {
+            format: format.to_string(),
+            bitrate,
+            size,
+            payload: None,
+            hash: None,
+        };
+        let synced = variant("flac", Some(900), 10_000_000);
+
+        let grown = up_to_date_reason(&synced, &variant("flac", Some(900), 10_200_000), 0.05);
+        assert!(grown.contains("within size_tolerance (5%)"), "{grown}");
+        let worse = up_to_date_reason(&synced, &variant("mp3", Some(320), 8_000_000), 0.05);
+        assert!(worse.contains("at least as good"), "{worse}");
+        assert!(worse.contains("mp3 320 kbit/s"), "{worse}");
+    }
+
+    #[test]
+    fn resync_reasons_cover_tolerance_edges_and_missing_outputs() {
+        let variant = |size| SourceVariant {
+            format: "flac".to_string(),
+            bitrate: None,
+            size,
+            payload: None,
+            hash: None,
+        };
+        let entry = |size| ManifestEntry {
+            id: "ncm:1".to_string(),
+            output: PathBuf::from("Song.flac"),
+            profile: "original-v1".to_string(),
+            source: variant(size),
+            stamp: None,
+            original: None,
+        };
+        let options = SyncOptions::default();
+        let output = Some(Path::new("/out/Song.flac"));
+        let reason = |source: u64, previous: Option<&ManifestEntry>, output, options| {
+            resync_reason(
+                &variant(source),
+                previous,
+                output,
+                "original-v1",
+                options,
+                |_| panic!("tags are only read with compare = \"tags\""),
+            )
+        };
+        let synced = entry(1_000_000);
+
+        assert_eq!(reason(1_000_000, Some(&synced), output, &options), None);
+        assert_eq!(reason(1_050_000, Some(&synced), output, &options), None);
+        assert_eq!(
+            reason(1_050_001, Some(&synced), output, &options),
+            Some("better source variant")
+        );
+        assert_eq!(reason(0, Some(&entry(0)), output, &options), None);
+        assert_eq!(
+            reason(1, Some(&entry(0)), output, &options),
+            Some("better source variant")
+        );
+        assert_eq!(
+            reason(1_000_000, Some(&synced), None, &options),
+            Some("no output yet")
+        );
+        assert_eq!(
+            reason(1_000_000, None, output, &options),
+            Some("output is not tracked")
+        );
+        let forced = SyncOptions {
+            force: true,
+            ..SyncOptions::default()
+        };
+        assert_eq!(
+            reason(1_000_000, Some(&synced), output, &forced),
+            Some("--force")
+        );
+        let reencoded = resync_reason(
+            &variant(1_000_000),
+            Some(&synced),
+            output,
+            "mp3-q2-v1",
+            &options,
+            |_| false,
+        );
+        assert_eq!(reencoded, Some("output profile changed"));
+        let by_tags = SyncOptions {
+            compare: Some(Compare::Tags),
+            ..SyncOptions::default()
+        };
+        let retagged = resync_reason(
+            &variant(1_000_000),
+            Some(&synced),
+            output,
+            "original-v1",
+            &by_tags,
+            |_| true,
+        );
+        assert_eq!(retagged, Some("tags differ from the output"));
+    }
+
+    #[test]
+    fn qmc_and_kgm_files_are_decrypted_and_unknown_ciphers_fail_per_file() -> Result<()> {
+        let workspace = tempdir()?;
+        let input = workspace.path().join("input");
+        let output = workspace.path().join("output");
+        fs::create_dir_all(&input)?;
+        fs::create_dir_all(&output)?;
+        let plain = workspace.path().join("plain.wav");
+        write_test_wav(&plain, Some("ncm:1"))?;
+        // The QMC cipher is a XOR stream, so decrypting plain audio encrypts it.
+        let encrypted = ncmdump::QmcDump::from_reader(fs::File::open(&plain)?)?.get_data()?;
+        fs::write(input.join("Song.qmc0"), encrypted)?;
+        let plain = workspace.path().join("kugou.wav");
+        write_test_wav(&plain, Some("ncm:2"))?;
+        let mut tag = id3::Tag::read_from_path(&plain)?;
+        tag.set_title("Kugou Song");
+        tag.write_to_path(&plain, Version::Id3v24)?;
+        let encrypted = crate::kgm::encrypt(&fs::read(&plain)?, *b"0123456789abcdef")?;
+        fs::write(input.join("Kugou.kgm"), encrypted)?;
+        fs::write(input.join("Other.mflac"), b"locked")?;
+        let config = Config {
+            inputs: vec![input],
+            output: output.clone(),
+            mode: crate::config::Mode::Original,
+            options: SyncOptions {
+                keep_going: Some(true),
+                ..SyncOptions::default()
+            },
+        };
+
+        let summary = run_with_progress(&config, |_| {})?;
+
+        assert_eq!(summary.processed, 2);
+        assert_eq!(summary.failed, 1);
+        assert!(summary.errors[0].message.contains(".mflac"));
+        assert!(
+            summary
+                .actions
+                .iter()
+                .filter(|action| action.error.is_none())
+                .all(|action| action.action == SyncActionKind::Write(JobAction::Decrypt))
+        );
+        for (song, title) in [("Song.wav", "Same Song"), ("Kugou.wav", "Kugou Song")] {
+            let song = output.join(song);
+            assert_eq!(fs::read(&song)?[..4], *b"RIFF");
+            assert_eq!(id3::Tag::read_from_path(&song)?.title(), Some(title));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn embedded_lyrics_are_exported_next_to_the_output() -> Result<()> {
+        let workspace = tempdir()?;
+        let input = workspace.path().join("input");
+        let output = workspace.path().join("output");
+        fs::create_dir_all(&input)?;
+        fs::create_dir_all(&output)?;
+        let song = input.join("Song.wav");
+        write_test_wav(&song, Some("ncm:1"))?;
+        let mut tag = id3::Tag::read_from_path(&song)?;
+        tag.add_frame(id3::frame::Lyrics {
+            lang: "chi".to_string(),
+            description: String::new(),
+            text: "[00:01.00]First line".to_string(),
+        });
+        tag.write_to_path(&song, Version::Id3v24)?;
+        write_test_wav(&input.join("Instrumental.wav"), Some("ncm:2"))?;
+        let mut config = Config {
+            inputs: vec![input],
+            output: output.clone(),
+            mode: crate::config::Mode::Original,
+            options: SyncOptions {
+                export_lyrics: Some(true),
+                ..SyncOptions::default()
+            },
+        };
+
+        run_with_progress(&config, |_| {})?;
+
+        assert_eq!(
+            fs::read_to_string(output.join("Song.lrc"))?,
+            "[00:01.00]First line"
+        );
+        assert!(!output.join("Instrumental.lrc").exists());
+
+        fs::remove_file(&song)?;
+        config.options.delete = true;
+        assert_eq!(run_with_progress(&config, |_| {})?.removed, 1);
+        assert!(!output.join("Song.lrc").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn superseded_outputs_take_their_lyrics_with_them() -> Result<()> {
+        let workspace = tempdir()?;
+        let old_output = workspace.path().join("Song.wav");
+        write_test_wav(&old_output, Some("ncm:1"))?;
+        fs::write(workspace.path().join("Song.lrc"), "[00:01.00]First line")?;
+        let mut job = Job {
+            target: workspace.path().join("Song.mp3"),
+            source: source("ncm:1"),
+            old_output: Some(old_output.clone()),
+            mode: Mode::Original,
+            ffmpeg: None,
+            transcode_slots: None,
+            output_dirs: Arc::default(),
+            original: None,
+            staging: None,
+            move_source: false,
+        };
+
+        // An output in a new format shares its lyrics with the one it replaces.
+        dump::remove_superseded(&job, &SyncOptions::default());
+        assert!(!old_output.exists());
+        assert!(workspace.path().join("Song.lrc").is_file());
+
+        write_test_wav(&old_output, Some("ncm:1"))?;
+        job.target = workspace.path().join("Song [ncm-1].wav");
+        dump::remove_superseded(&job, &SyncOptions::default());
+        assert!(!old_output.exists());
+        assert!(!workspace.path().join("Song.lrc").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn album_covers_are_saved_once_per_output_folder() -> Result<()> {
+        let workspace = tempdir()?;
+        let input = workspace.path().join("input");
+        let output = workspace.path().join("output");
+        fs::create_dir_all(input.join("Album"))?;
+        fs::create_dir_all(&output)?;
+        let cover = b"\xFF\xD8\xFF\xE0 album cover".to_vec();
+        for (name, id) in [("One.wav", "ncm:1"), ("Two.wav", "ncm:2")] {
+            let song = input.join("Album").join(name);
+            write_test_wav(&song, Some(id))?;
+            let mut tag = id3::Tag::read_from_path(&song)?;
+            tag.add_frame(id3::frame::Picture {
+                mime_type: "image/jpeg".to_string(),
+                picture_type: id3::frame::PictureType::CoverFront,
+                description: String::new(),
+                data: cover.clone(),
+            });
+            tag.write_to_path(&song, Version::Id3v24)?;
+        }
+        let mut config = Config {
+            inputs: vec![input],
+            output: output.clone(),
+            mode: crate::config::Mode::Original,
+            options: SyncOptions {
+                extract_cover: Some(true),
+                ..SyncOptions::default()
+            },
+        };
+        assert!(config.options.validate().is_err());
+        config.options.preserve_structure = Some(true);
+
+        run_with_progress(&config, |_| {})?;
+
+        assert_eq!(fs::read(output.join("Album").join("cover.jpg"))?, cover);
+        let leftovers = fs::read_dir(output.join("Album"))?
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| is_temporary(&entry.path()))
+            .count();
+        assert_eq!(leftovers, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn stripped_outputs_keep_only_the_track_id() -> Result<()> {
+        let workspace = tempdir()?;
+        let input = workspace.path().join("input");
+        let output = workspace.path().join("output");
+        fs::create_dir_all(&input)?;
+        fs::create_dir_all(&output)?;
+        let song = input.join("Song.wav");
+        write_test_wav(&song, Some("ncm:1"))?;
+        let mut tag = id3::Tag::read_from_path(&song)?;
+        tag.add_frame(id3::frame::Comment {
+            lang: "eng".to_string(),
+            description: String::new(),
+            text: "Purchased by someone".to_string(),
+        });
+        tag.add_frame(id3::frame::Picture {
+            mime_type: "image/jpeg".to_string(),
+            picture_type: id3::frame::PictureType::CoverFront,
+            description: String::new(),
+            data: b"\xFF\xD8\xFF\xE0 cover".to_vec(),
+        });
+        tag.write_to_path(&song, Version::Id3v24)?;
+        let mut config = Config {
+            inputs: vec![input],
+            output: output.clone(),
+            mode: crate::config::Mode::Original,
+            options: SyncOptions {
+                strip_metadata: Some(true),
+                max_cover_size: Some(300),
+                ..SyncOptions::default()
+            },
+        };
+        assert!(config.options.validate().is_err());
+        config.options.max_cover_size = None;
+        config.options.compare = Some(Compare::Tags);
+        assert!(config.options.validate().is_err());
+        config.options.compare = None;
+
+        assert_eq!(run_with_progress(&config, |_| {})?.processed, 1);
+        let stripped = id3::Tag::read_from_path(output.join("Song.wav"))?;
+        assert_eq!(stripped.title(), None);
+        assert_eq!(stripped.comments().count(), 0);
+        assert_eq!(stripped.pictures().count(), 0);
+        assert_eq!(dump::inspect_output(&output.join("Song.wav"))?.id, "ncm:1");
+        assert_eq!(run_with_progress(&config, |_| {})?.skipped, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn only_inputs_in_the_same_format_count_as_the_same_song() -> Result<()> {
+        let workspace = tempdir()?;
+        let master = workspace.path().join("Song.flac");
+        fs::write(&master, b"lossless")?;
+        fs::write(workspace.path().join("Song.lrc"), b"lyrics")?;
+
+        assert!(same_song_inputs(&workspace.path().join("Song.mp3"))?.is_empty());
+        assert_eq!(same_song_inputs(&master)?, vec![master]);
+        Ok(())
+    }
+
+    #[test]
+    fn two_way_imports_device_files_and_reports_conflicts() -> Result<()> {
+        let workspace = tempdir()?;
+        let input = workspace.path().join("input");
+        let output = workspace.path().join("output");
+        fs::create_dir_all(input.join("Live"))?;
+        fs::create_dir_all(output.join("Live"))?;
+        write_test_wav(&input.join("Song.wav"), Some("ncm:1"))?;
+        write_test_wav(&input.join("Live").join("Take.wav"), Some("ncm:2"))?;
+        let mut config = Config {
+            inputs: vec![input.clone()],
+            output: output.clone(),
+            mode: crate::config::Mode::Original,
+            options: SyncOptions::default(),
+        };
+        run_with_progress(&config, |_| {})?;
+
+        write_test_wav(&output.join("Added.wav"), None)?;
+        write_test_wav(&output.join("Live").join("Take.wav"), None)?;
+        let added = fs::read(output.join("Added.wav"))?;
+        let device_take = fs::read(output.join("Live").join("Take.wav"))?;
+        config.options.prefer = Some(Prefer::Dest);
+        assert!(config.options.validate().is_err());
+        config.options.two_way = true;
+        config.options.prefer = None;
+
+        let summary = run_with_progress(&config, |_| {})?;
+        assert_eq!(summary.imported, 1);
+        assert_eq!(summary.conflicts, 1);
+        assert_eq!(fs::read(input.join("Added.wav"))?, added);
+        let take = input.join("Live").join("Take.wav");
+        assert_ne!(fs::read(&take)?, device_take);
+
+        config.options.prefer = Some(Prefer::Dest);
+        let summary = run_with_progress(&config, |_| {})?;
+        assert_eq!((summary.imported, summary.conflicts), (1, 0));
+        assert_eq!(fs::read(&take)?, device_take);
+        let summary = run_with_progress(&config, |_| {})?;
+        assert_eq!((summary.imported, summary.conflicts), (0, 0));
+        Ok(())
+    }
+
+    #[test]
+    fn playlists_list_outputs_relative_to_the_playlist() -> Result<()> {
+        let workspace = tempdir()?;
+        let input = workspace.path().join("input");
+        let output = workspace.path().join("output");
+        fs::create_dir_all(&input)?;
+        fs::create_dir_all(&output)?;
+        write_test_wav(&input.join("Old.wav"), Some("ncm:1"))?;
+        let playlist = workspace.path().join("lists").join("library.m3u8");
+        fs::create_dir_all(playlist.parent().unwrap())?;
+        let mut config = Config {
+            inputs: vec![input.clone()],
+            output: output.clone(),
+            mode: crate::config::Mode::Original,
+            options: SyncOptions {
+                playlist: Some(playlist.clone()),
+                ..SyncOptions::default()
+            },
+        };
+        run_with_progress(&config, |_| {})?;
+        assert_eq!(
+            fs::read_to_string(&playlist)?,
+            "#EXTM3U\n../output/Old.wav\n"
+        );
+
+        write_test_wav(&input.join("New.wav"), Some("ncm:2"))?;
+        config.options.playlist_new_only = Some(true);
+        run_with_progress(&config, |_| {})?;
+        assert_eq!(
+            fs::read_to_string(&playlist)?,
+            "#EXTM3U\n../output/New.wav\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn only_transient_io_errors_are_retried() {
+        let io = |error: anyhow::Error| SyncError::Io {
+            song: "Song".to_string(),
+            source: error.into(),
+        };
+        let timeout = io(anyhow::Error::new(io::Error::from(io::ErrorKind::TimedOut))
+            .context("failed to copy Song.flac"));
+        let missing = io(anyhow::Error::new(io::Error::from(io::ErrorKind::NotFound)));
+        let corrupt = io(anyhow::Error::new(io::Error::from(
+            io::ErrorKind::InvalidData,
+        )));
+        assert!(is_transient(&timeout));
+        assert!(!is_transient(&missing));
+        assert!(!is_transient(&corrupt));
+        assert!(!is_transient(&io(anyhow::anyhow!("FFmpeg failed"))));
+        assert!(!is_transient(&SyncError::Cancelled));
+    }
+
+    #[test]
+    fn common_lossy_containers_are_scanned() {
+        for name in ["a.m4a", "b.OGG", "c.opus", "d.wav"] {
+            assert!(is_supported(Path::new(name)), "{name}");
+        }
+        assert!(!is_supported(Path::new("cover.jpg")));
+        assert!(!is_supported(Path::new("notes.txt")));
+    }
+
+    #[test]
+    fn input_root_prefers_the_most_specific_input() {
+        let inputs = vec![
+            PathBuf::from("/music"),
+            PathBuf::from("/music/bandcamp"),
+            PathBuf::from("/mnt/external"),
+        ];
+        assert_eq!(
+            input_root(&inputs, Path::new("/music/bandcamp/a.flac")),
+            Some(Path::new("/music/bandcamp"))
+        );
+        assert_eq!(
+            input_root(&inputs, Path::new("/music/netease/a.ncm")),
+            Some(Path::new("/music"))
+        );
+        assert_eq!(input_root(&inputs, Path::new("/tmp/a.mp3")), None);
+    }
+
+    #[test]
+    fn zip_archives_are_synced_without_manual_extraction() -> Result<()> {
+        let workspace = tempdir()?;
+        let input = workspace.path().join("input");
+        let output = workspace.path().join("output");
+        fs::create_dir_all(&input)?;
+        fs::create_dir_all(&output)?;
+        let track = workspace.path().join("Zipped Track.wav");
+        write_test_wav(&track, Some("ncm:7"))?;
+
+        let mut zip = zip::ZipWriter::new(fs::File::create(input.join("Album.zip"))?);
+        let options = zip::write::SimpleFileOptions::default();
+        zip.start_file("Album/Zipped Track.wav", options)?;
+        zip.write_all(&fs::read(&track)?)?;
+        zip.start_file("Album/cover.jpg", options)?;
+        zip.write_all(b"not audio")?;
+        zip.start_file("../Escaped.wav", options)?;
+        zip.write_all(&fs::read(&track)?)?;
+        zip.start_file("Album/Skipped Track.wav", options)?;
+        zip.write_all(&fs::read(&track)?)?;
+        zip.finish()?;
+
+        let cache = workspace.path().join("cache");
+        let config = Config {
+            inputs: vec![input],
+            output: output.clone(),
+            mode: crate::config::Mode::Original,
+            options: SyncOptions {
+                exclude: vec!["Album.zip/**/Skipped*".to_string()],
+                archive_cache: Some(cache.clone()),
+                ..SyncOptions::default()
+            },
+        };
+        let summary = run_with_progress(&config, |_| {})?;
+
+        assert_eq!(summary.processed, 1);
+        assert!(output.join("Zipped Track.wav").is_file());
+        assert!(!output.join("Skipped Track.wav").exists());
+        assert!(!workspace.path().join("Escaped.wav").exists());
+
+        // The unchanged archive is not extracted again.
+        let extracted = WalkDir::new(&cache)
+            .into_iter()
+            .filter_map(Result::ok)
+            .find(|entry| entry.file_name() == "Zipped Track.wav")
+            .context("the archive entry was not cached")?
+            .into_path();
+        let stamp = OutputStamp::of(&extracted);
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(run_with_progress(&config, |_| {})?.skipped, 1);
+        assert_eq!(OutputStamp::of(&extracted), stamp);
+        Ok(())
+    }
+
+    fn source(id: &str) -> SourceItem {
+        SourceItem {
+            path: PathBuf::from("source.ncm"),
+            id: id.to_string(),
+            fallback_id: "meta:v1:test".to_string(),
+            display_name: "Song".to_string(),
+            variant: SourceVariant {
+                format: "flac".to_string(),
+                bitrate: Some(900),
+                size: 100,
+                payload: None,
+                hash: None,
+            },
+            tags: SourceTags::default(),
+        }
+    }
+
+    #[test]
+    fn duplicate_groups_rank_by_format_then_bitrate_then_size() {
+        let copy = |id: &str, format: &str, bitrate: Option<u64>, size: u64| {
+            let mut source = source(id);
+            source.path = PathBuf::from(format!("{id}.{format}"));
+            source.variant.format = format.to_string();
+            source.variant.bitrate = bitrate;
+            source.variant.size = size;
+            source.tags = SourceTags {
+                artist: Some("Artist".to_string()),
+                title: Some("Song".to_string()),
+                ..SourceTags::default()
+            };
+            (id.to_string(), source)
+        };
+        // Sizes and bitrates disagree, and one copy has no bitrate at all.
+        let sources = BTreeMap::from([
+            copy("a", "flac", None, 500),
+            copy("b", "flac", Some(900), 100),
+            copy("c", "flac", Some(800), 900),
+            copy("d", "mp3", Some(320), 2000),
+        ]);
+
+        assert_eq!(
+            duplicate_groups(&sources, DedupeKey::Tags),
+            vec![vec!["b", "c", "a", "d"]]
+        );
+    }
+
+    #[test]
+    fn mp3_mode_copies_sources_at_or_below_the_bitrate_threshold() {
+        let mut config = Config {
+            inputs: Vec::new(),
+            output: PathBuf::from("output"),
+            mode: Mode::Mp3,
+            options: SyncOptions {
+                transcode_above_bitrate: Some(Bitrate(256)),
+                ..SyncOptions::default()
+            },
+        };
+        let mut small = source("ncm:1");
+        small.variant.format = "mp3".to_string();
+        small.variant.bitrate = Some(128);
+        let mut large = small.clone();
+        large.variant.bitrate = Some(320);
+        let lossless = source("ncm:2");
+
+        assert_eq!(source_mode(&config, &small), Mode::Original);
+        assert_eq!(source_mode(&config, &large), Mode::Mp3);
+        assert_eq!(source_mode(&config, &lossless), Mode::Mp3);
+        config.mode = Mode::Wav;
+        assert_eq!(source_mode(&config, &small), Mode::Wav);
+    }
+
+    #[test]
+    fn runs_that_cannot_fit_are_refused_before_writing() -> Result<()> {
+        let workspace = tempdir()?;
+        let mut config = Config {
+            inputs: Vec::new(),
+            output: workspace.path().join("output"),
+            mode: Mode::Original,
+            options: SyncOptions::default(),
+        };
+        let mut huge = source("ncm:1");
+        huge.variant.size = 1 << 60;
+        let job = Job {
+            target: config.output.join("Song.flac"),
+            source: huge,
+            old_output: None,
+            mode: Mode::Wav,
+            ffmpeg: None,
+            transcode_slots: None,
+            output_dirs: Arc::default(),
+            original: None,
+            staging: None,
+            move_source: false,
+        };
+        assert_eq!(estimated_output_size(&job), 1 << 61);
+
+        let error = check_free_space(&config, std::slice::from_ref(&job)).unwrap_err();
+        assert!(error.to_string().contains("not enough free space"));
+        config.options.dry_run = true;
+        check_free_space(&config, std::slice::from_ref(&job))?;
+        config.options.dry_run = false;
+        config.options.max_bytes = Some(ByteSize(1));
+        check_free_space(&config, &[job])?;
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(any(windows, target_os = "macos"))]
+    fn different_ids_receive_stable_filename_suffixes() {
+        let mut claims = HashMap::new();
+        claims.insert("song.mp3".to_string(), "ncm:1".to_string());
+        let target = reserve_target(PathBuf::from("Song.mp3"), &source("ncm:2"), &mut claims);
+        assert_eq!(target, PathBuf::from("Song [ncm-2].mp3"));
+    }
+
+    #[test]
+    fn manifest_paths_cannot_escape_the_output_directory() {
+        assert!(safe_relative_path(Path::new("artist/song.mp3")));
+        assert!(!safe_relative_path(Path::new("../song.mp3")));
+        if cfg!(windows) {
+            assert!(!safe_relative_path(Path::new("C:/song.mp3")));
+        }
     }
 
     #[test]
@@ -812,6 +5296,7 @@ mod tests {
             inputs: vec![input],
             output: output.clone(),
             mode: crate::config::Mode::Original,
+            options: SyncOptions::default(),
         };
 
         run(&config)?;
@@ -852,6 +5337,7 @@ mod tests {
             inputs: vec![first, second],
             output: output.clone(),
             mode: crate::config::Mode::Original,
+            options: SyncOptions::default(),
         };
 
         run(&config)?;
@@ -879,6 +5365,7 @@ mod tests {
             inputs: vec![first, second],
             output: output.clone(),
             mode: crate::config::Mode::Original,
+            options: SyncOptions::default(),
         };
 
         run(&config)?;
@@ -0,0 +1,118 @@
+use std::path::Path;
+use std::sync::mpsc;
+use std::time::Duration;
+
+use anyhow::{Context, Result, bail};
+use notify::{Event, EventKind, RecursiveMode, Watcher};
+
+use crate::config::Config;
+use crate::sync;
+
+/// Quiet period after the last filesystem event before a sync starts, so that a burst of
+/// writes from a download or a copy results in a single run.
+const DEBOUNCE: Duration = Duration::from_secs(2);
+
+/// Runs an initial sync, then re-syncs whenever audio inside the inputs changes.
+///
+/// Every run goes through the regular incremental pipeline, so only new or changed songs
+/// are processed. A failed run is reported and the watch continues.
+pub fn run(config: &Config) -> Result<()> {
+    if let Err(error) = sync::run(config) {
+        log::error!("{error:#}");
+    }
+    if sync::stop_requested() {
+        return Ok(());
+    }
+
+    let (sender, events) = mpsc::channel();
+    let mut watcher =
+        notify::recommended_watcher(sender).context("failed to start the filesystem watcher")?;
+    for input in &config.inputs {
+        let mode = if input.is_dir() {
+            RecursiveMode::Recursive
+        } else {
+            RecursiveMode::NonRecursive
+        };
+        watcher
+            .watch(input, mode)
+            .with_context(|| format!("failed to watch {}", input.display()))?;
+    }
+    log::info!(
+        "Watching {} inputs for changes; press Ctrl+C to stop.",
+        config.inputs.len()
+    );
+
+    loop {
+        let event = events
+            .recv()
+            .context("the filesystem watcher stopped unexpectedly")?;
+        if !triggers_sync(&event, &config.output) {
+            continue;
+        }
+        // Keep absorbing events until the inputs have been quiet for a full debounce period.
+        loop {
+            match events.recv_timeout(DEBOUNCE) {
+                Ok(_) => {}
+                Err(mpsc::RecvTimeoutError::Timeout) => break,
+                Err(mpsc::RecvTimeoutError::Disconnected) => {
+                    bail!("the filesystem watcher stopped unexpectedly")
+                }
+            }
+        }
+        if let Err(error) = sync::run(config) {
+            log::error!("{error:#}");
+        }
+        if sync::stop_requested() {
+            return Ok(());
+        }
+    }
+}
+
+/// Whether an event touches a syncable file that is not one of w4dj's own outputs.
+fn triggers_sync(event: &notify::Result<Event>, output: &Path) -> bool {
+    let Ok(event) = event else {
+        return false;
+    };
+    if matches!(event.kind, EventKind::Access(_)) {
+        return false;
+    }
+    event.paths.iter().any(|path| {
+        !path.starts_with(output)
+            && !sync::is_temporary(path)
+            && (sync::is_supported(path) || sync::is_archive(path))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use notify::event::{AccessKind, CreateKind};
+
+    use super::*;
+
+    fn event(kind: EventKind, path: &str) -> notify::Result<Event> {
+        Ok(Event::new(kind).add_path(PathBuf::from(path)))
+    }
+
+    #[test]
+    fn only_new_audio_outside_the_output_triggers_a_sync() {
+        let output = Path::new("/library/out");
+        let create = EventKind::Create(CreateKind::File);
+        assert!(triggers_sync(&event(create, "/music/Song.ncm"), output));
+        assert!(triggers_sync(&event(create, "/music/Album.zip"), output));
+        assert!(!triggers_sync(&event(create, "/music/cover.jpg"), output));
+        assert!(!triggers_sync(
+            &event(create, "/library/out/Song.mp3"),
+            output
+        ));
+        assert!(!triggers_sync(
+            &event(create, "/music/.w4dj-part.mp3"),
+            output
+        ));
+        assert!(!triggers_sync(
+            &event(EventKind::Access(AccessKind::Any), "/music/Song.ncm"),
+            output
+        ));
+    }
+}